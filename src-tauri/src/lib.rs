@@ -5,7 +5,7 @@ use std::collections::HashMap;
 use std::path::Path;
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex, OnceLock};
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::time::Duration;
 use std::sync::mpsc::{Receiver, Sender, channel, RecvTimeoutError};
 use std::thread;
@@ -130,10 +130,7 @@ fn save_project_canvas_to_disk_blocking(app: &tauri::AppHandle, project_id: Stri
 
   let path = canvas_store_path(app, &project_id)?;
   let bytes = serde_json::to_vec(&canvas).map_err(|e| e.to_string())?;
-  let tmp = path.with_extension(format!("json.tmp.{}", std::process::id()));
-  std::fs::write(&tmp, bytes).map_err(|e| e.to_string())?;
-  std::fs::rename(&tmp, &path).map_err(|e| e.to_string())?;
-  Ok(())
+  write_bytes_atomic(&path, &bytes)
 }
 
 fn sanitize_extension(ext: &str) -> Option<String> {
@@ -245,14 +242,154 @@ struct MemoryItem {
   #[serde(default)]
   importance: f32,
   #[serde(default)]
+  created_at: i64,
+  #[serde(default)]
   updated_at: i64,
+  #[serde(default)]
+  project_id: String, // 空 = 全局记忆
+  #[serde(default)]
+  tags: Vec<String>,
+  #[serde(default)]
+  source: String,
+  #[serde(default)]
+  use_count: u32, // 被检索并用于对话的次数
+  #[serde(default)]
+  last_used_at: i64, // 0 = 从未被使用
+  #[serde(default)]
+  decayed_at: i64, // 上次衰减结算的时间
+  #[serde(default)]
+  pin: Option<f32>, // 置顶优先级 0..1（越高越先保留）；None = 未置顶。置顶条目不衰减、不淘汰
 }
 
+// 对内只用纯文本 content + 图片列表；对外（serde）兼容 OpenAI：content 为字符串或 parts 数组
 #[derive(serde::Deserialize, serde::Serialize, Clone, Debug, Default)]
-#[serde(rename_all = "camelCase")]
+#[serde(from = "WireChatMessage", into = "WireChatMessage")]
 struct ChatMessage {
   role: String,
   content: String,
+  images: Vec<ChatImage>,
+  tool_calls: Vec<ToolCall>,    // assistant 发起的函数调用
+  tool_call_id: Option<String>, // role = tool 时对应的调用 id
+  name: Option<String>,
+  pin: Option<f32>, // 置顶优先级；构建上下文时移入置顶段，不随历史窗口和压缩丢失
+}
+
+impl ChatMessage {
+  fn text(role: &str, content: String) -> Self {
+    Self { role: role.to_string(), content, ..Default::default() }
+  }
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, Default)]
+struct ToolCall {
+  #[serde(default)]
+  id: String,
+  #[serde(rename = "type", default = "default_tool_call_type")]
+  kind: String,
+  #[serde(default)]
+  function: ToolCallFunction,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, Default)]
+struct ToolCallFunction {
+  #[serde(default)]
+  name: String,
+  #[serde(default)]
+  arguments: String, // JSON 字符串，原样透传
+}
+
+fn default_tool_call_type() -> String {
+  "function".to_string()
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+struct ChatImage {
+  url: String, // http(s) / data: URL / 本地缓存路径（file://、asset:// 或绝对路径）
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  detail: Option<String>, // auto | low | high
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ContentPart {
+  Text { text: String },
+  ImageUrl { image_url: ChatImage },
+  #[serde(other)]
+  Unsupported,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+#[serde(untagged)]
+enum WireContent {
+  Text(String),
+  Parts(Vec<ContentPart>),
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+struct WireChatMessage {
+  #[serde(default)]
+  role: String,
+  #[serde(default)]
+  content: Option<WireContent>,
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  tool_calls: Vec<ToolCall>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  tool_call_id: Option<String>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  name: Option<String>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pin: Option<f32>, // 非 OpenAI 字段，只在本地往返；chat_stream 发出前清掉
+}
+
+impl From<WireChatMessage> for ChatMessage {
+  fn from(wire: WireChatMessage) -> Self {
+    let mut texts: Vec<String> = vec![];
+    let mut images: Vec<ChatImage> = vec![];
+    match wire.content {
+      Some(WireContent::Text(t)) => texts.push(t),
+      Some(WireContent::Parts(parts)) => {
+        for part in parts {
+          match part {
+            ContentPart::Text { text } => texts.push(text),
+            ContentPart::ImageUrl { image_url } if !image_url.url.trim().is_empty() => images.push(image_url),
+            _ => {}
+          }
+        }
+      }
+      None => {}
+    }
+    Self {
+      role: wire.role,
+      content: texts.join("\n"),
+      images,
+      tool_calls: wire.tool_calls,
+      tool_call_id: wire.tool_call_id.filter(|id| !id.is_empty()),
+      name: wire.name.filter(|n| !n.is_empty()),
+      pin: wire.pin.filter(|p| p.is_finite()).map(|p| p.clamp(0.0, 1.0)),
+    }
+  }
+}
+
+impl From<ChatMessage> for WireChatMessage {
+  fn from(m: ChatMessage) -> Self {
+    let content = if m.images.is_empty() {
+      // 只有函数调用的 assistant 消息，content 按 OpenAI 约定为 null
+      if m.content.is_empty() && !m.tool_calls.is_empty() {
+        None
+      } else {
+        Some(WireContent::Text(m.content))
+      }
+    } else {
+      let mut parts: Vec<ContentPart> = vec![];
+      if !m.content.is_empty() {
+        parts.push(ContentPart::Text { text: m.content });
+      }
+      parts.extend(m.images.into_iter().map(|image_url| ContentPart::ImageUrl { image_url }));
+      Some(WireContent::Parts(parts))
+    };
+    Self { role: m.role, content, tool_calls: m.tool_calls, tool_call_id: m.tool_call_id, name: m.name, pin: m.pin }
+  }
 }
 
 #[derive(serde::Deserialize, Clone, Debug, Default)]
@@ -262,6 +399,10 @@ struct GraphNode {
   #[serde(rename = "type")]
   node_type: String,
   #[serde(default)]
+  x: f64,
+  #[serde(default)]
+  y: f64,
+  #[serde(default)]
   data: Value,
 }
 
@@ -281,6 +422,7 @@ struct UpstreamTextBlock {
   label: String,
   text: String,
   target: String,
+  truncated: bool,
 }
 
 #[derive(serde::Serialize, Clone, Debug, Default)]
@@ -291,6 +433,7 @@ struct UpstreamImageBlock {
   role: String,
   url: String,
   target: String,
+  truncated: bool,
 }
 
 #[derive(serde::Serialize, Clone, Debug, Default)]
@@ -298,6 +441,8 @@ struct UpstreamImageBlock {
 struct UpstreamInputs {
   text: Vec<UpstreamTextBlock>,
   images: Vec<UpstreamImageBlock>,
+  truncated: Vec<String>, // 被截断的节点 id
+  dropped: Vec<String>,   // 超出总预算被整块丢弃的节点 id
 }
 
 fn normalize_text(text: &str) -> String {
@@ -328,68 +473,172 @@ fn value_string(value: Option<&Value>, key: &str) -> String {
   normalize_text(v)
 }
 
-fn is_cjk(ch: char) -> bool {
-  ('\u{4E00}'..='\u{9FFF}').contains(&ch)
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum TokenScript {
+  Han,    // 汉字（含扩展区/兼容区）与日文假名：连写无空格，统一按二元组切分
+  Hangul, // 韩文音节/字母
+  Word,   // 拉丁/西里尔等以空格分词的文字与数字
+  Other,  // 标点、空白、符号：作为分隔符
+}
+
+fn is_han(ch: char) -> bool {
+  matches!(ch,
+    '\u{3400}'..='\u{4DBF}'     // Extension A
+    | '\u{4E00}'..='\u{9FFF}'   // URO
+    | '\u{F900}'..='\u{FAFF}'   // Compatibility Ideographs
+    | '\u{20000}'..='\u{2A6DF}' // Extension B
+    | '\u{2A700}'..='\u{2EBEF}' // Extension C–F
+    | '\u{2F800}'..='\u{2FA1F}' // Compatibility Supplement
+    | '\u{30000}'..='\u{323AF}' // Extension G–H
+    | '\u{3005}' | '\u{3007}'     // 々 〇
+  )
+}
+
+fn is_kana(ch: char) -> bool {
+  matches!(ch, '\u{3040}'..='\u{309F}' | '\u{30A0}'..='\u{30FF}' | '\u{31F0}'..='\u{31FF}')
+}
+
+fn is_hangul(ch: char) -> bool {
+  matches!(ch, '\u{AC00}'..='\u{D7AF}' | '\u{1100}'..='\u{11FF}' | '\u{3130}'..='\u{318F}' | '\u{A960}'..='\u{A97F}' | '\u{D7B0}'..='\u{D7FF}')
 }
 
+fn token_script(ch: char) -> TokenScript {
+  if is_han(ch) || is_kana(ch) {
+    TokenScript::Han
+  } else if is_hangul(ch) {
+    TokenScript::Hangul
+  } else if ch.is_alphanumeric() {
+    TokenScript::Word
+  } else {
+    TokenScript::Other
+  }
+}
+
+// 连续 CJK 片段输出重叠二元组（“主角穿红衣” → 主角/角穿/穿红/红衣），单字片段保留单字
+fn push_cjk_bigrams(run: &[char], tokens: &mut Vec<String>) {
+  if run.len() == 1 {
+    tokens.push(run[0].to_string());
+    return;
+  }
+  for pair in run.windows(2) {
+    tokens.push(pair.iter().collect());
+  }
+}
+
+// 搜索/索引共用分词：NFKC 统一全角/半角，CJK 按二元组，其余文字按词
 fn tokenize(text: &str) -> Vec<String> {
-  let t = normalize_text(text).to_lowercase();
+  tokenize_with(text, false)
+}
+
+// 建索引用：多字 CJK 片段额外输出单字，单字查询（如“猫”）也能命中“小猫咪”
+fn tokenize_for_index(text: &str) -> Vec<String> {
+  tokenize_with(text, true)
+}
+
+fn tokenize_with(text: &str, cjk_unigrams: bool) -> Vec<String> {
+  use unicode_normalization::UnicodeNormalization;
+
+  let t: String = normalize_text(text).nfkc().collect::<String>().to_lowercase();
   if t.is_empty() {
     return vec![];
   }
 
   let mut tokens: Vec<String> = vec![];
-  let mut buf = String::new();
+  let mut run: Vec<char> = vec![];
+  let mut run_script = TokenScript::Other;
+
   for ch in t.chars() {
-    if is_cjk(ch) {
-      if !buf.is_empty() {
-        for part in buf.split(|c: char| !(c.is_ascii_alphanumeric())) {
-          if !part.is_empty() {
-            tokens.push(part.to_string());
-          }
-        }
-        buf.clear();
-      }
-      tokens.push(ch.to_string());
-      continue;
+    let script = token_script(ch);
+    // 组合附加符号（如越南文声调）跟随前一个字
+    let script = if script == TokenScript::Other && !run.is_empty() && is_combining_mark(ch) { run_script } else { script };
+    if script != run_script && !run.is_empty() {
+      flush_token_run(&mut run, run_script, cjk_unigrams, &mut tokens);
     }
-    buf.push(ch);
-  }
-  if !buf.is_empty() {
-    for part in buf.split(|c: char| !(c.is_ascii_alphanumeric())) {
-      if !part.is_empty() {
-        tokens.push(part.to_string());
-      }
+    run_script = script;
+    if script != TokenScript::Other {
+      run.push(ch);
     }
   }
+  if !run.is_empty() {
+    flush_token_run(&mut run, run_script, cjk_unigrams, &mut tokens);
+  }
   tokens
 }
 
-fn score_match(query: &str, doc: &str) -> f32 {
-  let q = tokenize(query);
-  let d = tokenize(doc);
-  if q.is_empty() || d.is_empty() {
-    return 0.0;
+fn flush_token_run(run: &mut Vec<char>, script: TokenScript, cjk_unigrams: bool, tokens: &mut Vec<String>) {
+  match script {
+    TokenScript::Han | TokenScript::Hangul => {
+      push_cjk_bigrams(run, tokens);
+      if cjk_unigrams && run.len() > 1 {
+        tokens.extend(run.iter().map(|c| c.to_string()));
+      }
+    }
+    TokenScript::Word => tokens.push(run.iter().collect()),
+    TokenScript::Other => {}
   }
-  let qset: std::collections::HashSet<String> = q.into_iter().collect();
-  let dset: std::collections::HashSet<String> = d.into_iter().collect();
-  let mut hit = 0.0;
-  for tok in qset.iter() {
-    if dset.contains(tok) {
-      hit += 1.0;
+  run.clear();
+}
+
+fn is_combining_mark(ch: char) -> bool {
+  matches!(ch, '\u{0300}'..='\u{036F}' | '\u{1AB0}'..='\u{1AFF}' | '\u{1DC0}'..='\u{1DFF}' | '\u{20D0}'..='\u{20FF}')
+}
+
+#[derive(serde::Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct UpstreamCollectOptions {
+  #[serde(default)]
+  max_text_chars: i64,
+  #[serde(default)]
+  max_url_chars: i64,
+  #[serde(default)]
+  max_total_chars: i64, // 0 = 不限制
+  #[serde(default)]
+  order: String, // edge | position | priority
+}
+
+fn slice_with_flag(text: &str, max_chars: usize) -> (String, bool) {
+  let t = normalize_text(text);
+  let truncated = t.chars().count() > max_chars;
+  (safe_slice(&t, max_chars), truncated)
+}
+
+// 显式优先级：edge.data.priority，其次 promptOrder / imageOrder（画布上的连线序号）
+fn edge_priority(edge: &GraphEdge, order_key: &str) -> Option<i64> {
+  let data = edge.data.as_ref()?;
+  for key in ["priority", order_key] {
+    if let Some(v) = data.get(key) {
+      if let Some(n) = v.as_i64().or_else(|| v.as_f64().map(|f| f.round() as i64)) {
+        return Some(n);
+      }
     }
   }
-  let denom = ((qset.len() as f32) * (dset.len() as f32)).sqrt().max(1.0);
-  hit / denom
+  None
+}
+
+struct UpstreamCandidate<'a> {
+  edge_index: usize,
+  edge: &'a GraphEdge,
+  src: &'a GraphNode,
+  target: String,
 }
 
 #[tauri::command(rename_all = "camelCase")]
-fn graph_collect_upstream_inputs(focus_node_id: String, nodes: Vec<GraphNode>, edges: Vec<GraphEdge>) -> UpstreamInputs {
+fn graph_collect_upstream_inputs(
+  focus_node_id: String,
+  nodes: Vec<GraphNode>,
+  edges: Vec<GraphEdge>,
+  options: Option<UpstreamCollectOptions>,
+) -> UpstreamInputs {
   let focus_id = focus_node_id.trim().to_string();
   if focus_id.is_empty() {
     return UpstreamInputs::default();
   }
 
+  let opts = options.unwrap_or_default();
+  let max_text_chars = clamp_i64(if opts.max_text_chars > 0 { opts.max_text_chars } else { 520 }, 16, 20000) as usize;
+  let max_url_chars = clamp_i64(if opts.max_url_chars > 0 { opts.max_url_chars } else { 240 }, 16, 4000) as usize;
+  let mut total_left = if opts.max_total_chars > 0 { opts.max_total_chars as usize } else { usize::MAX };
+
   let mut node_by_id: HashMap<String, GraphNode> = HashMap::new();
   for n in nodes.into_iter() {
     if !n.id.trim().is_empty() {
@@ -400,272 +649,5233 @@ fn graph_collect_upstream_inputs(focus_node_id: String, nodes: Vec<GraphNode>, e
     return UpstreamInputs::default();
   }
 
-  let mut incoming: HashMap<String, Vec<GraphEdge>> = HashMap::new();
-  let mut outgoing: HashMap<String, Vec<GraphEdge>> = HashMap::new();
-  for e in edges.into_iter() {
-    if e.source.trim().is_empty() || e.target.trim().is_empty() {
-      continue;
+  let edges: Vec<GraphEdge> = edges
+    .into_iter()
+    .filter(|e| !e.source.trim().is_empty() && !e.target.trim().is_empty())
+    .collect();
+
+  // 配置节点按连线顺序去重，保证多次调用结果一致
+  let mut config_targets: Vec<String> = vec![];
+  for e in edges.iter().filter(|e| e.source == focus_id) {
+    if let Some(t) = node_by_id.get(&e.target) {
+      if (t.node_type == "imageConfig" || t.node_type == "videoConfig") && !config_targets.contains(&t.id) {
+        config_targets.push(t.id.clone());
+      }
     }
-    incoming.entry(e.target.clone()).or_default().push(e.clone());
-    outgoing.entry(e.source.clone()).or_default().push(e);
   }
 
-  let mut config_targets: Vec<String> = vec![];
-  if let Some(out) = outgoing.get(&focus_id) {
-    for e in out.iter() {
-      if let Some(t) = node_by_id.get(&e.target) {
-        if t.node_type == "imageConfig" || t.node_type == "videoConfig" {
-          config_targets.push(t.id.clone());
-        }
+  let mut text_candidates: Vec<UpstreamCandidate> = vec![];
+  let mut image_candidates: Vec<UpstreamCandidate> = vec![];
+  for cfg_id in config_targets.iter() {
+    for (edge_index, e) in edges.iter().enumerate().filter(|(_, e)| &e.target == cfg_id) {
+      let Some(src) = node_by_id.get(&e.source) else { continue };
+      let candidate = UpstreamCandidate { edge_index, edge: e, src, target: cfg_id.clone() };
+      if src.node_type == "text" && src.id != focus_id {
+        text_candidates.push(candidate);
+      } else if src.node_type == "image" {
+        image_candidates.push(candidate);
       }
     }
   }
 
-  let mut out_text: Vec<UpstreamTextBlock> = vec![];
-  let mut out_images: Vec<UpstreamImageBlock> = vec![];
+  let order = opts.order.trim().to_ascii_lowercase();
+  let sort_candidates = |list: &mut Vec<UpstreamCandidate>, order_key: &str| match order.as_str() {
+    "position" => list.sort_by(|a, b| {
+      a.src
+        .y
+        .total_cmp(&b.src.y)
+        .then(a.src.x.total_cmp(&b.src.x))
+        .then(a.edge_index.cmp(&b.edge_index))
+    }),
+    "priority" => list.sort_by_key(|c| {
+      let p = edge_priority(c.edge, order_key);
+      (p.is_none(), p.unwrap_or(0), c.edge_index)
+    }),
+    _ => list.sort_by_key(|c| c.edge_index),
+  };
+  sort_candidates(&mut text_candidates, "promptOrder");
+  sort_candidates(&mut image_candidates, "imageOrder");
+
+  let mut out = UpstreamInputs::default();
   let mut seen_text: std::collections::HashSet<String> = std::collections::HashSet::new();
   let mut seen_image: std::collections::HashSet<String> = std::collections::HashSet::new();
 
-  for cfg_id in config_targets.iter() {
-    let in_edges = incoming.get(cfg_id).cloned().unwrap_or_default();
-    for e in in_edges.iter() {
-      if let Some(src) = node_by_id.get(&e.source) {
-        if src.node_type == "text" {
-          if src.id == focus_id {
-            continue;
-          }
-          if seen_text.contains(&src.id) {
-            continue;
-          }
-          let content = value_string(Some(&src.data), "content");
-          if content.is_empty() {
-            continue;
-          }
-          let label = value_string(Some(&src.data), "label");
-          out_text.push(UpstreamTextBlock {
-            id: src.id.clone(),
-            label: if label.is_empty() { "文本节点".to_string() } else { label },
-            text: safe_slice(&content, 520),
-            target: cfg_id.clone(),
-          });
-          seen_text.insert(src.id.clone());
-        } else if src.node_type == "image" {
-          if seen_image.contains(&src.id) {
-            continue;
-          }
-          let label = value_string(Some(&src.data), "label");
-          let url = value_string(Some(&src.data), "url");
-          let role = value_string(e.data.as_ref(), "imageRole");
-          out_images.push(UpstreamImageBlock {
-            id: src.id.clone(),
-            label: if label.is_empty() { "参考图".to_string() } else { label },
-            role: if role.is_empty() { "input_reference".to_string() } else { role },
-            url: if url.starts_with("data:") { "".to_string() } else { safe_slice(&url, 240) },
-            target: cfg_id.clone(),
-          });
-          seen_image.insert(src.id.clone());
-        }
-      }
+  for c in text_candidates.iter() {
+    if seen_text.contains(&c.src.id) {
+      continue;
+    }
+    let content = value_string(Some(&c.src.data), "content");
+    if content.is_empty() {
+      continue;
+    }
+    seen_text.insert(c.src.id.clone());
+    if total_left == 0 {
+      out.dropped.push(c.src.id.clone());
+      continue;
+    }
+    let (text, mut truncated) = slice_with_flag(&content, max_text_chars);
+    // 省略号也计入总预算：截到 total_left - 1 再补省略号
+    let text = if text.chars().count() > total_left {
+      truncated = true;
+      safe_slice(&text, total_left - 1)
+    } else {
+      text
+    };
+    total_left = total_left.saturating_sub(text.chars().count());
+    if truncated {
+      out.truncated.push(c.src.id.clone());
+    }
+    let label = value_string(Some(&c.src.data), "label");
+    out.text.push(UpstreamTextBlock {
+      id: c.src.id.clone(),
+      label: if label.is_empty() { "文本节点".to_string() } else { label },
+      text,
+      target: c.target.clone(),
+      truncated,
+    });
+  }
+
+  for c in image_candidates.iter() {
+    if seen_image.contains(&c.src.id) {
+      continue;
+    }
+    seen_image.insert(c.src.id.clone());
+    let raw_url = value_string(Some(&c.src.data), "url");
+    let is_data_url = raw_url.starts_with("data:");
+    // 预算用完后的图片与文本一样整块丢弃（没有 URL 或 data URL 的也算），不留空 URL 的占位
+    if total_left == 0 {
+      out.dropped.push(c.src.id.clone());
+      continue;
+    }
+    let label = value_string(Some(&c.src.data), "label");
+    let role = value_string(c.edge.data.as_ref(), "imageRole");
+    let (url, truncated) = if is_data_url {
+      (String::new(), false)
+    } else {
+      let (u, t) = slice_with_flag(&raw_url, max_url_chars.min(total_left));
+      let u = if u.chars().count() > total_left { safe_slice(&u, total_left - 1) } else { u };
+      total_left = total_left.saturating_sub(u.chars().count());
+      (u, t)
+    };
+    if truncated {
+      out.truncated.push(c.src.id.clone());
     }
+    out.images.push(UpstreamImageBlock {
+      id: c.src.id.clone(),
+      label: if label.is_empty() { "参考图".to_string() } else { label },
+      role: if role.is_empty() { "input_reference".to_string() } else { role },
+      url,
+      target: c.target.clone(),
+      truncated,
+    });
   }
 
-  UpstreamInputs { text: out_text, images: out_images }
+  out
 }
 
-#[tauri::command(rename_all = "camelCase")]
-fn search_memory(query: String, items: Vec<MemoryItem>, limit: Option<usize>, min_score: Option<f32>) -> Vec<MemoryItem> {
-  let q = normalize_text(&query);
-  if q.is_empty() {
-    return vec![];
-  }
-  let limit = limit.unwrap_or(6).max(1);
-  let min_score = min_score.unwrap_or(0.12);
-  let now = std::time::SystemTime::now()
-    .duration_since(std::time::UNIX_EPOCH)
-    .map(|d| d.as_millis() as i64)
-    .unwrap_or(0);
+// ======== Model capabilities (mirrors src/config/models.js) ========
 
-  let mut scored: Vec<(f32, MemoryItem)> = items
-    .into_iter()
-    .map(|mut item| {
-      item.content = normalize_text(&item.content);
-      let base = score_match(&q, &item.content);
-      let importance = item.importance.clamp(0.0, 1.0);
-      let recency_days = if item.updated_at > 0 {
-        ((now - item.updated_at) as f32) / (1000.0 * 60.0 * 60.0 * 24.0)
-      } else {
-        30.0
-      };
-      let recency = (recency_days / 30.0).clamp(0.0, 1.0);
-      let recency_boost = 1.0 - recency;
-      let score = base * 0.7 + importance * 0.2 + recency_boost * 0.1;
-      (score, item)
-    })
-    .filter(|(s, _)| *s >= min_score)
-    .collect();
+const DEFAULT_IMAGE_MODEL: &str = "gemini-3-pro-image-preview";
+const DEFAULT_VIDEO_MODEL: &str = "sora-2-all";
 
-  scored.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(std::cmp::Ordering::Equal));
-  scored.into_iter().take(limit).map(|(_, i)| i).collect()
-}
+const IMAGE_ROLE_FIRST_FRAME: &str = "first_frame_image";
+const IMAGE_ROLE_LAST_FRAME: &str = "last_frame_image";
+const IMAGE_ROLE_REFERENCE: &str = "input_reference";
 
-#[derive(serde::Deserialize, Clone, Debug, Default)]
+#[derive(serde::Serialize, Clone, Debug)]
 #[serde(rename_all = "camelCase")]
-struct ContextConfig {
-  #[serde(default)]
-  max_chars: i64,
-  #[serde(default)]
-  max_history: i64,
-  #[serde(default)]
-  max_memory_items: i64,
-  #[serde(default)]
-  max_canvas_chars: i64,
-  #[serde(default)]
-  max_memory_chars: i64,
-  #[serde(default)]
-  max_summary_chars: i64,
-}
-
-fn clamp_i64(n: i64, a: i64, b: i64) -> i64 {
-  n.max(a).min(b)
+struct ModelCaps {
+  model_key: &'static str,
+  label: &'static str,
+  kind: &'static str, // image | video
+  requires_prompt: bool,
+  supports_first_frame: bool,
+  supports_last_frame: bool,
+  supports_reference_images: bool,
+  max_ref_images: u32,
+  max_images: u32, // 0 = 不限制总数
+  requires_reference_images: bool,
+  requires_first_frame_if_last_frame: bool,
 }
 
-fn estimate_chars(messages: &[ChatMessage]) -> usize {
-  messages.iter().map(|m| m.content.len()).sum()
+const fn image_caps(
+  model_key: &'static str,
+  label: &'static str,
+  requires_prompt: bool,
+  supports_reference_images: bool,
+  max_ref_images: u32,
+  requires_reference_images: bool,
+) -> ModelCaps {
+  ModelCaps {
+    model_key,
+    label,
+    kind: "image",
+    requires_prompt,
+    supports_first_frame: false,
+    supports_last_frame: false,
+    supports_reference_images,
+    max_ref_images,
+    max_images: 0,
+    requires_reference_images,
+    requires_first_frame_if_last_frame: false,
+  }
 }
 
-fn compact_lines(lines: &[String], max_chars: usize) -> String {
-  let mut out: Vec<String> = vec![];
-  let mut used = 0usize;
-  for line in lines {
-    let t = normalize_text(line);
-    if t.is_empty() {
-      continue;
-    }
-    if used + t.len() > max_chars {
-      break;
-    }
-    used += t.len();
-    out.push(t);
+#[allow(clippy::too_many_arguments)]
+const fn video_caps(
+  model_key: &'static str,
+  label: &'static str,
+  requires_prompt: bool,
+  supports_first_frame: bool,
+  supports_last_frame: bool,
+  supports_reference_images: bool,
+  max_ref_images: u32,
+  max_images: u32,
+  requires_reference_images: bool,
+) -> ModelCaps {
+  ModelCaps {
+    model_key,
+    label,
+    kind: "video",
+    requires_prompt,
+    supports_first_frame,
+    supports_last_frame,
+    supports_reference_images,
+    max_ref_images,
+    max_images,
+    requires_reference_images,
+    requires_first_frame_if_last_frame: false,
   }
-  out.join("\n")
 }
 
-#[tauri::command(rename_all = "camelCase")]
-fn build_chat_messages(
-  user_text: String,
-  system_prompt: String,
-  conversation: Vec<ChatMessage>,
-  memory_summary: String,
-  memory_items: Vec<MemoryItem>,
-  canvas_context: String,
-  config: Option<ContextConfig>,
-) -> Vec<ChatMessage> {
-  let user_query = normalize_text(&user_text);
-  let sys = normalize_text(&system_prompt);
-
-  let cfg = config.unwrap_or_default();
-  let max_chars = clamp_i64(if cfg.max_chars > 0 { cfg.max_chars } else { 12000 }, 2000, 50000) as usize;
-  let max_history = clamp_i64(if cfg.max_history > 0 { cfg.max_history } else { 16 }, 4, 64) as usize;
-  let max_memory_items = clamp_i64(if cfg.max_memory_items > 0 { cfg.max_memory_items } else { 6 }, 0, 30) as usize;
-  let max_canvas_chars = clamp_i64(if cfg.max_canvas_chars > 0 { cfg.max_canvas_chars } else { 1200 }, 0, 8000) as usize;
-  let max_memory_chars = clamp_i64(if cfg.max_memory_chars > 0 { cfg.max_memory_chars } else { 1200 }, 0, 8000) as usize;
-  let max_summary_chars = clamp_i64(if cfg.max_summary_chars > 0 { cfg.max_summary_chars } else { 600 }, 0, 4000) as usize;
+// 与 config/models.js 保持同步：新增/调整模型时两边一起改
+static MODEL_CAPS: &[ModelCaps] = &[
+  image_caps("gemini-3-pro-image-preview", "nano-banana-pro", false, true, 14, false),
+  image_caps("doubao-seedream-4-5-251128", "豆包 Seedream 4.5（doubao-seedream-4-5-251128）", true, true, 1, false),
+  image_caps("grok-4-image", "Grok 4 Image（Chat Completions）", true, false, 0, false),
+  image_caps("kling-image", "Kling Image（kling-v2-1）", true, true, 1, false),
+  image_caps("kling-omni-image", "Kling Omni-Image（kling-image-o1）", true, true, 6, false),
+  image_caps("flux-pro-1.1-ultra", "Flux Pro 1.1 Ultra", true, false, 0, false),
+  image_caps("gpt-image-1.5-all", "GPT Image 1.5 (All)", true, false, 0, false),
+  image_caps("qwen-image-max", "通义千问生图 (Chat)", true, false, 0, false),
+  image_caps("qwen-image-edit-2509", "通义千问编辑", true, true, 1, true),
+  image_caps("qwen-edit-multiple-angles", "Qwen 多角度转换（Multiple-angles）", false, true, 1, true),
+  image_caps("aigc-image-gem", "Tencent AIGC Gem", true, false, 0, false),
+  image_caps("aigc-image-qwen", "Tencent AIGC Qwen", true, false, 0, false),
+  video_caps("veo3.1-fast-components", "Veo 3.1 Fast Components ¥0.10", true, true, false, false, 0, 1, false),
+  video_caps("veo_3_1-components", "Veo 3.1 Components ¥0.27", true, true, false, false, 0, 1, false),
+  video_caps("veo3.1-4k", "Veo 3.1 4K ¥0.85", true, true, true, false, 0, 2, false),
+  video_caps("veo3.1-pro-4k", "Veo 3.1 Pro 4K ¥2.99", true, true, true, false, 0, 2, false),
+  video_caps("kling-video", "Kling 视频（kling-v2-6 · pro）", false, true, true, true, 1, 2, false),
+  video_caps("kling-v1-std", "Kling 视频（kling-v1 · std）", false, true, true, true, 1, 2, false),
+  video_caps("kling-v1-pro", "Kling 视频（kling-v1 · pro）", false, true, true, true, 1, 2, false),
+  video_caps("kling-v1-5-std", "Kling 视频（kling-v1-5 · std）", false, true, true, true, 1, 2, false),
+  video_caps("kling-v1-5-pro", "Kling 视频（kling-v1-5 · pro）", false, true, true, true, 1, 2, false),
+  video_caps("kling-v1-6-std", "Kling 视频（kling-v1-6 · std）", false, true, true, true, 1, 2, false),
+  video_caps("kling-v1-6-pro", "Kling 视频（kling-v1-6 · pro）", false, true, true, true, 1, 2, false),
+  video_caps("kling-v2-master-std", "Kling 视频（kling-v2-master · std）", false, true, true, true, 1, 2, false),
+  video_caps("kling-v2-1-std", "Kling 视频（kling-v2-1 · std）", false, true, true, true, 1, 2, false),
+  video_caps("kling-v2-1-pro", "Kling 视频（kling-v2-1 · pro）", false, true, true, true, 1, 2, false),
+  video_caps("kling-v2-1-master", "Kling 视频（kling-v2-1-master）", false, true, true, true, 1, 2, false),
+  video_caps("kling-v2-5-turbo-std", "Kling 视频（kling-v2-5-turbo · std）", false, true, true, true, 1, 2, false),
+  video_caps("kling-v2-5-turbo-pro", "Kling 视频（kling-v2-5-turbo · pro）", false, true, true, true, 1, 2, false),
+  video_caps("kling-v2-6-pro-silent", "Kling 视频（kling-v2-6 · pro · 无音频）", false, true, true, true, 1, 2, false),
+  video_caps("kling-v2-6-pro-sound", "Kling 视频（kling-v2-6 · pro · 有音频）", false, true, true, true, 1, 2, false),
+  video_caps("kling-multi-image2video", "Kling 多图参考生视频（kling-v1-6）", true, false, false, true, 4, 4, false),
+  video_caps("doubao-seedance-1-5-pro-251215", "seedance-1-5-pro（doubao-seedance-1-5-pro-251215）", true, true, true, false, 0, 2, false),
+  video_caps("wan2.6-i2v", "wan2.6-i2v（通义万象）", false, true, false, false, 0, 1, false),
+  video_caps("MiniMax-Hailuo-2.3", "MiniMax-Hailuo-2.3（海螺）", false, true, true, false, 0, 2, false),
+  video_caps("MiniMax-Hailuo-2.3-Fast", "MiniMax-Hailuo-2.3-Fast（海螺）", false, true, true, false, 0, 2, false),
+  ModelCaps {
+    requires_first_frame_if_last_frame: true,
+    ..video_caps("kling-omni-video", "kling-omni-video", true, true, true, true, 6, 6, false)
+  },
+  video_caps("luma_video_api", "luma_video_api", true, false, false, false, 0, 0, false),
+  video_caps("runwayml-gen3a_turbo-10", "runwayml-gen3a_turbo-10", false, true, false, false, 0, 0, false),
+  video_caps("sora-2-all", "Sora 2 All ¥0.07", true, true, true, false, 0, 2, false),
+  video_caps("sora-2", "Sora 2 (OpenAI官方格式) ¥0.37/4s", true, true, false, false, 0, 1, false),
+  video_caps("grok-video-3", "Grok Video 3 (6s)", true, false, false, true, 3, 3, true),
+  video_caps("grok-video-3-10s", "Grok Video 3 (10s)", true, false, false, true, 3, 3, true),
+];
+
+// 旧项目里保存的模型 key → 新 key（同 MODEL_ALIASES）
+static MODEL_ALIASES: &[(&str, &str)] = &[
+  ("gpt-image-1.5", "gpt-image-1.5-all"),
+  ("veo3.1-fast", "veo3.1-4k"),
+  ("veo3.1-pro", "veo3.1-pro-4k"),
+  ("veo_3_1-fast-components-4K", "veo3.1-fast-components"),
+  ("sora-2-pro", "sora-2-all"),
+  ("aigc-video-vidu", "sora-2-all"),
+  ("vidu-q2-turbo", "sora-2-all"),
+  ("vidu-q2", "sora-2-all"),
+  ("vidu-q2-pro", "sora-2-all"),
+  ("aigc-video-hailuo", "MiniMax-Hailuo-2.3-Fast"),
+  ("hailuo-2.3-fast", "MiniMax-Hailuo-2.3-Fast"),
+  ("hailuo-2.3", "MiniMax-Hailuo-2.3"),
+  ("hailuo-02", "MiniMax-Hailuo-2.3"),
+  ("kling-2.5", "kling-video"),
+  ("kling-2.1", "kling-video"),
+  ("kling-2.0", "kling-video"),
+  ("kling-1.6", "kling-video"),
+  ("kling-o1", "kling-video"),
+];
+
+fn find_model_caps(model_key: &str) -> Option<&'static ModelCaps> {
+  let key = model_key.trim();
+  let resolved = MODEL_ALIASES
+    .iter()
+    .find(|(from, _)| *from == key)
+    .map(|(_, to)| *to)
+    .unwrap_or(key);
+  MODEL_CAPS.iter().find(|c| c.model_key == resolved)
+}
 
-  let mut out: Vec<ChatMessage> = vec![];
-  if !sys.is_empty() {
-    out.push(ChatMessage { role: "system".to_string(), content: sys.clone() });
+// 按配置节点类型解析模型；未知 key 回落到该类默认模型（同 getImageModelConfig / getVideoModelConfig）
+fn resolve_model_caps(kind: &str, model_key: &str) -> (&'static ModelCaps, bool) {
+  if let Some(caps) = find_model_caps(model_key) {
+    if caps.kind == kind {
+      return (caps, true);
+    }
   }
+  let fallback = if kind == "video" { DEFAULT_VIDEO_MODEL } else { DEFAULT_IMAGE_MODEL };
+  let caps = MODEL_CAPS
+    .iter()
+    .find(|c| c.model_key == fallback)
+    .unwrap_or(&MODEL_CAPS[0]);
+  (caps, false)
+}
 
-  let mem_summary = normalize_text(&memory_summary);
-  if !mem_summary.is_empty() && max_summary_chars > 0 {
-    out.push(ChatMessage {
-      role: "system".to_string(),
-      content: format!("【长期记忆摘要】\n{}", mem_summary.chars().take(max_summary_chars).collect::<String>()),
-    });
+impl ModelCaps {
+  // 与 getVideoModelCaps 一致：未显式标注 maxRefImages 时保守回退到 maxImages
+  fn effective_max_ref_images(&self) -> u32 {
+    if !self.supports_reference_images {
+      return 0;
+    }
+    if self.max_ref_images == 0 && self.max_images > 0 {
+      return self.max_images;
+    }
+    self.max_ref_images
   }
 
-  if !memory_items.is_empty() && max_memory_items > 0 && max_memory_chars > 0 {
-    let mut lines: Vec<String> = vec![];
-    for m in memory_items.iter().take(max_memory_items) {
-      let c = normalize_text(&m.content);
-      if c.is_empty() {
-        continue;
-      }
-      let snippet: String = c.chars().take(260).collect();
-      lines.push(format!("- {}", snippet));
-    }
-    let packed = compact_lines(&lines, max_memory_chars);
-    if !packed.is_empty() {
-      out.push(ChatMessage {
-        role: "system".to_string(),
-        content: format!("【长期记忆（检索命中）】\n{}", packed),
-      });
+  fn allows_image_role(&self, role: &str) -> bool {
+    match role {
+      IMAGE_ROLE_FIRST_FRAME => self.supports_first_frame,
+      IMAGE_ROLE_LAST_FRAME => self.supports_last_frame,
+      IMAGE_ROLE_REFERENCE => self.supports_reference_images,
+      _ => false,
     }
   }
+}
 
-  let canvas = normalize_text(&canvas_context);
-  if !canvas.is_empty() && max_canvas_chars > 0 {
-    out.push(ChatMessage {
-      role: "system".to_string(),
-      content: format!("【当前项目上下文】\n{}", canvas.chars().take(max_canvas_chars).collect::<String>()),
-    });
+#[derive(serde::Serialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct ConfigInputIssue {
+  code: String,
+  message: String,
+  node_ids: Vec<String>,
+}
+
+#[derive(serde::Serialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct ConfigInputValidation {
+  ok: bool,
+  config_node_id: String,
+  model_key: String,
+  model_label: String,
+  prompt_count: usize,
+  image_count: usize,
+  errors: Vec<ConfigInputIssue>,
+  warnings: Vec<ConfigInputIssue>,
+}
+
+fn config_issue(code: &str, message: String, node_ids: Vec<String>) -> ConfigInputIssue {
+  ConfigInputIssue { code: code.to_string(), message, node_ids }
+}
+
+#[tauri::command(rename_all = "camelCase")]
+fn graph_validate_config_inputs(
+  config_node_id: String,
+  nodes: Vec<GraphNode>,
+  edges: Vec<GraphEdge>,
+  model_key: Option<String>,
+) -> Result<ConfigInputValidation, String> {
+  let cfg_id = config_node_id.trim().to_string();
+  if cfg_id.is_empty() {
+    return Err("configNodeId 不能为空".to_string());
   }
 
-  // history: keep last max_history, keep order
-  let filtered: Vec<ChatMessage> = conversation
+  let node_by_id: HashMap<String, GraphNode> = nodes
     .into_iter()
-    .filter(|m| !m.role.is_empty() && m.role != "system" && !normalize_text(&m.content).is_empty())
+    .filter(|n| !n.id.trim().is_empty())
+    .map(|n| (n.id.clone(), n))
     .collect();
-  let start = filtered.len().saturating_sub(max_history);
-  for m in filtered.iter().skip(start) {
-    out.push(ChatMessage { role: m.role.clone(), content: normalize_text(&m.content) });
-  }
-
-  out.push(ChatMessage { role: "user".to_string(), content: user_query.clone() });
+  let cfg = node_by_id.get(&cfg_id).ok_or_else(|| format!("配置节点不存在：{cfg_id}"))?;
+  let kind = match cfg.node_type.as_str() {
+    "imageConfig" => "image",
+    "videoConfig" => "video",
+    other => return Err(format!("节点 {cfg_id} 不是配置节点（type={other}）")),
+  };
 
-  // compress if exceed budget
-  if estimate_chars(&out) <= max_chars {
-    return out;
+  let requested = model_key
+    .map(|k| k.trim().to_string())
+    .filter(|k| !k.is_empty())
+    .unwrap_or_else(|| value_string(Some(&cfg.data), "model"));
+  let (caps, known) = resolve_model_caps(kind, &requested);
+
+  let mut errors: Vec<ConfigInputIssue> = vec![];
+  let mut warnings: Vec<ConfigInputIssue> = vec![];
+  if !requested.is_empty() && !known {
+    warnings.push(config_issue(
+      "unknown_model",
+      format!("未知模型「{requested}」，已按默认模型「{}」校验", caps.label),
+      vec![],
+    ));
   }
 
-  // fallback: keep fewer history
-  let keep_history = clamp_i64((max_history as i64) / 2, 2, max_history as i64) as usize;
-  let start2 = filtered.len().saturating_sub(keep_history);
-  let mut base: Vec<ChatMessage> = vec![];
-  if !sys.is_empty() {
-    base.push(ChatMessage { role: "system".to_string(), content: sys.clone() });
-  }
-  if !mem_summary.is_empty() && max_summary_chars > 0 {
-    base.push(ChatMessage {
-      role: "system".to_string(),
-      content: format!("【长期记忆摘要】\n{}", mem_summary.chars().take(max_summary_chars.min(360)).collect::<String>()),
-    });
-  }
-  if !canvas.is_empty() {
-    base.push(ChatMessage {
-      role: "system".to_string(),
-      content: format!("【当前项目上下文】\n{}", canvas.chars().take(360).collect::<String>()),
-    });
-  }
-  for m in filtered.iter().skip(start2) {
-    base.push(ChatMessage { role: m.role.clone(), content: normalize_text(&m.content) });
+  let mut prompt_nodes: Vec<String> = vec![];
+  // (node id, role)
+  let mut images: Vec<(String, String)> = vec![];
+  let mut seen: std::collections::HashSet<String> = std::collections::HashSet::new();
+  for e in edges.iter().filter(|e| e.target == cfg_id) {
+    let Some(src) = node_by_id.get(&e.source) else { continue };
+    if !seen.insert(src.id.clone()) {
+      continue;
+    }
+    if src.node_type == "text" {
+      if !value_string(Some(&src.data), "content").is_empty() {
+        prompt_nodes.push(src.id.clone());
+      }
+    } else if src.node_type == "image" {
+      let has_image = !value_string(Some(&src.data), "url").is_empty() || !value_string(Some(&src.data), "base64").is_empty();
+      if !has_image {
+        warnings.push(config_issue("image_missing", "上游图片节点还没有图片，已忽略".to_string(), vec![src.id.clone()]));
+        continue;
+      }
+      let role = value_string(e.data.as_ref(), "imageRole");
+      let role = if !role.is_empty() {
+        role
+      } else if kind == "video" {
+        // 与 VideoConfigNode 一致：未标注角色默认首帧
+        IMAGE_ROLE_FIRST_FRAME.to_string()
+      } else {
+        IMAGE_ROLE_REFERENCE.to_string()
+      };
+      images.push((src.id.clone(), role));
+    }
   }
-  base.push(ChatMessage { role: "user".to_string(), content: user_query.clone() });
 
-  if estimate_chars(&base) <= max_chars {
-    return base;
-  }
+  let image_ids = |pred: &dyn Fn(&str) -> bool| -> Vec<String> {
+    images.iter().filter(|(_, r)| pred(r)).map(|(id, _)| id.clone()).collect()
+  };
 
-  // final: system + user
-  let mut minimal: Vec<ChatMessage> = vec![];
-  if !sys.is_empty() {
-    minimal.push(ChatMessage { role: "system".to_string(), content: sys });
+  if prompt_nodes.is_empty() {
+    if caps.requires_prompt {
+      errors.push(config_issue("prompt_required", format!("「{}」需要提示词，请连接文本节点", caps.label), vec![]));
+    } else if images.is_empty() {
+      errors.push(config_issue("empty_input", "请连接提示词或参考图".to_string(), vec![]));
+    }
   }
-  minimal.push(ChatMessage { role: "user".to_string(), content: user_query });
-  minimal
-}
 
-#[tauri::command(rename_all = "camelCase")]
+  if kind == "image" {
+    let all = image_ids(&|_| true);
+    let max_ref = caps.effective_max_ref_images() as usize;
+    if caps.requires_reference_images && all.is_empty() {
+      errors.push(config_issue("reference_required", format!("「{}」需要至少 1 张参考图", caps.label), vec![]));
+    }
+    if !all.is_empty() && !caps.supports_reference_images {
+      errors.push(config_issue("reference_not_supported", format!("「{}」不支持参考图，请断开图片连线", caps.label), all));
+    } else if all.len() > max_ref {
+      errors.push(config_issue(
+        "too_many_images",
+        format!("「{}」最多支持 {} 张参考图，当前 {} 张", caps.label, max_ref, all.len()),
+        all,
+      ));
+    }
+  } else {
+    let invalid = image_ids(&|r| r != IMAGE_ROLE_FIRST_FRAME && r != IMAGE_ROLE_LAST_FRAME && r != IMAGE_ROLE_REFERENCE);
+    if !invalid.is_empty() {
+      errors.push(config_issue(
+        "invalid_role",
+        format!("图片角色无效，仅支持 {IMAGE_ROLE_FIRST_FRAME} / {IMAGE_ROLE_LAST_FRAME} / {IMAGE_ROLE_REFERENCE}"),
+        invalid,
+      ));
+    }
+
+    let first = image_ids(&|r| r == IMAGE_ROLE_FIRST_FRAME);
+    let last = image_ids(&|r| r == IMAGE_ROLE_LAST_FRAME);
+    let refs = image_ids(&|r| r == IMAGE_ROLE_REFERENCE);
+    for (role, ids, name) in [
+      (IMAGE_ROLE_FIRST_FRAME, &first, "首帧"),
+      (IMAGE_ROLE_LAST_FRAME, &last, "尾帧"),
+      (IMAGE_ROLE_REFERENCE, &refs, "参考图"),
+    ] {
+      if ids.is_empty() {
+        continue;
+      }
+      if !caps.allows_image_role(role) {
+        errors.push(config_issue("role_not_supported", format!("「{}」不支持{}", caps.label, name), ids.clone()));
+      } else if role != IMAGE_ROLE_REFERENCE && ids.len() > 1 {
+        errors.push(config_issue("duplicate_role", format!("{}只能连接 1 张图片，当前 {} 张", name, ids.len()), ids.clone()));
+      }
+    }
+
+    if caps.requires_reference_images && refs.is_empty() {
+      errors.push(config_issue("reference_required", format!("「{}」需要至少 1 张参考图", caps.label), vec![]));
+    }
+    let max_ref = caps.effective_max_ref_images() as usize;
+    if caps.supports_reference_images && refs.len() > max_ref {
+      errors.push(config_issue(
+        "too_many_images",
+        format!("「{}」最多支持 {} 张参考图，当前 {} 张", caps.label, max_ref, refs.len()),
+        refs.clone(),
+      ));
+    }
+    let valid_count = first.len() + last.len() + refs.len();
+    if caps.max_images > 0 && valid_count > caps.max_images as usize {
+      errors.push(config_issue(
+        "too_many_images",
+        format!("「{}」最多支持 {} 张输入图片，当前 {} 张", caps.label, caps.max_images, valid_count),
+        image_ids(&|_| true),
+      ));
+    }
+    if caps.requires_first_frame_if_last_frame && !last.is_empty() && first.is_empty() {
+      errors.push(config_issue("first_frame_required", format!("「{}」使用尾帧时必须同时提供首帧", caps.label), last.clone()));
+    }
+  }
+
+  Ok(ConfigInputValidation {
+    ok: errors.is_empty(),
+    config_node_id: cfg_id,
+    model_key: caps.model_key.to_string(),
+    model_label: caps.label.to_string(),
+    prompt_count: prompt_nodes.len(),
+    image_count: images.len(),
+    errors,
+    warnings,
+  })
+}
+
+// ======== Canvas context summary (assistant) ========
+
+#[derive(serde::Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct CanvasContextOptions {
+  #[serde(default)]
+  max_chars: i64,
+  #[serde(default)]
+  max_node_chars: i64,
+  #[serde(default)]
+  neighbor_depth: i64,
+  #[serde(default)]
+  max_failures: i64,
+  #[serde(default)]
+  locale: String, // 摘要文案语言：zh（默认）| en
+}
+
+#[derive(serde::Serialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct CanvasTypeCount {
+  node_type: String,
+  count: usize,
+}
+
+#[derive(serde::Serialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct CanvasFailure {
+  node_id: String,
+  node_type: String,
+  message: String,
+  updated_at: i64,
+}
+
+#[derive(serde::Serialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct CanvasContextSummary {
+  text: String,
+  node_count: usize,
+  edge_count: usize,
+  type_counts: Vec<CanvasTypeCount>,
+  model_keys: Vec<String>,
+  failures: Vec<CanvasFailure>,
+  included_node_ids: Vec<String>,
+  omitted_node_count: usize,
+  truncated: bool,
+}
+
+// 缺失或非数字时返回 None，由调用方决定回退顺序（不要当成 0）
+fn value_timestamp(value: &Value, key: &str) -> Option<i64> {
+  value.get(key).and_then(|v| v.as_i64().or_else(|| v.as_f64().map(|f| f as i64)))
+}
+
+fn value_i64(value: &Value, key: &str) -> i64 {
+  value_timestamp(value, key).unwrap_or(0)
+}
+
+fn locale_is_english(locale: &str) -> bool {
+  locale.trim().to_ascii_lowercase().starts_with("en")
+}
+
+// 画布摘要里的固定文案（概览、状态词、关系标签），跟随 locale
+struct CanvasContextLabels {
+  english: bool,
+}
+
+impl CanvasContextLabels {
+  fn new(locale: &str) -> Self {
+    Self { english: locale_is_english(locale) }
+  }
+
+  fn pick(&self, zh: &'static str, en: &'static str) -> &'static str {
+    if self.english { en } else { zh }
+  }
+
+  fn list_sep(&self) -> &'static str {
+    self.pick("，", ", ")
+  }
+
+  fn overview(&self, nodes: usize, edges: usize, types: &str) -> String {
+    if self.english {
+      format!("Canvas overview: {nodes} nodes, {edges} edges ({types})")
+    } else {
+      format!("画布概览：{nodes} 个节点，{edges} 条连线（{types}）")
+    }
+  }
+
+  fn model_with_label(&self, key: &str, label: &str) -> String {
+    if self.english { format!("{key} ({label})") } else { format!("{key}（{label}）") }
+  }
+
+  fn models(&self, models: &str) -> String {
+    format!("{}{models}", self.pick("使用中的模型：", "Models in use: "))
+  }
+
+  fn failures(&self) -> String {
+    self.pick("最近生成失败：", "Recent generation failures:").to_string()
+  }
+
+  fn selected(&self, ids: &str) -> String {
+    format!("{}{ids}", self.pick("当前选中：", "Selected: "))
+  }
+
+  fn omitted(&self, n: usize) -> String {
+    if self.english { format!("… ({n} more nodes not listed)") } else { format!("…（另有 {n} 个节点未列出）") }
+  }
+}
+
+fn one_line(text: &str) -> String {
+  normalize_text(text).split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+// 单个节点的一行描述：只挑对助手有用的字段，避免把 base64/长 URL 塞进上下文
+fn describe_canvas_node(n: &GraphNode, max_chars: usize, tag: &str, labels: &CanvasContextLabels) -> String {
+  let mut parts: Vec<String> = vec![format!("- {} type={}", n.id, n.node_type)];
+  if !tag.is_empty() {
+    parts.push(format!("[{tag}]"));
+  }
+  let label = value_string(Some(&n.data), "label");
+  if !label.is_empty() {
+    parts.push(format!("label=\"{}\"", one_line(&label)));
+  }
+  let model = value_string(Some(&n.data), "model");
+  if !model.is_empty() {
+    parts.push(format!("model={model}"));
+  }
+  match n.node_type.as_str() {
+    "text" => {
+      let content = one_line(&value_string(Some(&n.data), "content"));
+      if !content.is_empty() {
+        parts.push(format!("content=\"{content}\""));
+      }
+    }
+    "image" | "video" | "audio" => {
+      let url = value_string(Some(&n.data), "url");
+      let has_media = !url.is_empty() || !value_string(Some(&n.data), "base64").is_empty();
+      parts.push(if has_media { labels.pick("已生成", "generated") } else { labels.pick("空", "empty") }.to_string());
+    }
+    _ => {}
+  }
+  if n.data.get("loading").and_then(|v| v.as_bool()).unwrap_or(false) {
+    parts.push(labels.pick("生成中", "generating").to_string());
+  }
+  let err = value_string(Some(&n.data), "error");
+  if !err.is_empty() {
+    parts.push(format!("error=\"{}\"", one_line(&err)));
+  }
+  let line = parts.join(" ");
+  if line.chars().count() > max_chars {
+    safe_slice(&line, max_chars)
+  } else {
+    line
+  }
+}
+
+struct ContextBudget {
+  lines: Vec<String>,
+  left: usize,
+  truncated: bool,
+}
+
+impl ContextBudget {
+  fn push(&mut self, line: String) -> bool {
+    let cost = line.chars().count() + 1;
+    if cost > self.left {
+      self.truncated = true;
+      return false;
+    }
+    self.left -= cost;
+    self.lines.push(line);
+    true
+  }
+}
+
+#[tauri::command(rename_all = "camelCase")]
+fn graph_build_canvas_context(
+  nodes: Vec<GraphNode>,
+  edges: Vec<GraphEdge>,
+  selected_node_ids: Option<Vec<String>>,
+  options: Option<CanvasContextOptions>,
+) -> CanvasContextSummary {
+  let opts = options.unwrap_or_default();
+  let max_chars = clamp_i64(if opts.max_chars > 0 { opts.max_chars } else { 1200 }, 200, 8000) as usize;
+  let max_node_chars = clamp_i64(if opts.max_node_chars > 0 { opts.max_node_chars } else { 200 }, 60, 1000) as usize;
+  let neighbor_depth = clamp_i64(if opts.neighbor_depth > 0 { opts.neighbor_depth } else { 2 }, 1, 6) as usize;
+  let max_failures = clamp_i64(if opts.max_failures > 0 { opts.max_failures } else { 3 }, 1, 20) as usize;
+  let labels = CanvasContextLabels::new(&opts.locale);
+
+  let nodes: Vec<GraphNode> = nodes.into_iter().filter(|n| !n.id.trim().is_empty()).collect();
+  if nodes.is_empty() {
+    return CanvasContextSummary::default();
+  }
+  let index_by_id: HashMap<String, usize> = nodes.iter().enumerate().map(|(i, n)| (n.id.clone(), i)).collect();
+
+  let mut neighbors: HashMap<&str, Vec<(&str, &'static str)>> = HashMap::new();
+  let mut edge_count = 0usize;
+  for e in edges.iter() {
+    if !index_by_id.contains_key(&e.source) || !index_by_id.contains_key(&e.target) {
+      continue;
+    }
+    edge_count += 1;
+    neighbors.entry(e.target.as_str()).or_default().push((e.source.as_str(), labels.pick("上游", "upstream")));
+    neighbors.entry(e.source.as_str()).or_default().push((e.target.as_str(), labels.pick("下游", "downstream")));
+  }
+
+  // counts by type (stable: 按首次出现顺序)
+  let mut type_counts: Vec<CanvasTypeCount> = vec![];
+  for n in nodes.iter() {
+    match type_counts.iter_mut().find(|c| c.node_type == n.node_type) {
+      Some(c) => c.count += 1,
+      None => type_counts.push(CanvasTypeCount { node_type: n.node_type.clone(), count: 1 }),
+    }
+  }
+
+  let mut model_keys: Vec<String> = vec![];
+  for n in nodes.iter().filter(|n| n.node_type.ends_with("Config") || n.node_type.ends_with("Tool")) {
+    let model = value_string(Some(&n.data), "model");
+    if !model.is_empty() && !model_keys.contains(&model) {
+      model_keys.push(model);
+    }
+  }
+
+  let mut failures: Vec<CanvasFailure> = nodes
+    .iter()
+    .filter_map(|n| {
+      let message = value_string(Some(&n.data), "error");
+      if message.is_empty() {
+        return None;
+      }
+      Some(CanvasFailure {
+        node_id: n.id.clone(),
+        node_type: n.node_type.clone(),
+        message: safe_slice(&one_line(&message), 160),
+        updated_at: value_i64(&n.data, "updatedAt"),
+      })
+    })
+    .collect();
+  failures.sort_by_key(|f| std::cmp::Reverse(f.updated_at));
+  failures.truncate(max_failures);
+
+  // selected first, then BFS neighbors by depth, then the rest (most recently updated first)
+  let selected: Vec<&str> = selected_node_ids
+    .unwrap_or_default()
+    .iter()
+    .filter_map(|id| index_by_id.get(id.trim()).map(|i| nodes[*i].id.as_str()))
+    .collect();
+  let mut visited: std::collections::HashSet<&str> = selected.iter().copied().collect();
+  let mut ranked: Vec<(&str, String)> = selected.iter().map(|id| (*id, labels.pick("选中", "selected").to_string())).collect();
+  let mut frontier: Vec<&str> = selected.clone();
+  for depth in 1..=neighbor_depth {
+    let mut next: Vec<&str> = vec![];
+    for id in frontier.iter() {
+      for (nb, dir) in neighbors.get(id).map(|v| v.as_slice()).unwrap_or(&[]) {
+        if visited.insert(nb) {
+          ranked.push((nb, format!("{dir}{depth}")));
+          next.push(nb);
+        }
+      }
+    }
+    frontier = next;
+  }
+  let mut rest: Vec<&GraphNode> = nodes.iter().filter(|n| !visited.contains(n.id.as_str())).collect();
+  // 有 updatedAt 的按时间倒序在前；缺失的保持节点原顺序排在后面（稳定排序）
+  rest.sort_by_key(|n| std::cmp::Reverse(value_timestamp(&n.data, "updatedAt")));
+  ranked.extend(rest.into_iter().map(|n| (n.id.as_str(), String::new())));
+
+  let mut budget = ContextBudget { lines: vec![], left: max_chars, truncated: false };
+  let counts_text = type_counts
+    .iter()
+    .map(|c| format!("{}×{}", c.node_type, c.count))
+    .collect::<Vec<_>>()
+    .join(labels.list_sep());
+  budget.push(labels.overview(nodes.len(), edge_count, &counts_text));
+  if !model_keys.is_empty() {
+    let models = model_keys
+      .iter()
+      .map(|k| match find_model_caps(k) {
+        Some(caps) if caps.label != k.as_str() => labels.model_with_label(k, caps.label),
+        _ => k.clone(),
+      })
+      .collect::<Vec<_>>()
+      .join(labels.list_sep());
+    budget.push(safe_slice(&labels.models(&models), max_node_chars));
+  }
+  if !failures.is_empty() {
+    budget.push(labels.failures());
+    for f in failures.iter() {
+      budget.push(safe_slice(&format!("- {} type={} {}", f.node_id, f.node_type, f.message), max_node_chars));
+    }
+  }
+  if !selected.is_empty() {
+    budget.push(labels.selected(&selected.join(labels.list_sep())));
+  }
+
+  // 预留末尾提示的空间，保证助手知道上下文不完整
+  let reserve = 32.min(budget.left);
+  budget.left -= reserve;
+  let mut included_node_ids: Vec<String> = vec![];
+  for (id, tag) in ranked.iter() {
+    let n = &nodes[index_by_id[*id]];
+    if !budget.push(describe_canvas_node(n, max_node_chars, tag, &labels)) {
+      break;
+    }
+    included_node_ids.push(n.id.clone());
+  }
+  budget.left += reserve;
+
+  let omitted_node_count = nodes.len() - included_node_ids.len();
+  if omitted_node_count > 0 {
+    budget.push(labels.omitted(omitted_node_count));
+  }
+
+  CanvasContextSummary {
+    text: budget.lines.join("\n"),
+    node_count: nodes.len(),
+    edge_count,
+    type_counts,
+    model_keys,
+    failures,
+    included_node_ids,
+    omitted_node_count,
+    truncated: budget.truncated,
+  }
+}
+
+// ======== Memory search (BM25) ========
+
+const MEMORY_STORE_DIR: &str = "nexus-memory";
+const BM25_INDEX_VERSION: u32 = 4; // 分词规则或文档字段变化时递增，旧索引自动重建
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct Bm25Doc {
+  hash: String,
+  len: u32,
+  terms: Vec<String>,
+  #[serde(default)]
+  pinyin: Vec<PinyinRun>, // 随内容缓存，模糊检索时不必逐条重算
+}
+
+// (doc id, query term) -> 模糊命中的折扣权重
+type FuzzyMatcher<'a> = dyn Fn(&str, &str) -> Option<f32> + 'a;
+
+// term -> (doc id -> tf)；docs 记录每篇文档的词项，便于增量删除
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct Bm25Index {
+  version: u32,
+  total_len: u64,
+  docs: HashMap<String, Bm25Doc>,
+  postings: HashMap<String, HashMap<String, u32>>,
+}
+
+impl Bm25Index {
+  // 磁盘上的索引只是缓存：读不到或版本不符就从空索引重建，再与条目对齐。返回索引与是否需要回写
+  fn from_cache(raw: Option<&[u8]>, items: &[MemoryItem]) -> (Self, bool) {
+    let mut index = raw
+      .and_then(|raw| serde_json::from_slice::<Bm25Index>(raw).ok())
+      .filter(|idx| idx.version == BM25_INDEX_VERSION)
+      .unwrap_or(Bm25Index { version: BM25_INDEX_VERSION, ..Default::default() });
+    let changed = index.sync(items);
+    (index, changed)
+  }
+
+  fn remove(&mut self, id: &str) -> bool {
+    let Some(doc) = self.docs.remove(id) else { return false };
+    self.total_len = self.total_len.saturating_sub(doc.len as u64);
+    for term in doc.terms.iter() {
+      if let Some(list) = self.postings.get_mut(term) {
+        list.remove(id);
+        if list.is_empty() {
+          self.postings.remove(term);
+        }
+      }
+    }
+    true
+  }
+
+  // 内容未变化时跳过，返回是否修改了索引
+  fn upsert(&mut self, id: &str, content: &str) -> bool {
+    let hash = hash_key(content);
+    if self.docs.get(id).is_some_and(|d| d.hash == hash) {
+      return false;
+    }
+    self.remove(id);
+
+    let tokens = tokenize_for_index(content);
+    let mut tf: HashMap<String, u32> = HashMap::new();
+    for t in tokens.iter() {
+      *tf.entry(t.clone()).or_default() += 1;
+    }
+    let mut terms: Vec<String> = tf.keys().cloned().collect();
+    terms.sort();
+    for (term, n) in tf.into_iter() {
+      self.postings.entry(term).or_default().insert(id.to_string(), n);
+    }
+    self.total_len += tokens.len() as u64;
+    let pinyin = pinyin_runs(content);
+    self.docs.insert(id.to_string(), Bm25Doc { hash, len: tokens.len() as u32, terms, pinyin });
+    true
+  }
+
+  // 与传入的条目集合对齐：新增/变更的重新索引，不在集合里的删除
+  fn sync(&mut self, items: &[MemoryItem]) -> bool {
+    let mut changed = false;
+    let live: std::collections::HashSet<&str> = items.iter().map(|i| i.id.as_str()).collect();
+    let stale: Vec<String> = self.docs.keys().filter(|id| !live.contains(id.as_str())).cloned().collect();
+    for id in stale.iter() {
+      changed |= self.remove(id);
+    }
+    for item in items.iter() {
+      changed |= self.upsert(&item.id, &normalize_text(&item.content));
+    }
+    changed
+  }
+
+  fn idf(&self, term: &str) -> f32 {
+    self.idf_for_df(self.postings.get(term).map(|l| l.len()).unwrap_or(0))
+  }
+
+  fn idf_for_df(&self, df: usize) -> f32 {
+    let n_docs = self.docs.len() as f32;
+    let df = df as f32;
+    (1.0 + (n_docs - df + 0.5) / (df + 0.5)).ln()
+  }
+
+  // 归一化到 0..1：除以查询在 tf 饱和时能拿到的上限 Σ idf·(k1+1)。
+  // fuzzy(doc id, term) 给没有精确命中的词一个折扣权重，按“出现一次、平均长度”计分；
+  // idf 取模糊命中的文档数（即被匹配到的文档词的 df），拼错的查询词自身 df 为 0、idf 最大，不能直接用
+  fn score_with(
+    &self,
+    query_terms: &[String],
+    term_weights: &HashMap<String, f32>,
+    k1: f32,
+    b: f32,
+    fuzzy: Option<&FuzzyMatcher>,
+  ) -> HashMap<String, f32> {
+    let mut out: HashMap<String, f32> = HashMap::new();
+    let n_docs = self.docs.len() as f32;
+    if n_docs == 0.0 {
+      return out;
+    }
+    let avg_len = (self.total_len as f32 / n_docs).max(1.0);
+    let mut upper = 0.0f32;
+    for term in query_terms.iter() {
+      // 未出现的词也计入上限，避免只命中一个词的长查询得分虚高；
+      // 扩展词（权重 < 1）只加分不计入上限，以免上下文稀释用户原话的得分
+      let weight = term_weights.get(term).copied().unwrap_or(1.0);
+      if weight >= 1.0 {
+        upper += self.idf(term) * (k1 + 1.0);
+      }
+      let idf = self.idf(term) * weight;
+      let list = self.postings.get(term);
+      if let Some(list) = list {
+        for (id, tf) in list.iter() {
+          let len = self.docs.get(id).map(|d| d.len as f32).unwrap_or(avg_len);
+          let tf = *tf as f32;
+          let part = idf * tf * (k1 + 1.0) / (tf + k1 * (1.0 - b + b * len / avg_len));
+          *out.entry(id.clone()).or_default() += part;
+        }
+      }
+      if let Some(fuzzy) = fuzzy {
+        let hits: Vec<(&String, f32)> = self
+          .docs
+          .keys()
+          .filter(|id| !list.is_some_and(|l| l.contains_key(*id)))
+          .filter_map(|id| fuzzy(id, term).map(|w| (id, w)))
+          .collect();
+        if !hits.is_empty() {
+          let exact_df = list.map(|l| l.len()).unwrap_or(0);
+          let fuzzy_idf = self.idf_for_df(exact_df + hits.len()) * weight;
+          for (id, w) in hits {
+            *out.entry(id.clone()).or_default() += fuzzy_idf * w;
+          }
+        }
+      }
+    }
+    if upper > 0.0 {
+      for v in out.values_mut() {
+        *v = (*v / upper).clamp(0.0, 1.0);
+      }
+    }
+    out
+  }
+}
+
+// ======== Fuzzy memory matching (pinyin / prefix / typo) ========
+
+struct FuzzyWeights {
+  pinyin: f32,
+  prefix: f32,
+  typo: f32,
+}
+
+// 一段连续汉字：每个字的候选读音
+type PinyinRun = Vec<Vec<String>>;
+
+// 每个连续汉字片段 → 每个字的候选读音（多音字全部保留；ü 写作 v，与键盘输入一致）
+fn pinyin_runs(text: &str) -> Vec<PinyinRun> {
+  use pinyin::ToPinyinMulti;
+  use unicode_normalization::UnicodeNormalization;
+
+  let mut runs: Vec<PinyinRun> = vec![];
+  let mut run: PinyinRun = vec![];
+  for ch in text.nfkc() {
+    let readings: Vec<String> = ch
+      .to_pinyin_multi()
+      .map(|multi| multi.into_iter().map(|p| p.plain().replace('ü', "v")).collect())
+      .unwrap_or_default();
+    if readings.is_empty() {
+      if !run.is_empty() {
+        runs.push(std::mem::take(&mut run));
+      }
+      continue;
+    }
+    run.push(readings);
+  }
+  if !run.is_empty() {
+    runs.push(run);
+  }
+  runs
+}
+
+// query 是否恰好等于某段连续汉字的读音拼接（按音节边界对齐），如 zhujue → 主角
+fn pinyin_matches(query: &str, runs: &[PinyinRun]) -> bool {
+  fn matches_from(query: &str, run: &[Vec<String>]) -> bool {
+    let Some((readings, rest)) = run.split_first() else { return false };
+    readings.iter().any(|r| {
+      if query == r {
+        return true;
+      }
+      query.starts_with(r.as_str()) && matches_from(&query[r.len()..], rest)
+    })
+  }
+  runs.iter().any(|run| (0..run.len()).any(|i| matches_from(query, &run[i..])))
+}
+
+// 受限的 Damerau–Levenshtein（OSA），超过 max 立即返回 false
+fn within_edit_distance(a: &str, b: &str, max: usize) -> bool {
+  let a: Vec<char> = a.chars().collect();
+  let b: Vec<char> = b.chars().collect();
+  if a.len().abs_diff(b.len()) > max {
+    return false;
+  }
+  let mut prev2: Vec<usize> = vec![0; b.len() + 1];
+  let mut prev: Vec<usize> = (0..=b.len()).collect();
+  for i in 1..=a.len() {
+    let mut cur: Vec<usize> = vec![i; b.len() + 1];
+    let mut row_min = cur[0];
+    for j in 1..=b.len() {
+      let cost = usize::from(a[i - 1] != b[j - 1]);
+      cur[j] = (prev[j] + 1).min(cur[j - 1] + 1).min(prev[j - 1] + cost);
+      if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+        cur[j] = cur[j].min(prev2[j - 2] + 1);
+      }
+      row_min = row_min.min(cur[j]);
+    }
+    if row_min > max {
+      return false;
+    }
+    prev2 = std::mem::replace(&mut prev, cur);
+  }
+  prev[b.len()] <= max
+}
+
+// 没有精确命中时的折扣权重：拼音 > 前缀 > 拼写错误，均低于精确命中
+fn fuzzy_term_weight(term: &str, doc_terms: &[String], pinyin: &[PinyinRun], w: &FuzzyWeights) -> Option<f32> {
+  fuzzy_term_hit(term, doc_terms, pinyin, w).map(|(_, weight)| weight)
+}
+
+// 同上，同时返回命中类型（pinyin | prefix | typo），用于解释检索结果
+fn fuzzy_term_hit(term: &str, doc_terms: &[String], pinyin: &[PinyinRun], w: &FuzzyWeights) -> Option<(&'static str, f32)> {
+  let latin = term.chars().all(|c| c.is_ascii_alphabetic());
+  if latin && term.len() >= 3 && w.pinyin > 0.0 && pinyin_matches(term, pinyin) {
+    return Some(("pinyin", w.pinyin));
+  }
+
+  let cjk = term.chars().all(|c| matches!(token_script(c), TokenScript::Han | TokenScript::Hangul));
+  let prefix_min = if cjk { 1 } else { 3 };
+  if term.chars().count() >= prefix_min
+    && w.prefix > 0.0
+    && doc_terms.iter().any(|d| d.len() > term.len() && d.starts_with(term))
+  {
+    return Some(("prefix", w.prefix));
+  }
+
+  if latin && term.len() >= 4 && w.typo > 0.0 {
+    let max = if term.len() >= 8 { 2 } else { 1 };
+    if doc_terms
+      .iter()
+      .any(|d| d.chars().all(|c| c.is_ascii_alphabetic()) && within_edit_distance(term, d, max))
+    {
+      return Some(("typo", w.typo));
+    }
+  }
+  None
+}
+
+#[derive(serde::Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct MemorySearchWeights {
+  #[serde(default)]
+  lexical: Option<f32>,
+  #[serde(default)]
+  importance: Option<f32>,
+  #[serde(default)]
+  recency: Option<f32>,
+  #[serde(default)]
+  recency_days: Option<f32>,
+  #[serde(default)]
+  k1: Option<f32>,
+  #[serde(default)]
+  b: Option<f32>,
+  #[serde(default)]
+  fuzzy_pinyin: Option<f32>,
+  #[serde(default)]
+  fuzzy_prefix: Option<f32>,
+  #[serde(default)]
+  fuzzy_typo: Option<f32>,
+  #[serde(default)]
+  semantic: Option<f32>, // 混合检索时向量相似度在相关度中的占比
+  #[serde(default)]
+  diversity: Option<f32>, // MMR 中与已选条目相似度的惩罚占比，默认 0 = 不做多样性重排（需显式开启）
+  #[serde(default)]
+  context_weight: Option<f32>, // 近几轮对话扩展出的查询词相对用户原话的权重
+}
+
+struct ResolvedMemoryWeights {
+  lexical: f32,
+  importance: f32,
+  recency: f32,
+  recency_days: f32,
+  k1: f32,
+  b: f32,
+  fuzzy: FuzzyWeights,
+  semantic: f32,
+  diversity: f32,
+  context_weight: f32,
+}
+
+impl MemorySearchWeights {
+  fn resolve(&self) -> ResolvedMemoryWeights {
+    ResolvedMemoryWeights {
+      lexical: self.lexical.unwrap_or(0.7).max(0.0),
+      importance: self.importance.unwrap_or(0.2).max(0.0),
+      recency: self.recency.unwrap_or(0.1).max(0.0),
+      recency_days: self.recency_days.unwrap_or(30.0).max(1.0),
+      k1: self.k1.unwrap_or(1.2).clamp(0.0, 3.0),
+      b: self.b.unwrap_or(0.75).clamp(0.0, 1.0),
+      fuzzy: FuzzyWeights {
+        pinyin: self.fuzzy_pinyin.unwrap_or(0.8).clamp(0.0, 0.95),
+        prefix: self.fuzzy_prefix.unwrap_or(0.6).clamp(0.0, 0.95),
+        typo: self.fuzzy_typo.unwrap_or(0.5).clamp(0.0, 0.95),
+      },
+      semantic: self.semantic.unwrap_or(0.5).clamp(0.0, 1.0),
+      diversity: self.diversity.unwrap_or(0.0).clamp(0.0, 1.0),
+      context_weight: self.context_weight.unwrap_or(0.35).clamp(0.0, 0.95),
+    }
+  }
+}
+
+fn now_millis() -> i64 {
+  std::time::SystemTime::now()
+    .duration_since(std::time::UNIX_EPOCH)
+    .map(|d| d.as_millis() as i64)
+    .unwrap_or(0)
+}
+
+impl ResolvedMemoryWeights {
+  fn importance_part(&self, item: &MemoryItem) -> f32 {
+    item.importance.clamp(0.0, 1.0) * self.importance
+  }
+
+  fn recency_part(&self, item: &MemoryItem, now: i64) -> f32 {
+    let recency_days = if item.updated_at > 0 {
+      ((now - item.updated_at) as f32) / (1000.0 * 60.0 * 60.0 * 24.0)
+    } else {
+      self.recency_days
+    };
+    let recency = (recency_days / self.recency_days).clamp(0.0, 1.0);
+    (1.0 - recency) * self.recency
+  }
+}
+
+// ======== Memory store (Rust-owned, per project + global) ========
+
+const MEMORY_STORE_VERSION: u32 = 1;
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct MemoryStore {
+  version: u32,
+  items: Vec<MemoryItem>,
+  #[serde(default)]
+  policy: MemoryPolicy,
+}
+
+struct MemoryState {
+  store: MemoryStore,
+  index: Bm25Index,
+  embeddings: Option<EmbeddingCache>, // 首次语义检索时才加载
+  store_path: PathBuf,
+  index_path: PathBuf,
+  embeddings_path: PathBuf,
+  saved_at: i64,
+  unsaved: bool, // 有未落盘的使用计数（memory_reinforce 每轮都会调用，按间隔落盘）
+}
+
+const MEMORY_SAVE_INTERVAL_MS: i64 = 30_000;
+
+static MEMORY_STATE: OnceLock<Mutex<Option<MemoryState>>> = OnceLock::new();
+static MEMORY_ID_SEQ: AtomicU64 = AtomicU64::new(0);
+
+fn memory_store_dir(app: &tauri::AppHandle) -> Result<PathBuf, String> {
+  let dir = app
+    .path()
+    .app_data_dir()
+    .map_err(|e| e.to_string())?
+    .join(MEMORY_STORE_DIR);
+  std::fs::create_dir_all(&dir).map_err(|e| e.to_string())?;
+  Ok(dir)
+}
+
+fn write_json_atomic<T: serde::Serialize>(path: &Path, value: &T) -> Result<(), String> {
+  let bytes = serde_json::to_vec(value).map_err(|e| e.to_string())?;
+  write_bytes_atomic(path, &bytes)
+}
+
+static ATOMIC_WRITE_SEQ: AtomicU64 = AtomicU64::new(0);
+
+fn write_bytes_atomic(path: &Path, bytes: &[u8]) -> Result<(), String> {
+  let name = path.file_name().and_then(|n| n.to_str()).ok_or("无效的文件路径")?;
+  // 同一文件可能被并发写入：临时文件名带进程号和序号，各写各的，最后一次改名生效
+  let seq = ATOMIC_WRITE_SEQ.fetch_add(1, Ordering::Relaxed);
+  let tmp = path.with_file_name(format!("{name}.tmp.{}-{seq}", std::process::id()));
+  std::fs::write(&tmp, bytes).map_err(|e| e.to_string())?;
+  std::fs::rename(&tmp, path).map_err(|e| e.to_string())?;
+  Ok(())
+}
+
+fn load_memory_state(app: &tauri::AppHandle) -> Result<MemoryState, String> {
+  let dir = memory_store_dir(app)?;
+  let store_path = dir.join("store.json");
+  let index_path = dir.join("bm25-index.json");
+
+  let store = if store_path.exists() {
+    let raw = std::fs::read(&store_path).map_err(|e| e.to_string())?;
+    serde_json::from_slice::<MemoryStore>(&raw).map_err(|e| format!("记忆库文件损坏：{e}"))?
+  } else {
+    MemoryStore { version: MEMORY_STORE_VERSION, ..Default::default() }
+  };
+
+  let (index, changed) = Bm25Index::from_cache(std::fs::read(&index_path).ok().as_deref(), &store.items);
+  if changed {
+    if let Err(err) = write_json_atomic(&index_path, &index) {
+      log::warn!("[memory] 保存 BM25 索引失败: {}", err);
+    }
+  }
+
+  let mut state = MemoryState {
+    store,
+    index,
+    embeddings: None,
+    store_path,
+    index_path,
+    embeddings_path: dir.join("embeddings.json"),
+    saved_at: now_millis(),
+    unsaved: false,
+  };
+  // 启动时结算一次衰减（开启 auto_evict 时顺带淘汰）
+  let report = auto_settle_memory(&mut state, None, &Default::default(), now_millis());
+  if !report.decayed.is_empty() || !report.evicted.is_empty() {
+    log::info!("[memory] 衰减 {} 条，淘汰 {} 条", report.decayed.len(), report.evicted.len());
+    write_json_atomic(&state.store_path, &state.store)?;
+    if let Err(err) = write_json_atomic(&state.index_path, &state.index) {
+      log::warn!("[memory] 保存 BM25 索引失败: {}", err);
+    }
+  }
+  Ok(state)
+}
+
+// 在全局记忆状态上执行 f；首次使用时从磁盘加载。f 返回 true 表示记忆库有修改，需要落盘
+fn with_memory<R>(
+  app: &tauri::AppHandle,
+  f: impl FnOnce(&mut MemoryState) -> Result<(R, bool), String>,
+) -> Result<R, String> {
+  let lock = MEMORY_STATE.get_or_init(|| Mutex::new(None));
+  let mut guard = lock.lock().map_err(|_| "记忆库锁异常".to_string())?;
+  if guard.is_none() {
+    *guard = Some(load_memory_state(app)?);
+  }
+  let Some(state) = guard.as_mut() else {
+    return Err("记忆库未初始化".to_string());
+  };
+  let (out, dirty) = f(state)?;
+  let now = now_millis();
+  if dirty {
+    write_json_atomic(&state.store_path, &state.store)?;
+    if let Err(err) = write_json_atomic(&state.index_path, &state.index) {
+      log::warn!("[memory] 保存 BM25 索引失败: {}", err);
+    }
+  } else if state.unsaved && now - state.saved_at >= MEMORY_SAVE_INTERVAL_MS {
+    // 只有使用计数变化：内容没变，索引不用重写
+    write_json_atomic(&state.store_path, &state.store)?;
+  } else {
+    return Ok(out);
+  }
+  state.saved_at = now;
+  state.unsaved = false;
+  Ok(out)
+}
+
+// 退出时写入还没到落盘间隔的使用计数；记忆库从未加载过就什么都不做
+fn flush_memory_state() {
+  let Some(lock) = MEMORY_STATE.get() else { return };
+  let Ok(mut guard) = lock.lock() else { return };
+  let Some(state) = guard.as_mut().filter(|s| s.unsaved) else { return };
+  match write_json_atomic(&state.store_path, &state.store) {
+    Ok(()) => {
+      state.saved_at = now_millis();
+      state.unsaved = false;
+    }
+    Err(err) => log::warn!("[memory] 退出时保存使用计数失败: {}", err),
+  }
+}
+
+fn new_memory_id() -> String {
+  let seq = MEMORY_ID_SEQ.fetch_add(1, Ordering::Relaxed);
+  format!("mem-{}", &hash_key(&format!("{}-{}-{}", now_millis(), std::process::id(), seq))[..16])
+}
+
+fn normalize_project_id(project_id: Option<String>) -> String {
+  project_id.map(|p| p.trim().to_string()).unwrap_or_default()
+}
+
+// project_id 为空 = 只看全局；否则看该项目，include_global 时连同全局
+fn memory_in_scope(item: &MemoryItem, project_id: &str, include_global: bool) -> bool {
+  if item.project_id == project_id {
+    return true;
+  }
+  include_global && item.project_id.is_empty()
+}
+
+#[tauri::command(rename_all = "camelCase")]
+async fn memory_list(
+  app: tauri::AppHandle,
+  project_id: Option<String>,
+  include_global: Option<bool>,
+  pinned_only: Option<bool>,
+) -> Result<Vec<MemoryItem>, String> {
+  tauri::async_runtime::spawn_blocking(move || -> Result<Vec<MemoryItem>, String> {
+    let project_id = normalize_project_id(project_id);
+    let include_global = include_global.unwrap_or(true);
+    let pinned_only = pinned_only.unwrap_or(false); // 每轮对话只取置顶条目时用，免得拉全量
+    with_memory(&app, |state| {
+      let mut items: Vec<MemoryItem> = state
+        .store
+        .items
+        .iter()
+        .filter(|i| memory_in_scope(i, &project_id, include_global) && (!pinned_only || i.pin.is_some()))
+        .cloned()
+        .collect();
+      items.sort_by_key(|i| std::cmp::Reverse(i.updated_at));
+      Ok((items, false))
+    })
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+#[tauri::command(rename_all = "camelCase")]
+async fn memory_add(
+  app: tauri::AppHandle,
+  content: String,
+  importance: Option<f32>,
+  project_id: Option<String>,
+  tags: Option<Vec<String>>,
+  source: Option<String>,
+  dedup_threshold: Option<f32>,
+) -> Result<MemoryItem, String> {
+  tauri::async_runtime::spawn_blocking(move || -> Result<MemoryItem, String> {
+    let content = normalize_text(&content);
+    if content.is_empty() {
+      return Err("记忆内容不能为空".to_string());
+    }
+    let project_id = normalize_project_id(project_id);
+    let importance = importance.unwrap_or(0.6).clamp(0.0, 1.0);
+    let tags: Vec<String> = tags.unwrap_or_default().into_iter().map(|t| t.trim().to_string()).filter(|t| !t.is_empty()).collect();
+    let now = now_millis();
+
+    with_memory(&app, |state| {
+      // 同一作用域下内容完全相同：只刷新时间并取较高的重要度
+      if let Some(existing) = state
+        .store
+        .items
+        .iter_mut()
+        .find(|i| i.project_id == project_id && i.content == content)
+      {
+        existing.updated_at = now;
+        existing.importance = existing.importance.max(importance);
+        return Ok((existing.clone(), true));
+      }
+
+      // 可选：近似重复（改写过的同一事实）并入已有条目，而不是再存一份。
+      // 与 merge_memory_items 一致以最新措辞为准：内容换成新文本，重要度取高、标签合并
+      if let Some(threshold) = dedup_threshold.filter(|t| *t > 0.0) {
+        let threshold = threshold.clamp(0.3, 1.0);
+        if let Some(dup_id) = find_near_duplicate(&state.store.items, &project_id, &content, threshold).map(|i| i.id.clone()) {
+          if let Some(existing) = state.store.items.iter_mut().find(|i| i.id == dup_id) {
+            existing.content = content;
+            existing.updated_at = now;
+            existing.importance = existing.importance.max(importance);
+            for tag in tags {
+              if !existing.tags.contains(&tag) {
+                existing.tags.push(tag);
+              }
+            }
+            let item = existing.clone();
+            state.index.upsert(&item.id, &item.content);
+            return Ok((item, true));
+          }
+        }
+      }
+
+      let item = MemoryItem {
+        id: new_memory_id(),
+        content,
+        importance,
+        created_at: now,
+        updated_at: now,
+        project_id,
+        tags,
+        source: source.unwrap_or_default(),
+        ..Default::default()
+      };
+      state.index.upsert(&item.id, &item.content);
+      state.store.items.push(item.clone());
+      let keep = std::collections::HashSet::from([item.id.clone()]);
+      auto_settle_memory(state, Some(&item.project_id), &keep, now);
+      Ok((item, true))
+    })
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+#[tauri::command(rename_all = "camelCase")]
+async fn memory_update(
+  app: tauri::AppHandle,
+  id: String,
+  content: Option<String>,
+  importance: Option<f32>,
+  tags: Option<Vec<String>>,
+) -> Result<MemoryItem, String> {
+  tauri::async_runtime::spawn_blocking(move || -> Result<MemoryItem, String> {
+    let id = id.trim().to_string();
+    with_memory(&app, |state| {
+      let item = state
+        .store
+        .items
+        .iter_mut()
+        .find(|i| i.id == id)
+        .ok_or_else(|| format!("记忆不存在：{id}"))?;
+      if let Some(c) = content {
+        let c = normalize_text(&c);
+        if c.is_empty() {
+          return Err("记忆内容不能为空".to_string());
+        }
+        item.content = c;
+      }
+      if let Some(v) = importance {
+        item.importance = v.clamp(0.0, 1.0);
+      }
+      if let Some(t) = tags {
+        item.tags = t.into_iter().map(|t| t.trim().to_string()).filter(|t| !t.is_empty()).collect();
+      }
+      item.updated_at = now_millis();
+      let item = item.clone();
+      state.index.upsert(&item.id, &item.content);
+      Ok((item, true))
+    })
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+#[tauri::command(rename_all = "camelCase")]
+async fn memory_delete(app: tauri::AppHandle, id: String) -> Result<bool, String> {
+  tauri::async_runtime::spawn_blocking(move || -> Result<bool, String> {
+    let id = id.trim().to_string();
+    with_memory(&app, |state| {
+      let before = state.store.items.len();
+      state.store.items.retain(|i| i.id != id);
+      let removed = state.store.items.len() != before;
+      if removed {
+        state.index.remove(&id);
+      }
+      Ok((removed, removed))
+    })
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+#[tauri::command(rename_all = "camelCase")]
+async fn memory_clear(app: tauri::AppHandle, project_id: Option<String>) -> Result<usize, String> {
+  tauri::async_runtime::spawn_blocking(move || -> Result<usize, String> {
+    let project_id = normalize_project_id(project_id);
+    with_memory(&app, |state| {
+      let (drop, keep): (Vec<MemoryItem>, Vec<MemoryItem>) =
+        std::mem::take(&mut state.store.items).into_iter().partition(|i| i.project_id == project_id);
+      state.store.items = keep;
+      for item in drop.iter() {
+        state.index.remove(&item.id);
+      }
+      Ok((drop.len(), !drop.is_empty()))
+    })
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+// 迁移前端 localStorage 里的旧记忆：按 id / 内容去重，保留原时间戳（可重复调用）
+#[tauri::command(rename_all = "camelCase")]
+async fn memory_migrate_legacy(app: tauri::AppHandle, items: Vec<MemoryItem>, project_id: Option<String>) -> Result<usize, String> {
+  tauri::async_runtime::spawn_blocking(move || -> Result<usize, String> {
+    let project_id = normalize_project_id(project_id);
+    let now = now_millis();
+    with_memory(&app, |state| {
+      let mut added = 0usize;
+      for mut item in items.into_iter() {
+        item.content = normalize_text(&item.content);
+        if item.content.is_empty() {
+          continue;
+        }
+        if item.id.trim().is_empty() {
+          item.id = new_memory_id();
+        }
+        if state
+          .store
+          .items
+          .iter()
+          .any(|i| i.id == item.id || (i.project_id == project_id && i.content == item.content))
+        {
+          continue;
+        }
+        item.project_id = project_id.clone();
+        item.importance = item.importance.clamp(0.0, 1.0);
+        if item.updated_at <= 0 {
+          item.updated_at = now;
+        }
+        if item.created_at <= 0 {
+          item.created_at = item.updated_at;
+        }
+        state.index.upsert(&item.id, &item.content);
+        state.store.items.push(item);
+        added += 1;
+      }
+      if added > 0 {
+        auto_settle_memory(state, Some(&project_id), &Default::default(), now);
+      }
+      Ok((added, added > 0))
+    })
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+// ======== Memory policy (decay / reinforcement / eviction) ========
+
+const DAY_MS: f32 = 1000.0 * 60.0 * 60.0 * 24.0;
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase", default)]
+struct MemoryPolicy {
+  max_items_per_scope: usize, // 每个作用域（全局 / 单个项目）的条目上限
+  decay_grace_days: f32,      // 新建/更新后这么多天内不衰减
+  decay_half_life_days: f32,  // 从未被使用的条目，重要度按此半衰期衰减
+  min_importance: f32,        // 衰减到此以下且从未被使用的条目会被淘汰
+  reinforce_step: f32,        // 每次被使用，重要度向 1 靠近的比例
+  auto_evict: bool,           // 启动/新增/导入时是否顺带淘汰；默认关闭，只能经 memory_evict 预览后确认删除
+}
+
+impl Default for MemoryPolicy {
+  fn default() -> Self {
+    Self {
+      max_items_per_scope: 500,
+      decay_grace_days: 14.0,
+      decay_half_life_days: 60.0,
+      min_importance: 0.05,
+      reinforce_step: 0.1,
+      auto_evict: false,
+    }
+  }
+}
+
+impl MemoryPolicy {
+  fn sanitized(mut self) -> Self {
+    self.max_items_per_scope = self.max_items_per_scope.clamp(10, 100_000);
+    self.decay_grace_days = self.decay_grace_days.clamp(0.0, 3650.0);
+    self.decay_half_life_days = self.decay_half_life_days.clamp(1.0, 3650.0);
+    self.min_importance = self.min_importance.clamp(0.0, 0.9);
+    self.reinforce_step = self.reinforce_step.clamp(0.0, 1.0);
+    self
+  }
+
+  // 从未被使用的条目：宽限期过后，自上次结算起按半衰期衰减；结算时间推进后不会重复计算
+  fn decayed_importance(&self, item: &MemoryItem, now: i64) -> f32 {
+    if item.use_count > 0 || item.pin.is_some() {
+      return item.importance;
+    }
+    let grace_end = item.updated_at.max(item.created_at) + (self.decay_grace_days * DAY_MS) as i64;
+    let from = grace_end.max(item.decayed_at);
+    if now <= from {
+      return item.importance;
+    }
+    let days = (now - from) as f32 / DAY_MS;
+    item.importance * 0.5f32.powf(days / self.decay_half_life_days)
+  }
+
+  // 超出容量时按此保留分从低到高淘汰
+  fn retention(&self, item: &MemoryItem, importance: f32, now: i64) -> f32 {
+    let last = item.last_used_at.max(item.updated_at);
+    let idle_days = if last > 0 { (now - last).max(0) as f32 / DAY_MS } else { 90.0 };
+    let recency = 1.0 - (idle_days / 90.0).clamp(0.0, 1.0);
+    let usage = (item.use_count.min(10) as f32) / 10.0;
+    importance * 0.6 + recency * 0.25 + usage * 0.15
+  }
+}
+
+#[derive(serde::Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+struct MemoryDecayChange {
+  id: String,
+  from: f32,
+  to: f32,
+}
+
+#[derive(serde::Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+struct MemoryEviction {
+  id: String,
+  project_id: String,
+  content: String,
+  importance: f32,
+  reason: String, // low_importance | over_capacity
+}
+
+#[derive(serde::Serialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct MemoryEvictionReport {
+  dry_run: bool,
+  decayed: Vec<MemoryDecayChange>,
+  evicted: Vec<MemoryEviction>,
+  remaining: usize,
+}
+
+#[tauri::command(rename_all = "camelCase")]
+async fn memory_get_policy(app: tauri::AppHandle) -> Result<MemoryPolicy, String> {
+  tauri::async_runtime::spawn_blocking(move || -> Result<MemoryPolicy, String> {
+    with_memory(&app, |state| Ok((state.store.policy.clone(), false)))
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+#[tauri::command(rename_all = "camelCase")]
+async fn memory_set_policy(app: tauri::AppHandle, policy: MemoryPolicy) -> Result<MemoryPolicy, String> {
+  tauri::async_runtime::spawn_blocking(move || -> Result<MemoryPolicy, String> {
+    let policy = policy.sanitized();
+    with_memory(&app, |state| {
+      state.store.policy = policy.clone();
+      Ok((policy, true))
+    })
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+// 检索结果真正用于对话后调用：计数 +1，重要度按 reinforce_step 向 1 靠近
+#[tauri::command(rename_all = "camelCase")]
+async fn memory_reinforce(app: tauri::AppHandle, ids: Vec<String>) -> Result<usize, String> {
+  tauri::async_runtime::spawn_blocking(move || -> Result<usize, String> {
+    let ids: std::collections::HashSet<String> = ids.into_iter().map(|id| id.trim().to_string()).collect();
+    if ids.is_empty() {
+      return Ok(0);
+    }
+    let now = now_millis();
+    with_memory(&app, |state| {
+      let step = state.store.policy.reinforce_step;
+      let mut touched = 0usize;
+      for item in state.store.items.iter_mut().filter(|i| ids.contains(&i.id)) {
+        item.use_count = item.use_count.saturating_add(1);
+        item.last_used_at = now;
+        item.importance = (item.importance + (1.0 - item.importance) * step).clamp(0.0, 1.0);
+        touched += 1;
+      }
+      // 每轮都会调用：不立即重写记忆库，按间隔合并落盘
+      state.unsaved |= touched > 0;
+      Ok((touched, false))
+    })
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+// pinned = false 取消置顶；priority 缺省 1.0
+#[tauri::command(rename_all = "camelCase")]
+async fn memory_pin(app: tauri::AppHandle, ids: Vec<String>, pinned: bool, priority: Option<f32>) -> Result<usize, String> {
+  tauri::async_runtime::spawn_blocking(move || -> Result<usize, String> {
+    let ids: std::collections::HashSet<String> = ids.into_iter().map(|id| id.trim().to_string()).collect();
+    if ids.is_empty() {
+      return Ok(0);
+    }
+    let pin = pinned.then(|| priority.filter(|p| p.is_finite()).unwrap_or(1.0).clamp(0.0, 1.0));
+    with_memory(&app, |state| {
+      let mut touched = 0usize;
+      // 置顶不算改写内容：不动 updated_at，否则衰减宽限期与检索的新近度都会被重置
+      for item in state.store.items.iter_mut().filter(|i| ids.contains(&i.id) && i.pin != pin) {
+        item.pin = pin;
+        touched += 1;
+      }
+      Ok((touched, touched > 0))
+    })
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+// 结算衰减并淘汰：低于 min_importance 的从未使用条目，以及超出容量的低保留分条目。
+// dry_run（默认）只返回将要发生的变化；project_id 缺省 = 所有作用域，"" = 仅全局
+#[tauri::command(rename_all = "camelCase")]
+async fn memory_evict(
+  app: tauri::AppHandle,
+  project_id: Option<String>,
+  dry_run: Option<bool>,
+) -> Result<MemoryEvictionReport, String> {
+  tauri::async_runtime::spawn_blocking(move || -> Result<MemoryEvictionReport, String> {
+    let scope = project_id.map(|p| p.trim().to_string());
+    let dry_run = dry_run.unwrap_or(true);
+    with_memory(&app, |state| {
+      let report = settle_memory(state, scope.as_deref(), &Default::default(), dry_run, now_millis());
+      let dirty = !dry_run && (!report.decayed.is_empty() || !report.evicted.is_empty());
+      Ok((report, dirty))
+    })
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+// 结算衰减并淘汰（scope 为 None = 所有作用域）；keep 中的条目不淘汰（刚写入的）。
+// 真正删除只发生在 memory_evict 确认时，或 policy.auto_evict 打开后经 auto_settle_memory
+fn settle_memory(
+  state: &mut MemoryState,
+  scope: Option<&str>,
+  keep: &std::collections::HashSet<String>,
+  dry_run: bool,
+  now: i64,
+) -> MemoryEvictionReport {
+  let policy = state.store.policy.clone().sanitized();
+  let mut report = MemoryEvictionReport { dry_run, ..Default::default() };
+  let mut decayed: HashMap<String, f32> = HashMap::new();
+  let mut evict: HashMap<String, &'static str> = HashMap::new();
+  let mut by_scope: HashMap<&str, Vec<(f32, &MemoryItem)>> = HashMap::new();
+
+  for item in state.store.items.iter() {
+    // 置顶条目不衰减、不淘汰，也不占容量
+    if scope.is_some_and(|p| item.project_id != p) || item.pin.is_some() {
+      continue;
+    }
+    let importance = policy.decayed_importance(item, now);
+    if importance < item.importance {
+      decayed.insert(item.id.clone(), importance);
+      report.decayed.push(MemoryDecayChange { id: item.id.clone(), from: item.importance, to: importance });
+    }
+    if item.use_count == 0 && importance < policy.min_importance && !keep.contains(&item.id) {
+      evict.insert(item.id.clone(), "low_importance");
+      continue;
+    }
+    by_scope
+      .entry(item.project_id.as_str())
+      .or_default()
+      .push((policy.retention(item, importance, now), item));
+  }
+
+  for (_, mut items) in by_scope.into_iter() {
+    if items.len() <= policy.max_items_per_scope {
+      continue;
+    }
+    items.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal).then(a.1.updated_at.cmp(&b.1.updated_at)));
+    let over = items.len() - policy.max_items_per_scope;
+    for (_, item) in items.into_iter().filter(|(_, i)| !keep.contains(&i.id)).take(over) {
+      evict.insert(item.id.clone(), "over_capacity");
+    }
+  }
+
+  for item in state.store.items.iter().filter(|i| evict.contains_key(&i.id)) {
+    report.evicted.push(MemoryEviction {
+      id: item.id.clone(),
+      project_id: item.project_id.clone(),
+      content: item.content.clone(),
+      importance: decayed.get(&item.id).copied().unwrap_or(item.importance),
+      reason: evict[&item.id].to_string(),
+    });
+  }
+
+  if dry_run {
+    report.remaining = state.store.items.len() - report.evicted.len();
+    return report;
+  }
+
+  for item in state.store.items.iter_mut() {
+    if let Some(v) = decayed.get(&item.id) {
+      item.importance = *v;
+      item.decayed_at = now;
+    }
+  }
+  state.store.items.retain(|i| !evict.contains_key(&i.id));
+  for id in evict.keys() {
+    state.index.remove(id);
+  }
+  report.remaining = state.store.items.len();
+  report
+}
+
+// 启动加载与新增/导入后的结算：默认只落实衰减，不删除任何条目；policy.auto_evict 打开时才按规则淘汰
+fn auto_settle_memory(
+  state: &mut MemoryState,
+  scope: Option<&str>,
+  keep: &std::collections::HashSet<String>,
+  now: i64,
+) -> MemoryEvictionReport {
+  if state.store.policy.auto_evict {
+    return settle_memory(state, scope, keep, false, now);
+  }
+  let mut report = settle_memory(state, scope, keep, true, now);
+  let decayed: HashMap<&str, f32> = report.decayed.iter().map(|c| (c.id.as_str(), c.to)).collect();
+  for item in state.store.items.iter_mut() {
+    if let Some(v) = decayed.get(item.id.as_str()) {
+      item.importance = *v;
+      item.decayed_at = now;
+    }
+  }
+  report.dry_run = false;
+  report.evicted.clear();
+  report.remaining = state.store.items.len();
+  report
+}
+
+// ======== Memory import / export (JSON + Markdown) ========
+
+const MEMORY_EXPORT_VERSION: u32 = 1;
+const MEMORY_MD_GLOBAL: &str = "## 全局";
+const MEMORY_MD_PROJECT: &str = "## 项目：";
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct MemoryExportFile {
+  version: u32,
+  exported_at: i64,
+  items: Vec<MemoryItem>,
+}
+
+#[derive(serde::Serialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct MemoryExportResult {
+  format: String,
+  content: String,
+  count: usize,
+  path: Option<String>,
+}
+
+#[derive(serde::Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+struct MemoryImportError {
+  index: usize, // 条目在文件中的序号（从 0 开始）
+  id: Option<String>,
+  message: String,
+}
+
+#[derive(serde::Serialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct MemoryImportReport {
+  imported: usize,
+  overwritten: usize,
+  skipped: usize,
+  renamed: usize, // keep_both 时因 id 冲突换了新 id 的条目
+  errors: Vec<MemoryImportError>,
+}
+
+fn memory_format(format: Option<String>, content: Option<&str>) -> &'static str {
+  match format.as_deref().map(|f| f.trim().to_ascii_lowercase()).as_deref() {
+    Some("md") | Some("markdown") => "markdown",
+    Some("json") => "json",
+    _ => match content.map(|c| c.trim_start().chars().next()) {
+      Some(Some('{')) | Some(Some('[')) | None => "json",
+      _ => "markdown",
+    },
+  }
+}
+
+// 人可读，同时可以原样导回：### 后是 id，元数据是 "- key: value"，正文逐行放在引用块里。
+// 数值按最短可还原的精度写出，tags 写成 JSON 数组（标签里可以有逗号）；
+// 使用次数、上次使用/衰减时间只在 JSON 导出中保留，Markdown 导回后从零开始
+fn memory_to_markdown(items: &[MemoryItem], exported_at: i64) -> String {
+  let mut out = format!("# Nexus 记忆导出\n\n- version: {MEMORY_EXPORT_VERSION}\n- exportedAt: {exported_at}\n- count: {}\n", items.len());
+  let mut scopes: Vec<&str> = items.iter().map(|i| i.project_id.as_str()).collect();
+  scopes.sort_unstable();
+  scopes.dedup();
+  for scope in scopes {
+    if scope.is_empty() {
+      out.push_str(&format!("\n{MEMORY_MD_GLOBAL}\n"));
+    } else {
+      out.push_str(&format!("\n{MEMORY_MD_PROJECT}{scope}\n"));
+    }
+    for item in items.iter().filter(|i| i.project_id == scope) {
+      out.push_str(&format!("\n### {}\n\n", item.id));
+      out.push_str(&format!("- importance: {}\n", item.importance));
+      if let Some(pin) = item.pin {
+        out.push_str(&format!("- pin: {}\n", pin));
+      }
+      if !item.tags.is_empty() {
+        out.push_str(&format!("- tags: {}\n", serde_json::to_string(&item.tags).unwrap_or_default()));
+      }
+      if !item.source.is_empty() {
+        out.push_str(&format!("- source: {}\n", item.source));
+      }
+      out.push_str(&format!("- createdAt: {}\n- updatedAt: {}\n\n", item.created_at, item.updated_at));
+      for line in item.content.lines() {
+        out.push_str(&format!("> {line}\n"));
+      }
+    }
+  }
+  out
+}
+
+// Markdown 解析为 JSON 条目，再和 JSON 导入共用校验
+fn memory_entries_from_markdown(text: &str) -> Vec<Value> {
+  let mut entries: Vec<Value> = vec![];
+  let mut project_id = String::new();
+  let mut current: Option<(serde_json::Map<String, Value>, Vec<String>)> = None;
+
+  let finish = |entries: &mut Vec<Value>, current: &mut Option<(serde_json::Map<String, Value>, Vec<String>)>| {
+    if let Some((mut obj, content)) = current.take() {
+      obj.insert("content".to_string(), Value::String(content.join("\n")));
+      entries.push(Value::Object(obj));
+    }
+  };
+
+  for line in text.lines() {
+    let line = line.strip_suffix('\r').unwrap_or(line);
+    let t = line.trim_end();
+    if let Some(rest) = t.strip_prefix("### ") {
+      finish(&mut entries, &mut current);
+      let mut obj = serde_json::Map::new();
+      obj.insert("id".to_string(), Value::String(rest.trim().to_string()));
+      obj.insert("projectId".to_string(), Value::String(project_id.clone()));
+      current = Some((obj, vec![]));
+    } else if t == MEMORY_MD_GLOBAL {
+      finish(&mut entries, &mut current);
+      project_id.clear();
+    } else if let Some(rest) = t.strip_prefix(MEMORY_MD_PROJECT) {
+      finish(&mut entries, &mut current);
+      project_id = rest.trim().to_string();
+    } else if let Some((obj, content)) = current.as_mut() {
+      // 正文行不去尾部空白，保持原文
+      if let Some(quoted) = line.strip_prefix('>') {
+        content.push(quoted.strip_prefix(' ').unwrap_or(quoted).to_string());
+      } else if let Some((key, value)) = t.strip_prefix("- ").and_then(|kv| kv.split_once(':')) {
+        let (key, value) = (key.trim(), value.trim());
+        let value = match key {
+          "importance" | "pin" | "createdAt" | "updatedAt" => {
+            value.parse::<f64>().ok().and_then(serde_json::Number::from_f64).map(Value::Number).unwrap_or(Value::String(value.to_string()))
+          }
+          // 旧版导出是逗号分隔的纯文本
+          "tags" => serde_json::from_str::<Vec<String>>(value)
+            .map(|tags| Value::Array(tags.into_iter().map(Value::String).collect()))
+            .unwrap_or_else(|_| Value::Array(value.split(',').map(|t| Value::String(t.trim().to_string())).collect())),
+          _ => Value::String(value.to_string()),
+        };
+        obj.insert(key.to_string(), value);
+      }
+    }
+  }
+  finish(&mut entries, &mut current);
+  entries
+}
+
+fn memory_entries_from_json(text: &str) -> Result<Vec<Value>, String> {
+  let value: Value = serde_json::from_str(text).map_err(|e| format!("JSON 解析失败：{e}"))?;
+  match value {
+    Value::Array(items) => Ok(items),
+    Value::Object(mut obj) => match obj.remove("items") {
+      Some(Value::Array(items)) => Ok(items),
+      _ => Err("JSON 中缺少 items 数组".to_string()),
+    },
+    _ => Err("JSON 顶层应为对象或数组".to_string()),
+  }
+}
+
+fn validate_import_entry(entry: &Value) -> Result<MemoryItem, String> {
+  let obj = entry.as_object().ok_or("条目不是对象")?;
+  let content = normalize_text(obj.get("content").and_then(|v| v.as_str()).unwrap_or(""));
+  if content.is_empty() {
+    return Err("content 为空".to_string());
+  }
+  let importance = match obj.get("importance") {
+    None | Some(Value::Null) => 0.6,
+    Some(v) => {
+      let n = v.as_f64().ok_or("importance 不是数字")?;
+      if !(0.0..=1.0).contains(&n) {
+        return Err(format!("importance 超出 0..1：{n}"));
+      }
+      n as f32
+    }
+  };
+  let pin = match obj.get("pin") {
+    None | Some(Value::Null) => None,
+    Some(v) => {
+      let n = v.as_f64().ok_or("pin 不是数字")?;
+      if !(0.0..=1.0).contains(&n) {
+        return Err(format!("pin 超出 0..1：{n}"));
+      }
+      Some(n as f32)
+    }
+  };
+  let int_field = |key: &str| -> Result<i64, String> {
+    match obj.get(key) {
+      None | Some(Value::Null) => Ok(0),
+      Some(v) => v.as_f64().filter(|n| *n >= 0.0).map(|n| n as i64).ok_or(format!("{key} 不是有效时间戳")),
+    }
+  };
+  let str_field = |key: &str| obj.get(key).and_then(|v| v.as_str()).map(|s| s.trim().to_string()).unwrap_or_default();
+  let tags = match obj.get("tags") {
+    None | Some(Value::Null) => vec![],
+    Some(Value::Array(tags)) => tags.iter().filter_map(|t| t.as_str()).map(|t| t.trim().to_string()).filter(|t| !t.is_empty()).collect(),
+    Some(_) => return Err("tags 应为字符串数组".to_string()),
+  };
+  let now = now_millis();
+  let created_at = int_field("createdAt")?;
+  let updated_at = int_field("updatedAt")?;
+  Ok(MemoryItem {
+    id: str_field("id"),
+    content,
+    importance,
+    created_at: if created_at > 0 { created_at } else { now },
+    updated_at: if updated_at > 0 { updated_at } else { now },
+    project_id: str_field("projectId"),
+    tags,
+    source: str_field("source"),
+    pin,
+    ..Default::default()
+  })
+}
+
+// project_id 缺省 = 整个记忆库；否则只导出该作用域（include_global 时连同全局）。
+// 给了 path 就同时写文件
+#[tauri::command(rename_all = "camelCase")]
+async fn memory_export(
+  app: tauri::AppHandle,
+  project_id: Option<String>,
+  include_global: Option<bool>,
+  format: Option<String>,
+  path: Option<String>,
+) -> Result<MemoryExportResult, String> {
+  tauri::async_runtime::spawn_blocking(move || -> Result<MemoryExportResult, String> {
+    let format = memory_format(format, None);
+    let scope = project_id.map(|p| p.trim().to_string());
+    let include_global = include_global.unwrap_or(false);
+    let items: Vec<MemoryItem> = with_memory(&app, |state| {
+      let items = state
+        .store
+        .items
+        .iter()
+        .filter(|i| scope.as_deref().map_or(true, |p| memory_in_scope(i, p, include_global)))
+        .cloned()
+        .collect();
+      Ok((items, false))
+    })?;
+
+    let exported_at = now_millis();
+    let content = if format == "markdown" {
+      memory_to_markdown(&items, exported_at)
+    } else {
+      let file = MemoryExportFile { version: MEMORY_EXPORT_VERSION, exported_at, items: items.clone() };
+      serde_json::to_string_pretty(&file).map_err(|e| e.to_string())?
+    };
+    let path = path.map(|p| p.trim().to_string()).filter(|p| !p.is_empty());
+    if let Some(p) = path.as_deref() {
+      write_bytes_atomic(Path::new(p), content.as_bytes())?;
+    }
+    Ok(MemoryExportResult { format: format.to_string(), content, count: items.len(), path })
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+// 导入 JSON / Markdown。conflict：skip（默认）| overwrite | keep_both（换新 id 另存）。
+// 单条校验失败只记入 errors，不影响其余条目；project_id 给出时覆盖条目自带的作用域
+#[tauri::command(rename_all = "camelCase")]
+async fn memory_import(
+  app: tauri::AppHandle,
+  content: Option<String>,
+  path: Option<String>,
+  format: Option<String>,
+  project_id: Option<String>,
+  conflict: Option<String>,
+) -> Result<MemoryImportReport, String> {
+  tauri::async_runtime::spawn_blocking(move || -> Result<MemoryImportReport, String> {
+    let text = match (content, path.map(|p| p.trim().to_string()).filter(|p| !p.is_empty())) {
+      (Some(c), _) if !c.trim().is_empty() => c,
+      (_, Some(p)) => std::fs::read_to_string(&p).map_err(|e| format!("读取导入文件失败：{e}"))?,
+      _ => return Err("content 与 path 不能同时为空".to_string()),
+    };
+    let conflict = match conflict.as_deref().map(str::trim).unwrap_or("skip") {
+      "skip" | "" => "skip",
+      "overwrite" => "overwrite",
+      "keep_both" | "keepBoth" => "keep_both",
+      other => return Err(format!("未知的冲突处理方式：{other}")),
+    };
+    let entries = if memory_format(format, Some(&text)) == "markdown" {
+      memory_entries_from_markdown(&text)
+    } else {
+      memory_entries_from_json(&text)?
+    };
+    let target_scope = project_id.map(|p| p.trim().to_string());
+
+    with_memory(&app, |state| {
+      let mut report = MemoryImportReport::default();
+      let mut scopes: std::collections::HashSet<String> = std::collections::HashSet::new();
+      let mut imported: std::collections::HashSet<String> = std::collections::HashSet::new();
+      for (index, entry) in entries.iter().enumerate() {
+        let mut item = match validate_import_entry(entry) {
+          Ok(item) => item,
+          Err(message) => {
+            let id = entry.get("id").and_then(|v| v.as_str()).map(str::to_string);
+            report.errors.push(MemoryImportError { index, id, message });
+            continue;
+          }
+        };
+        if let Some(scope) = target_scope.as_ref() {
+          item.project_id = scope.clone();
+        }
+        if item.id.is_empty() {
+          item.id = new_memory_id();
+        }
+        match state.store.items.iter().position(|i| i.id == item.id) {
+          Some(_) if conflict == "skip" => {
+            report.skipped += 1;
+            continue;
+          }
+          Some(pos) if conflict == "overwrite" => {
+            state.store.items[pos] = item.clone();
+            report.overwritten += 1;
+          }
+          Some(_) => {
+            item.id = new_memory_id();
+            state.store.items.push(item.clone());
+            report.renamed += 1;
+            report.imported += 1;
+          }
+          None => {
+            state.store.items.push(item.clone());
+            report.imported += 1;
+          }
+        }
+        state.index.upsert(&item.id, &item.content);
+        scopes.insert(item.project_id.clone());
+        imported.insert(item.id.clone());
+      }
+      // 导入后结算受影响的作用域，本次导入的条目不淘汰
+      for scope in scopes.iter() {
+        auto_settle_memory(state, Some(scope), &imported, now_millis());
+      }
+      let dirty = report.imported + report.overwritten > 0;
+      Ok((report, dirty))
+    })
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+// ======== Memory near-duplicate detection ========
+
+const DEFAULT_DEDUP_THRESHOLD: f32 = 0.7;
+
+fn memory_token_set(text: &str) -> std::collections::HashSet<String> {
+  tokenize(text).into_iter().collect()
+}
+
+fn jaccard(a: &std::collections::HashSet<String>, b: &std::collections::HashSet<String>) -> f32 {
+  if a.is_empty() || b.is_empty() {
+    return 0.0;
+  }
+  let inter = a.intersection(b).count() as f32;
+  inter / ((a.len() + b.len()) as f32 - inter)
+}
+
+// 合并后的条目：沿用最近更新那条的 id/措辞，重要度取最高，时间取最新，标签取并集
+fn merge_memory_items(items: &[&MemoryItem]) -> Option<MemoryItem> {
+  let latest = items.iter().max_by_key(|i| i.updated_at)?;
+  let mut merged = (*latest).clone();
+  for item in items.iter() {
+    merged.importance = merged.importance.max(item.importance);
+    merged.updated_at = merged.updated_at.max(item.updated_at);
+    merged.last_used_at = merged.last_used_at.max(item.last_used_at);
+    merged.decayed_at = merged.decayed_at.max(item.decayed_at);
+    merged.pin = match (merged.pin, item.pin) {
+      (Some(a), Some(b)) => Some(a.max(b)),
+      (a, b) => a.or(b),
+    };
+    if !std::ptr::eq(*item, *latest) {
+      merged.use_count = merged.use_count.saturating_add(item.use_count);
+    }
+    if item.created_at > 0 && (merged.created_at <= 0 || item.created_at < merged.created_at) {
+      merged.created_at = item.created_at;
+    }
+    for tag in item.tags.iter() {
+      if !merged.tags.contains(tag) {
+        merged.tags.push(tag.clone());
+      }
+    }
+  }
+  Some(merged)
+}
+
+fn find_near_duplicate<'a>(items: &'a [MemoryItem], project_id: &str, content: &str, threshold: f32) -> Option<&'a MemoryItem> {
+  let tokens = memory_token_set(content);
+  items
+    .iter()
+    .filter(|i| i.project_id == project_id)
+    .map(|i| (jaccard(&tokens, &memory_token_set(&i.content)), i))
+    .filter(|(sim, _)| *sim >= threshold)
+    .max_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal))
+    .map(|(_, i)| i)
+}
+
+// 完全链接聚类：两组只有在跨组的每一对都达到阈值时才合并，
+// 避免 A≈B、B≈C 把并不相似的 A 和 C 串进同一组。返回 (成员下标, 组内最高相似度)，只含两条以上的组
+fn duplicate_clusters(sets: &[std::collections::HashSet<String>], threshold: f32) -> Vec<(Vec<usize>, f32)> {
+  let mut similar: HashMap<(usize, usize), f32> = HashMap::new();
+  for i in 0..sets.len() {
+    for j in (i + 1)..sets.len() {
+      let (a, b) = (sets[i].len() as f32, sets[j].len() as f32);
+      // |A∩B|/|A∪B| ≤ min/max，长度差太大时不可能达到阈值
+      if a.min(b) < threshold * a.max(b) {
+        continue;
+      }
+      let sim = jaccard(&sets[i], &sets[j]);
+      if sim >= threshold {
+        similar.insert((i, j), sim);
+      }
+    }
+  }
+
+  // 先合并最相似的一对
+  let mut pairs: Vec<((usize, usize), f32)> = similar.iter().map(|(k, v)| (*k, *v)).collect();
+  pairs.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal).then(a.0.cmp(&b.0)));
+  let mut cluster_of: Vec<usize> = (0..sets.len()).collect();
+  let mut members: Vec<Vec<usize>> = (0..sets.len()).map(|i| vec![i]).collect();
+  for ((i, j), _) in pairs {
+    let (ci, cj) = (cluster_of[i], cluster_of[j]);
+    if ci == cj {
+      continue;
+    }
+    let linked = members[ci]
+      .iter()
+      .all(|a| members[cj].iter().all(|b| similar.contains_key(&((*a).min(*b), (*a).max(*b)))));
+    if !linked {
+      continue;
+    }
+    let moved = std::mem::take(&mut members[cj]);
+    for m in moved.iter() {
+      cluster_of[*m] = ci;
+    }
+    members[ci].extend(moved);
+  }
+
+  members
+    .into_iter()
+    .filter(|group| group.len() > 1)
+    .map(|mut group| {
+      group.sort();
+      let mut best = 0.0f32;
+      for (n, a) in group.iter().enumerate() {
+        for b in group[n + 1..].iter() {
+          best = best.max(similar.get(&(*a, *b)).copied().unwrap_or(0.0));
+        }
+      }
+      (group, best)
+    })
+    .collect()
+}
+
+#[derive(serde::Serialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct MemoryDuplicateCluster {
+  items: Vec<MemoryItem>,
+  max_similarity: f32,
+  proposed: MemoryItem, // 建议保留的合并结果（id 为保留条目）
+}
+
+#[tauri::command(rename_all = "camelCase")]
+async fn memory_find_duplicates(
+  app: tauri::AppHandle,
+  project_id: Option<String>,
+  threshold: Option<f32>,
+) -> Result<Vec<MemoryDuplicateCluster>, String> {
+  tauri::async_runtime::spawn_blocking(move || -> Result<Vec<MemoryDuplicateCluster>, String> {
+    let project_id = normalize_project_id(project_id);
+    let threshold = threshold.unwrap_or(DEFAULT_DEDUP_THRESHOLD).clamp(0.3, 1.0);
+    with_memory(&app, |state| {
+      // 只在同一作用域内比较；全局记忆单独成组
+      let items: Vec<&MemoryItem> = state.store.items.iter().filter(|i| i.project_id == project_id).collect();
+      let sets: Vec<std::collections::HashSet<String>> = items.iter().map(|i| memory_token_set(&i.content)).collect();
+      let mut clusters: Vec<MemoryDuplicateCluster> = duplicate_clusters(&sets, threshold)
+        .into_iter()
+        .filter_map(|(members, max_similarity)| {
+          let group: Vec<&MemoryItem> = members.iter().map(|i| items[*i]).collect();
+          let proposed = merge_memory_items(&group)?;
+          Some(MemoryDuplicateCluster { items: group.into_iter().cloned().collect(), max_similarity, proposed })
+        })
+        .collect();
+      clusters.sort_by(|a, b| b.max_similarity.partial_cmp(&a.max_similarity).unwrap_or(std::cmp::Ordering::Equal));
+      Ok((clusters, false))
+    })
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+// 合并若干条记忆为一条：keep_id 缺省时保留最近更新的那条
+#[tauri::command(rename_all = "camelCase")]
+async fn memory_merge(app: tauri::AppHandle, ids: Vec<String>, keep_id: Option<String>) -> Result<MemoryItem, String> {
+  tauri::async_runtime::spawn_blocking(move || -> Result<MemoryItem, String> {
+    let ids: Vec<String> = ids.into_iter().map(|id| id.trim().to_string()).filter(|id| !id.is_empty()).collect();
+    with_memory(&app, |state| {
+      let group: Vec<&MemoryItem> = state.store.items.iter().filter(|i| ids.contains(&i.id)).collect();
+      if group.len() < 2 {
+        return Err("至少需要两条存在的记忆才能合并".to_string());
+      }
+      // 不同项目（或项目与全局）的记忆不能合并，否则会把一方的记忆挪进另一方的作用域
+      if group.iter().any(|i| i.project_id != group[0].project_id) {
+        return Err("只能合并同一项目下的记忆".to_string());
+      }
+      let mut merged = merge_memory_items(&group).ok_or("合并失败")?;
+      if let Some(keep) = keep_id.map(|k| k.trim().to_string()).filter(|k| !k.is_empty()) {
+        let kept = group.iter().find(|i| i.id == keep).ok_or_else(|| format!("keepId 不在合并列表中：{keep}"))?;
+        merged.id = kept.id.clone();
+        merged.content = kept.content.clone();
+        merged.project_id = kept.project_id.clone();
+        merged.source = kept.source.clone();
+      }
+
+      for id in ids.iter().filter(|id| **id != merged.id) {
+        state.index.remove(id);
+      }
+      state.store.items.retain(|i| i.id == merged.id || !ids.contains(&i.id));
+      if let Some(slot) = state.store.items.iter_mut().find(|i| i.id == merged.id) {
+        *slot = merged.clone();
+      }
+      state.index.upsert(&merged.id, &merged.content);
+      Ok((merged, true))
+    })
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+// 查询扩展：取最近几轮对话，新词最多这么多个（越近的轮次越优先）
+const MEMORY_CONTEXT_TURNS: usize = 3;
+const MEMORY_CONTEXT_MAX_TERMS: usize = 32;
+
+struct MemoryQuery {
+  terms: Vec<String>,
+  term_weights: HashMap<String, f32>, // 仅扩展词；缺省权重 1
+  project_id: String,
+  fuzzy: bool,
+  weights: ResolvedMemoryWeights,
+}
+
+impl MemoryQuery {
+  fn new(query: &str, project_id: String, weights: Option<MemorySearchWeights>, fuzzy: Option<bool>) -> Self {
+    let mut terms = tokenize(query);
+    terms.sort();
+    terms.dedup();
+    Self {
+      terms,
+      term_weights: HashMap::new(),
+      project_id,
+      fuzzy: fuzzy.unwrap_or(false),
+      weights: weights.unwrap_or_default().resolve(),
+    }
+  }
+
+  // 把最近几轮对话里的新词以较低权重并入查询（context 按时间顺序，最后一条最近）
+  fn expand(mut self, context: Option<Vec<String>>) -> Self {
+    let weight = self.weights.context_weight;
+    if weight <= 0.0 {
+      return self;
+    }
+    let mut added = 0usize;
+    for turn in context.unwrap_or_default().iter().rev().take(MEMORY_CONTEXT_TURNS) {
+      for term in tokenize(&normalize_text(turn)) {
+        if added >= MEMORY_CONTEXT_MAX_TERMS {
+          break;
+        }
+        if self.terms.contains(&term) {
+          continue;
+        }
+        self.term_weights.insert(term.clone(), weight);
+        self.terms.push(term);
+        added += 1;
+      }
+    }
+    self
+  }
+}
+
+#[derive(serde::Serialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct MemoryScoreBreakdown {
+  lexical: f32,            // BM25（含模糊命中）归一化得分 0..1
+  semantic: Option<f32>,   // 向量相似度 0..1（仅语义检索）
+  relevance_part: f32,     // 相关度 × lexical 权重
+  importance_part: f32,
+  recency_part: f32,
+  score: f32,
+}
+
+struct RankedMemory<'a> {
+  item: &'a MemoryItem,
+  breakdown: MemoryScoreBreakdown,
+}
+
+// 作用域内全部条目按最终得分降序；semantic 存在时相关度 = 词法与向量相似度按权重混合
+fn rank_memory<'a>(
+  state: &'a MemoryState,
+  query: &MemoryQuery,
+  semantic: Option<&HashMap<String, f32>>,
+  now: i64,
+) -> Vec<RankedMemory<'a>> {
+  let w = &query.weights;
+  let lexical = if query.fuzzy {
+    let index = &state.index;
+    let matcher = |id: &str, term: &str| -> Option<f32> {
+      let doc = index.docs.get(id)?;
+      fuzzy_term_weight(term, &doc.terms, &doc.pinyin, &w.fuzzy)
+    };
+    index.score_with(&query.terms, &query.term_weights, w.k1, w.b, Some(&matcher))
+  } else {
+    state.index.score_with(&query.terms, &query.term_weights, w.k1, w.b, None)
+  };
+
+  let mut ranked: Vec<RankedMemory> = state
+    .store
+    .items
+    .iter()
+    .filter(|item| memory_in_scope(item, &query.project_id, true))
+    .map(|item| {
+      let lex = lexical.get(&item.id).copied().unwrap_or(0.0);
+      let sem = semantic.map(|sem| sem.get(&item.id).copied().unwrap_or(0.0));
+      let relevance = match sem {
+        Some(sem) => lex * (1.0 - w.semantic) + sem * w.semantic,
+        None => lex,
+      };
+      let relevance_part = relevance * w.lexical;
+      let importance_part = w.importance_part(item);
+      let recency_part = w.recency_part(item, now);
+      RankedMemory {
+        item,
+        breakdown: MemoryScoreBreakdown {
+          lexical: lex,
+          semantic: sem,
+          relevance_part,
+          importance_part,
+          recency_part,
+          score: relevance_part + importance_part + recency_part,
+        },
+      }
+    })
+    .collect();
+  ranked.sort_by(|a, b| b.breakdown.score.partial_cmp(&a.breakdown.score).unwrap_or(std::cmp::Ordering::Equal));
+  ranked
+}
+
+// 过阈值后取前 limit 条；diversity > 0 时在前 limit×4 个候选上做 MMR，
+// 每次挑 (1-d)·得分/最高分 - d·与已选条目的最大相似度 最大者，避免同一主题占满结果
+fn select_memory_hits<'a>(
+  ranked: Vec<RankedMemory<'a>>,
+  min_score: f32,
+  limit: usize,
+  diversity: f32,
+) -> Vec<RankedMemory<'a>> {
+  let candidates = ranked.into_iter().filter(|r| r.breakdown.score >= min_score);
+  if diversity <= 0.0 {
+    return candidates.take(limit).collect();
+  }
+  let mut pool: Vec<RankedMemory> = candidates.take(limit.saturating_mul(4)).collect();
+  let top = pool.first().map(|r| r.breakdown.score).unwrap_or(0.0).max(f32::EPSILON);
+  let mut sets: Vec<std::collections::HashSet<String>> = pool.iter().map(|r| memory_token_set(&r.item.content)).collect();
+  let mut picked: Vec<RankedMemory> = vec![];
+  let mut picked_sets: Vec<std::collections::HashSet<String>> = vec![];
+  while picked.len() < limit && !pool.is_empty() {
+    let mut best = 0usize;
+    let mut best_mmr = f32::MIN;
+    for (i, r) in pool.iter().enumerate() {
+      let redundancy = picked_sets.iter().map(|p| jaccard(p, &sets[i])).fold(0.0f32, f32::max);
+      let mmr = (1.0 - diversity) * r.breakdown.score / top - diversity * redundancy;
+      if mmr > best_mmr {
+        best = i;
+        best_mmr = mmr;
+      }
+    }
+    picked.push(pool.remove(best));
+    picked_sets.push(sets.remove(best));
+  }
+  picked
+}
+
+#[tauri::command(rename_all = "camelCase")]
+#[allow(clippy::too_many_arguments)]
+async fn search_memory(
+  app: tauri::AppHandle,
+  query: String,
+  project_id: Option<String>,
+  limit: Option<usize>,
+  min_score: Option<f32>,
+  weights: Option<MemorySearchWeights>,
+  fuzzy: Option<bool>,
+  context: Option<Vec<String>>,
+) -> Result<Vec<MemoryItem>, String> {
+  let q = normalize_text(&query);
+  if q.is_empty() {
+    return Ok(vec![]);
+  }
+  let query = MemoryQuery::new(&q, normalize_project_id(project_id), weights, fuzzy).expand(context);
+  let limit = limit.unwrap_or(6).max(1);
+  let min_score = min_score.unwrap_or(0.12);
+
+  tauri::async_runtime::spawn_blocking(move || {
+    with_memory(&app, |state| {
+      let ranked = rank_memory(state, &query, None, now_millis());
+      let hits = select_memory_hits(ranked, min_score, limit, query.weights.diversity)
+        .into_iter()
+        .map(|r| r.item.clone())
+        .collect();
+      Ok((hits, false))
+    })
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+#[derive(serde::Serialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct MemoryFuzzyMatch {
+  token: String,
+  kind: String, // pinyin | prefix | typo
+}
+
+#[derive(serde::Serialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct MemorySearchHit {
+  item: MemoryItem,
+  #[serde(flatten)]
+  breakdown: MemoryScoreBreakdown,
+  matched_tokens: Vec<String>,
+  fuzzy_matches: Vec<MemoryFuzzyMatch>,
+}
+
+#[derive(serde::Serialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct MemorySearchExplain {
+  query_terms: Vec<String>,
+  min_score: f32,
+  hits: Vec<MemorySearchHit>,
+  near_misses: Vec<MemorySearchHit>, // 仅 debug：阈值以下、得分不低于阈值一半的条目
+}
+
+fn explain_memory_hit(state: &MemoryState, query: &MemoryQuery, ranked: RankedMemory) -> MemorySearchHit {
+  let id = &ranked.item.id;
+  let matched_tokens: Vec<String> = query
+    .terms
+    .iter()
+    .filter(|t| state.index.postings.get(*t).is_some_and(|l| l.contains_key(id)))
+    .cloned()
+    .collect();
+  let mut fuzzy_matches: Vec<MemoryFuzzyMatch> = vec![];
+  if query.fuzzy {
+    if let Some(doc) = state.index.docs.get(id) {
+      for term in query.terms.iter().filter(|t| !matched_tokens.contains(t)) {
+        if let Some((kind, _)) = fuzzy_term_hit(term, &doc.terms, &doc.pinyin, &query.weights.fuzzy) {
+          fuzzy_matches.push(MemoryFuzzyMatch { token: term.clone(), kind: kind.to_string() });
+        }
+      }
+    }
+  }
+  MemorySearchHit { item: ranked.item.clone(), breakdown: ranked.breakdown, matched_tokens, fuzzy_matches }
+}
+
+// search_memory 的可解释版本：每条命中附带各项得分与命中的词，debug 时返回阈值下的近似命中
+#[tauri::command(rename_all = "camelCase")]
+#[allow(clippy::too_many_arguments)]
+async fn search_memory_explain(
+  app: tauri::AppHandle,
+  query: String,
+  project_id: Option<String>,
+  limit: Option<usize>,
+  min_score: Option<f32>,
+  weights: Option<MemorySearchWeights>,
+  fuzzy: Option<bool>,
+  context: Option<Vec<String>>,
+  debug: Option<bool>,
+) -> Result<MemorySearchExplain, String> {
+  let q = normalize_text(&query);
+  let limit = limit.unwrap_or(6).max(1);
+  let min_score = min_score.unwrap_or(0.12);
+  if q.is_empty() {
+    return Ok(MemorySearchExplain { min_score, ..Default::default() });
+  }
+  let query = MemoryQuery::new(&q, normalize_project_id(project_id), weights, fuzzy).expand(context);
+  let debug = debug.unwrap_or(false);
+
+  tauri::async_runtime::spawn_blocking(move || {
+    with_memory(&app, |state| Ok((explain_memory_search(state, &query, min_score, limit, debug, now_millis()), false)))
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+fn explain_memory_search(
+  state: &MemoryState,
+  query: &MemoryQuery,
+  min_score: f32,
+  limit: usize,
+  debug: bool,
+  now: i64,
+) -> MemorySearchExplain {
+  let (above, below): (Vec<RankedMemory>, Vec<RankedMemory>) =
+    rank_memory(state, query, None, now).into_iter().partition(|r| r.breakdown.score >= min_score);
+  let hits = select_memory_hits(above, min_score, limit, query.weights.diversity)
+    .into_iter()
+    .map(|r| explain_memory_hit(state, query, r))
+    .collect();
+  let near_misses = if debug {
+    below
+      .into_iter()
+      .filter(|r| r.breakdown.score >= min_score * 0.5)
+      .take(limit)
+      .map(|r| explain_memory_hit(state, query, r))
+      .collect()
+  } else {
+    vec![]
+  };
+  MemorySearchExplain { query_terms: query.terms.clone(), min_score, hits, near_misses }
+}
+
+// ======== Semantic memory search (embeddings) ========
+
+const EMBEDDING_CACHE_VERSION: u32 = 1;
+const EMBEDDING_BATCH_SIZE: usize = 64;
+
+#[derive(serde::Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct EmbeddingConfig {
+  #[serde(default)]
+  base_url: String, // OpenAI 兼容端点，如 https://api.example.com/v1（本地服务同样可用）
+  #[serde(default)]
+  api_key: Option<String>,
+  #[serde(default)]
+  model: String,
+  #[serde(default)]
+  dimensions: Option<u32>,
+}
+
+type EmbedFuture<'a> = std::pin::Pin<Box<dyn std::future::Future<Output = Result<Vec<Vec<f32>>, String>> + Send + 'a>>;
+
+trait EmbeddingProvider: Send + Sync {
+  // 向量缓存的命名空间：换端点/模型后旧向量自动失效
+  fn cache_key(&self) -> String;
+  fn embed<'a>(&'a self, inputs: &'a [String]) -> EmbedFuture<'a>;
+}
+
+struct OpenAiEmbeddingProvider {
+  endpoint: String,
+  api_key: String,
+  model: String,
+  dimensions: Option<u32>,
+  client: reqwest::Client,
+}
+
+impl OpenAiEmbeddingProvider {
+  fn new(cfg: &EmbeddingConfig) -> Result<Self, String> {
+    let base = cfg.base_url.trim().trim_end_matches('/');
+    if base.is_empty() {
+      return Err("embedding baseUrl 不能为空".to_string());
+    }
+    if cfg.model.trim().is_empty() {
+      return Err("embedding model 不能为空".to_string());
+    }
+    let endpoint = if base.ends_with("/embeddings") { base.to_string() } else { format!("{base}/embeddings") };
+    let client = reqwest::Client::builder()
+      .user_agent("Nexus/1.0")
+      .timeout(Duration::from_secs(REQUEST_TIMEOUT_SECS))
+      .connect_timeout(Duration::from_secs(30))
+      .build()
+      .map_err(|e| e.to_string())?;
+    Ok(Self {
+      endpoint,
+      api_key: cfg.api_key.clone().unwrap_or_default().trim().to_string(),
+      model: cfg.model.trim().to_string(),
+      dimensions: cfg.dimensions.filter(|d| *d > 0),
+      client,
+    })
+  }
+
+  async fn embed_batch(&self, inputs: &[String]) -> Result<Vec<Vec<f32>>, String> {
+    let mut body = serde_json::json!({ "model": self.model, "input": inputs });
+    if let Some(d) = self.dimensions {
+      body["dimensions"] = Value::from(d);
+    }
+    let mut request = self
+      .client
+      .post(&self.endpoint)
+      .header(reqwest::header::CONTENT_TYPE, "application/json")
+      .body(serde_json::to_vec(&body).map_err(|e| e.to_string())?);
+    if !self.api_key.is_empty() {
+      request = request.header(reqwest::header::AUTHORIZATION, format!("Bearer {}", self.api_key));
+    }
+    let response = request.send().await.map_err(|e| format!("embedding 请求失败：{e}"))?;
+    let status = response.status();
+    let raw = response.bytes().await.map_err(|e| e.to_string())?;
+    if !status.is_success() {
+      let detail = String::from_utf8_lossy(&raw);
+      return Err(format!("embedding HTTP {}：{}", status, safe_slice(&detail, 200)));
+    }
+    let parsed: Value = serde_json::from_slice(&raw).map_err(|e| format!("embedding 响应解析失败：{e}"))?;
+    let data = parsed.get("data").and_then(|d| d.as_array()).ok_or("embedding 响应缺少 data")?;
+
+    let mut out: Vec<Option<Vec<f32>>> = vec![None; inputs.len()];
+    for (pos, entry) in data.iter().enumerate() {
+      let idx = entry.get("index").and_then(|v| v.as_u64()).map(|v| v as usize).unwrap_or(pos);
+      let vector: Vec<f32> = entry
+        .get("embedding")
+        .and_then(|v| v.as_array())
+        .map(|arr| arr.iter().filter_map(|x| x.as_f64()).map(|x| x as f32).collect())
+        .unwrap_or_default();
+      if let Some(slot) = out.get_mut(idx) {
+        *slot = Some(vector);
+      }
+    }
+    out
+      .into_iter()
+      .map(|v| v.filter(|v| !v.is_empty()).ok_or_else(|| "embedding 响应条数与输入不一致".to_string()))
+      .collect()
+  }
+}
+
+impl EmbeddingProvider for OpenAiEmbeddingProvider {
+  fn cache_key(&self) -> String {
+    let dims = self.dimensions.map(|d| d.to_string()).unwrap_or_default();
+    hash_key(&format!("openai|{}|{}|{}", self.endpoint, self.model, dims))[..16].to_string()
+  }
+
+  fn embed<'a>(&'a self, inputs: &'a [String]) -> EmbedFuture<'a> {
+    Box::pin(async move {
+      let mut out: Vec<Vec<f32>> = Vec::with_capacity(inputs.len());
+      for batch in inputs.chunks(EMBEDDING_BATCH_SIZE) {
+        out.extend(self.embed_batch(batch).await?);
+      }
+      Ok(out)
+    })
+  }
+}
+
+fn embedding_provider(cfg: &EmbeddingConfig) -> Result<Box<dyn EmbeddingProvider>, String> {
+  Ok(Box::new(OpenAiEmbeddingProvider::new(cfg)?))
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct CachedEmbedding {
+  hash: String, // 记忆内容的哈希，内容变化后重新计算
+  vector: Vec<f32>,
+}
+
+// provider cache key -> (memory id -> vector)
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct EmbeddingCache {
+  version: u32,
+  providers: HashMap<String, HashMap<String, CachedEmbedding>>,
+}
+
+impl MemoryState {
+  fn embedding_cache(&mut self) -> &mut EmbeddingCache {
+    let path = &self.embeddings_path;
+    self.embeddings.get_or_insert_with(|| {
+      std::fs::read(path)
+        .ok()
+        .and_then(|raw| serde_json::from_slice::<EmbeddingCache>(&raw).ok())
+        .filter(|c| c.version == EMBEDDING_CACHE_VERSION)
+        .unwrap_or(EmbeddingCache { version: EMBEDDING_CACHE_VERSION, ..Default::default() })
+    })
+  }
+
+  // 落盘前顺带清掉已删除记忆的向量
+  fn save_embedding_cache(&mut self) {
+    let live: std::collections::HashSet<String> = self.store.items.iter().map(|i| i.id.clone()).collect();
+    let path = self.embeddings_path.clone();
+    let cache = self.embedding_cache();
+    for vectors in cache.providers.values_mut() {
+      vectors.retain(|id, _| live.contains(id));
+    }
+    if let Err(err) = write_json_atomic(&path, &*cache) {
+      log::warn!("[memory] 保存向量缓存失败: {}", err);
+    }
+  }
+
+  // 作用域内缺向量、或内容哈希与缓存不符的记忆：(id, content, hash)
+  fn pending_embeddings(&mut self, cache_key: &str, project_id: &str) -> Vec<(String, String, String)> {
+    let in_scope: Vec<(String, String)> = self
+      .store
+      .items
+      .iter()
+      .filter(|i| memory_in_scope(i, project_id, true))
+      .map(|i| (i.id.clone(), i.content.clone()))
+      .collect();
+    let cached = self.embedding_cache().providers.get(cache_key);
+    in_scope
+      .into_iter()
+      .filter_map(|(id, content)| {
+        let hash = hash_key(&content);
+        let fresh = cached.and_then(|c| c.get(&id)).is_some_and(|e| e.hash == hash);
+        if fresh { None } else { Some((id, content, hash)) }
+      })
+      .collect()
+  }
+
+  fn store_embeddings(&mut self, cache_key: &str, vectors: impl IntoIterator<Item = ((String, String, String), Vec<f32>)>) {
+    let cache = self.embedding_cache().providers.entry(cache_key.to_string()).or_default();
+    for ((id, _, hash), vector) in vectors {
+      cache.insert(id, CachedEmbedding { hash, vector });
+    }
+  }
+
+  // memory id -> 与查询向量的余弦相似度（截到 0..1）
+  fn semantic_scores(&mut self, cache_key: &str, query_vector: &[f32]) -> HashMap<String, f32> {
+    self
+      .embedding_cache()
+      .providers
+      .get(cache_key)
+      .map(|cache| {
+        cache
+          .iter()
+          .map(|(id, e)| (id.clone(), cosine_similarity(query_vector, &e.vector).clamp(0.0, 1.0)))
+          .collect()
+      })
+      .unwrap_or_default()
+  }
+}
+
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+  if a.len() != b.len() || a.is_empty() {
+    return 0.0;
+  }
+  let (mut dot, mut na, mut nb) = (0.0f32, 0.0f32, 0.0f32);
+  for (x, y) in a.iter().zip(b.iter()) {
+    dot += x * y;
+    na += x * x;
+    nb += y * y;
+  }
+  if na == 0.0 || nb == 0.0 {
+    return 0.0;
+  }
+  dot / (na.sqrt() * nb.sqrt())
+}
+
+#[tauri::command(rename_all = "camelCase")]
+#[allow(clippy::too_many_arguments)]
+async fn search_memory_semantic(
+  app: tauri::AppHandle,
+  query: String,
+  embedding: EmbeddingConfig,
+  project_id: Option<String>,
+  limit: Option<usize>,
+  min_score: Option<f32>,
+  weights: Option<MemorySearchWeights>,
+  fuzzy: Option<bool>,
+  context: Option<Vec<String>>,
+) -> Result<Vec<MemoryItem>, String> {
+  let q = normalize_text(&query);
+  if q.is_empty() {
+    return Ok(vec![]);
+  }
+  let provider = embedding_provider(&embedding)?;
+  let cache_key = provider.cache_key();
+  // 向量只按用户原话生成；上下文扩展只作用于词法部分
+  let query = MemoryQuery::new(&q, normalize_project_id(project_id), weights, fuzzy).expand(context);
+  let limit = limit.unwrap_or(6).max(1);
+  let min_score = min_score.unwrap_or(0.12);
+
+  // 1) 找出缺向量/内容已变的记忆（不持锁发请求）
+  let pending = {
+    let app = app.clone();
+    let cache_key = cache_key.clone();
+    let project_id = query.project_id.clone();
+    tauri::async_runtime::spawn_blocking(move || {
+      with_memory(&app, |state| Ok((state.pending_embeddings(&cache_key, &project_id), false)))
+    })
+    .await
+    .map_err(|e| e.to_string())??
+  };
+
+  // 2) 查询和待补的记忆一起批量请求
+  let mut inputs: Vec<String> = vec![q.clone()];
+  inputs.extend(pending.iter().map(|(_, content, _)| content.clone()));
+  let mut vectors = provider.embed(&inputs).await?.into_iter();
+  let query_vector = vectors.next().ok_or("embedding 响应为空")?;
+
+  // 3) 写回缓存并混合打分
+  tauri::async_runtime::spawn_blocking(move || {
+    with_memory(&app, |state| {
+      if !pending.is_empty() {
+        state.store_embeddings(&cache_key, pending.into_iter().zip(vectors));
+        state.save_embedding_cache();
+      }
+      let semantic = state.semantic_scores(&cache_key, &query_vector);
+      let ranked = rank_memory(state, &query, Some(&semantic), now_millis());
+      let hits = select_memory_hits(ranked, min_score, limit, query.weights.diversity)
+        .into_iter()
+        .map(|r| r.item.clone())
+        .collect();
+      Ok((hits, false))
+    })
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+// ======== Conversation summary (extractive, TextRank) ========
+
+const SUMMARY_MAX_SENTENCES: usize = 300;
+const SUMMARY_DAMPING: f32 = 0.85;
+
+// 按中英文句末标点与换行切句；英文句点后需跟空白/结尾，且不切 "Mr." 这类短的大写缩写
+fn split_sentences(text: &str) -> Vec<String> {
+  let mut out: Vec<String> = vec![];
+  let mut cur = String::new();
+  let mut chars = text.chars().peekable();
+  while let Some(ch) = chars.next() {
+    if ch == '\n' || ch == '\r' {
+      flush_sentence(&mut out, &mut cur);
+      continue;
+    }
+    cur.push(ch);
+    let boundary = match ch {
+      '。' | '！' | '？' | '；' | '…' | '!' | '?' | ';' => true,
+      '.' => chars.peek().map_or(true, |c| c.is_whitespace()) && !ends_with_abbreviation(&cur),
+      _ => false,
+    };
+    if boundary {
+      // 连续的结束符（"？！"、"……"）归到同一句
+      while let Some(&next) = chars.peek() {
+        if matches!(next, '。' | '！' | '？' | '…' | '!' | '?' | '"' | '”' | '’' | '）' | ')') {
+          cur.push(next);
+          chars.next();
+        } else {
+          break;
+        }
+      }
+      flush_sentence(&mut out, &mut cur);
+    }
+  }
+  flush_sentence(&mut out, &mut cur);
+  out
+}
+
+fn ends_with_abbreviation(cur: &str) -> bool {
+  let word = cur.trim_end_matches('.').rsplit(char::is_whitespace).next().unwrap_or("");
+  let n = word.chars().count();
+  (1..=3).contains(&n) && word.chars().next().is_some_and(|c| c.is_ascii_uppercase()) && word.chars().all(|c| c.is_ascii_alphabetic())
+}
+
+fn flush_sentence(out: &mut Vec<String>, cur: &mut String) {
+  let t = normalize_text(cur);
+  cur.clear();
+  // 太短的句子（"好的。"、"OK"）不值得进摘要
+  if t.chars().count() >= 4 {
+    out.push(t.chars().take(200).collect());
+  }
+}
+
+struct SummarySentence {
+  text: String,
+  terms: std::collections::HashSet<String>,
+  bonus: f32, // 旧摘要（已浓缩过的历史）与用户原话额外加分
+}
+
+// 加权 PageRank：边权为 TextRank 的相似度 |A∩B| / (ln|A| + ln|B|)
+fn textrank(sentences: &[SummarySentence]) -> Vec<f32> {
+  let n = sentences.len();
+  let mut weights = vec![vec![0.0f32; n]; n];
+  for i in 0..n {
+    for j in (i + 1)..n {
+      let (a, b) = (&sentences[i].terms, &sentences[j].terms);
+      if a.is_empty() || b.is_empty() {
+        continue;
+      }
+      let overlap = a.intersection(b).count() as f32;
+      if overlap == 0.0 {
+        continue;
+      }
+      let w = overlap / ((a.len() as f32 + 1.0).ln() + (b.len() as f32 + 1.0).ln());
+      weights[i][j] = w;
+      weights[j][i] = w;
+    }
+  }
+  let out_sum: Vec<f32> = weights.iter().map(|row| row.iter().sum()).collect();
+  let mut scores = vec![1.0f32; n];
+  for _ in 0..50 {
+    let mut next = vec![1.0 - SUMMARY_DAMPING; n];
+    for (j, row) in weights.iter().enumerate() {
+      if out_sum[j] <= 0.0 {
+        continue;
+      }
+      let share = SUMMARY_DAMPING * scores[j] / out_sum[j];
+      for (i, w) in row.iter().enumerate() {
+        if *w > 0.0 {
+          next[i] += w * share;
+        }
+      }
+    }
+    let delta: f32 = next.iter().zip(scores.iter()).map(|(a, b)| (a - b).abs()).sum();
+    scores = next;
+    if delta < 1e-4 {
+      break;
+    }
+  }
+  // 归一到均值 1 后再加分，孤立句（与其他句无重合）也有机会保留
+  let mean = (scores.iter().sum::<f32>() / n.max(1) as f32).max(f32::EPSILON);
+  scores.iter().zip(sentences.iter()).map(|(s, sent)| s / mean + sent.bonus).collect()
+}
+
+#[derive(serde::Serialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct ConversationSummary {
+  summary: String,
+  summarized_turns: usize, // 本次新并入摘要的旧对话条数（不含保留原文的最近几轮）
+  summarized_until: usize, // 已并入摘要的对话条数（按过滤后的 user/assistant 计），下次原样传回
+  sentence_count: usize,
+}
+
+// 滚动摘要：最近 keep_recent 条原样留给 build_chat_messages；旧摘要只与上次之后
+// 新滑出窗口的对话一起做句子级抽取，按原顺序拼回，不超过 max_summary_chars。
+// summarized_until 超过当前对话长度时（对话被清空/替换）从头开始
+#[tauri::command(rename_all = "camelCase")]
+fn summarize_conversation(
+  conversation: Vec<ChatMessage>,
+  previous_summary: Option<String>,
+  max_summary_chars: Option<usize>,
+  keep_recent: Option<usize>,
+  summarized_until: Option<usize>,
+  locale: Option<String>,
+) -> ConversationSummary {
+  let max_chars = max_summary_chars.unwrap_or(600).min(4000);
+  let keep_recent = keep_recent.unwrap_or(16);
+  let turns: Vec<&ChatMessage> = conversation
+    .iter()
+    .filter(|m| (m.role == "user" || m.role == "assistant") && !normalize_text(&m.content).is_empty())
+    .collect();
+  let end = turns.len().saturating_sub(keep_recent);
+  let cursor = summarized_until.filter(|n| *n <= turns.len()).unwrap_or(0);
+  let older = &turns[cursor.min(end)..end];
+  let previous = normalize_text(previous_summary.as_deref().unwrap_or(""));
+  if older.is_empty() {
+    // 没有新滑出窗口的对话：摘要不变
+    return ConversationSummary { summary: previous.chars().take(max_chars).collect(), summarized_until: end.max(cursor), ..Default::default() };
+  }
+
+  let mut sentences: Vec<SummarySentence> = vec![];
+  for text in split_sentences(&previous) {
+    sentences.push(SummarySentence { terms: memory_token_set(&text), text, bonus: 1.0 });
+  }
+  let user_prefix = if locale_is_english(locale.as_deref().unwrap_or("")) { "User: " } else { "用户：" };
+  let mut slid_out: Vec<SummarySentence> = vec![];
+  for m in older.iter() {
+    let (prefix, bonus) = if m.role == "user" { (user_prefix, 0.2) } else { ("", 0.0) };
+    for text in split_sentences(&m.content) {
+      // 相似度按原句计算，前缀不参与
+      let terms = memory_token_set(&text);
+      let text = if text.starts_with(prefix) { text } else { format!("{prefix}{text}") };
+      slid_out.push(SummarySentence { terms, text, bonus });
+    }
+  }
+  // 新滑出的句子太多时只保留最新的部分，控制 O(n²) 的相似度矩阵；
+  // 旧摘要本身受 max_chars 限制，始终全部参与，不会因为一次滑出很多对话而被挤掉
+  if slid_out.len() > SUMMARY_MAX_SENTENCES {
+    slid_out.drain(..slid_out.len() - SUMMARY_MAX_SENTENCES);
+  }
+  sentences.extend(slid_out);
+  if max_chars == 0 || sentences.is_empty() {
+    return ConversationSummary {
+      summary: previous.chars().take(max_chars).collect(),
+      summarized_turns: older.len(),
+      summarized_until: end,
+      sentence_count: 0,
+    };
+  }
+
+  let scores = textrank(&sentences);
+  let mut order: Vec<usize> = (0..sentences.len()).collect();
+  order.sort_by(|a, b| scores[*b].partial_cmp(&scores[*a]).unwrap_or(std::cmp::Ordering::Equal).then(a.cmp(b)));
+
+  let mut picked: Vec<usize> = vec![];
+  let mut used = 0usize;
+  for i in order {
+    let len = sentences[i].text.chars().count();
+    let sep = usize::from(!picked.is_empty());
+    if used + len + sep > max_chars {
+      continue;
+    }
+    // 与已选句高度重合的不再重复收录
+    if picked.iter().any(|p| jaccard(&sentences[*p].terms, &sentences[i].terms) >= 0.7) {
+      continue;
+    }
+    used += len + sep;
+    picked.push(i);
+  }
+  picked.sort_unstable();
+
+  ConversationSummary {
+    summary: picked.iter().map(|i| sentences[*i].text.as_str()).collect::<Vec<_>>().join("\n"),
+    summarized_turns: older.len(),
+    summarized_until: end,
+    sentence_count: picked.len(),
+  }
+}
+
+#[derive(serde::Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct ContextConfig {
+  #[serde(default)]
+  max_chars: i64,
+  #[serde(default)]
+  max_history: i64,
+  #[serde(default)]
+  max_memory_items: i64,
+  #[serde(default)]
+  max_canvas_chars: i64,
+  #[serde(default)]
+  max_memory_chars: i64,
+  #[serde(default)]
+  max_summary_chars: i64,
+  // 置顶段（置顶的消息与记忆）单独的上限，不超过总预算的 1/3
+  #[serde(default)]
+  max_pinned_chars: i64,
+  #[serde(default)]
+  max_pinned_tokens: i64,
+  // 以 token 计的预算：max_tokens > 0 时各段都按 token 计量，*_chars 不再生效
+  #[serde(default)]
+  max_tokens: i64,
+  #[serde(default)]
+  max_canvas_tokens: i64,
+  #[serde(default)]
+  max_memory_tokens: i64,
+  #[serde(default)]
+  max_summary_tokens: i64,
+  #[serde(default)]
+  model: String, // 用于推断编码
+  #[serde(default)]
+  encoding: String, // cl100k_base | o200k_base | heuristic；空 = 按 model 推断
+  // 图片单独预算：不占文本额度，按张数限制，从最新的消息往前保留
+  #[serde(default)]
+  max_images: i64,
+  #[serde(default)]
+  image_detail: String, // 图片未指定 detail 时使用：auto | low | high
+  #[serde(default)]
+  locale: String, // 段落标题语言：zh（默认）| en
+  #[serde(default)]
+  templates: ContextTemplates,
+}
+
+// 自定义段落模板，覆盖 locale 的内置模板。占位符：{content} 段落正文，{count} 行数/条数；
+// 缺少 {content} 时正文接在模板后一行
+#[derive(serde::Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct ContextTemplates {
+  #[serde(default)]
+  pinned: Option<String>,
+  #[serde(default)]
+  summary: Option<String>,
+  #[serde(default)]
+  memory: Option<String>,
+  #[serde(default)]
+  canvas: Option<String>,
+  #[serde(default)]
+  elision: Option<String>, // 长消息中间省略处的提示
+}
+
+struct SectionTemplates {
+  pinned: String,
+  summary: String,
+  memory: String,
+  canvas: String,
+  elision: String, // 已包含前后换行
+}
+
+impl SectionTemplates {
+  fn resolve(cfg: &ContextConfig) -> Self {
+    let english = locale_is_english(&cfg.locale);
+    let (pinned, summary, memory, canvas, elision) = if english {
+      (
+        "[Pinned notes (always apply)]\n{content}",
+        "[Long-term memory summary]\n{content}",
+        "[Relevant memories]\n{content}",
+        "[Current project context]\n{content}",
+        "… (omitted) …",
+      )
+    } else {
+      (
+        "【置顶信息（始终有效）】\n{content}",
+        "【长期记忆摘要】\n{content}",
+        "【长期记忆（检索命中）】\n{content}",
+        "【当前项目上下文】\n{content}",
+        "…（中间省略）…",
+      )
+    };
+    let pick = |custom: &Option<String>, builtin: &str| {
+      custom.as_deref().map(str::trim).filter(|t| !t.is_empty()).unwrap_or(builtin).to_string()
+    };
+    Self {
+      pinned: pick(&cfg.templates.pinned, pinned),
+      summary: pick(&cfg.templates.summary, summary),
+      memory: pick(&cfg.templates.memory, memory),
+      canvas: pick(&cfg.templates.canvas, canvas),
+      elision: format!("\n{}\n", pick(&cfg.templates.elision, elision)),
+    }
+  }
+}
+
+fn fill_template(template: &str, content: &str, count: usize) -> String {
+  let t = template.replace("{count}", &count.to_string());
+  if t.contains("{content}") {
+    t.replace("{content}", content)
+  } else {
+    format!("{t}\n{content}")
+  }
+}
+
+fn clamp_i64(n: i64, a: i64, b: i64) -> i64 {
+  n.max(a).min(b)
+}
+
+// ======== Token counting (BPE + heuristic fallback) ========
+
+// OpenAI 聊天格式每条消息的固定开销（role、分隔符），以及回复的起始标记
+const TOKENS_PER_MESSAGE: usize = 4;
+const TOKENS_REPLY_PRIMING: usize = 3;
+
+static BPE_CL100K: OnceLock<Option<Arc<tiktoken_rs::CoreBPE>>> = OnceLock::new();
+static BPE_O200K: OnceLock<Option<Arc<tiktoken_rs::CoreBPE>>> = OnceLock::new();
+
+#[derive(Clone)]
+enum TokenCounter {
+  Bpe(&'static str, Arc<tiktoken_rs::CoreBPE>),
+  Heuristic,
+}
+
+impl TokenCounter {
+  // encoding 显式指定优先；否则按模型名推断，未知模型用 cl100k（中文计数偏保守）
+  fn for_model(model: &str, encoding: &str) -> Self {
+    let model = model.trim().to_ascii_lowercase();
+    let encoding = match encoding.trim().to_ascii_lowercase().as_str() {
+      "" => {
+        let o200k = ["gpt-4o", "gpt-4.1", "gpt-5", "o1", "o3", "o4", "chatgpt-4o"];
+        if o200k.iter().any(|p| model.starts_with(p)) { "o200k_base" } else { "cl100k_base" }
+      }
+      "o200k_base" | "o200k" => "o200k_base",
+      "cl100k_base" | "cl100k" => "cl100k_base",
+      _ => "heuristic",
+    };
+    let bpe = match encoding {
+      "o200k_base" => BPE_O200K.get_or_init(|| load_bpe(encoding, tiktoken_rs::o200k_base)),
+      "cl100k_base" => BPE_CL100K.get_or_init(|| load_bpe(encoding, tiktoken_rs::cl100k_base)),
+      _ => return TokenCounter::Heuristic,
+    };
+    bpe.clone().map(|bpe| TokenCounter::Bpe(encoding, bpe)).unwrap_or(TokenCounter::Heuristic)
+  }
+
+  fn count(&self, text: &str) -> usize {
+    match self {
+      TokenCounter::Bpe(_, bpe) => bpe.encode_ordinary(text).len(),
+      TokenCounter::Heuristic => heuristic_token_count(text),
+    }
+  }
+
+  fn name(&self) -> &'static str {
+    match self {
+      TokenCounter::Bpe(name, _) => name,
+      TokenCounter::Heuristic => "heuristic",
+    }
+  }
+}
+
+fn load_bpe<E: std::fmt::Display>(
+  name: &str,
+  load: fn() -> Result<tiktoken_rs::CoreBPE, E>,
+) -> Option<Arc<tiktoken_rs::CoreBPE>> {
+  match load() {
+    Ok(bpe) => Some(Arc::new(bpe)),
+    Err(err) => {
+      log::warn!("[context] 加载 {} 编码失败，改用估算: {}", name, err);
+      None
+    }
+  }
+}
+
+// 估算：CJK 字符约 1 token/字，其余约 4 字符/token
+fn heuristic_token_count(text: &str) -> usize {
+  let mut cjk = 0usize;
+  let mut other = 0usize;
+  for ch in text.chars() {
+    match token_script(ch) {
+      TokenScript::Han | TokenScript::Hangul => cjk += 1, // 假名归在 Han
+      _ => other += 1,
+    }
+  }
+  cjk + other.div_ceil(4)
+}
+
+// 上下文计量：按字符（旧配置）或按 token（配置了 max_tokens）
+#[derive(Clone)]
+enum ContextMeter {
+  Chars,
+  Tokens(TokenCounter),
+}
+
+impl ContextMeter {
+  fn from_config(cfg: &ContextConfig) -> Self {
+    if cfg.max_tokens > 0 {
+      ContextMeter::Tokens(TokenCounter::for_model(&cfg.model, &cfg.encoding))
+    } else {
+      ContextMeter::Chars
+    }
+  }
+
+  fn is_tokens(&self) -> bool {
+    matches!(self, ContextMeter::Tokens(_))
+  }
+
+  fn unit(&self) -> &'static str {
+    match self {
+      ContextMeter::Chars => "chars",
+      ContextMeter::Tokens(counter) => counter.name(),
+    }
+  }
+
+  // 同一个上限在两种计量下的取值
+  fn pick(&self, chars: usize, tokens: usize) -> usize {
+    if self.is_tokens() { tokens } else { chars }
+  }
+
+  fn len(&self, text: &str) -> usize {
+    match self {
+      ContextMeter::Chars => text.chars().count(),
+      ContextMeter::Tokens(counter) => counter.count(text),
+    }
+  }
+
+  // 截到不超过 max 个单位（保留开头）
+  fn truncate(&self, text: &str, max: usize) -> String {
+    self.fit(text, max, false)
+  }
+
+  // 同上，保留结尾
+  fn truncate_tail(&self, text: &str, max: usize) -> String {
+    self.fit(text, max, true)
+  }
+
+  // 过长的文本保留头尾、省略中间（头 60%，尾 40%），mark 为省略提示
+  fn elide_middle(&self, text: &str, max: usize, mark: &str) -> String {
+    if self.len(text) <= max {
+      return text.to_string();
+    }
+    let room = max.saturating_sub(self.len(mark));
+    if room == 0 {
+      return self.truncate(text, max);
+    }
+    let head = room * 3 / 5;
+    format!("{}{}{}", self.truncate(text, head), mark, self.truncate_tail(text, room - head))
+  }
+
+  // token 计量时对字符数二分
+  fn fit(&self, text: &str, max: usize, tail: bool) -> String {
+    if self.len(text) <= max {
+      return text.to_string();
+    }
+    let chars: Vec<char> = text.chars().collect();
+    let take = |n: usize| -> String {
+      if tail { chars[chars.len() - n..].iter().collect() } else { chars[..n].iter().collect() }
+    };
+    match self {
+      ContextMeter::Chars => take(max.min(chars.len())),
+      ContextMeter::Tokens(counter) => {
+        let (mut lo, mut hi) = (0usize, chars.len());
+        while lo < hi {
+          let mid = (lo + hi).div_ceil(2);
+          if counter.count(&take(mid)) <= max {
+            lo = mid;
+          } else {
+            hi = mid - 1;
+          }
+        }
+        take(lo)
+      }
+    }
+  }
+
+  fn messages(&self, messages: &[ChatMessage]) -> usize {
+    let body: usize = messages
+      .iter()
+      .map(|m| {
+        let calls: usize = m.tool_calls.iter().map(|c| self.len(&c.function.name) + self.len(&c.function.arguments)).sum();
+        self.len(&m.content) + calls
+      })
+      .sum();
+    if self.is_tokens() {
+      body + messages.len() * TOKENS_PER_MESSAGE + TOKENS_REPLY_PRIMING
+    } else {
+      body
+    }
+  }
+}
+
+fn compact_lines(lines: &[String], max: usize, meter: &ContextMeter) -> String {
+  let mut out: Vec<String> = vec![];
+  let mut used = 0usize;
+  for line in lines {
+    let t = normalize_text(line);
+    if t.is_empty() {
+      continue;
+    }
+    let sep = usize::from(!out.is_empty());
+    let n = meter.len(&t) + sep;
+    if used + n > max {
+      // 放不下的那一行截断后放入剩余空间，不让一条超长的行把后面整段挤掉
+      let cut = meter.truncate(&t, max.saturating_sub(used + sep));
+      if !cut.is_empty() {
+        out.push(cut);
+      }
+      break;
+    }
+    used += n;
+    out.push(t);
+  }
+  out.join("\n")
+}
+
+fn config_limit(n: i64, default: i64, min: i64, max: i64) -> usize {
+  clamp_i64(if n > 0 { n } else { default }, min, max) as usize
+}
+
+// ======== Chat images (budget + data URL inlining) ========
+
+const MAX_INLINE_IMAGE_BYTES: u64 = 20 * 1024 * 1024;
+// OpenAI 视觉计费：low 固定 85；high/auto 按 1024² 四块估算 85 + 170×4
+const IMAGE_TOKENS_LOW: usize = 85;
+const IMAGE_TOKENS_HIGH: usize = 765;
+
+fn percent_decode(text: &str) -> String {
+  let bytes = text.as_bytes();
+  let mut out: Vec<u8> = Vec::with_capacity(bytes.len());
+  let mut i = 0;
+  while i < bytes.len() {
+    if bytes[i] == b'%' && i + 2 < bytes.len() {
+      let hex = |b: u8| (b as char).to_digit(16);
+      if let (Some(hi), Some(lo)) = (hex(bytes[i + 1]), hex(bytes[i + 2])) {
+        out.push((hi * 16 + lo) as u8);
+        i += 3;
+        continue;
+      }
+    }
+    out.push(bytes[i]);
+    i += 1;
+  }
+  String::from_utf8_lossy(&out).to_string()
+}
+
+// 本地图片的几种写法：绝对路径、file://、convertFileSrc 生成的 asset 协议 URL
+fn local_image_path(url: &str) -> Option<PathBuf> {
+  let url = url.trim();
+  for prefix in ["asset://localhost/", "http://asset.localhost/", "https://asset.localhost/"] {
+    if let Some(rest) = url.strip_prefix(prefix) {
+      return Some(PathBuf::from(percent_decode(rest)));
+    }
+  }
+  if url.starts_with("file://") {
+    return reqwest::Url::parse(url).ok()?.to_file_path().ok();
+  }
+  let path = Path::new(url);
+  path.is_absolute().then(|| path.to_path_buf())
+}
+
+fn image_mime(path: &Path, bytes: &[u8]) -> &'static str {
+  match bytes {
+    [0x89, b'P', b'N', b'G', ..] => return "image/png",
+    [0xFF, 0xD8, 0xFF, ..] => return "image/jpeg",
+    [b'G', b'I', b'F', b'8', ..] => return "image/gif",
+    [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => return "image/webp",
+    _ => {}
+  }
+  match path.extension().and_then(|e| e.to_str()).map(|e| e.to_ascii_lowercase()).as_deref() {
+    Some("jpg") | Some("jpeg") => "image/jpeg",
+    Some("gif") => "image/gif",
+    Some("webp") => "image/webp",
+    Some("avif") => "image/avif",
+    _ => "image/png",
+  }
+}
+
+// 只内联 root 目录下的文件，避免前端传任意路径把本机文件发给模型
+fn inline_local_image(path: &Path, root: Option<&Path>) -> Result<String, String> {
+  let root = root.ok_or("无法确定缓存目录")?;
+  let canonical = path.canonicalize().map_err(|e| format!("{}: {e}", path.display()))?;
+  let root = root.canonicalize().map_err(|e| e.to_string())?;
+  if !canonical.starts_with(&root) {
+    return Err(format!("{}: 不在应用缓存目录内", path.display()));
+  }
+  let size = std::fs::metadata(&canonical).map_err(|e| e.to_string())?.len();
+  if size > MAX_INLINE_IMAGE_BYTES {
+    return Err(format!("{}: 图片过大（{} bytes）", path.display(), size));
+  }
+  let bytes = std::fs::read(&canonical).map_err(|e| e.to_string())?;
+  Ok(format!("data:{};base64,{}", image_mime(&canonical, &bytes), general_purpose::STANDARD.encode(&bytes)))
+}
+
+fn default_image_detail(cfg: &ContextConfig) -> Option<String> {
+  match cfg.image_detail.trim() {
+    "low" | "high" | "auto" => Some(cfg.image_detail.trim().to_string()),
+    _ => None,
+  }
+}
+
+fn image_tokens(detail: Option<&str>) -> usize {
+  if detail == Some("low") { IMAGE_TOKENS_LOW } else { IMAGE_TOKENS_HIGH }
+}
+
+// 组装前预留给图片的 token：与 apply_image_budget 同样从最新往前、去重、按张数上限取，
+// 不考虑读取失败，因此是实际用量的上界
+fn image_token_reserve<'a>(messages: impl Iterator<Item = &'a ChatMessage>, cfg: &ContextConfig) -> usize {
+  let max_images = config_limit(cfg.max_images, 4, 0, 16);
+  let default_detail = default_image_detail(cfg);
+  let mut seen: std::collections::HashSet<&str> = std::collections::HashSet::new();
+  messages
+    .flat_map(|m| m.images.iter())
+    .filter(|image| seen.insert(image.url.trim()))
+    .take(max_images)
+    .map(|image| image_tokens(image.detail.as_deref().or(default_detail.as_deref())))
+    .sum()
+}
+
+// 从最新的消息往前保留至多 max_images 张（同一 URL 只保留最新一次），其余移除；
+// 保留下来的本地图片此时才读文件转 data URL
+fn apply_image_budget(messages: &mut [ChatMessage], cfg: &ContextConfig, root: Option<&Path>) -> ContextImageReport {
+  let mut report = ContextImageReport::default();
+  let max_images = config_limit(cfg.max_images, 4, 0, 16);
+  let default_detail = default_image_detail(cfg);
+  let mut seen: std::collections::HashSet<String> = std::collections::HashSet::new();
+  for m in messages.iter_mut().rev() {
+    if m.images.is_empty() {
+      continue;
+    }
+    let images = std::mem::take(&mut m.images);
+    for mut image in images {
+      let url = image.url.trim().to_string();
+      if !seen.insert(url.clone()) {
+        continue;
+      }
+      if report.kept >= max_images {
+        report.dropped += 1;
+        continue;
+      }
+      if let Some(path) = local_image_path(&url) {
+        match inline_local_image(&path, root) {
+          Ok(data_url) => {
+            image.url = data_url;
+            report.inlined += 1;
+          }
+          Err(err) => {
+            report.errors.push(err);
+            continue;
+          }
+        }
+      }
+      if image.detail.is_none() {
+        image.detail = default_detail.clone();
+      }
+      report.est_tokens += image_tokens(image.detail.as_deref());
+      report.kept += 1;
+      m.images.push(image);
+    }
+  }
+  report
+}
+
+// ======== Conversation threads (per project, branching) ========
+
+const CONVERSATION_STORE_DIR: &str = "nexus-conversations";
+const CONVERSATION_STORE_VERSION: u32 = 1;
+
+// 消息以 parent_id 串成树：编辑较早的消息 = 在其 parent 下追加新消息，形成分支
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct ThreadMessage {
+  id: String,
+  #[serde(default)]
+  parent_id: Option<String>, // None = 根消息
+  message: ChatMessage,
+  created_at: i64,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct ConversationThread {
+  id: String,
+  #[serde(default)]
+  title: String,
+  created_at: i64,
+  updated_at: i64,
+  #[serde(default)]
+  head_id: Option<String>, // 当前分支末端
+  #[serde(default)]
+  forked_from: Option<String>,
+  #[serde(default)]
+  messages: Vec<ThreadMessage>, // 按创建顺序
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct ConversationStore {
+  version: u32,
+  threads: Vec<ConversationThread>,
+}
+
+#[derive(serde::Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+struct ConversationThreadSummary {
+  id: String,
+  title: String,
+  created_at: i64,
+  updated_at: i64,
+  head_id: Option<String>,
+  forked_from: Option<String>,
+  message_count: usize,
+  branch_count: usize, // 叶子数
+  preview: String,     // head 消息的一行摘要
+}
+
+#[derive(serde::Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+struct BranchMessage {
+  #[serde(flatten)]
+  entry: ThreadMessage,
+  siblings: Vec<String>, // 同一 parent 下的全部消息 id（含自身，按时间），用于切换分支
+}
+
+#[derive(serde::Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+struct ConversationBranch {
+  thread: ConversationThreadSummary,
+  tip_id: Option<String>,
+  messages: Vec<BranchMessage>, // 根 → tip
+}
+
+// build_chat_messages 用：指定线程与分支末端代替完整的 conversation 数组
+#[derive(serde::Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct ConversationRef {
+  #[serde(default)]
+  project_id: String,
+  thread_id: String,
+  #[serde(default)]
+  tip_id: Option<String>, // 缺省取线程当前 head
+}
+
+const CONVERSATION_CACHE_MAX: usize = 8; // 内存中最多保留几个项目的线程库，超出按最久未用淘汰
+
+// 每个项目一把锁：读写磁盘只锁本项目；全局锁只在取槽位/淘汰时短暂持有
+type ConversationSlot = Arc<Mutex<Option<ConversationStore>>>; // None = 尚未从磁盘加载
+
+struct ConversationCacheEntry {
+  slot: ConversationSlot,
+  last_used: u64,
+}
+
+static CONVERSATION_STATE: OnceLock<Mutex<HashMap<String, ConversationCacheEntry>>> = OnceLock::new();
+static CONVERSATION_TICK: AtomicU64 = AtomicU64::new(0);
+static CONVERSATION_ID_SEQ: AtomicU64 = AtomicU64::new(0);
+
+fn new_conversation_id(prefix: &str) -> String {
+  let seq = CONVERSATION_ID_SEQ.fetch_add(1, Ordering::Relaxed);
+  format!("{prefix}-{}", &hash_key(&format!("{}-{}-{}", now_millis(), std::process::id(), seq))[..16])
+}
+
+fn conversation_store_path(app: &tauri::AppHandle, project_id: &str) -> Result<PathBuf, String> {
+  let dir = app
+    .path()
+    .app_data_dir()
+    .map_err(|e| e.to_string())?
+    .join(CONVERSATION_STORE_DIR);
+  std::fs::create_dir_all(&dir).map_err(|e| e.to_string())?;
+  Ok(dir.join(format!("{}.json", hash_key(project_id))))
+}
+
+// 取某项目的槽位；缓存满时淘汰最久未用、且没有调用方正在使用的项目（修改都已落盘，直接丢弃）
+fn conversation_slot(project_id: &str) -> Result<ConversationSlot, String> {
+  let lock = CONVERSATION_STATE.get_or_init(|| Mutex::new(HashMap::new()));
+  let mut map = lock.lock().map_err(|_| "对话线程锁异常".to_string())?;
+  let tick = CONVERSATION_TICK.fetch_add(1, Ordering::Relaxed);
+  if let Some(entry) = map.get_mut(project_id) {
+    entry.last_used = tick;
+    return Ok(entry.slot.clone());
+  }
+  while map.len() >= CONVERSATION_CACHE_MAX {
+    let victim = map
+      .iter()
+      .filter(|(_, e)| Arc::strong_count(&e.slot) == 1)
+      .min_by_key(|(_, e)| e.last_used)
+      .map(|(k, _)| k.clone());
+    match victim {
+      Some(k) => {
+        map.remove(&k);
+      }
+      None => break,
+    }
+  }
+  let slot: ConversationSlot = Arc::new(Mutex::new(None));
+  map.insert(project_id.to_string(), ConversationCacheEntry { slot: slot.clone(), last_used: tick });
+  Ok(slot)
+}
+
+// 在某项目的线程库上执行 f；首次访问时从磁盘加载。f 返回 true 表示有修改，需要落盘
+fn with_conversations<R>(
+  app: &tauri::AppHandle,
+  project_id: &str,
+  f: impl FnOnce(&mut ConversationStore) -> Result<(R, bool), String>,
+) -> Result<R, String> {
+  let path = conversation_store_path(app, project_id)?;
+  let slot = conversation_slot(project_id)?;
+  let mut guard = slot.lock().map_err(|_| "对话线程锁异常".to_string())?;
+  if guard.is_none() {
+    let store = if path.exists() {
+      let raw = std::fs::read(&path).map_err(|e| e.to_string())?;
+      serde_json::from_slice::<ConversationStore>(&raw).map_err(|e| format!("对话线程文件损坏：{e}"))?
+    } else {
+      ConversationStore { version: CONVERSATION_STORE_VERSION, ..Default::default() }
+    };
+    *guard = Some(store);
+  }
+  let Some(store) = guard.as_mut() else {
+    return Err("对话线程库未初始化".to_string());
+  };
+  let (out, dirty) = f(store)?;
+  if dirty {
+    write_json_atomic(&path, store)?;
+  }
+  Ok(out)
+}
+
+impl ConversationStore {
+  fn thread(&self, id: &str) -> Result<&ConversationThread, String> {
+    self.threads.iter().find(|t| t.id == id).ok_or_else(|| format!("对话线程不存在：{id}"))
+  }
+
+  fn thread_mut(&mut self, id: &str) -> Result<&mut ConversationThread, String> {
+    self.threads.iter_mut().find(|t| t.id == id).ok_or_else(|| format!("对话线程不存在：{id}"))
+  }
+}
+
+impl ConversationThread {
+  fn find(&self, id: &str) -> Option<&ThreadMessage> {
+    self.messages.iter().find(|m| m.id == id)
+  }
+
+  fn children(&self, parent: Option<&str>) -> Vec<&ThreadMessage> {
+    self.messages.iter().filter(|m| m.parent_id.as_deref() == parent).collect()
+  }
+
+  // 根 → tip 的路径；tip 缺省取 head，线程为空时返回空
+  fn path_to(&self, tip: Option<&str>) -> Result<Vec<&ThreadMessage>, String> {
+    let Some(tip) = tip.or(self.head_id.as_deref()) else {
+      return Ok(vec![]);
+    };
+    let by_id: HashMap<&str, &ThreadMessage> = self.messages.iter().map(|m| (m.id.as_str(), m)).collect();
+    let mut path = vec![];
+    let mut cursor = Some(tip);
+    while let Some(id) = cursor {
+      let msg = by_id.get(id).ok_or_else(|| format!("消息不存在：{id}"))?;
+      if path.len() >= self.messages.len() {
+        return Err("对话线程结构损坏：父子关系成环".to_string());
+      }
+      path.push(*msg);
+      cursor = msg.parent_id.as_deref();
+    }
+    path.reverse();
+    Ok(path)
+  }
+
+  // 从某条消息一路沿最新的子消息走到叶子：切换分支时显示该分支最近的对话
+  fn latest_leaf(&self, from: &str) -> String {
+    let mut current = from.to_string();
+    for _ in 0..self.messages.len() {
+      match self.children(Some(&current)).last() {
+        Some(child) => current = child.id.clone(),
+        None => break,
+      }
+    }
+    current
+  }
+
+  // parent_id 缺省接在 head 后，空字符串表示新建根分支；追加后 head 移到新消息
+  fn append(&mut self, message: ChatMessage, parent_id: Option<String>, now: i64) -> Result<ThreadMessage, String> {
+    let parent_id = match parent_id.map(|p| p.trim().to_string()) {
+      Some(p) if p.is_empty() => None,
+      Some(p) => Some(p),
+      None => self.head_id.clone(),
+    };
+    if let Some(parent) = parent_id.as_deref() {
+      if self.find(parent).is_none() {
+        return Err(format!("父消息不存在：{parent}"));
+      }
+    }
+    if self.title.is_empty() && message.role == "user" {
+      self.title = thread_title_from(&message);
+    }
+    let entry = ThreadMessage { id: new_conversation_id("msg"), parent_id, message, created_at: now };
+    self.head_id = Some(entry.id.clone());
+    self.updated_at = now;
+    self.messages.push(entry.clone());
+    Ok(entry)
+  }
+
+  // head 移到 message_id 所在分支最新的叶子，返回 head 是否变化
+  fn set_head(&mut self, message_id: &str) -> Result<bool, String> {
+    if self.find(message_id).is_none() {
+      return Err(format!("消息不存在：{message_id}"));
+    }
+    let leaf = self.latest_leaf(message_id);
+    let changed = self.head_id.as_deref() != Some(leaf.as_str());
+    self.head_id = Some(leaf);
+    Ok(changed)
+  }
+
+  // 根 → tip（缺省 head）这条分支复制成新线程，其它分支不带过去
+  fn fork(&self, tip: Option<&str>, title: String, now: i64) -> Result<ConversationThread, String> {
+    let path = self.path_to(tip)?;
+    Ok(ConversationThread {
+      id: new_conversation_id("thread"),
+      title: if title.is_empty() { self.title.clone() } else { title },
+      created_at: now,
+      updated_at: now,
+      head_id: path.last().map(|m| m.id.clone()),
+      forked_from: Some(self.id.clone()),
+      messages: path.into_iter().cloned().collect(),
+    })
+  }
+
+  fn summary(&self) -> ConversationThreadSummary {
+    let parents: std::collections::HashSet<&str> = self.messages.iter().filter_map(|m| m.parent_id.as_deref()).collect();
+    let preview = self
+      .head_id
+      .as_deref()
+      .and_then(|id| self.find(id))
+      .map(|m| safe_slice(&one_line(&m.message.content), 80))
+      .unwrap_or_default();
+    ConversationThreadSummary {
+      id: self.id.clone(),
+      title: self.title.clone(),
+      created_at: self.created_at,
+      updated_at: self.updated_at,
+      head_id: self.head_id.clone(),
+      forked_from: self.forked_from.clone(),
+      message_count: self.messages.len(),
+      branch_count: self.messages.iter().filter(|m| !parents.contains(m.id.as_str())).count(),
+      preview,
+    }
+  }
+
+  fn branch(&self, tip: Option<&str>) -> Result<ConversationBranch, String> {
+    let path = self.path_to(tip)?;
+    let messages = path
+      .iter()
+      .map(|m| BranchMessage {
+        entry: (*m).clone(),
+        siblings: self.children(m.parent_id.as_deref()).into_iter().map(|s| s.id.clone()).collect(),
+      })
+      .collect();
+    Ok(ConversationBranch {
+      thread: self.summary(),
+      tip_id: path.last().map(|m| m.id.clone()),
+      messages,
+    })
+  }
+}
+
+fn thread_title_from(message: &ChatMessage) -> String {
+  safe_slice(&one_line(&message.content), 40)
+}
+
+#[tauri::command(rename_all = "camelCase")]
+async fn conversation_list(app: tauri::AppHandle, project_id: Option<String>) -> Result<Vec<ConversationThreadSummary>, String> {
+  tauri::async_runtime::spawn_blocking(move || -> Result<Vec<ConversationThreadSummary>, String> {
+    let project_id = normalize_project_id(project_id);
+    with_conversations(&app, &project_id, |store| {
+      let mut out: Vec<ConversationThreadSummary> = store.threads.iter().map(|t| t.summary()).collect();
+      out.sort_by_key(|t| std::cmp::Reverse(t.updated_at));
+      Ok((out, false))
+    })
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+#[tauri::command(rename_all = "camelCase")]
+async fn conversation_create(
+  app: tauri::AppHandle,
+  project_id: Option<String>,
+  title: Option<String>,
+) -> Result<ConversationThreadSummary, String> {
+  tauri::async_runtime::spawn_blocking(move || -> Result<ConversationThreadSummary, String> {
+    let project_id = normalize_project_id(project_id);
+    let now = now_millis();
+    let thread = ConversationThread {
+      id: new_conversation_id("thread"),
+      title: one_line(&title.unwrap_or_default()),
+      created_at: now,
+      updated_at: now,
+      ..Default::default()
+    };
+    with_conversations(&app, &project_id, |store| {
+      let summary = thread.summary();
+      store.threads.push(thread);
+      Ok((summary, true))
+    })
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+// tip_id 缺省取线程当前 head；只读，不改变 head
+#[tauri::command(rename_all = "camelCase")]
+async fn conversation_load(
+  app: tauri::AppHandle,
+  project_id: Option<String>,
+  thread_id: String,
+  tip_id: Option<String>,
+) -> Result<ConversationBranch, String> {
+  tauri::async_runtime::spawn_blocking(move || -> Result<ConversationBranch, String> {
+    let project_id = normalize_project_id(project_id);
+    with_conversations(&app, &project_id, |store| {
+      let thread = store.thread(thread_id.trim())?;
+      Ok((thread.branch(tip_id.as_deref().map(str::trim).filter(|t| !t.is_empty()))?, false))
+    })
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+// parent_id 缺省接在 head 后；传已有消息的 id 即从该处分支（编辑第 n 条 = 以第 n-1 条为 parent），
+// 传空字符串则新建一个根分支。追加后 head 移到新消息
+#[tauri::command(rename_all = "camelCase")]
+async fn conversation_append(
+  app: tauri::AppHandle,
+  project_id: Option<String>,
+  thread_id: String,
+  message: ChatMessage,
+  parent_id: Option<String>,
+) -> Result<ThreadMessage, String> {
+  tauri::async_runtime::spawn_blocking(move || -> Result<ThreadMessage, String> {
+    if message.role.trim().is_empty() {
+      return Err("消息 role 不能为空".to_string());
+    }
+    let project_id = normalize_project_id(project_id);
+    with_conversations(&app, &project_id, |store| {
+      let entry = store.thread_mut(thread_id.trim())?.append(message, parent_id, now_millis())?;
+      Ok((entry, true))
+    })
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+// 切换分支：head 移到 message_id 所在分支最新的叶子
+#[tauri::command(rename_all = "camelCase")]
+async fn conversation_set_head(
+  app: tauri::AppHandle,
+  project_id: Option<String>,
+  thread_id: String,
+  message_id: String,
+) -> Result<ConversationBranch, String> {
+  tauri::async_runtime::spawn_blocking(move || -> Result<ConversationBranch, String> {
+    let project_id = normalize_project_id(project_id);
+    with_conversations(&app, &project_id, |store| {
+      let thread = store.thread_mut(thread_id.trim())?;
+      let changed = thread.set_head(message_id.trim())?;
+      Ok((thread.branch(None)?, changed))
+    })
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+// 置顶/取消置顶线程中的一条消息；置顶消息在构建上下文时移出历史，放入置顶段
+#[tauri::command(rename_all = "camelCase")]
+async fn conversation_pin(
+  app: tauri::AppHandle,
+  project_id: Option<String>,
+  thread_id: String,
+  message_id: String,
+  pinned: bool,
+  priority: Option<f32>,
+) -> Result<ThreadMessage, String> {
+  tauri::async_runtime::spawn_blocking(move || -> Result<ThreadMessage, String> {
+    let project_id = normalize_project_id(project_id);
+    let pin = pinned.then(|| priority.filter(|p| p.is_finite()).unwrap_or(1.0).clamp(0.0, 1.0));
+    with_conversations(&app, &project_id, |store| {
+      let thread = store.thread_mut(thread_id.trim())?;
+      let message_id = message_id.trim();
+      let Some(entry) = thread.messages.iter_mut().find(|m| m.id == message_id) else {
+        return Err(format!("消息不存在：{message_id}"));
+      };
+      let changed = entry.message.pin != pin;
+      entry.message.pin = pin;
+      let out = entry.clone();
+      if changed {
+        thread.updated_at = now_millis();
+      }
+      Ok((out, changed))
+    })
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+// 把根 → message_id（缺省 head）这条分支复制成新线程
+#[tauri::command(rename_all = "camelCase")]
+async fn conversation_fork(
+  app: tauri::AppHandle,
+  project_id: Option<String>,
+  thread_id: String,
+  message_id: Option<String>,
+  title: Option<String>,
+) -> Result<ConversationThreadSummary, String> {
+  tauri::async_runtime::spawn_blocking(move || -> Result<ConversationThreadSummary, String> {
+    let project_id = normalize_project_id(project_id);
+    with_conversations(&app, &project_id, |store| {
+      let tip = message_id.as_deref().map(str::trim).filter(|m| !m.is_empty());
+      let thread = store.thread(thread_id.trim())?.fork(tip, one_line(&title.unwrap_or_default()), now_millis())?;
+      let summary = thread.summary();
+      store.threads.push(thread);
+      Ok((summary, true))
+    })
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+#[tauri::command(rename_all = "camelCase")]
+async fn conversation_delete(app: tauri::AppHandle, project_id: Option<String>, thread_id: String) -> Result<bool, String> {
+  tauri::async_runtime::spawn_blocking(move || -> Result<bool, String> {
+    let project_id = normalize_project_id(project_id);
+    with_conversations(&app, &project_id, |store| {
+      let before = store.threads.len();
+      store.threads.retain(|t| t.id != thread_id.trim());
+      let removed = store.threads.len() != before;
+      Ok((removed, removed))
+    })
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+// 对话历史来源：给了 thread 就取其分支路径，否则用直接传入的 conversation。
+// 前端若已先把本次提问写入线程，路径末尾的同一条用户消息会被去掉，避免重复
+fn resolve_conversation(
+  app: &tauri::AppHandle,
+  conversation: Option<Vec<ChatMessage>>,
+  thread: Option<ConversationRef>,
+  user_text: &str,
+) -> Result<Vec<ChatMessage>, String> {
+  let Some(thread_ref) = thread.filter(|t| !t.thread_id.trim().is_empty()) else {
+    return Ok(conversation.unwrap_or_default());
+  };
+  let project_id = thread_ref.project_id.trim().to_string();
+  let mut messages: Vec<ChatMessage> = with_conversations(app, &project_id, |store| {
+    let thread = store.thread(thread_ref.thread_id.trim())?;
+    let path = thread.path_to(thread_ref.tip_id.as_deref().map(str::trim).filter(|t| !t.is_empty()))?;
+    Ok((path.into_iter().map(|m| m.message.clone()).collect(), false))
+  })?;
+  if messages
+    .last()
+    .is_some_and(|m| m.role == "user" && normalize_text(&m.content) == normalize_text(user_text))
+  {
+    messages.pop();
+  }
+  Ok(messages)
+}
+
+// ======== Context assembly (progressive compression) ========
+
+enum SectionBody {
+  Text(String),
+  Lines(Vec<String>), // 按整行收缩（记忆条目）
+}
+
+struct ContextSection {
+  key: &'static str, // pinned | summary | memory | canvas
+  template: String,
+  body: SectionBody,
+  len: usize,     // 完整内容长度
+  base: usize,    // min(配置上限, 实际长度)
+  priority: f32,  // 越高收缩得越慢、越晚被整段丢弃
+  pinned: bool,   // 置顶段：不参与收缩和丢弃，只受自身上限约束
+}
+
+impl ContextSection {
+  fn new(
+    key: &'static str,
+    template: String,
+    body: SectionBody,
+    limit: usize,
+    priority: f32,
+    meter: &ContextMeter,
+  ) -> Option<Self> {
+    let len = match &body {
+      SectionBody::Text(t) => meter.len(t),
+      SectionBody::Lines(lines) => lines.iter().map(|l| meter.len(l) + 1).sum::<usize>().saturating_sub(1),
+    };
+    // 段落标题（模板去掉正文后的部分）也占本段预算
+    let header = meter.len(&fill_template(&template, "", 0));
+    let base = limit.saturating_sub(header).min(len);
+    (base > 0).then_some(Self { key, template, body, len, base, priority, pinned: false })
+  }
+
+  // factor ∈ [0, 1]：整体收缩比例，按优先级放大后作用在 base 上
+  fn render_body(&self, factor: f32, meter: &ContextMeter) -> Option<String> {
+    let scale = if self.pinned { 1.0 } else { (factor * self.priority).min(1.0) };
+    let limit = (self.base as f32 * scale) as usize;
+    if limit == 0 {
+      return None;
+    }
+    let text = match &self.body {
+      SectionBody::Text(t) => meter.truncate(t, limit),
+      SectionBody::Lines(lines) => compact_lines(lines, limit, meter),
+    };
+    (!text.is_empty()).then_some(text)
+  }
+
+  fn render(&self, factor: f32, meter: &ContextMeter) -> Option<ChatMessage> {
+    let body = self.render_body(factor, meter)?;
+    Some(ChatMessage::text("system", fill_template(&self.template, &body, body.lines().count())))
+  }
+}
+
+struct ContextFit {
+  messages: Vec<ChatMessage>,
+  stage: &'static str,
+  shape: Option<ContextShape>, // None = 只剩 system + user
+}
+
+#[derive(serde::Serialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct ContextSectionReport {
+  key: String, // pinned | summary | memory | canvas
+  included: bool,
+  size: usize,          // 实际放入的长度（按 unit 计）
+  original_size: usize, // 完整内容长度
+  truncated: bool,
+  lines: usize,
+}
+
+#[derive(serde::Serialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct ContextMemoryReport {
+  id: String,
+  included: bool,  // 是否出现在最终的记忆段（置顶条目看置顶段）中
+  truncated: bool, // 内容被截断成摘录
+  pinned: bool,
+}
+
+#[derive(serde::Serialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct ChatContextReport {
+  unit: String, // chars | cl100k_base | o200k_base | heuristic
+  budget: usize,
+  used: usize,
+  // full | elide_history | elide_latest | shrink_sections_75/50/25 | drop_turns | drop_sections | minimal
+  stage: String,
+  sections: Vec<ContextSectionReport>,
+  memory_items: Vec<ContextMemoryReport>,
+  history_messages_total: usize,  // 过滤后的全部历史消息（不含置顶）
+  history_messages_pinned: usize, // 移入置顶段的消息
+  history_messages_window: usize, // max_history 窗口内的消息
+  history_messages_kept: usize,
+  history_messages_elided: usize, // 头尾保留、中间省略的消息
+  turns_kept: usize,
+  turns_dropped: usize, // 因超预算整组丢弃的对话（窗口内）
+  system_truncated: bool,
+  user_truncated: bool,
+  images: ContextImageReport,
+}
+
+#[derive(serde::Serialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct ContextImageReport {
+  kept: usize,
+  dropped: usize,      // 超出张数上限
+  inlined: usize,      // 本地文件转成了 data URL
+  est_tokens: usize,   // 按 detail 粗估的图片 token（token 计量时预留在总预算内，并计入 used）
+  errors: Vec<String>, // 读不到 / 过大 / 不在缓存目录的本地图片
+}
+
+#[derive(serde::Serialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct ChatContextBuild {
+  messages: Vec<ChatMessage>,
+  report: ChatContextReport,
+}
+
+struct ContextPlan {
+  elision: String,
+  system: Option<ChatMessage>,
+  sections: Vec<ContextSection>, // 输出顺序
+  turns: Vec<Vec<ChatMessage>>,  // 以 user 消息开头的一问一答，整组保留或丢弃
+  user: ChatMessage,
+}
+
+#[derive(Clone, Copy)]
+struct ContextShape {
+  factor: f32,            // 段落收缩比例
+  first_turn: usize,      // 从第几组对话开始保留
+  msg_cap: Option<usize>, // 历史消息单条上限（头尾保留、中间省略）
+  cap_latest: bool,       // 上限是否也作用于最近一组
+  dropped_sections: usize, // 按优先级从低到高整段丢弃的段数
+}
+
+impl ContextShape {
+  const FULL: ContextShape = ContextShape { factor: 1.0, first_turn: 0, msg_cap: None, cap_latest: false, dropped_sections: 0 };
+}
+
+impl ContextPlan {
+  fn dropped_sections(&self, n: usize) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..self.sections.len()).filter(|i| !self.sections[*i].pinned).collect();
+    order.sort_by(|a, b| self.sections[*a].priority.partial_cmp(&self.sections[*b].priority).unwrap_or(std::cmp::Ordering::Equal));
+    order.into_iter().take(n).collect()
+  }
+
+  fn assemble(&self, shape: ContextShape, meter: &ContextMeter) -> Vec<ChatMessage> {
+    let mut out: Vec<ChatMessage> = vec![];
+    out.extend(self.system.clone());
+    let dropped = self.dropped_sections(shape.dropped_sections);
+    for (i, section) in self.sections.iter().enumerate() {
+      if !dropped.contains(&i) {
+        out.extend(section.render(shape.factor, meter));
+      }
+    }
+    let latest = self.turns.len().saturating_sub(1);
+    for (i, turn) in self.turns.iter().enumerate().skip(shape.first_turn) {
+      for m in turn.iter() {
+        let content = match shape.msg_cap {
+          Some(cap) if i < latest || shape.cap_latest => meter.elide_middle(&m.content, cap, &self.elision),
+          _ => m.content.clone(),
+        };
+        out.push(ChatMessage { content, ..m.clone() });
+      }
+    }
+    out.push(self.user.clone());
+    drop_orphan_tool_messages(out)
+  }
+
+  // 依次尝试越来越紧的形态，返回第一个放得下的（即放得下的最丰富的上下文）：
+  // 1) 原样 2) 长消息头尾省略 3) 段落按优先级收缩 4) 从最旧开始整组丢对话
+  // 5) 按优先级整段丢弃 6) 只剩 system + user，必要时两者都做头尾省略
+  fn fit(&self, max_total: usize, meter: &ContextMeter) -> ContextFit {
+    let fits = |stage: &'static str, shape: ContextShape| -> Option<ContextFit> {
+      let messages = self.assemble(shape, meter);
+      (meter.messages(&messages) <= max_total).then_some(ContextFit { messages, stage, shape: Some(shape) })
+    };
+    let msg_cap = (max_total / 6).max(meter.pick(400, 160));
+    let capped = ContextShape { msg_cap: Some(msg_cap), cap_latest: true, ..ContextShape::FULL };
+    let shrunk = ContextShape { factor: 0.25, ..capped };
+    let staged = [
+      ("full", ContextShape::FULL),
+      ("elide_history", ContextShape { msg_cap: Some(msg_cap), ..ContextShape::FULL }),
+      ("elide_latest", capped),
+      ("shrink_sections_75", ContextShape { factor: 0.75, ..capped }),
+      ("shrink_sections_50", ContextShape { factor: 0.5, ..capped }),
+      ("shrink_sections_25", shrunk),
+    ];
+    if let Some(fit) = staged.into_iter().find_map(|(stage, shape)| fits(stage, shape)) {
+      return fit;
+    }
+
+    // 丢得越多越短：二分出最少需要丢掉的对话组数
+    let (mut lo, mut hi) = (1usize, self.turns.len());
+    let mut best: Option<ContextFit> = None;
+    while lo <= hi {
+      let mid = (lo + hi) / 2;
+      match fits("drop_turns", ContextShape { first_turn: mid, ..shrunk }) {
+        Some(fit) => {
+          best = Some(fit);
+          hi = mid - 1;
+        }
+        None => lo = mid + 1,
+      }
+    }
+    if let Some(fit) = best {
+      return fit;
+    }
+
+    let no_turns = ContextShape { first_turn: self.turns.len(), ..shrunk };
+    let droppable = self.sections.iter().filter(|s| !s.pinned).count();
+    (1..=droppable)
+      .find_map(|dropped_sections| fits("drop_sections", ContextShape { dropped_sections, ..no_turns }))
+      .unwrap_or_else(|| ContextFit { messages: self.minimal(max_total, meter), stage: "minimal", shape: None })
+  }
+
+  // 按最终形态统计：各段大小、保留/丢弃的对话组、被省略的消息
+  fn report(&self, fit: &ContextFit, meter: &ContextMeter) -> ChatContextReport {
+    let shape = fit.shape.unwrap_or(ContextShape {
+      first_turn: self.turns.len(),
+      dropped_sections: self.sections.len(),
+      ..ContextShape::FULL
+    });
+    let dropped = self.dropped_sections(shape.dropped_sections);
+    let sections = self
+      .sections
+      .iter()
+      .enumerate()
+      .map(|(i, section)| {
+        let rendered = if dropped.contains(&i) { None } else { section.render_body(shape.factor, meter) };
+        let included = rendered.is_some();
+        let body = rendered.unwrap_or_default();
+        let size = meter.len(&body);
+        ContextSectionReport {
+          key: section.key.to_string(),
+          included,
+          size,
+          original_size: section.len,
+          truncated: size < section.len,
+          lines: body.lines().count(),
+        }
+      })
+      .collect();
+    let turns_kept = self.turns.len() - shape.first_turn.min(self.turns.len());
+    let history_out = fit.messages.iter().filter(|m| m.role != "system").count().saturating_sub(1);
+    let history_out_msgs = fit.messages.iter().filter(|m| m.role != "system");
+    let history_elided = history_out_msgs.take(history_out).filter(|m| m.content.contains(self.elision.as_str())).count();
+    ChatContextReport {
+      unit: meter.unit().to_string(),
+      used: meter.messages(&fit.messages),
+      stage: fit.stage.to_string(),
+      sections,
+      turns_kept,
+      turns_dropped: self.turns.len() - turns_kept,
+      history_messages_kept: history_out,
+      history_messages_elided: history_elided,
+      system_truncated: match (&self.system, fit.messages.first()) {
+        (Some(sys), Some(first)) => first.role == "system" && first.content != sys.content,
+        _ => false,
+      },
+      user_truncated: fit.messages.last().is_some_and(|m| m.content != self.user.content),
+      ..Default::default()
+    }
+  }
+
+  fn minimal(&self, max_total: usize, meter: &ContextMeter) -> Vec<ChatMessage> {
+    let mut system = self.system.clone();
+    // 置顶段在上限内原样保留，其余预算留给 system + user
+    let pinned: Vec<ChatMessage> = self.sections.iter().filter(|s| s.pinned).filter_map(|s| s.render(1.0, meter)).collect();
+    let mut frame = pinned.clone();
+    frame.extend([ChatMessage::default(), ChatMessage::default()]);
+    let overhead = meter.messages(&frame);
+    let room = max_total.saturating_sub(overhead);
+    let user_len = meter.len(&self.user.content);
+    // 放不下时先压 system（至多压到一半），尽量保住用户原话
+    if let Some(m) = system.as_mut() {
+      let sys_len = meter.len(&m.content);
+      if sys_len + user_len > room {
+        m.content = meter.elide_middle(&m.content, room.saturating_sub(user_len).max(room / 2), &self.elision);
+      }
+    }
+    let sys_len = system.as_ref().map(|m| meter.len(&m.content)).unwrap_or(0);
+    let user = ChatMessage {
+      content: meter.elide_middle(&self.user.content, room.saturating_sub(sys_len), &self.elision),
+      ..self.user.clone()
+    };
+    system.into_iter().chain(pinned).chain(std::iter::once(user)).collect()
+  }
+}
+
+// 函数调用与结果必须成对：丢掉找不到调用的 tool 结果，以及没有结果的调用
+// （assistant 消息的调用全部落空且没有正文时整条丢掉）。历史窗口截断后也靠这里兜底
+fn drop_orphan_tool_messages(messages: Vec<ChatMessage>) -> Vec<ChatMessage> {
+  let mut called: std::collections::HashSet<&str> = std::collections::HashSet::new();
+  let mut answered: std::collections::HashSet<&str> = std::collections::HashSet::new();
+  for m in messages.iter() {
+    if m.role == "tool" {
+      if let Some(id) = m.tool_call_id.as_deref().filter(|id| called.contains(id)) {
+        answered.insert(id);
+      }
+    }
+    called.extend(m.tool_calls.iter().map(|c| c.id.as_str()));
+  }
+  let answered: std::collections::HashSet<String> = answered.into_iter().map(str::to_string).collect();
+
+  let mut out: Vec<ChatMessage> = Vec::with_capacity(messages.len());
+  for mut m in messages {
+    if m.role == "tool" {
+      if m.tool_call_id.as_ref().is_some_and(|id| answered.contains(id)) {
+        out.push(m);
+      }
+      continue;
+    }
+    if !m.tool_calls.is_empty() {
+      m.tool_calls.retain(|c| answered.contains(&c.id));
+      if m.tool_calls.is_empty() && m.content.is_empty() && m.images.is_empty() {
+        continue;
+      }
+    }
+    out.push(m);
+  }
+  out
+}
+
+// 一问一答分组：每个 user 消息开启新的一组（其后的 assistant 调用与 tool 结果都归入该组），
+// 开头的非 user 消息单独成组
+fn group_turns(messages: Vec<ChatMessage>) -> Vec<Vec<ChatMessage>> {
+  let mut turns: Vec<Vec<ChatMessage>> = vec![];
+  for m in messages {
+    match turns.last_mut() {
+      Some(turn) if m.role != "user" => turn.push(m),
+      _ => turns.push(vec![m]),
+    }
+  }
+  turns
+}
+
+#[derive(Default)]
+struct ChatContextInput {
+  user_text: String,
+  user_images: Vec<ChatImage>,
+  system_prompt: String,
+  conversation: Vec<ChatMessage>,
+  memory_summary: String,
+  memory_items: Vec<MemoryItem>,
+  canvas_context: String,
+  config: Option<ContextConfig>,
+}
+
+fn pinned_role_prefix(role: &str, english: bool) -> String {
+  match (role, english) {
+    ("user", false) => "用户：".to_string(),
+    ("user", true) => "User: ".to_string(),
+    ("assistant", false) => "助手：".to_string(),
+    ("assistant", true) => "Assistant: ".to_string(),
+    (other, false) => format!("{other}："),
+    (other, true) => format!("{other}: "),
+  }
+}
+
+// image_root：允许内联为 data URL 的本地目录（应用缓存目录）；None 时本地图片一律丢弃
+fn assemble_chat_context(input: ChatContextInput, image_root: Option<&Path>) -> ChatContextBuild {
+  let ChatContextInput {
+    user_text,
+    user_images,
+    system_prompt,
+    conversation,
+    memory_summary,
+    memory_items,
+    canvas_context,
+    config,
+  } = input;
+  let user_query = normalize_text(&user_text);
+  let sys = normalize_text(&system_prompt);
+
+  let cfg = config.unwrap_or_default();
+  let meter = ContextMeter::from_config(&cfg);
+  let max_history = config_limit(cfg.max_history, 16, 4, 64);
+  let max_memory_items = clamp_i64(if cfg.max_memory_items > 0 { cfg.max_memory_items } else { 6 }, 0, 30) as usize;
+  let (max_total, max_canvas, max_memory, max_summary) = if meter.is_tokens() {
+    (
+      config_limit(cfg.max_tokens, 4000, 512, 200_000),
+      config_limit(cfg.max_canvas_tokens, 400, 0, 8000),
+      config_limit(cfg.max_memory_tokens, 400, 0, 8000),
+      config_limit(cfg.max_summary_tokens, 200, 0, 4000),
+    )
+  } else {
+    (
+      config_limit(cfg.max_chars, 12000, 2000, 50000),
+      config_limit(cfg.max_canvas_chars, 1200, 0, 8000),
+      config_limit(cfg.max_memory_chars, 1200, 0, 8000),
+      config_limit(cfg.max_summary_chars, 600, 0, 4000),
+    )
+  };
+  let max_pinned = if meter.is_tokens() {
+    config_limit(cfg.max_pinned_tokens, 300, 0, 4000)
+  } else {
+    config_limit(cfg.max_pinned_chars, 800, 0, 8000)
+  }
+  .min(max_total / 3);
+  let snippet_max = meter.pick(260, 120);
+
+  // 置顶的消息与记忆：(优先级, 行前缀, 内容, 记忆下标)，按优先级从高到低放入置顶段
+  let mut pinned: Vec<(f32, String, String, Option<usize>)> = vec![];
+  let mut memory_lines: Vec<String> = vec![];
+  let mut memory_report: Vec<(ContextMemoryReport, Option<usize>)> = vec![]; // 行号用于判断最终是否放入
+  let mut ranked = 0usize;
+  for (i, m) in memory_items.iter().enumerate() {
+    let c = one_line(&m.content); // 一条记忆一行，便于按行收缩和统计
+    let mut entry = ContextMemoryReport { id: m.id.clone(), ..Default::default() };
+    if let Some(priority) = m.pin.filter(|_| !c.is_empty()) {
+      entry.pinned = true;
+      pinned.push((priority, "- ".to_string(), c, Some(i)));
+      memory_report.push((entry, None));
+      continue;
+    }
+    ranked += 1;
+    if ranked > max_memory_items || c.is_empty() {
+      memory_report.push((entry, None));
+      continue;
+    }
+    let snippet = meter.truncate(&c, snippet_max);
+    entry.truncated = snippet.len() < c.len();
+    memory_report.push((entry, Some(memory_lines.len())));
+    memory_lines.push(format!("- {}", snippet));
+  }
+
+  // history: keep last max_history, keep order；置顶消息移出历史，改放置顶段（带角色前缀，分清是谁说的）
+  let english = locale_is_english(&cfg.locale);
+  let mut filtered: Vec<ChatMessage> = vec![];
+  let mut history_pinned = 0usize;
+  for m in conversation.into_iter().filter(|m| {
+    !m.role.is_empty()
+      && m.role != "system"
+      && (!normalize_text(&m.content).is_empty() || !m.images.is_empty() || !m.tool_calls.is_empty() || m.role == "tool")
+  }) {
+    let content = normalize_text(&m.content);
+    match m.pin {
+      // 函数调用/结果必须成对留在历史里，不能单独置顶
+      Some(priority) if m.role != "tool" && m.tool_calls.is_empty() && !content.is_empty() => {
+        pinned.push((priority, format!("- {}", pinned_role_prefix(&m.role, english)), one_line(&content), None));
+        history_pinned += 1;
+      }
+      _ => filtered.push(ChatMessage { content, pin: None, ..m }),
+    }
+  }
+  pinned.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(std::cmp::Ordering::Equal));
+
+  // 每条置顶内容截到“置顶段预算 - 段标题 - 行前缀”，单条再长也不会超出整段预算
+  let templates = SectionTemplates::resolve(&cfg);
+  let pinned_room = max_pinned.saturating_sub(meter.len(&fill_template(&templates.pinned, "", pinned.len())));
+  let pinned_lines: Vec<String> = pinned
+    .iter()
+    .map(|(_, prefix, text, memory)| {
+      let line = meter.truncate(text, pinned_room.saturating_sub(meter.len(prefix)));
+      if let Some(entry) = memory.and_then(|i| memory_report.get_mut(i)) {
+        entry.0.truncated = line.len() < text.len();
+      }
+      format!("{prefix}{line}")
+    })
+    .collect();
+
+  // 优先级：置顶（不收缩）> 画布（当前任务）> 检索命中的记忆 > 长期摘要
+  let sections: Vec<ContextSection> = [
+    ContextSection::new("pinned", templates.pinned, SectionBody::Lines(pinned_lines), max_pinned, 1.0, &meter)
+      .map(|s| ContextSection { pinned: true, ..s }),
+    ContextSection::new("summary", templates.summary, SectionBody::Text(normalize_text(&memory_summary)), max_summary, 1.0, &meter),
+    ContextSection::new("memory", templates.memory, SectionBody::Lines(memory_lines), max_memory, 1.25, &meter),
+    ContextSection::new("canvas", templates.canvas, SectionBody::Text(normalize_text(&canvas_context)), max_canvas, 1.5, &meter),
+  ]
+  .into_iter()
+  .flatten()
+  .collect();
+
+  let history_total = filtered.len();
+  let start = history_total.saturating_sub(max_history);
+
+  let plan = ContextPlan {
+    elision: templates.elision,
+    system: (!sys.is_empty()).then(|| ChatMessage::text("system", sys)),
+    sections,
+    turns: group_turns(filtered.into_iter().skip(start).collect()),
+    user: ChatMessage { images: user_images, ..ChatMessage::text("user", user_query) },
+  };
+  // token 计量时先从总预算里扣掉图片的预估，文本只用剩下的部分（至少保留一半）；字符计量无法折算图片，不预留
+  let image_reserve = if meter.is_tokens() {
+    let newest_first = std::iter::once(&plan.user).chain(plan.turns.iter().rev().flat_map(|t| t.iter().rev()));
+    image_token_reserve(newest_first, &cfg).min(max_total / 2)
+  } else {
+    0
+  };
+  let mut fit = plan.fit(max_total - image_reserve, &meter);
+  let mut report = plan.report(&fit, &meter);
+  report.images = apply_image_budget(&mut fit.messages, &cfg, image_root);
+  // 只有图片的历史消息，图片一张都没带上时整条丢掉，不发送空消息（末尾的用户消息保留）
+  let before = fit.messages.len();
+  let last = before.saturating_sub(1);
+  fit.messages = fit
+    .messages
+    .into_iter()
+    .enumerate()
+    .filter(|(i, m)| *i == last || m.role == "tool" || !m.content.trim().is_empty() || !m.images.is_empty() || !m.tool_calls.is_empty())
+    .map(|(_, m)| m)
+    .collect();
+  if fit.messages.len() < before {
+    report.history_messages_kept = report.history_messages_kept.saturating_sub(before - fit.messages.len());
+    report.used = meter.messages(&fit.messages);
+  }
+  if meter.is_tokens() {
+    report.used += report.images.est_tokens;
+  }
+  report.budget = max_total;
+  report.history_messages_total = history_total;
+  report.history_messages_window = history_total - start;
+  report.history_messages_pinned = history_pinned;
+  let lines_kept = |key: &str| report.sections.iter().find(|s| s.key == key).map(|s| s.lines).unwrap_or(0);
+  let memory_lines_kept = lines_kept("memory");
+  let pinned_kept: std::collections::HashSet<usize> = pinned.iter().take(lines_kept("pinned")).filter_map(|p| p.3).collect();
+  report.memory_items = memory_report
+    .into_iter()
+    .enumerate()
+    .map(|(i, (mut entry, line))| {
+      entry.included = if entry.pinned { pinned_kept.contains(&i) } else { line.is_some_and(|l| l < memory_lines_kept) };
+      entry
+    })
+    .collect();
+  ChatContextBuild { messages: fit.messages, report }
+}
+
+fn chat_image_root(app: &tauri::AppHandle) -> Option<PathBuf> {
+  app.path().app_cache_dir().ok()
+}
+
+// 对话历史二选一：conversation 数组，或 thread（线程 id + 分支末端，由后端线程库读取）
+#[tauri::command(rename_all = "camelCase")]
+#[allow(clippy::too_many_arguments)]
+async fn build_chat_messages(
+  app: tauri::AppHandle,
+  user_text: String,
+  system_prompt: String,
+  conversation: Option<Vec<ChatMessage>>,
+  memory_summary: String,
+  memory_items: Vec<MemoryItem>,
+  canvas_context: String,
+  config: Option<ContextConfig>,
+  user_images: Option<Vec<ChatImage>>,
+  thread: Option<ConversationRef>,
+) -> Result<Vec<ChatMessage>, String> {
+  // 线程读取与本地图片内联都是磁盘 I/O，放到阻塞线程池
+  tauri::async_runtime::spawn_blocking(move || -> Result<Vec<ChatMessage>, String> {
+    let conversation = resolve_conversation(&app, conversation, thread, &user_text)?;
+    let input = ChatContextInput {
+      user_text,
+      user_images: user_images.unwrap_or_default(),
+      system_prompt,
+      conversation,
+      memory_summary,
+      memory_items,
+      canvas_context,
+      config,
+    };
+    Ok(assemble_chat_context(input, chat_image_root(&app).as_deref()).messages)
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+// 同 build_chat_messages，另附构建报告：各段是否放入及大小、保留/丢弃的对话、截断的记忆、所用压缩阶段
+#[tauri::command(rename_all = "camelCase")]
+#[allow(clippy::too_many_arguments)]
+async fn build_chat_context(
+  app: tauri::AppHandle,
+  user_text: String,
+  system_prompt: String,
+  conversation: Option<Vec<ChatMessage>>,
+  memory_summary: String,
+  memory_items: Vec<MemoryItem>,
+  canvas_context: String,
+  config: Option<ContextConfig>,
+  user_images: Option<Vec<ChatImage>>,
+  thread: Option<ConversationRef>,
+) -> Result<ChatContextBuild, String> {
+  // 线程读取与本地图片内联都是磁盘 I/O，放到阻塞线程池
+  tauri::async_runtime::spawn_blocking(move || -> Result<ChatContextBuild, String> {
+    let conversation = resolve_conversation(&app, conversation, thread, &user_text)?;
+    let input = ChatContextInput {
+      user_text,
+      user_images: user_images.unwrap_or_default(),
+      system_prompt,
+      conversation,
+      memory_summary,
+      memory_items,
+      canvas_context,
+      config,
+    };
+    Ok(assemble_chat_context(input, chat_image_root(&app).as_deref()))
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+// ======== Chat proxy (OpenAI-compatible streaming) ========
+
+const CHAT_STREAM_EVENT: &str = "nexus:chat-stream";
+const CHAT_STREAM_DONE_EVENT: &str = "nexus:chat-stream-done";
+const CHAT_STREAM_IDLE_SECS: u64 = 120; // 两个数据块之间的最长等待；整体不限时，长回复不会被截断
+const MAX_STREAM_TOOL_CALLS: usize = 64;
+const CHAT_STREAM_KEEP_SECS: i64 = 600; // 结束的对话保留多久，供刷新后的页面取回结果
+const CHAT_PROVIDERS_FILE: &str = "nexus-chat-providers.json";
+const CHAT_PROVIDERS_VERSION: u32 = 1;
+
+// 服务商配置（含 API Key）只保存在后端；前端通过 id 引用，列表里不返回 Key
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct ChatProviderProfile {
+  #[serde(default)]
+  id: String,
+  #[serde(default)]
+  name: String,
+  base_url: String,
+  #[serde(default)]
+  api_key: Option<String>,
+  #[serde(default)]
+  model: String, // 默认模型；chat_stream 可按次覆盖
+  #[serde(default)]
+  temperature: Option<f32>,
+  #[serde(default)]
+  max_tokens: Option<u32>,
+  #[serde(default)]
+  include_usage: Option<bool>, // stream_options.include_usage，默认开；个别网关不认可时关闭
+  #[serde(default)]
+  extra: Option<Value>, // 额外请求体字段（tools、top_p 等），原样合并
+}
+
+#[derive(serde::Serialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct ChatProviderInfo {
+  id: String,
+  name: String,
+  base_url: String,
+  model: String,
+  has_api_key: bool,
+  temperature: Option<f32>,
+  max_tokens: Option<u32>,
+  include_usage: Option<bool>,
+  extra: Option<Value>,
+}
+
+impl From<&ChatProviderProfile> for ChatProviderInfo {
+  fn from(p: &ChatProviderProfile) -> Self {
+    Self {
+      id: p.id.clone(),
+      name: p.name.clone(),
+      base_url: p.base_url.clone(),
+      model: p.model.clone(),
+      has_api_key: p.api_key.as_deref().is_some_and(|k| !k.trim().is_empty()),
+      temperature: p.temperature,
+      max_tokens: p.max_tokens,
+      include_usage: p.include_usage,
+      extra: p.extra.clone(),
+    }
+  }
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct ChatProviderStore {
+  version: u32,
+  #[serde(default)]
+  profiles: Vec<ChatProviderProfile>,
+}
+
+static CHAT_PROVIDERS_LOCK: Mutex<()> = Mutex::new(());
+
+fn chat_providers_path(app: &tauri::AppHandle) -> Result<PathBuf, String> {
+  let dir = app.path().app_data_dir().map_err(|e| e.to_string())?;
+  std::fs::create_dir_all(&dir).map_err(|e| e.to_string())?;
+  Ok(dir.join(CHAT_PROVIDERS_FILE))
+}
+
+fn load_chat_providers(path: &Path) -> Result<ChatProviderStore, String> {
+  if !path.exists() {
+    return Ok(ChatProviderStore { version: CHAT_PROVIDERS_VERSION, ..Default::default() });
+  }
+  let raw = std::fs::read(path).map_err(|e| e.to_string())?;
+  serde_json::from_slice(&raw).map_err(|e| format!("服务商配置文件损坏：{e}"))
+}
+
+// 配置文件很小，每次读写整份；锁只用于串行化读-改-写
+fn with_chat_providers<R>(
+  app: &tauri::AppHandle,
+  f: impl FnOnce(&mut ChatProviderStore) -> Result<(R, bool), String>,
+) -> Result<R, String> {
+  let _guard = CHAT_PROVIDERS_LOCK.lock().map_err(|_| "服务商配置锁异常".to_string())?;
+  let path = chat_providers_path(app)?;
+  let mut store = load_chat_providers(&path)?;
+  let (out, dirty) = f(&mut store)?;
+  if dirty {
+    store.version = CHAT_PROVIDERS_VERSION;
+    write_json_atomic(&path, &store)?;
+  }
+  Ok(out)
+}
+
+#[tauri::command(rename_all = "camelCase")]
+async fn chat_provider_list(app: tauri::AppHandle) -> Result<Vec<ChatProviderInfo>, String> {
+  tauri::async_runtime::spawn_blocking(move || {
+    with_chat_providers(&app, |store| Ok((store.profiles.iter().map(ChatProviderInfo::from).collect(), false)))
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+// 新增或更新；api_key 为 None 时保留已保存的 Key，空字符串表示清除
+#[tauri::command(rename_all = "camelCase")]
+async fn chat_provider_save(app: tauri::AppHandle, profile: ChatProviderProfile) -> Result<ChatProviderInfo, String> {
+  let mut profile = profile;
+  profile.id = profile.id.trim().to_string();
+  if profile.id.is_empty() {
+    return Err("服务商 id 不能为空".to_string());
+  }
+  profile.base_url = profile.base_url.trim().trim_end_matches('/').to_string();
+  if profile.base_url.is_empty() {
+    return Err("baseUrl 不能为空".to_string());
+  }
+  profile.model = profile.model.trim().to_string();
+  tauri::async_runtime::spawn_blocking(move || {
+    with_chat_providers(&app, |store| {
+      match store.profiles.iter_mut().find(|p| p.id == profile.id) {
+        Some(existing) => {
+          if profile.api_key.is_none() {
+            profile.api_key = existing.api_key.take();
+          }
+          *existing = profile.clone();
+        }
+        None => store.profiles.push(profile.clone()),
+      }
+      Ok((ChatProviderInfo::from(&profile), true))
+    })
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+#[tauri::command(rename_all = "camelCase")]
+async fn chat_provider_delete(app: tauri::AppHandle, id: String) -> Result<bool, String> {
+  let id = id.trim().to_string();
+  tauri::async_runtime::spawn_blocking(move || {
+    with_chat_providers(&app, |store| {
+      let before = store.profiles.len();
+      store.profiles.retain(|p| p.id != id);
+      let removed = store.profiles.len() != before;
+      Ok((removed, removed))
+    })
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct ChatUsage {
+  #[serde(default, alias = "prompt_tokens")]
+  prompt_tokens: u32,
+  #[serde(default, alias = "completion_tokens")]
+  completion_tokens: u32,
+  #[serde(default, alias = "total_tokens")]
+  total_tokens: u32,
+}
+
+#[derive(serde::Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+struct ChatStreamDelta {
+  request_id: String,
+  seq: u32,
+  delta: String,
+}
+
+#[derive(serde::Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+struct ChatCompletionResult {
+  request_id: String,
+  message: ChatMessage,
+  finish_reason: Option<String>,
+  usage: Option<ChatUsage>,
+  cancelled: bool, // 被 chat_cancel 中止；message 为已收到的部分
+}
+
+// 结束事件：页面刷新后原来的 invoke 已丢失，重新挂上的页面靠它拿结果
+#[derive(serde::Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+struct ChatStreamDone {
+  request_id: String,
+  result: Option<ChatCompletionResult>,
+  error: Option<String>,
+}
+
+// 对话任务：进行中可中止；已收到的文本随增量累积，结束后保留一段时间供 chat_stream_attach 取回
+struct ChatStreamEntry {
+  abort: futures_util::future::AbortHandle,
+  content: String,
+  seq: u32,
+  done: Option<ChatStreamDone>,
+  finished_at: i64,
+}
+
+#[derive(serde::Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+struct ChatStreamSnapshot {
+  request_id: String,
+  content: String,
+  seq: u32,
+  finished: bool,
+  result: Option<ChatCompletionResult>,
+  error: Option<String>,
+}
+
+static CHAT_STREAMS: OnceLock<Mutex<HashMap<String, ChatStreamEntry>>> = OnceLock::new();
+
+fn chat_streams() -> &'static Mutex<HashMap<String, ChatStreamEntry>> {
+  CHAT_STREAMS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+// 流式累积：文本增量 + 按 index 拼接的工具调用分片
+#[derive(Default)]
+struct ChatStreamState {
+  content: String,
+  tool_calls: Vec<ToolCall>,
+  finish_reason: Option<String>,
+  usage: Option<ChatUsage>,
+}
+
+impl ChatStreamState {
+  // 处理一个 chunk，返回其中的文本增量
+  fn apply(&mut self, chunk: &Value) -> Result<String, String> {
+    if let Some(err) = chunk.get("error").filter(|e| !e.is_null()) {
+      let msg = err.get("message").and_then(|m| m.as_str()).map(str::to_string).unwrap_or_else(|| err.to_string());
+      return Err(format!("对话流返回错误：{}", safe_slice(&msg, 200)));
+    }
+    if let Some(usage) = chunk.get("usage").filter(|u| u.is_object()) {
+      self.usage = serde_json::from_value(usage.clone()).ok();
+    }
+    let Some(choice) = chunk.get("choices").and_then(|c| c.get(0)) else {
+      return Ok(String::new());
+    };
+    if let Some(reason) = choice.get("finish_reason").and_then(|r| r.as_str()) {
+      self.finish_reason = Some(reason.to_string());
+    }
+    // 非流式响应只有 message，按一次性增量处理
+    let Some(delta) = choice.get("delta").or_else(|| choice.get("message")) else {
+      return Ok(String::new());
+    };
+    for part in delta.get("tool_calls").and_then(|t| t.as_array()).into_iter().flatten() {
+      let index = match part.get("index").and_then(|i| i.as_u64()) {
+        Some(i) => i as usize,
+        None if part.get("id").is_some() => self.tool_calls.len(),
+        None => self.tool_calls.len().saturating_sub(1),
+      };
+      if index >= MAX_STREAM_TOOL_CALLS {
+        continue;
+      }
+      if self.tool_calls.len() <= index {
+        self.tool_calls.resize_with(index + 1, || ToolCall { kind: default_tool_call_type(), ..Default::default() });
+      }
+      let call = &mut self.tool_calls[index];
+      if let Some(id) = part.get("id").and_then(|v| v.as_str()).filter(|v| !v.is_empty()) {
+        call.id = id.to_string();
+      }
+      if let Some(function) = part.get("function") {
+        if let Some(name) = function.get("name").and_then(|v| v.as_str()) {
+          if call.function.name.is_empty() {
+            call.function.name = name.to_string();
+          }
+        }
+        if let Some(args) = function.get("arguments").and_then(|v| v.as_str()) {
+          call.function.arguments.push_str(args);
+        }
+      }
+    }
+    let text = delta.get("content").and_then(|c| c.as_str()).unwrap_or_default();
+    self.content.push_str(text);
+    Ok(text.to_string())
+  }
+
+  fn into_message(self) -> ChatMessage {
+    ChatMessage {
+      tool_calls: self.tool_calls.into_iter().filter(|c| !c.function.name.is_empty()).collect(),
+      ..ChatMessage::text("assistant", self.content)
+    }
+  }
+}
+
+fn sse_data(line: &[u8]) -> Option<&str> {
+  std::str::from_utf8(line).ok()?.trim().strip_prefix("data:").map(str::trim)
+}
+
+// 逐行处理缓冲区中已完整的 SSE 行（不完整的留到下次），读到 [DONE] 时返回 true
+fn drain_sse_lines(buf: &mut Vec<u8>, state: &mut ChatStreamState, emit: &mut dyn FnMut(String)) -> Result<bool, String> {
+  while let Some(pos) = buf.iter().position(|b| *b == b'\n') {
+    let line: Vec<u8> = buf.drain(..=pos).collect();
+    let Some(data) = sse_data(&line) else { continue };
+    if data == "[DONE]" {
+      return Ok(true);
+    }
+    // 心跳、注释等无法解析的行直接跳过
+    let Ok(parsed) = serde_json::from_str::<Value>(data) else { continue };
+    emit(state.apply(&parsed)?);
+  }
+  Ok(false)
+}
+
+fn apply_whole_response(raw: &[u8], state: &mut ChatStreamState) -> Result<String, String> {
+  let parsed: Value = serde_json::from_slice(raw).map_err(|e| format!("对话响应解析失败：{e}"))?;
+  state.apply(&parsed)
+}
+
+async fn run_chat_stream(
+  app: &tauri::AppHandle,
+  request_id: &str,
+  request: reqwest::RequestBuilder,
+  state: &mut ChatStreamState,
+) -> Result<(), String> {
+  let response = request.send().await.map_err(|e| format!("对话请求失败：{e}"))?;
+  let status = response.status();
+  if !status.is_success() {
+    let raw = response.bytes().await.unwrap_or_default();
+    let detail = String::from_utf8_lossy(&raw);
+    return Err(format!("对话 HTTP {}：{}", status, safe_slice(&detail, 200)));
+  }
+  let is_sse = response
+    .headers()
+    .get(reqwest::header::CONTENT_TYPE)
+    .and_then(|v| v.to_str().ok())
+    .is_some_and(|v| v.contains("event-stream"));
+
+  let mut seq: u32 = 0;
+  let mut emit_delta = |delta: String| {
+    if delta.is_empty() {
+      return;
+    }
+    seq += 1;
+    if let Some(entry) = chat_streams().lock().ok().as_mut().and_then(|map| map.get_mut(request_id)) {
+      entry.content.push_str(&delta);
+      entry.seq = seq;
+    }
+    let _ = app.emit(CHAT_STREAM_EVENT, ChatStreamDelta { request_id: request_id.to_string(), seq, delta });
+  };
+
+  // 网关忽略 stream 直接返回整条 JSON
+  if !is_sse {
+    let raw = response.bytes().await.map_err(|e| format!("对话响应读取失败：{e}"))?;
+    emit_delta(apply_whole_response(&raw, state)?);
+    return Ok(());
+  }
+
+  let mut buf: Vec<u8> = vec![];
+  let mut stream = response.bytes_stream();
+  let mut finished = false;
+  while !finished {
+    let next = stream.next().await;
+    match &next {
+      Some(chunk) => buf.extend_from_slice(chunk.as_ref().map_err(|e| format!("对话流读取失败：{e}"))?),
+      None => buf.push(b'\n'), // 收尾：处理最后一行
+    }
+    finished = drain_sse_lines(&mut buf, state, &mut emit_delta)? || next.is_none();
+  }
+  Ok(())
+}
+
+// 后端代理对话：按 profileId 读取后端保存的服务商配置，API Key 不经过渲染进程。
+// 增量以 nexus:chat-stream 事件推送（按 requestId 区分），结束后返回完整消息、结束原因与用量，
+// 并发出 nexus:chat-stream-done；chat_cancel 中止时返回已收到的部分。model / extra 按次覆盖配置
+#[tauri::command(rename_all = "camelCase")]
+async fn chat_stream(
+  app: tauri::AppHandle,
+  request_id: String,
+  messages: Vec<ChatMessage>,
+  profile_id: String,
+  model: Option<String>,
+  extra: Option<Value>,
+) -> Result<ChatCompletionResult, String> {
+  let request_id = request_id.trim().to_string();
+  if request_id.is_empty() {
+    return Err("requestId 不能为空".to_string());
+  }
+  if messages.is_empty() {
+    return Err("messages 不能为空".to_string());
+  }
+  let profile_id = profile_id.trim().to_string();
+  let lookup_app = app.clone();
+  let profile = tauri::async_runtime::spawn_blocking(move || {
+    with_chat_providers(&lookup_app, |store| Ok((store.profiles.iter().find(|p| p.id == profile_id).cloned(), false)))
+  })
+  .await
+  .map_err(|e| e.to_string())??
+  .ok_or("未找到对话服务商配置")?;
+  let base = profile.base_url.trim().trim_end_matches('/');
+  if base.is_empty() {
+    return Err("baseUrl 不能为空".to_string());
+  }
+  let model = model.map(|m| m.trim().to_string()).filter(|m| !m.is_empty()).unwrap_or_else(|| profile.model.trim().to_string());
+  if model.is_empty() {
+    return Err("model 不能为空".to_string());
+  }
+  let endpoint = if base.ends_with("/chat/completions") { base.to_string() } else { format!("{base}/chat/completions") };
+
+  // pin 是本地字段：调用方不一定经过 assemble_chat_context，发出前一律清掉，严格兼容 OpenAI 的服务商会拒绝多余字段
+  let messages: Vec<ChatMessage> = messages.into_iter().map(|m| ChatMessage { pin: None, ..m }).collect();
+  let mut body = serde_json::json!({ "model": model, "messages": messages, "stream": true });
+  if let Some(t) = profile.temperature {
+    body["temperature"] = Value::from(t);
+  }
+  if let Some(n) = profile.max_tokens.filter(|n| *n > 0) {
+    body["max_tokens"] = Value::from(n);
+  }
+  if profile.include_usage.unwrap_or(true) {
+    body["stream_options"] = serde_json::json!({ "include_usage": true });
+  }
+  for extra in [profile.extra, extra] {
+    if let (Some(Value::Object(extra)), Some(obj)) = (extra, body.as_object_mut()) {
+      for (k, v) in extra {
+        if !matches!(k.as_str(), "model" | "messages" | "stream") {
+          obj.insert(k, v);
+        }
+      }
+    }
+  }
+
+  let client = reqwest::Client::builder()
+    .user_agent("Nexus/1.0")
+    .connect_timeout(Duration::from_secs(30))
+    .read_timeout(Duration::from_secs(CHAT_STREAM_IDLE_SECS))
+    .build()
+    .map_err(|e| e.to_string())?;
+  let mut request = client
+    .post(&endpoint)
+    .header(reqwest::header::CONTENT_TYPE, "application/json")
+    .header(reqwest::header::ACCEPT, "text/event-stream")
+    .body(serde_json::to_vec(&body).map_err(|e| e.to_string())?);
+  let api_key = profile.api_key.unwrap_or_default();
+  if !api_key.trim().is_empty() {
+    request = request.header(reqwest::header::AUTHORIZATION, format!("Bearer {}", api_key.trim()));
+  }
+
+  let (abort, registration) = futures_util::future::AbortHandle::new_pair();
+  {
+    let mut map = chat_streams().lock().map_err(|_| "对话任务锁异常".to_string())?;
+    let now = now_millis();
+    map.retain(|_, e| e.done.is_none() || now - e.finished_at < CHAT_STREAM_KEEP_SECS * 1000);
+    if map.get(&request_id).is_some_and(|e| e.done.is_none()) {
+      return Err("该 requestId 的对话仍在进行中".to_string());
+    }
+    map.insert(request_id.clone(), ChatStreamEntry { abort, content: String::new(), seq: 0, done: None, finished_at: 0 });
+  }
+
+  let mut state = ChatStreamState::default();
+  let outcome =
+    futures_util::future::Abortable::new(run_chat_stream(&app, &request_id, request, &mut state), registration).await;
+  let cancelled = outcome.is_err();
+  let result = match outcome {
+    Ok(Err(err)) => Err(err),
+    Ok(Ok(())) | Err(futures_util::future::Aborted) => {
+      let finish_reason = state.finish_reason.take();
+      let usage = state.usage.take();
+      Ok(ChatCompletionResult {
+        request_id: request_id.clone(),
+        message: state.into_message(),
+        finish_reason,
+        usage,
+        cancelled,
+      })
+    }
+  };
+
+  let done = ChatStreamDone { request_id: request_id.clone(), result: result.clone().ok(), error: result.clone().err() };
+  if let Ok(mut map) = chat_streams().lock() {
+    if let Some(entry) = map.get_mut(&request_id) {
+      entry.done = Some(done.clone());
+      entry.finished_at = now_millis();
+    }
+  }
+  let _ = app.emit(CHAT_STREAM_DONE_EVENT, done);
+  result
+}
+
+// 页面刷新后重新挂上进行中（或刚结束）的对话：返回已收到的文本与 seq，之后的增量按 seq 去重
+#[tauri::command(rename_all = "camelCase")]
+fn chat_stream_attach(request_id: String) -> Result<Option<ChatStreamSnapshot>, String> {
+  let map = chat_streams().lock().map_err(|_| "对话任务锁异常".to_string())?;
+  Ok(map.get(request_id.trim()).map(|e| ChatStreamSnapshot {
+    request_id: request_id.trim().to_string(),
+    content: e.content.clone(),
+    seq: e.seq,
+    finished: e.done.is_some(),
+    result: e.done.as_ref().and_then(|d| d.result.clone()),
+    error: e.done.as_ref().and_then(|d| d.error.clone()),
+  }))
+}
+
+#[tauri::command(rename_all = "camelCase")]
+fn chat_cancel(request_id: String) -> Result<(), String> {
+  let map = chat_streams().lock().map_err(|_| "对话任务锁异常".to_string())?;
+  if let Some(entry) = map.get(request_id.trim()) {
+    entry.abort.abort();
+  }
+  Ok(())
+}
+
+#[tauri::command(rename_all = "camelCase")]
 async fn load_project_canvas(app: tauri::AppHandle, project_id: String) -> Result<Option<Value>, String> {
   if project_id.trim().is_empty() {
     return Ok(None);
@@ -691,6 +5901,7 @@ async fn delete_project_canvas(app: tauri::AppHandle, project_id: String) -> Res
     return Ok(());
   }
   let path = canvas_store_path(&app, &project_id)?;
+  forget_open_project(&project_id);
   tauri::async_runtime::spawn_blocking(move || -> Result<(), String> {
     if path.exists() {
       std::fs::remove_file(&path).map_err(|e| e.to_string())?;
@@ -702,15 +5913,629 @@ async fn delete_project_canvas(app: tauri::AppHandle, project_id: String) -> Res
   Ok(())
 }
 
+// ======== Media cache (LRU eviction + size cap) ========
+
+const MEDIA_CACHE_INDEX_FILE: &str = "nexus-cache-index.json";
+const MEDIA_CACHE_INDEX_VERSION: u32 = 1;
+const MEDIA_CACHE_SAVE_INTERVAL_MS: i64 = 30_000; // 只刷新访问时间时最多 30 秒落盘一次
+const GIB: u64 = 1024 * 1024 * 1024;
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase", default)]
+struct MediaCachePolicy {
+  max_bytes: u64, // 图片 + 媒体缓存的总上限
+  low_water: f32, // 超限后淘汰到 max_bytes × low_water，避免每次下载都触发淘汰
+}
+
+impl Default for MediaCachePolicy {
+  fn default() -> Self {
+    Self { max_bytes: 10 * GIB, low_water: 0.9 }
+  }
+}
+
+impl MediaCachePolicy {
+  fn sanitized(mut self) -> Self {
+    self.max_bytes = self.max_bytes.clamp(GIB / 4, 4096 * GIB);
+    self.low_water = self.low_water.clamp(0.5, 1.0);
+    self
+  }
+
+  fn target_bytes(&self) -> u64 {
+    (self.max_bytes as f64 * self.low_water as f64) as u64
+  }
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct MediaCacheEntry {
+  kind: String, // image | media
+  size: u64,
+  created_at: i64,
+  last_access: i64,
+  #[serde(default)]
+  projects: Vec<String>, // 下载时指定或在项目画布中引用到它的项目
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct MediaCacheIndex {
+  version: u32,
+  #[serde(default)]
+  policy: MediaCachePolicy,
+  #[serde(default)]
+  entries: HashMap<String, MediaCacheEntry>, // key：相对 app_cache_dir 的路径，如 nexus-media-cache/media-<hash>.mp4
+}
+
+struct MediaCacheState {
+  index: MediaCacheIndex,
+  root: PathBuf,
+  index_path: PathBuf,
+  saved_at: i64,
+  unsaved: bool, // 有未落盘的访问时间
+}
+
+#[derive(serde::Serialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct MediaCacheProjectUsage {
+  project_id: String,
+  count: usize,
+  bytes: u64,
+}
+
+#[derive(serde::Serialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct MediaCacheStats {
+  total_bytes: u64,
+  max_bytes: u64,
+  entry_count: usize,
+  image_count: usize,
+  image_bytes: u64,
+  media_count: usize,
+  media_bytes: u64,
+  protected_count: usize, // 被已保存的画布或已打开项目引用、不会被淘汰的文件
+  protected_bytes: u64,
+  open_projects: Vec<String>,
+  projects: Vec<MediaCacheProjectUsage>,
+}
+
+#[derive(serde::Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+struct MediaCacheEviction {
+  path: String,
+  kind: String,
+  size: u64,
+  last_access: i64,
+}
+
+#[derive(serde::Serialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct MediaCacheEvictionReport {
+  dry_run: bool,
+  evicted: Vec<MediaCacheEviction>,
+  freed_bytes: u64,
+  remaining_bytes: u64,
+  protected_skipped: usize,
+  errors: Vec<String>,
+}
+
+static MEDIA_CACHE_STATE: OnceLock<Mutex<Option<MediaCacheState>>> = OnceLock::new();
+static MEDIA_CACHE_EVICTING: AtomicBool = AtomicBool::new(false);
+static CANVAS_REF_CACHE: OnceLock<Mutex<CanvasRefCache>> = OnceLock::new();
+static OPEN_PROJECTS: OnceLock<Mutex<std::collections::HashSet<String>>> = OnceLock::new();
+
+fn open_projects() -> &'static Mutex<std::collections::HashSet<String>> {
+  OPEN_PROJECTS.get_or_init(|| Mutex::new(std::collections::HashSet::new()))
+}
+
+// 已打开项目只由前端 media_cache_set_open_projects 报告（加载画布不算打开）；删除项目时顺带移除
+fn forget_open_project(project_id: &str) {
+  let project_id = project_id.trim();
+  if project_id.is_empty() {
+    return;
+  }
+  if let Ok(mut set) = open_projects().lock() {
+    set.remove(project_id);
+  }
+}
+
+// 缓存文件名 → 引用它的画布文件 stem（即 hash_key(项目 id)）
+type CanvasRefOwners = HashMap<String, Vec<String>>;
+
+struct CanvasRefFile {
+  size: u64,
+  mtime: i64,
+  names: Vec<String>,
+}
+
+// 已保存画布的引用按文件大小/修改时间缓存：只重读变化过的画布，超限淘汰时不必每次扫描全部画布
+#[derive(Default)]
+struct CanvasRefCache {
+  files: HashMap<String, CanvasRefFile>,
+  owners: Arc<CanvasRefOwners>,
+}
+
+// nexus-canvas 下所有已保存画布引用到的缓存文件（不论项目是否打开）；在媒体缓存锁之外调用
+fn saved_canvas_refs(app: &tauri::AppHandle) -> Arc<CanvasRefOwners> {
+  let Ok(dir) = canvas_store_dir(app) else {
+    return Arc::default();
+  };
+  let lock = CANVAS_REF_CACHE.get_or_init(|| Mutex::new(CanvasRefCache::default()));
+  let Ok(mut cache) = lock.lock() else {
+    return Arc::default();
+  };
+  let mut seen: std::collections::HashSet<String> = std::collections::HashSet::new();
+  let mut changed = false;
+  if let Ok(read) = std::fs::read_dir(&dir) {
+    for entry in read.flatten() {
+      let name = entry.file_name().to_string_lossy().to_string();
+      let Some(stem) = name.strip_suffix(".json").filter(|s| !s.contains(".tmp")) else { continue };
+      let Ok(meta) = entry.metadata() else { continue };
+      if !meta.is_file() {
+        continue;
+      }
+      let (size, mtime) = (meta.len(), file_mtime_millis(&meta));
+      seen.insert(stem.to_string());
+      if cache.files.get(stem).is_some_and(|f| f.size == size && f.mtime == mtime) {
+        continue;
+      }
+      let mut names = std::collections::HashSet::new();
+      if let Some(canvas) = std::fs::read(entry.path()).ok().and_then(|raw| serde_json::from_slice::<Value>(&raw).ok()) {
+        collect_cache_refs(&canvas, &mut names);
+      }
+      cache.files.insert(stem.to_string(), CanvasRefFile { size, mtime, names: names.into_iter().collect() });
+      changed = true;
+    }
+  }
+  let before = cache.files.len();
+  cache.files.retain(|k, _| seen.contains(k));
+  if changed || cache.files.len() != before {
+    let mut owners: CanvasRefOwners = HashMap::new();
+    for (stem, file) in cache.files.iter() {
+      for name in file.names.iter() {
+        owners.entry(name.clone()).or_default().push(stem.clone());
+      }
+    }
+    cache.owners = Arc::new(owners);
+  }
+  cache.owners.clone()
+}
+
+fn media_cache_key(root: &Path, path: &Path) -> Option<String> {
+  let rel = path.strip_prefix(root).ok()?;
+  Some(rel.components().map(|c| c.as_os_str().to_string_lossy()).collect::<Vec<_>>().join("/"))
+}
+
+fn file_mtime_millis(meta: &std::fs::Metadata) -> i64 {
+  meta
+    .modified()
+    .ok()
+    .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+    .map(|d| d.as_millis() as i64)
+    .unwrap_or(0)
+}
+
+impl MediaCacheState {
+  // 与磁盘对齐：补登记未记录的文件，移除已不存在的条目。返回是否有变化
+  fn reconcile(&mut self) -> bool {
+    let mut seen: std::collections::HashSet<String> = std::collections::HashSet::new();
+    let mut changed = false;
+    for (dir, kind) in [(IMAGE_CACHE_DIR, "image"), (MEDIA_CACHE_DIR, "media")] {
+      let Ok(read) = std::fs::read_dir(self.root.join(dir)) else { continue };
+      for entry in read.flatten() {
+        let path = entry.path();
+        let Ok(meta) = entry.metadata() else { continue };
+        let name = entry.file_name().to_string_lossy().to_string();
+        // 中断遗留的临时文件（.part 下载、.tmp.* 原子写入，超过一天）顺手清掉；临时文件与校验文件不算缓存条目
+        let temp = name.ends_with(".part") || name.contains(".tmp.");
+        if temp && meta.is_file() && now_millis() - file_mtime_millis(&meta) > DAY_MS as i64 {
+          let _ = std::fs::remove_file(&path);
+          continue;
+        }
+        if !meta.is_file() || name.contains(".tmp") || name.ends_with(".part") || name.ends_with(".meta.json") {
+          continue;
+        }
+        let Some(key) = media_cache_key(&self.root, &path) else { continue };
+        let mtime = file_mtime_millis(&meta);
+        // 大小以磁盘为准，总量与淘汰统计才对得上；截断由命中时的校验文件（.meta.json）检查
+        let entry = self.index.entries.entry(key.clone()).or_insert_with(|| {
+          changed = true;
+          MediaCacheEntry { kind: kind.to_string(), size: meta.len(), created_at: mtime, last_access: mtime, projects: vec![] }
+        });
+        if entry.size != meta.len() {
+          entry.size = meta.len();
+          changed = true;
+        }
+        seen.insert(key);
+      }
+    }
+    let before = self.index.entries.len();
+    self.index.entries.retain(|k, _| seen.contains(k));
+    changed || self.index.entries.len() != before
+  }
+
+  fn total_bytes(&self) -> u64 {
+    self.index.entries.values().map(|e| e.size).sum()
+  }
+
+  // 任一已保存画布引用的文件都受保护；已打开项目的画布引用到的文件记为该项目所属
+  fn protected_keys(&mut self, refs: &CanvasRefOwners) -> std::collections::HashSet<String> {
+    let projects: Vec<String> = open_projects().lock().map(|s| s.iter().cloned().collect()).unwrap_or_default();
+    let open_stems: HashMap<String, &String> = projects.iter().map(|p| (hash_key(p), p)).collect();
+    let mut protected = std::collections::HashSet::new();
+    for (key, entry) in self.index.entries.iter_mut() {
+      let name = key.rsplit('/').next().unwrap_or(key);
+      // 下载时指定了已打开项目的文件，即使画布尚未保存也保护
+      let mut hit = entry.projects.iter().any(|p| projects.contains(p));
+      if let Some(stems) = refs.get(name) {
+        hit = true;
+        for p in stems.iter().filter_map(|stem| open_stems.get(stem)) {
+          if !entry.projects.contains(p) {
+            entry.projects.push((*p).clone());
+            self.unsaved = true;
+          }
+        }
+      }
+      if hit {
+        protected.insert(key.clone());
+      }
+    }
+    protected
+  }
+
+  // 按最近访问时间从旧到新删除，直到总量不超过 target；protected 与 keep 不删
+  fn evict_lru(
+    &mut self,
+    target: u64,
+    protected: &std::collections::HashSet<String>,
+    keep: Option<&str>,
+    dry_run: bool,
+  ) -> MediaCacheEvictionReport {
+    let mut report = MediaCacheEvictionReport { dry_run, ..Default::default() };
+    let mut total = self.total_bytes();
+    let mut candidates: Vec<(&String, &MediaCacheEntry)> = self.index.entries.iter().collect();
+    candidates.sort_by_key(|(_, e)| e.last_access);
+    let mut removed: Vec<String> = vec![];
+    for (key, entry) in candidates {
+      if total <= target {
+        break;
+      }
+      if protected.contains(key) || keep == Some(key.as_str()) {
+        report.protected_skipped += 1;
+        continue;
+      }
+      let path = self.root.join(key);
+      if !dry_run {
+        if let Err(err) = std::fs::remove_file(&path) {
+          if path.exists() {
+            report.errors.push(format!("{}：{}", key, err));
+            continue;
+          }
+        }
+        let _ = std::fs::remove_file(media_sidecar_path(&path));
+      }
+      total = total.saturating_sub(entry.size);
+      report.freed_bytes += entry.size;
+      report.evicted.push(MediaCacheEviction {
+        path: path.to_string_lossy().to_string(),
+        kind: entry.kind.clone(),
+        size: entry.size,
+        last_access: entry.last_access,
+      });
+      removed.push(key.clone());
+    }
+    if !dry_run {
+      for key in removed {
+        self.index.entries.remove(&key);
+      }
+    }
+    report.remaining_bytes = total;
+    report
+  }
+}
+
+fn collect_cache_refs(value: &Value, out: &mut std::collections::HashSet<String>) {
+  match value {
+    Value::String(s) => cache_file_names(s, out),
+    Value::Array(items) => items.iter().for_each(|v| collect_cache_refs(v, out)),
+    Value::Object(map) => map.values().for_each(|v| collect_cache_refs(v, out)),
+    _ => {}
+  }
+}
+
+// 从任意字符串（本地路径、asset:// URL 等）中找出 image-<sha256>.<ext> / media-<sha256>.<ext>
+fn cache_file_names(text: &str, out: &mut std::collections::HashSet<String>) {
+  for prefix in ["image-", "media-"] {
+    let mut from = 0;
+    while let Some(pos) = text[from..].find(prefix) {
+      let start = from + pos;
+      let rest = &text.as_bytes()[start + prefix.len()..];
+      let hex_len = rest.iter().take_while(|b| b.is_ascii_hexdigit()).count();
+      if hex_len == 64 && rest.get(64) == Some(&b'.') {
+        let ext_len = rest[65..].iter().take_while(|b| b.is_ascii_alphanumeric()).count();
+        if (2..=6).contains(&ext_len) {
+          out.insert(text[start..start + prefix.len() + 65 + ext_len].to_string());
+        }
+      }
+      from = start + prefix.len();
+    }
+  }
+}
+
+fn load_media_cache_state(app: &tauri::AppHandle) -> Result<MediaCacheState, String> {
+  let root = app.path().app_cache_dir().map_err(|e| e.to_string())?;
+  std::fs::create_dir_all(&root).map_err(|e| e.to_string())?;
+  let index_path = root.join(MEDIA_CACHE_INDEX_FILE);
+  // 索引可由目录重建：读不到或损坏就从头登记
+  let index = std::fs::read(&index_path)
+    .ok()
+    .and_then(|raw| serde_json::from_slice::<MediaCacheIndex>(&raw).ok())
+    .filter(|idx| idx.version == MEDIA_CACHE_INDEX_VERSION)
+    .unwrap_or(MediaCacheIndex { version: MEDIA_CACHE_INDEX_VERSION, ..Default::default() });
+  let mut state = MediaCacheState { index, root, index_path, saved_at: 0, unsaved: false };
+  state.unsaved = state.reconcile();
+  Ok(state)
+}
+
+// 同 with_memory；f 返回 true 立即落盘，只有访问时间变化（unsaved）时按间隔落盘
+fn with_media_cache<R>(
+  app: &tauri::AppHandle,
+  f: impl FnOnce(&mut MediaCacheState) -> Result<(R, bool), String>,
+) -> Result<R, String> {
+  let lock = MEDIA_CACHE_STATE.get_or_init(|| Mutex::new(None));
+  let mut guard = lock.lock().map_err(|_| "媒体缓存锁异常".to_string())?;
+  if guard.is_none() {
+    *guard = Some(load_media_cache_state(app)?);
+  }
+  let Some(state) = guard.as_mut() else {
+    return Err("媒体缓存未初始化".to_string());
+  };
+  let (out, dirty) = f(state)?;
+  let now = now_millis();
+  if dirty || (state.unsaved && now - state.saved_at >= MEDIA_CACHE_SAVE_INTERVAL_MS) {
+    state.index.policy = state.index.policy.clone().sanitized();
+    write_json_atomic(&state.index_path, &state.index)?;
+    state.saved_at = now;
+    state.unsaved = false;
+  }
+  Ok(out)
+}
+
+// 同 flush_memory_state：退出时写入还没到落盘间隔的访问时间
+fn flush_media_cache_state() {
+  let Some(lock) = MEDIA_CACHE_STATE.get() else { return };
+  let Ok(mut guard) = lock.lock() else { return };
+  let Some(state) = guard.as_mut().filter(|s| s.unsaved) else { return };
+  match write_json_atomic(&state.index_path, &state.index) {
+    Ok(()) => {
+      state.saved_at = now_millis();
+      state.unsaved = false;
+    }
+    Err(err) => log::warn!("[media_cache] 退出时保存缓存索引失败: {}", err),
+  }
+}
+
+// 刷新访问时间、登记所属项目；新下载的文件使总量超限时返回它的 key，交给后台淘汰
+fn touch_media_cache_entry(
+  app: &tauri::AppHandle,
+  path: &Path,
+  kind: &str,
+  project_id: &str,
+  downloaded: bool,
+) -> Result<Option<String>, String> {
+  with_media_cache(app, |state| {
+    let Some(key) = media_cache_key(&state.root, path) else {
+      return Ok((None, false));
+    };
+    let now = now_millis();
+    let size = std::fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+    let mut dirty = downloaded || !state.index.entries.contains_key(&key);
+    let entry = state.index.entries.entry(key.clone()).or_insert_with(|| MediaCacheEntry {
+      kind: kind.to_string(),
+      created_at: now,
+      ..Default::default()
+    });
+    entry.size = size;
+    entry.last_access = now;
+    if !project_id.is_empty() && !entry.projects.iter().any(|p| p == project_id) {
+      entry.projects.push(project_id.to_string());
+      dirty = true;
+    }
+    state.unsaved = true;
+
+    let over = downloaded && state.total_bytes() > state.index.policy.clone().sanitized().max_bytes;
+    Ok((over.then_some(key), dirty))
+  })
+}
+
+// 超限淘汰在后台阻塞线程里跑，同一时间只跑一个；不动刚下载的 keep
+fn evict_media_cache_in_background(app: tauri::AppHandle, keep: String) {
+  if MEDIA_CACHE_EVICTING.swap(true, Ordering::AcqRel) {
+    return;
+  }
+  tauri::async_runtime::spawn_blocking(move || {
+    let refs = saved_canvas_refs(&app);
+    let res = with_media_cache(&app, |state| {
+      let policy = state.index.policy.clone().sanitized();
+      if state.total_bytes() <= policy.max_bytes {
+        return Ok((None, false));
+      }
+      let protected = state.protected_keys(&refs);
+      let report = state.evict_lru(policy.target_bytes(), &protected, Some(&keep), false);
+      let dirty = !report.evicted.is_empty();
+      Ok((Some(report), dirty))
+    });
+    MEDIA_CACHE_EVICTING.store(false, Ordering::Release);
+    match res {
+      Ok(Some(report)) => {
+        if !report.evicted.is_empty() {
+          log::info!("[media_cache] 超出上限，淘汰 {} 个文件，释放 {} bytes", report.evicted.len(), report.freed_bytes);
+        }
+        for err in report.errors.iter() {
+          log::warn!("[media_cache] 删除失败: {}", err);
+        }
+      }
+      Ok(None) => {}
+      Err(err) => log::warn!("[media_cache] 淘汰失败: {}", err),
+    }
+  });
+}
+
+// cache_remote_image / cache_remote_media 命中或下载后调用；索引读写放到阻塞线程，失败只记日志，不影响返回路径
+async fn record_media_cache_access(app: &tauri::AppHandle, path: &Path, kind: &str, project_id: &str, downloaded: bool) {
+  let (handle, path, kind, project_id) = (app.clone(), path.to_path_buf(), kind.to_string(), project_id.to_string());
+  let res = tauri::async_runtime::spawn_blocking(move || touch_media_cache_entry(&handle, &path, &kind, &project_id, downloaded))
+    .await
+    .map_err(|e| e.to_string())
+    .and_then(|r| r);
+  match res {
+    Ok(Some(key)) => evict_media_cache_in_background(app.clone(), key),
+    Ok(None) => {}
+    Err(err) => log::warn!("[media_cache] 更新缓存索引失败: {}", err),
+  }
+}
+
+#[tauri::command(rename_all = "camelCase")]
+async fn media_cache_stats(app: tauri::AppHandle) -> Result<MediaCacheStats, String> {
+  tauri::async_runtime::spawn_blocking(move || -> Result<MediaCacheStats, String> {
+    let refs = saved_canvas_refs(&app);
+    with_media_cache(&app, |state| {
+      let changed = state.reconcile();
+      let protected = state.protected_keys(&refs);
+      let mut stats = MediaCacheStats { max_bytes: state.index.policy.max_bytes, ..Default::default() };
+      let mut projects: HashMap<&str, MediaCacheProjectUsage> = HashMap::new();
+      for (key, e) in state.index.entries.iter() {
+        stats.total_bytes += e.size;
+        stats.entry_count += 1;
+        if e.kind == "image" {
+          stats.image_count += 1;
+          stats.image_bytes += e.size;
+        } else {
+          stats.media_count += 1;
+          stats.media_bytes += e.size;
+        }
+        if protected.contains(key) {
+          stats.protected_count += 1;
+          stats.protected_bytes += e.size;
+        }
+        for p in e.projects.iter() {
+          let usage = projects
+            .entry(p.as_str())
+            .or_insert_with(|| MediaCacheProjectUsage { project_id: p.clone(), ..Default::default() });
+          usage.count += 1;
+          usage.bytes += e.size;
+        }
+      }
+      stats.projects = projects.into_values().collect();
+      stats.projects.sort_by_key(|p| std::cmp::Reverse(p.bytes));
+      stats.open_projects = open_projects().lock().map(|s| s.iter().cloned().collect()).unwrap_or_default();
+      stats.open_projects.sort();
+      Ok((stats, changed))
+    })
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+#[tauri::command(rename_all = "camelCase")]
+fn media_cache_get_policy(app: tauri::AppHandle) -> Result<MediaCachePolicy, String> {
+  with_media_cache(&app, |state| Ok((state.index.policy.clone(), false)))
+}
+
+#[tauri::command(rename_all = "camelCase")]
+fn media_cache_set_policy(app: tauri::AppHandle, policy: MediaCachePolicy) -> Result<MediaCachePolicy, String> {
+  let policy = policy.sanitized();
+  let (policy, over) = with_media_cache(&app, |state| {
+    state.index.policy = policy.clone();
+    let over = state.total_bytes() > policy.max_bytes;
+    Ok(((policy, over), true))
+  })?;
+  // 调低上限后立即淘汰到新的水位，不等下一次下载
+  if over {
+    evict_media_cache_in_background(app, String::new());
+  }
+  Ok(policy)
+}
+
+// 替换“已打开项目”集合：前端切换/关闭画布时调用
+#[tauri::command(rename_all = "camelCase")]
+fn media_cache_set_open_projects(project_ids: Vec<String>) -> Result<(), String> {
+  let mut set = open_projects().lock().map_err(|_| "媒体缓存锁异常".to_string())?;
+  *set = project_ids.into_iter().map(|p| p.trim().to_string()).filter(|p| !p.is_empty()).collect();
+  Ok(())
+}
+
+// 按 LRU 淘汰到 target_bytes（缺省 = 上限 × low_water）；已保存画布与已打开项目引用的文件不动。dry_run 默认 true
+#[tauri::command(rename_all = "camelCase")]
+async fn media_cache_evict(
+  app: tauri::AppHandle,
+  target_bytes: Option<u64>,
+  dry_run: Option<bool>,
+) -> Result<MediaCacheEvictionReport, String> {
+  tauri::async_runtime::spawn_blocking(move || -> Result<MediaCacheEvictionReport, String> {
+    let dry_run = dry_run.unwrap_or(true);
+    let refs = saved_canvas_refs(&app);
+    with_media_cache(&app, |state| {
+      let changed = state.reconcile();
+      let target = target_bytes.unwrap_or_else(|| state.index.policy.clone().sanitized().target_bytes());
+      let protected = state.protected_keys(&refs);
+      let report = state.evict_lru(target, &protected, None, dry_run);
+      let dirty = changed || (!dry_run && !report.evicted.is_empty());
+      Ok((report, dirty))
+    })
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
+// 清空缓存；给了 project_id 只清该项目的文件（仍被其他画布或已打开项目引用的保留）。
+// include_protected 时连同受保护的文件一起删（用户明确要求清理）
+#[tauri::command(rename_all = "camelCase")]
+async fn media_cache_clear(
+  app: tauri::AppHandle,
+  project_id: Option<String>,
+  include_protected: Option<bool>,
+) -> Result<MediaCacheEvictionReport, String> {
+  tauri::async_runtime::spawn_blocking(move || -> Result<MediaCacheEvictionReport, String> {
+    let project_id = normalize_project_id(project_id);
+    let include_protected = include_protected.unwrap_or(false);
+    let refs = saved_canvas_refs(&app);
+    with_media_cache(&app, |state| {
+      state.reconcile();
+      let mut protected = if include_protected { Default::default() } else { state.protected_keys(&refs) };
+      if !project_id.is_empty() {
+        // 只清该项目：其余文件一律视为受保护；该项目本身的引用不算保护
+        let stem = hash_key(&project_id);
+        for (key, e) in state.index.entries.iter() {
+          let name = key.rsplit('/').next().unwrap_or(key);
+          let canvases = refs.get(name).map(Vec::as_slice).unwrap_or_default();
+          let own = e.projects.contains(&project_id) || canvases.contains(&stem);
+          let others = e.projects.iter().any(|p| *p != project_id) || canvases.iter().any(|s| *s != stem);
+          if !own || (others && protected.contains(key)) {
+            protected.insert(key.clone());
+          } else if !include_protected {
+            protected.remove(key);
+          }
+        }
+      }
+      let report = state.evict_lru(0, &protected, None, false);
+      Ok((report, true))
+    })
+  })
+  .await
+  .map_err(|e| e.to_string())?
+}
+
 #[tauri::command(rename_all = "camelCase")]
 async fn cache_remote_image(
   app: tauri::AppHandle,
   url: String,
   auth_token: Option<String>,
+  project_id: Option<String>,
 ) -> Result<String, String> {
   if url.starts_with("data:") || url.starts_with("blob:") {
     return Ok(url);
   }
+  let project_id = normalize_project_id(project_id);
 
   let cache_root = app
     .path()
@@ -731,7 +6556,8 @@ async fn cache_remote_image(
 
   if let Some(ext) = extension_from_url(&url) {
     let cached = cache_root.join(format!("{}.{}", file_stem, ext));
-    if cached.exists() {
+    if accept_image_cache_hit(&cached).await {
+      record_media_cache_access(&app, &cached, "image", &project_id, false).await;
       return Ok(cached.to_string_lossy().to_string());
     }
   }
@@ -764,11 +6590,16 @@ async fn cache_remote_image(
     .get(reqwest::header::CONTENT_TYPE)
     .and_then(|v| v.to_str().ok())
     .map(|v| v.to_string());
+  let expected_len = response.content_length();
 
   let bytes = response.bytes().await.map_err(|e| {
     log::error!("[cache_remote_image] 读取响应失败: {}", e);
     e.to_string()
   })?;
+  if let Some(expected) = expected_len.filter(|n| *n != bytes.len() as u64) {
+    log::error!("[cache_remote_image] 下载不完整: {} / {} bytes", bytes.len(), expected);
+    return Err(format!("下载不完整：收到 {} / {} bytes", bytes.len(), expected));
+  }
   if bytes.len() as u64 > MAX_IMAGE_BYTES {
     log::error!("[cache_remote_image] 图片过大: {} bytes", bytes.len());
     return Err("图片过大，已拒绝缓存".to_string());
@@ -780,26 +6611,229 @@ async fn cache_remote_image(
 
   log::info!("[cache_remote_image] Content-Type: {:?}, 扩展名: {}, 大小: {} bytes", content_type, ext, bytes.len());
 
-  if !target.exists() {
-    std::fs::write(&target, &bytes).map_err(|e| {
+  let downloaded = !accept_image_cache_hit(&target).await;
+  if downloaded {
+    // 先写临时文件再改名，中断时不会留下半张图；校验文件记下大小，命中时核对
+    write_bytes_atomic(&target, &bytes).map_err(|e| {
       log::error!("[cache_remote_image] 写入文件失败: {}", e);
-      e.to_string()
+      e
     })?;
+    let integrity = MediaIntegrity { size: bytes.len() as u64, sha256: hex::encode(Sha256::digest(&bytes)), completed_at: now_millis() };
+    if let Err(err) = write_json_atomic(&media_sidecar_path(&target), &integrity) {
+      log::warn!("[cache_remote_image] 写入校验文件失败: {}", err);
+    }
   }
+  record_media_cache_access(&app, &target, "image", &project_id, downloaded).await;
 
   log::info!("[cache_remote_image] 缓存成功: {}", target.to_string_lossy());
   Ok(target.to_string_lossy().to_string())
 }
 
+// ======== Media download integrity ========
+
+// 图片命中：按校验文件核对大小；没有校验文件的旧缓存按图片格式检查是否完整
+// （索引里的大小取自文件本身，核对不出截断）。不符的删掉重下，旧缓存核对通过后补写校验文件
+async fn accept_image_cache_hit(path: &Path) -> bool {
+  if !path.exists() {
+    return false;
+  }
+  let reason = match check_media_cache_file(path, false).await {
+    MediaHitCheck::Valid => return true,
+    MediaHitCheck::Corrupt(reason) => reason,
+    MediaHitCheck::Unverified => {
+      let owned = path.to_path_buf();
+      let read = tauri::async_runtime::spawn_blocking(move || std::fs::read(&owned))
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|r| r.map_err(|e| e.to_string()));
+      match read {
+        Err(err) => format!("读取失败：{err}"),
+        Ok(bytes) if bytes.is_empty() => "文件为空".to_string(),
+        Ok(bytes) => match image_bytes_complete(&bytes) {
+          Some(true) => {
+            let integrity =
+              MediaIntegrity { size: bytes.len() as u64, sha256: hex::encode(Sha256::digest(&bytes)), completed_at: now_millis() };
+            if let Err(err) = write_json_atomic(&media_sidecar_path(path), &integrity) {
+              log::warn!("[cache_remote_image] 写入校验文件失败: {}", err);
+            }
+            return true;
+          }
+          Some(false) => "图片数据不完整".to_string(),
+          // 认不出的格式无法核对：不算命中，重新下载后覆盖
+          None => {
+            log::info!("[cache_remote_image] 旧缓存格式无法核对，重新下载: {}", path.to_string_lossy());
+            return false;
+          }
+        },
+      }
+    }
+  };
+  log::warn!("[cache_remote_image] 缓存文件损坏（{}），删除后重新下载: {}", reason, path.to_string_lossy());
+  remove_media_cache_file(path);
+  false
+}
+
+// 按格式检查图片是否完整：PNG 的 IEND、JPEG 的 EOI、GIF 的结尾符，WebP / BMP 核对头部记录的长度。
+// 认不出的格式返回 None
+fn image_bytes_complete(bytes: &[u8]) -> Option<bool> {
+  const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+  const PNG_IEND: &[u8] = b"IEND\xAE\x42\x60\x82";
+  let tail = |n: usize| &bytes[bytes.len().saturating_sub(n)..];
+  if bytes.starts_with(PNG_SIGNATURE) {
+    // IEND 之后偶尔带少量附加数据
+    return Some(tail(64).windows(PNG_IEND.len()).any(|w| w == PNG_IEND));
+  }
+  if bytes.starts_with(&[0xFF, 0xD8, 0xFF]) {
+    // 部分编码器会在 EOI 之后补零
+    let end = bytes.iter().rposition(|b| *b != 0).map_or(0, |i| i + 1);
+    return Some(bytes[..end].ends_with(&[0xFF, 0xD9]));
+  }
+  if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") {
+    return Some(bytes.last() == Some(&0x3B));
+  }
+  if bytes.len() >= 12 && bytes.starts_with(b"RIFF") && &bytes[8..12] == b"WEBP" {
+    let declared = u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]) as u64;
+    return Some(declared + 8 <= bytes.len() as u64);
+  }
+  if bytes.len() >= 6 && bytes.starts_with(b"BM") {
+    let declared = u32::from_le_bytes([bytes[2], bytes[3], bytes[4], bytes[5]]) as u64;
+    return Some(declared <= bytes.len() as u64);
+  }
+  None
+}
+
+static MEDIA_PART_SEQ: AtomicU64 = AtomicU64::new(0);
+
+// 与缓存文件同名的 <file>.meta.json：下载完成时的大小与内容哈希
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+struct MediaIntegrity {
+  size: u64,
+  sha256: String,
+  completed_at: i64,
+}
+
+enum MediaHitCheck {
+  Valid,
+  Unverified, // 没有校验文件（旧版本缓存或写校验文件前中断）
+  Corrupt(String),
+}
+
+fn media_sidecar_path(path: &Path) -> PathBuf {
+  let name = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+  path.with_file_name(format!("{name}.meta.json"))
+}
+
+fn sha256_file(path: &Path) -> Result<String, String> {
+  let mut file = std::fs::File::open(path).map_err(|e| e.to_string())?;
+  let mut hasher = Sha256::new();
+  std::io::copy(&mut file, &mut hasher).map_err(|e| e.to_string())?;
+  Ok(hex::encode(hasher.finalize()))
+}
+
+// 默认只比对大小；verify_hash 时重算 sha256（大文件较慢，放到阻塞线程）
+async fn check_media_cache_file(path: &Path, verify_hash: bool) -> MediaHitCheck {
+  let integrity = std::fs::read(media_sidecar_path(path))
+    .ok()
+    .and_then(|raw| serde_json::from_slice::<MediaIntegrity>(&raw).ok());
+  let Some(integrity) = integrity else {
+    return MediaHitCheck::Unverified;
+  };
+  let size = std::fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+  if size != integrity.size {
+    return MediaHitCheck::Corrupt(format!("大小不符：{} / {} bytes", size, integrity.size));
+  }
+  if !verify_hash {
+    return MediaHitCheck::Valid;
+  }
+  let owned = path.to_path_buf();
+  match tauri::async_runtime::spawn_blocking(move || sha256_file(&owned)).await {
+    Ok(Ok(hash)) if hash == integrity.sha256 => MediaHitCheck::Valid,
+    Ok(Ok(_)) => MediaHitCheck::Corrupt("内容哈希不符".to_string()),
+    Ok(Err(err)) => MediaHitCheck::Corrupt(format!("读取失败：{err}")),
+    Err(err) => MediaHitCheck::Corrupt(format!("校验任务失败：{err}")),
+  }
+}
+
+// 为没有校验文件的旧缓存补写校验文件（哈希在阻塞线程里算）
+async fn write_media_integrity(path: &Path, size: u64) -> Result<(), String> {
+  let owned = path.to_path_buf();
+  let sha256 = tauri::async_runtime::spawn_blocking(move || sha256_file(&owned))
+    .await
+    .map_err(|e| e.to_string())??;
+  let integrity = MediaIntegrity { size, sha256, completed_at: now_millis() };
+  write_json_atomic(&media_sidecar_path(path), &integrity)
+}
+
+fn remove_media_cache_file(path: &Path) {
+  let _ = std::fs::remove_file(path);
+  let _ = std::fs::remove_file(media_sidecar_path(path));
+}
+
+// 命中且校验通过返回 true；校验失败的删掉重下；未校验的旧缓存记为 fallback，
+// 由下载时按 Content-Length 核对（一致即补写校验文件沿用），重新下载失败时也沿用它
+async fn accept_media_cache_hit(path: &Path, verify_hash: bool, fallback: &mut Option<PathBuf>) -> bool {
+  if !path.exists() {
+    return false;
+  }
+  match check_media_cache_file(path, verify_hash).await {
+    MediaHitCheck::Valid => true,
+    MediaHitCheck::Unverified => {
+      log::info!("[cache_remote_media] 缓存缺少校验信息，按响应长度核对: {}", path.to_string_lossy());
+      *fallback = Some(path.to_path_buf());
+      false
+    }
+    MediaHitCheck::Corrupt(reason) => {
+      log::warn!("[cache_remote_media] 缓存文件损坏（{}），删除后重新下载: {}", reason, path.to_string_lossy());
+      remove_media_cache_file(path);
+      false
+    }
+  }
+}
+
+// 流式写入临时文件并计算哈希；长度与 Content-Length 不符（连接中断）时报错，由调用方删除临时文件
+async fn download_to_part(response: reqwest::Response, part: &Path) -> Result<(u64, String), String> {
+  let expected = response.content_length();
+  let mut file = tokio::fs::File::create(part).await.map_err(|e| e.to_string())?;
+  let mut hasher = Sha256::new();
+  let mut size: u64 = 0;
+  let mut stream = response.bytes_stream();
+  while let Some(chunk) = stream.next().await {
+    let bytes = chunk.map_err(|e| format!("下载中断：{e}"))?;
+    size += bytes.len() as u64;
+    if size > MAX_MEDIA_BYTES {
+      log::error!("[cache_remote_media] 文件过大: {} bytes", size);
+      return Err("媒体文件过大，已拒绝缓存".to_string());
+    }
+    hasher.update(&bytes);
+    file.write_all(&bytes).await.map_err(|e| e.to_string())?;
+  }
+  file.flush().await.map_err(|e| e.to_string())?;
+  file.sync_all().await.map_err(|e| e.to_string())?;
+  if let Some(expected) = expected.filter(|n| *n != size) {
+    return Err(format!("下载不完整：收到 {size} / {expected} bytes"));
+  }
+  if size == 0 {
+    return Err("下载内容为空".to_string());
+  }
+  Ok((size, hex::encode(hasher.finalize())))
+}
+
+// 下载先写入 .part 临时文件，校验长度并记录哈希后才改名为正式缓存；命中时按校验文件检查，
+// 损坏的重新下载。verify_hash 时命中也重算哈希（默认只比对大小）
 #[tauri::command(rename_all = "camelCase")]
 async fn cache_remote_media(
   app: tauri::AppHandle,
   url: String,
   auth_token: Option<String>,
+  project_id: Option<String>,
+  verify_hash: Option<bool>,
 ) -> Result<String, String> {
   if url.starts_with("data:") || url.starts_with("blob:") {
     return Ok(url);
   }
+  let project_id = normalize_project_id(project_id);
+  let verify_hash = verify_hash.unwrap_or(false);
 
   let cache_root = app
     .path()
@@ -818,13 +6852,41 @@ async fn cache_remote_media(
   let hash = hex::encode(hasher.finalize());
   let file_stem = format!("media-{}", hash);
 
+  let mut fallback: Option<PathBuf> = None;
   if let Some(ext) = extension_from_url(&url) {
     let cached = cache_root.join(format!("{}.{}", file_stem, ext));
-    if cached.exists() {
+    if accept_media_cache_hit(&cached, verify_hash, &mut fallback).await {
+      record_media_cache_access(&app, &cached, "media", &project_id, false).await;
       return Ok(cached.to_string_lossy().to_string());
     }
   }
 
+  match download_remote_media(&url, token_ref, &cache_root, &file_stem, verify_hash, &mut fallback).await {
+    Ok((target, downloaded)) => {
+      record_media_cache_access(&app, &target, "media", &project_id, downloaded).await;
+      Ok(target.to_string_lossy().to_string())
+    }
+    // 离线等情况下，未经校验的旧缓存总比没有好
+    Err(err) => match fallback {
+      Some(path) if path.exists() => {
+        log::warn!("[cache_remote_media] 重新下载失败（{}），沿用未校验的缓存: {}", err, path.to_string_lossy());
+        record_media_cache_access(&app, &path, "media", &project_id, false).await;
+        Ok(path.to_string_lossy().to_string())
+      }
+      _ => Err(err),
+    },
+  }
+}
+
+// 返回 (缓存路径, 是否新下载)
+async fn download_remote_media(
+  url: &str,
+  token_ref: &str,
+  cache_root: &Path,
+  file_stem: &str,
+  verify_hash: bool,
+  fallback: &mut Option<PathBuf>,
+) -> Result<(PathBuf, bool), String> {
   // 视频下载需要更长的超时时间
   let client = reqwest::Client::builder()
     .user_agent("Nexus/1.0")
@@ -834,7 +6896,7 @@ async fn cache_remote_media(
     .build()
     .map_err(|e| e.to_string())?;
 
-  let mut request = client.get(&url);
+  let mut request = client.get(url);
   if !token_ref.is_empty() {
     request = request.header(reqwest::header::AUTHORIZATION, format!("Bearer {}", token_ref));
   }
@@ -853,35 +6915,61 @@ async fn cache_remote_media(
     .map(|v| v.to_string());
 
   // 扩展名优先级：URL 扩展名 > Content-Type > URL 路径推断 > 默认 mp4
-  let ext = extension_from_url(&url)
+  let ext = extension_from_url(url)
     .or_else(|| extension_from_content_type(content_type.as_deref()))
-    .or_else(|| extension_from_url_path(&url))
+    .or_else(|| extension_from_url_path(url))
     .unwrap_or_else(|| "mp4".to_string()); // 默认 mp4 而不是 bin
-  
+
   log::info!("[cache_remote_media] Content-Type: {:?}, 扩展名: {}", content_type, ext);
   let target = cache_root.join(format!("{}.{}", file_stem, ext));
 
-  if target.exists() {
-    return Ok(target.to_string_lossy().to_string());
+  if fallback.is_none() && accept_media_cache_hit(&target, verify_hash, fallback).await {
+    return Ok((target, false));
   }
 
-  let mut file = tokio::fs::File::create(&target).await.map_err(|e| e.to_string())?;
-  let mut size: u64 = 0;
-  let mut stream = response.bytes_stream();
-  while let Some(chunk) = stream.next().await {
-    let bytes = chunk.map_err(|e| e.to_string())?;
-    size += bytes.len() as u64;
-    if size > MAX_MEDIA_BYTES {
-      let _ = tokio::fs::remove_file(&target).await;
-      log::error!("[cache_remote_media] 文件过大: {} bytes", size);
-      return Err("媒体文件过大，已拒绝缓存".to_string());
+  // 没有校验文件的旧缓存：大小与 Content-Length 一致就补写校验文件直接沿用，不一致才当损坏重下
+  if let Some(legacy) = fallback.clone().filter(|p| p.exists()) {
+    let size = std::fs::metadata(&legacy).map(|m| m.len()).unwrap_or(0);
+    match response.content_length() {
+      Some(expected) if expected == size && size > 0 => {
+        if let Err(err) = write_media_integrity(&legacy, size).await {
+          log::warn!("[cache_remote_media] 写入校验文件失败: {}", err);
+        }
+        log::info!("[cache_remote_media] 旧缓存大小与响应一致，沿用: {}", legacy.to_string_lossy());
+        return Ok((legacy, false));
+      }
+      Some(expected) => {
+        log::warn!("[cache_remote_media] 旧缓存大小不符（{} / {} bytes），删除后重新下载", size, expected);
+        remove_media_cache_file(&legacy);
+        *fallback = None;
+      }
+      None => {} // 无法核对：照常重新下载，失败时再沿用
     }
-    file.write_all(&bytes).await.map_err(|e| e.to_string())?;
   }
-  file.flush().await.map_err(|e| e.to_string())?;
+
+  // 同一 URL 可能被并发请求：各写各的临时文件，最后一次改名生效
+  let seq = MEDIA_PART_SEQ.fetch_add(1, Ordering::Relaxed);
+  let part = cache_root.join(format!("{}.{}.{}-{}.part", file_stem, ext, std::process::id(), seq));
+  let (size, sha256) = match download_to_part(response, &part).await {
+    Ok(done) => done,
+    Err(err) => {
+      let _ = tokio::fs::remove_file(&part).await;
+      log::error!("[cache_remote_media] {}", err);
+      return Err(err);
+    }
+  };
+  if let Err(err) = tokio::fs::rename(&part, &target).await {
+    let _ = tokio::fs::remove_file(&part).await;
+    return Err(err.to_string());
+  }
+  // 先改名再写校验文件：中途退出只会留下“未校验”的文件，下次按响应长度核对
+  let integrity = MediaIntegrity { size, sha256, completed_at: now_millis() };
+  if let Err(err) = write_json_atomic(&media_sidecar_path(&target), &integrity) {
+    log::warn!("[cache_remote_media] 写入校验文件失败: {}", err);
+  }
 
   log::info!("[cache_remote_media] 下载完成: {} bytes -> {}", size, target.to_string_lossy());
-  Ok(target.to_string_lossy().to_string())
+  Ok((target, true))
 }
 
 #[derive(serde::Serialize, Clone, Debug, Default)]
@@ -1022,7 +7110,7 @@ async fn editor_export_worker(
 
     // If input is http(s), cache it first (stable for FFmpeg)
     if input.starts_with("http://") || input.starts_with("https://") {
-      input = cache_remote_media(app.clone(), input, token.clone()).await?;
+      input = cache_remote_media(app.clone(), input, token.clone(), Some(project_id.clone()), None).await?;
     }
 
     if input.starts_with("data:") || input.starts_with("blob:") || input.starts_with("asset://") {
@@ -1303,6 +7391,12 @@ pub fn run() {
     .invoke_handler(tauri::generate_handler![
       cache_remote_image,
       cache_remote_media,
+      media_cache_stats,
+      media_cache_get_policy,
+      media_cache_set_policy,
+      media_cache_set_open_projects,
+      media_cache_evict,
+      media_cache_clear,
       editor_export_start,
       editor_export_cancel,
       log_frontend,
@@ -1311,11 +7405,1077 @@ pub fn run() {
       compress_json_lz4_base64,
       decompress_json_lz4_base64,
       graph_collect_upstream_inputs,
+      graph_validate_config_inputs,
+      graph_build_canvas_context,
       search_memory,
+      search_memory_semantic,
+      search_memory_explain,
+      memory_list,
+      memory_add,
+      memory_update,
+      memory_delete,
+      memory_clear,
+      memory_migrate_legacy,
+      memory_find_duplicates,
+      memory_merge,
+      memory_get_policy,
+      memory_set_policy,
+      memory_reinforce,
+      memory_pin,
+      memory_evict,
+      memory_export,
+      memory_import,
       build_chat_messages,
+      build_chat_context,
+      conversation_list,
+      conversation_create,
+      conversation_load,
+      conversation_append,
+      conversation_set_head,
+      conversation_pin,
+      conversation_fork,
+      conversation_delete,
+      chat_stream,
+      chat_stream_attach,
+      chat_cancel,
+      chat_provider_list,
+      chat_provider_save,
+      chat_provider_delete,
+      summarize_conversation,
       load_project_canvas,
       delete_project_canvas
     ])
-    .run(tauri::generate_context!())
-    .expect("error while running tauri application");
+    .build(tauri::generate_context!())
+    .expect("error while running tauri application")
+    .run(|_app, event| {
+      if let tauri::RunEvent::Exit = event {
+        flush_memory_state();
+        flush_media_cache_state();
+      }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn tokenize_splits_cjk_into_bigrams() {
+    assert_eq!(tokenize("主角穿红衣"), vec!["主角", "角穿", "穿红", "红衣"]);
+    assert_eq!(tokenize("猫"), vec!["猫"]);
+    assert_eq!(tokenize("Ｈｅｌｌｏ，世界 v2"), vec!["hello", "世界", "v2"]);
+  }
+
+  #[test]
+  fn index_tokens_add_unigrams_for_cjk_runs() {
+    let tokens = tokenize_for_index("小猫咪");
+    assert!(tokens.iter().any(|t| t == "猫咪"));
+    assert!(tokens.iter().any(|t| t == "猫"));
+    // 查询侧不输出单字，避免多字查询被常用字稀释
+    assert!(!tokenize("小猫咪").iter().any(|t| t == "猫"));
+  }
+
+  #[test]
+  fn single_han_query_matches_inside_longer_words() {
+    let mut index = Bm25Index { version: BM25_INDEX_VERSION, ..Default::default() };
+    index.upsert("a", "我家的小猫咪很黏人");
+    index.upsert("b", "周末去爬山");
+    let scores = index.score_with(&tokenize("猫"), &HashMap::new(), 1.2, 0.75, None);
+    assert!(scores.get("a").is_some_and(|s| *s > 0.0));
+    assert!(!scores.contains_key("b"));
+  }
+
+  fn assert_same_scores(a: &Bm25Index, b: &Bm25Index, query: &str) {
+    let terms = tokenize(query);
+    let (sa, sb) = (a.score_with(&terms, &HashMap::new(), 1.2, 0.75, None), b.score_with(&terms, &HashMap::new(), 1.2, 0.75, None));
+    assert_eq!(sa.len(), sb.len(), "query {query}");
+    for (id, score) in sa.iter() {
+      assert!((score - sb[id]).abs() < 1e-6, "query {query}, doc {id}");
+    }
+  }
+
+  #[test]
+  fn incremental_index_scores_match_a_fresh_build() {
+    let mut incremental = Bm25Index { version: BM25_INDEX_VERSION, ..Default::default() };
+    incremental.upsert("a", "赛博朋克风格的城市夜景");
+    incremental.upsert("b", "watercolor forest at dawn");
+    incremental.upsert("c", "主角穿红色风衣");
+    incremental.upsert("b", "watercolor city poster");
+    assert!(incremental.remove("c"));
+
+    let mut fresh = Bm25Index { version: BM25_INDEX_VERSION, ..Default::default() };
+    fresh.upsert("a", "赛博朋克风格的城市夜景");
+    fresh.upsert("b", "watercolor city poster");
+
+    assert_eq!(incremental.total_len, fresh.total_len);
+    assert_eq!(incremental.postings, fresh.postings);
+    for query in ["城市", "watercolor city", "forest", "红色风衣"] {
+      assert_same_scores(&incremental, &fresh, query);
+    }
+  }
+
+  #[test]
+  fn outdated_index_version_is_rebuilt_from_items() {
+    let items = vec![test_item("a", "cyberpunk city"), test_item("b", "neon night street")];
+    // 旧版本的缓存里残留着已删除的条目
+    let mut stale = Bm25Index { version: BM25_INDEX_VERSION - 1, ..Default::default() };
+    stale.upsert("gone", "cyberpunk poster");
+    stale.upsert("a", "cyberpunk city");
+    let raw = serde_json::to_vec(&stale).expect("serialize");
+
+    let (rebuilt, changed) = Bm25Index::from_cache(Some(&raw), &items);
+    assert!(changed);
+    assert_eq!(rebuilt.version, BM25_INDEX_VERSION);
+    assert!(!rebuilt.docs.contains_key("gone"));
+    let (fresh, _) = Bm25Index::from_cache(None, &items);
+    assert_same_scores(&rebuilt, &fresh, "cyberpunk street");
+
+    // 版本一致且内容未变：直接复用，不需要回写
+    let (_, changed) = Bm25Index::from_cache(Some(&serde_json::to_vec(&fresh).expect("serialize")), &items);
+    assert!(!changed);
+  }
+
+  fn test_item(id: &str, content: &str) -> MemoryItem {
+    let now = now_millis();
+    MemoryItem { id: id.to_string(), content: content.to_string(), importance: 0.5, created_at: now, updated_at: now, ..Default::default() }
+  }
+
+  fn test_memory_state(items: Vec<MemoryItem>) -> MemoryState {
+    let mut index = Bm25Index { version: BM25_INDEX_VERSION, ..Default::default() };
+    index.sync(&items);
+    MemoryState {
+      store: MemoryStore { items, ..Default::default() },
+      index,
+      embeddings: None,
+      store_path: PathBuf::new(),
+      index_path: PathBuf::new(),
+      embeddings_path: PathBuf::new(),
+      saved_at: now_millis(),
+      unsaved: false,
+    }
+  }
+
+  #[test]
+  fn fuzzy_hit_of_misspelling_ranks_below_exact_hit() {
+    let now = now_millis();
+    // 同一个词：a 拼写正确，b 是拼错的 cyberpnuk，其余条件完全相同
+    let state = test_memory_state(vec![test_item("a", "cyberpunk city"), test_item("b", "cyberpnuk city")]);
+    let query = MemoryQuery::new("cyberpunk", String::new(), None, Some(true));
+    let ranked = rank_memory(&state, &query, None, now);
+    let lexical = |id: &str| ranked.iter().find(|r| r.item.id == id).map(|r| r.breakdown.lexical).unwrap_or(0.0);
+    assert_eq!(ranked[0].item.id, "a");
+    assert!(lexical("b") > 0.0);
+    assert!(lexical("b") < lexical("a"));
+
+    let hit = explain_memory_hit(&state, &query, ranked.into_iter().find(|r| r.item.id == "b").expect("b ranked"));
+    assert!(hit.matched_tokens.is_empty());
+    assert_eq!(hit.fuzzy_matches.len(), 1);
+    assert_eq!(hit.fuzzy_matches[0].kind, "typo");
+  }
+
+  #[test]
+  fn embedding_cache_is_invalidated_by_content_hash() {
+    let mut state = test_memory_state(vec![test_item("a", "cyberpunk city"), test_item("b", "neon night street")]);
+    let pending = state.pending_embeddings("p", "");
+    assert_eq!(pending.len(), 2);
+    state.store_embeddings("p", pending.into_iter().zip([vec![1.0, 0.0], vec![0.0, 1.0]]));
+    assert!(state.pending_embeddings("p", "").is_empty());
+    // 换了 provider 的缓存命名空间，向量不能复用
+    assert_eq!(state.pending_embeddings("other", "").len(), 2);
+
+    // 只改 a 的内容：只有 a 需要重算，b 的向量继续命中
+    state.store.items[0].content = "cyberpunk city at night".to_string();
+    let pending = state.pending_embeddings("p", "");
+    assert_eq!(pending.len(), 1);
+    assert_eq!(pending[0].0, "a");
+    assert_eq!(pending[0].2, hash_key("cyberpunk city at night"));
+    state.store_embeddings("p", pending.into_iter().zip([vec![0.6, 0.8]]));
+    assert!(state.pending_embeddings("p", "").is_empty());
+
+    let scores = state.semantic_scores("p", &[0.0, 1.0]);
+    assert!((scores["a"] - 0.8).abs() < 1e-5);
+    assert!((scores["b"] - 1.0).abs() < 1e-5);
+  }
+
+  #[test]
+  fn duplicate_clusters_do_not_chain_dissimilar_items() {
+    let set = |words: &str| words.split(' ').map(|w| w.to_string()).collect::<std::collections::HashSet<String>>();
+    // a≈b、b≈c（各 3/5），但 a 与 c 只有 1/7，不能因为 b 串成一组
+    let sets = vec![set("w1 w2 w3 w4"), set("w2 w3 w4 w5"), set("w3 w4 w5 w6"), set("x1 x2 x3 x4")];
+    let clusters = duplicate_clusters(&sets, 0.5);
+    assert_eq!(clusters.len(), 1);
+    let (members, best) = &clusters[0];
+    assert_eq!(members.len(), 2);
+    assert!(members.contains(&1));
+    assert!((best - 0.6).abs() < 1e-5);
+
+    // 三者两两相似时仍然归为一组
+    let sets = vec![set("w1 w2 w3 w4"), set("w1 w2 w3 w5"), set("w1 w2 w3 w6")];
+    let clusters = duplicate_clusters(&sets, 0.5);
+    assert_eq!(clusters.len(), 1);
+    assert_eq!(clusters[0].0, vec![0, 1, 2]);
+  }
+
+  #[test]
+  fn explained_breakdown_adds_up_to_the_score() {
+    let now = now_millis();
+    let item = |id: &str, content: &str, importance: f32, days: i64| MemoryItem {
+      importance,
+      created_at: now - days * 86_400_000,
+      updated_at: now - days * 86_400_000,
+      ..test_item(id, content)
+    };
+    let state = test_memory_state(vec![
+      item("a", "cyberpunk city with neon rain", 0.9, 0),
+      item("b", "cyberpunk poster", 0.6, 5),
+      item("c", "watercolor forest", 0.4, 3),
+    ]);
+    let query = MemoryQuery::new("cyberpunk neon", String::new(), None, Some(false));
+    let ranked = rank_memory(&state, &query, None, now);
+    let score_of = |id: &str| ranked.iter().find(|r| r.item.id == id).map(|r| r.breakdown.score).unwrap_or(0.0);
+    // 只让 c 落在 [min/2, min) 之间，成为调试用的近似命中
+    let min_score = score_of("c") * 1.5;
+    assert!(score_of("b") >= min_score);
+
+    let explain = explain_memory_search(&state, &query, min_score, 6, true, now);
+    assert_eq!(explain.query_terms, vec!["cyberpunk".to_string(), "neon".to_string()]);
+    assert_eq!(explain.hits.iter().map(|h| h.item.id.as_str()).collect::<Vec<_>>(), vec!["a", "b"]);
+    assert_eq!(explain.near_misses.len(), 1);
+    assert_eq!(explain.near_misses[0].item.id, "c");
+
+    for hit in explain.hits.iter().chain(explain.near_misses.iter()) {
+      let b = &hit.breakdown;
+      assert!((b.relevance_part + b.importance_part + b.recency_part - b.score).abs() < 1e-5);
+      assert!((b.lexical * query.weights.lexical - b.relevance_part).abs() < 1e-5);
+      assert!((b.importance_part - query.weights.importance_part(&hit.item)).abs() < 1e-5);
+      assert!((b.recency_part - query.weights.recency_part(&hit.item, now)).abs() < 1e-5);
+    }
+    assert_eq!(explain.hits[0].matched_tokens, vec!["cyberpunk".to_string(), "neon".to_string()]);
+    assert_eq!(explain.hits[1].matched_tokens, vec!["cyberpunk".to_string()]);
+    assert!(explain.near_misses[0].matched_tokens.is_empty());
+
+    // 非调试模式不返回阈值下的条目
+    assert!(explain_memory_search(&state, &query, min_score, 6, false, now).near_misses.is_empty());
+  }
+
+  #[test]
+  fn hybrid_score_blends_lexical_and_semantic_relevance() {
+    let now = now_millis();
+    let state = test_memory_state(vec![test_item("a", "cyberpunk city"), test_item("b", "neon night street")]);
+    let semantic = HashMap::from([("a".to_string(), 0.1), ("b".to_string(), 0.9)]);
+    let query = |share: f32| {
+      let weights = MemorySearchWeights { semantic: Some(share), ..Default::default() };
+      MemoryQuery::new("cyberpunk", String::new(), Some(weights), Some(false))
+    };
+
+    let lexical = rank_memory(&state, &query(0.5), None, now);
+    assert_eq!(lexical[0].item.id, "a");
+    assert_eq!(lexical[1].breakdown.relevance_part, 0.0);
+
+    // 相关度 = 词法 × (1 - 占比) + 语义 × 占比，再乘相关度权重
+    let q = query(0.5);
+    let hybrid = rank_memory(&state, &q, Some(&semantic), now);
+    for r in hybrid.iter() {
+      let expected = (r.breakdown.lexical * 0.5 + semantic[&r.item.id] * 0.5) * q.weights.lexical;
+      assert!((r.breakdown.relevance_part - expected).abs() < 1e-5);
+    }
+    // 语义占满时，只在向量上相近的 b 排到前面
+    assert_eq!(rank_memory(&state, &query(1.0), Some(&semantic), now)[0].item.id, "b");
+  }
+
+  #[test]
+  fn settle_enforces_capacity_but_keeps_new_items() {
+    let now = now_millis();
+    let item = |i: usize| MemoryItem { importance: 0.2 + i as f32 * 0.01, ..test_item(&format!("m{i}"), &format!("记忆 {i}")) };
+    let mut state = test_memory_state((0..12).map(item).collect());
+    state.store.policy.max_items_per_scope = 10;
+    let keep = std::collections::HashSet::from(["m0".to_string()]);
+    let report = settle_memory(&mut state, Some(""), &keep, false, now);
+    assert_eq!(report.evicted.len(), 2);
+    assert_eq!(state.store.items.len(), 10);
+    // 保留分最低的是 m0、m1，m0 刚写入不淘汰，于是淘汰 m1、m2
+    assert!(state.store.items.iter().any(|i| i.id == "m0"));
+    assert!(!state.store.items.iter().any(|i| i.id == "m1" || i.id == "m2"));
+  }
+
+  #[test]
+  fn auto_settle_only_decays_unless_auto_evict_is_on() {
+    let now = now_millis();
+    let old = now - 400 * DAY_MS as i64;
+    let item = |i: usize| MemoryItem {
+      importance: 0.2,
+      created_at: if i < 2 { old } else { now },
+      updated_at: if i < 2 { old } else { now },
+      ..test_item(&format!("m{i}"), &format!("记忆 {i}"))
+    };
+    let items: Vec<MemoryItem> = (0..12).map(item).collect();
+    let mut state = test_memory_state(items.clone());
+    state.store.policy.max_items_per_scope = 10;
+
+    // 默认：m0、m1 衰减到淘汰线以下、总数也超出容量，但只落实衰减，一条都不删
+    let report = auto_settle_memory(&mut state, None, &Default::default(), now);
+    assert!(!report.dry_run);
+    assert!(report.evicted.is_empty());
+    assert_eq!(state.store.items.len(), 12);
+    assert_eq!(report.decayed.len(), 2);
+    assert!(state.store.items[0].importance < state.store.policy.min_importance);
+    assert_eq!(state.store.items[0].decayed_at, now);
+
+    // 删除要经 memory_evict：先预览，确认后才真正删
+    let preview = settle_memory(&mut state, None, &Default::default(), true, now);
+    assert_eq!(preview.evicted.len(), 2);
+    assert_eq!(state.store.items.len(), 12);
+
+    let mut state = test_memory_state(items);
+    state.store.policy.max_items_per_scope = 10;
+    state.store.policy.auto_evict = true;
+    let report = auto_settle_memory(&mut state, None, &Default::default(), now);
+    assert_eq!(report.evicted.len(), 2);
+    assert_eq!(state.store.items.len(), 10);
+    assert!(!state.index.docs.contains_key("m0"));
+  }
+
+  #[test]
+  fn summary_keeps_previous_summary_when_many_turns_slide_out() {
+    // 一次滑出的句子远超上限：旧摘要仍参与排序，而不是最先被丢掉
+    let conversation: Vec<ChatMessage> = (0..SUMMARY_MAX_SENTENCES + 40)
+      .map(|i| ChatMessage::text(if i % 2 == 0 { "user" } else { "assistant" }, format!("第{i}条闲聊内容编号{i}。")))
+      .chain(std::iter::once(ChatMessage::text("user", "最近一轮。".to_string())))
+      .collect();
+    let previous = "主角始终穿红色风衣。主角的红色风衣是故事的关键道具。";
+    let summary = summarize_conversation(conversation, Some(previous.to_string()), Some(4000), Some(1), None, None);
+    assert_eq!(summary.summarized_until, SUMMARY_MAX_SENTENCES + 40);
+    assert!(summary.summary.contains("红色风衣"));
+    // 对话部分仍按上限保留最新的句子
+    assert!(!summary.summary.contains("第0条"));
+  }
+
+  #[test]
+  fn markdown_export_round_trips() {
+    let items = vec![
+      MemoryItem {
+        id: "mem_a".to_string(),
+        content: "主角穿红色风衣\n  缩进的第二行  \n\n> 引用开头的一行".to_string(),
+        importance: 0.737,
+        created_at: 1_700_000_000_123,
+        updated_at: 1_700_000_500_456,
+        tags: vec!["角色, 造型".to_string(), "red \"coat\"".to_string()],
+        source: "chat".to_string(),
+        pin: Some(0.333),
+        ..Default::default()
+      },
+      MemoryItem {
+        id: "mem_b".to_string(),
+        content: "背景是雨夜的霓虹街道".to_string(),
+        importance: 0.1,
+        created_at: 1_700_000_000_000,
+        updated_at: 1_700_000_000_000,
+        project_id: "proj-1".to_string(),
+        ..Default::default()
+      },
+    ];
+    let md = memory_to_markdown(&items, 1_700_000_900_000);
+    let parsed: Vec<MemoryItem> = memory_entries_from_markdown(&md)
+      .iter()
+      .map(|e| validate_import_entry(e).expect("valid entry"))
+      .collect();
+    assert_eq!(parsed.len(), items.len());
+    for (a, b) in items.iter().zip(parsed.iter()) {
+      assert_eq!(a.id, b.id);
+      assert_eq!(a.content, b.content);
+      assert_eq!(a.importance, b.importance);
+      assert_eq!(a.pin, b.pin);
+      assert_eq!(a.tags, b.tags);
+      assert_eq!(a.source, b.source);
+      assert_eq!(a.project_id, b.project_id);
+      assert_eq!(a.created_at, b.created_at);
+      assert_eq!(a.updated_at, b.updated_at);
+    }
+
+    // 旧版导出的逗号分隔标签仍能导入
+    let legacy = memory_entries_from_markdown("## 全局\n\n### m\n\n- tags: a, b\n\n> 内容\n");
+    assert_eq!(validate_import_entry(&legacy[0]).expect("valid entry").tags, vec!["a".to_string(), "b".to_string()]);
+  }
+
+  #[test]
+  fn summary_only_folds_in_turns_after_cursor() {
+    let texts = ["主角穿红色风衣。", "好的，记下主角造型。", "背景是雨夜的霓虹街道。", "明白，街道加上霓虹灯牌。", "镜头改成俯拍角度。", "收到。"];
+    let conversation: Vec<ChatMessage> = texts
+      .iter()
+      .enumerate()
+      .map(|(i, t)| ChatMessage::text(if i % 2 == 0 { "user" } else { "assistant" }, t.to_string()))
+      .collect();
+    let first = summarize_conversation(conversation.clone(), None, Some(600), Some(2), None, None);
+    assert_eq!(first.summarized_turns, 4);
+    assert_eq!(first.summarized_until, 4);
+    assert!(first.summary.contains("红色风衣"));
+
+    // 游标之后没有新滑出窗口的对话：摘要原样返回
+    let again = summarize_conversation(conversation.clone(), Some("旧摘要保持不变。".to_string()), Some(600), Some(2), Some(4), None);
+    assert_eq!(again.summarized_turns, 0);
+    assert_eq!(again.summary, "旧摘要保持不变。");
+
+    // 只并入游标之后的对话
+    let next = summarize_conversation(conversation, Some(first.summary.clone()), Some(600), Some(1), Some(4), None);
+    assert_eq!(next.summarized_turns, 1);
+    assert_eq!(next.summarized_until, 5);
+    assert!(next.summary.contains("俯拍"));
+  }
+
+  #[test]
+  fn image_only_history_message_is_dropped_when_image_fails() {
+    let image_only = ChatMessage {
+      images: vec![ChatImage { url: "/not/in/cache/shot.png".to_string(), detail: None }],
+      ..ChatMessage::text("user", String::new())
+    };
+    let input = ChatContextInput {
+      user_text: "继续".to_string(),
+      system_prompt: "你是画布助手".to_string(),
+      conversation: vec![image_only, ChatMessage::text("assistant", "收到图片".to_string())],
+      ..Default::default()
+    };
+    let build = assemble_chat_context(input, None);
+    assert_eq!(build.report.images.errors.len(), 1);
+    assert!(build.messages.iter().all(|m| !m.content.trim().is_empty() || !m.images.is_empty()));
+    assert_eq!(build.messages.last().map(|m| m.content.as_str()), Some("继续"));
+  }
+
+  #[test]
+  fn image_tokens_are_reserved_inside_the_token_budget() {
+    let image = |url: &str| ChatImage { url: url.to_string(), detail: None };
+    let conversation: Vec<ChatMessage> = (0..40)
+      .flat_map(|i| {
+        [
+          ChatMessage {
+            images: if i % 10 == 0 { vec![image(&format!("https://example.com/{i}.png"))] } else { vec![] },
+            ..ChatMessage::text("user", format!("第{i}轮：主角穿红色风衣，背景是雨夜的霓虹街道。").repeat(3))
+          },
+          ChatMessage::text("assistant", format!("第{i}轮回复：好的，已按要求调整画面。").repeat(3)),
+        ]
+      })
+      .collect();
+    let input = ChatContextInput {
+      user_text: "继续".to_string(),
+      user_images: vec![image("https://example.com/latest.png")],
+      system_prompt: "你是画布助手".to_string(),
+      conversation,
+      config: Some(ContextConfig { max_tokens: 2000, max_history: 64, model: "gpt-4o".to_string(), ..Default::default() }),
+      ..Default::default()
+    };
+    let build = assemble_chat_context(input, None);
+    let report = &build.report;
+    assert!(report.images.kept > 0);
+    assert!(report.turns_dropped > 0);
+    let meter = ContextMeter::Tokens(TokenCounter::for_model("gpt-4o", ""));
+    // used 含图片预估，且整体仍在预算内
+    assert_eq!(report.used, meter.messages(&build.messages) + report.images.est_tokens);
+    assert!(report.used <= report.budget, "{} > {}", report.used, report.budget);
+  }
+
+  fn tool_call(id: &str) -> ToolCall {
+    ToolCall {
+      id: id.to_string(),
+      kind: default_tool_call_type(),
+      function: ToolCallFunction { name: "create_node".to_string(), arguments: "{}".to_string() },
+    }
+  }
+
+  fn tool_result(id: &str, content: &str) -> ChatMessage {
+    ChatMessage { tool_call_id: Some(id.to_string()), ..ChatMessage::text("tool", content.to_string()) }
+  }
+
+  #[test]
+  fn orphan_tool_messages_are_dropped() {
+    let calls = |ids: &[&str], content: &str| ChatMessage {
+      tool_calls: ids.iter().map(|id| tool_call(id)).collect(),
+      ..ChatMessage::text("assistant", content.to_string())
+    };
+
+    // 调用所在的那一轮被丢掉后，剩下的结果没有对应的调用
+    let out = drop_orphan_tool_messages(vec![tool_result("c0", "旧结果"), ChatMessage::text("user", "继续".to_string())]);
+    assert_eq!(out.len(), 1);
+    assert_eq!(out[0].role, "user");
+
+    // 只回答了部分调用：未回答的调用去掉，已回答的与结果成对保留
+    let out = drop_orphan_tool_messages(vec![
+      ChatMessage::text("user", "画两张图".to_string()),
+      calls(&["c1", "c2"], ""),
+      tool_result("c1", "ok"),
+      // 结果出现在调用之前也算孤立
+      tool_result("c3", "too early"),
+      calls(&["c3"], ""),
+    ]);
+    let roles: Vec<&str> = out.iter().map(|m| m.role.as_str()).collect();
+    assert_eq!(roles, vec!["user", "assistant", "tool"]);
+    assert_eq!(out[1].tool_calls.iter().map(|c| c.id.as_str()).collect::<Vec<_>>(), vec!["c1"]);
+    assert_eq!(out[2].tool_call_id.as_deref(), Some("c1"));
+
+    // 调用全部落空但带有正文的 assistant 消息保留正文
+    let out = drop_orphan_tool_messages(vec![calls(&["c9"], "我来画")]);
+    assert_eq!(out.len(), 1);
+    assert!(out[0].tool_calls.is_empty());
+    assert_eq!(out[0].content, "我来画");
+  }
+
+  #[test]
+  fn turns_start_at_user_messages() {
+    let turns = group_turns(vec![
+      ChatMessage::text("assistant", "欢迎".to_string()),
+      tool_result("c0", "ok"),
+      ChatMessage::text("user", "画一张图".to_string()),
+      ChatMessage { tool_calls: vec![tool_call("c1")], ..ChatMessage::text("assistant", String::new()) },
+      tool_result("c1", "ok"),
+      ChatMessage::text("user", "再来一张".to_string()),
+    ]);
+    let roles: Vec<Vec<&str>> = turns.iter().map(|t| t.iter().map(|m| m.role.as_str()).collect()).collect();
+    // 开头的非 user 消息单独成组；调用与结果归入发起它的那一轮
+    assert_eq!(roles, vec![vec!["assistant", "tool"], vec!["user", "assistant", "tool"], vec!["user"]]);
+    assert!(group_turns(vec![]).is_empty());
+  }
+
+  #[test]
+  fn history_window_never_splits_a_tool_call_from_its_result() {
+    let conversation = vec![
+      ChatMessage::text("user", "画一张雨夜街道".to_string()),
+      ChatMessage { tool_calls: vec![tool_call("c1")], ..ChatMessage::text("assistant", String::new()) },
+      tool_result("c1", "node-1"),
+      ChatMessage::text("assistant", "画好了".to_string()),
+      ChatMessage::text("user", "加点霓虹".to_string()),
+      ChatMessage::text("assistant", "已加上".to_string()),
+    ];
+    // 窗口只留最后 4 条：调用落在窗口外，窗口内的结果成了孤立消息
+    let input = ChatContextInput {
+      user_text: "继续".to_string(),
+      conversation,
+      config: Some(ContextConfig { max_history: 4, ..Default::default() }),
+      ..Default::default()
+    };
+    let build = assemble_chat_context(input, None);
+    assert!(build.messages.iter().all(|m| m.role != "tool" && m.tool_calls.is_empty()));
+    let contents: Vec<&str> = build.messages.iter().map(|m| m.content.as_str()).collect();
+    assert_eq!(contents, vec!["画好了", "加点霓虹", "已加上", "继续"]);
+  }
+
+  #[test]
+  fn stream_state_joins_tool_call_fragments_across_chunks() {
+    let mut state = ChatStreamState::default();
+    let chunks = [
+      serde_json::json!({ "choices": [{ "delta": { "role": "assistant", "content": "好的，" } }] }),
+      serde_json::json!({ "choices": [{ "delta": { "tool_calls": [{ "index": 0, "id": "c1", "type": "function", "function": { "name": "create_node", "arguments": "{\"type\":" } }] } }] }),
+      // 两个调用交错到达
+      serde_json::json!({ "choices": [{ "delta": { "tool_calls": [{ "index": 1, "id": "c2", "function": { "name": "connect", "arguments": "{}" } }] } }] }),
+      serde_json::json!({ "choices": [{ "delta": { "tool_calls": [{ "index": 0, "function": { "arguments": "\"image\"}" } }] } }] }),
+      // 不带 index 的网关：有 id 视为新调用，没有 id 续写上一个
+      serde_json::json!({ "choices": [{ "delta": { "tool_calls": [{ "id": "c3", "function": { "name": "run", "arguments": "{\"id\":" } }] } }] }),
+      serde_json::json!({ "choices": [{ "delta": { "tool_calls": [{ "function": { "arguments": "1}" } }] } }] }),
+      serde_json::json!({ "choices": [{ "delta": { "content": "马上创建。" }, "finish_reason": "tool_calls" }] }),
+      // 最后一个 chunk 只有用量，choices 为空
+      serde_json::json!({ "choices": [], "usage": { "prompt_tokens": 12, "completion_tokens": 8, "total_tokens": 20 } }),
+    ];
+    let deltas: Vec<String> = chunks.iter().map(|c| state.apply(c).expect("chunk")).collect();
+    assert_eq!(deltas.concat(), "好的，马上创建。");
+    assert_eq!(state.finish_reason.as_deref(), Some("tool_calls"));
+    let usage = state.usage.clone().expect("usage");
+    assert_eq!((usage.prompt_tokens, usage.completion_tokens, usage.total_tokens), (12, 8, 20));
+
+    let message = state.into_message();
+    assert_eq!(message.content, "好的，马上创建。");
+    let calls: Vec<(&str, &str, &str)> =
+      message.tool_calls.iter().map(|c| (c.id.as_str(), c.function.name.as_str(), c.function.arguments.as_str())).collect();
+    assert_eq!(calls, vec![("c1", "create_node", "{\"type\":\"image\"}"), ("c2", "connect", "{}"), ("c3", "run", "{\"id\":1}")]);
+
+    let mut state = ChatStreamState::default();
+    let err = state.apply(&serde_json::json!({ "error": { "message": "quota exceeded" } })).unwrap_err();
+    assert!(err.contains("quota exceeded"));
+  }
+
+  #[test]
+  fn sse_lines_are_split_and_stop_at_done() {
+    assert_eq!(sse_data(b"data: {\"a\":1}\r\n"), Some("{\"a\":1}"));
+    assert_eq!(sse_data(b"data:[DONE]\n"), Some("[DONE]"));
+    assert_eq!(sse_data(b": keep-alive\n"), None);
+    assert_eq!(sse_data(b"event: message\n"), None);
+    assert_eq!(sse_data(b"\n"), None);
+    assert_eq!(sse_data(&[b'd', b'a', b't', b'a', b':', 0xFF, b'\n']), None);
+
+    let chunk = |text: &str| format!("data: {}\n", serde_json::json!({ "choices": [{ "delta": { "content": text } }] }));
+    let mut state = ChatStreamState::default();
+    let mut deltas: Vec<String> = vec![];
+    // 第二行只到了一半：先处理完整的行，残行留在缓冲区
+    let second = chunk("世界");
+    let mut buf: Vec<u8> = format!("{}: ping\n\n{}", chunk("你好"), &second[..10]).into_bytes();
+    assert!(!drain_sse_lines(&mut buf, &mut state, &mut |d| deltas.push(d)).expect("lines"));
+    assert_eq!(deltas, vec!["你好"]);
+    assert_eq!(buf, second.as_bytes()[..10].to_vec());
+
+    // 流结束时最后一行没有换行：run_chat_stream 补一个 '\n' 收尾
+    buf.extend_from_slice(second[10..].trim_end().as_bytes());
+    buf.push(b'\n');
+    assert!(!drain_sse_lines(&mut buf, &mut state, &mut |d| deltas.push(d)).expect("lines"));
+    assert_eq!(deltas, vec!["你好", "世界"]);
+    assert!(buf.is_empty());
+
+    // [DONE] 之后的内容不再处理
+    let mut buf = format!("{}data: [DONE]\n{}", chunk("！"), chunk("多余")).into_bytes();
+    assert!(drain_sse_lines(&mut buf, &mut state, &mut |d| deltas.push(d)).expect("lines"));
+    assert_eq!(state.content, "你好世界！");
+  }
+
+  #[test]
+  fn non_streaming_response_is_applied_at_once() {
+    let raw = serde_json::json!({
+      "choices": [{
+        "message": {
+          "role": "assistant",
+          "content": "已创建节点",
+          "tool_calls": [{ "id": "c1", "type": "function", "function": { "name": "create_node", "arguments": "{}" } }]
+        },
+        "finish_reason": "stop"
+      }],
+      "usage": { "prompt_tokens": 5, "completion_tokens": 3, "total_tokens": 8 }
+    });
+    let mut state = ChatStreamState::default();
+    let delta = apply_whole_response(raw.to_string().as_bytes(), &mut state).expect("response");
+    assert_eq!(delta, "已创建节点");
+    assert_eq!(state.finish_reason.as_deref(), Some("stop"));
+    assert_eq!(state.usage.as_ref().map(|u| u.total_tokens), Some(8));
+    assert_eq!(state.into_message().tool_calls.len(), 1);
+
+    assert!(apply_whole_response(b"<html>bad gateway</html>", &mut ChatStreamState::default()).is_err());
+  }
+
+  #[test]
+  fn pinned_message_keeps_its_role() {
+    let input = ChatContextInput {
+      user_text: "继续".to_string(),
+      system_prompt: "你是画布助手".to_string(),
+      conversation: vec![
+        ChatMessage { pin: Some(1.0), ..ChatMessage::text("user", "主角穿红色外套".to_string()) },
+        ChatMessage { pin: Some(0.5), ..ChatMessage::text("assistant", "好的，统一用红色外套".to_string()) },
+      ],
+      ..Default::default()
+    };
+    let build = assemble_chat_context(input, None);
+    let text: String = build.messages.iter().map(|m| m.content.as_str()).collect::<Vec<_>>().join("\n");
+    assert!(text.contains("- 用户：主角穿红色外套"));
+    assert!(text.contains("- 助手：好的，统一用红色外套"));
+  }
+
+  #[test]
+  fn oversized_pinned_item_is_cut_to_fit_the_section() {
+    let long = "主角的外套必须是红色".repeat(200);
+    let memory = |id: &str, content: &str, pin: f32| MemoryItem { pin: Some(pin), ..test_item(id, content) };
+    let input = ChatContextInput {
+      user_text: "继续".to_string(),
+      system_prompt: "你是画布助手".to_string(),
+      memory_items: vec![memory("long", &long, 1.0), memory("short", "背景是雪山", 0.5)],
+      config: Some(ContextConfig { max_pinned_chars: 300, ..Default::default() }),
+      ..Default::default()
+    };
+    let build = assemble_chat_context(input, None);
+    let section = build.messages.iter().find(|m| m.content.contains("主角的外套")).expect("pinned section");
+    assert!(section.content.chars().count() <= 300);
+    let long_report = build.report.memory_items.iter().find(|m| m.id == "long").expect("report");
+    assert!(long_report.included && long_report.truncated);
+  }
+
+  fn test_node(id: &str, node_type: &str, data: Value) -> GraphNode {
+    GraphNode { id: id.to_string(), node_type: node_type.to_string(), data, ..Default::default() }
+  }
+
+  #[test]
+  fn canvas_context_follows_locale() {
+    let nodes = vec![
+      test_node("n1", "text", serde_json::json!({ "content": "a red coat" })),
+      test_node("n2", "image", serde_json::json!({ "error": "quota exceeded" })),
+    ];
+    let edges = vec![GraphEdge { source: "n1".to_string(), target: "n2".to_string(), ..Default::default() }];
+    let options = CanvasContextOptions { locale: "en-US".to_string(), ..Default::default() };
+    let summary = graph_build_canvas_context(nodes, edges, Some(vec!["n1".to_string()]), Some(options));
+    assert!(summary.text.starts_with("Canvas overview: 2 nodes, 1 edges"));
+    assert!(summary.text.contains("[selected]") && summary.text.contains("[downstream1]"));
+    assert!(!summary.text.chars().any(is_han));
+  }
+
+  #[test]
+  fn section_header_counts_against_its_budget() {
+    let meter = ContextMeter::Chars;
+    let body = "x".repeat(100);
+    let section = ContextSection::new("summary", "[Header]\n{content}".to_string(), SectionBody::Text(body), 50, 1.0, &meter)
+      .expect("section");
+    let rendered = section.render(1.0, &meter).expect("rendered");
+    assert!(meter.len(&rendered.content) <= 50);
+  }
+
+  #[test]
+  fn conversation_cache_evicts_least_recently_used_idle_projects() {
+    let held = conversation_slot("p0").expect("slot");
+    for i in 1..=CONVERSATION_CACHE_MAX {
+      conversation_slot(&format!("p{i}")).expect("slot");
+    }
+    let map = CONVERSATION_STATE.get().expect("state").lock().expect("lock");
+    assert_eq!(map.len(), CONVERSATION_CACHE_MAX);
+    // 正在使用的 p0 不会被淘汰，淘汰的是最久未用的空闲项目 p1
+    assert!(map.contains_key("p0"));
+    assert!(!map.contains_key("p1"));
+    drop(held);
+  }
+
+  fn thread_with(turns: &[(&str, &str)]) -> (ConversationThread, Vec<String>) {
+    let mut thread = ConversationThread { id: "t".to_string(), ..Default::default() };
+    let ids = turns
+      .iter()
+      .enumerate()
+      .map(|(i, (role, content))| thread.append(ChatMessage::text(role, content.to_string()), None, i as i64).expect("append").id)
+      .collect();
+    (thread, ids)
+  }
+
+  fn contents(path: &[&ThreadMessage]) -> Vec<String> {
+    path.iter().map(|m| m.message.content.clone()).collect()
+  }
+
+  #[test]
+  fn editing_message_n_branches_from_message_n_minus_one() {
+    let (mut thread, ids) = thread_with(&[("user", "q1"), ("assistant", "a1"), ("user", "q2"), ("assistant", "a2")]);
+    assert_eq!(thread.title, "q1");
+    assert_eq!(contents(&thread.path_to(None).expect("path")), ["q1", "a1", "q2", "a2"]);
+
+    // 编辑第 3 条 = 以第 2 条为 parent 追加
+    let edited = thread.append(ChatMessage::text("user", "q2 edited".to_string()), Some(ids[1].clone()), 10).expect("edit");
+    assert_eq!(thread.head_id.as_deref(), Some(edited.id.as_str()));
+    assert_eq!(contents(&thread.path_to(None).expect("path")), ["q1", "a1", "q2 edited"]);
+    // 原分支原样保留
+    assert_eq!(contents(&thread.path_to(Some(&ids[3])).expect("path")), ["q1", "a1", "q2", "a2"]);
+    let branch = thread.branch(None).expect("branch");
+    assert_eq!(branch.messages[2].siblings, [ids[2].clone(), edited.id.clone()]);
+    assert_eq!(thread.summary().branch_count, 2);
+
+    // 空字符串新建根分支；不存在的 parent 报错
+    let root = thread.append(ChatMessage::text("user", "fresh".to_string()), Some(String::new()), 11).expect("root");
+    assert!(root.parent_id.is_none());
+    assert!(thread.append(ChatMessage::text("user", "x".to_string()), Some("missing".to_string()), 12).is_err());
+  }
+
+  #[test]
+  fn set_head_moves_to_the_newest_leaf_of_the_branch() {
+    let (mut thread, ids) = thread_with(&[("user", "q1"), ("assistant", "a1"), ("user", "q2"), ("assistant", "a2")]);
+    let edited = thread.append(ChatMessage::text("user", "q2 b".to_string()), Some(ids[1].clone()), 10).expect("edit");
+    let reply = thread.append(ChatMessage::text("assistant", "a2 b".to_string()), None, 11).expect("reply");
+    assert_eq!(thread.latest_leaf(&ids[0]), reply.id);
+
+    // 切回旧分支：head 落到该分支的叶子 a2
+    assert!(thread.set_head(&ids[2]).expect("set head"));
+    assert_eq!(thread.head_id.as_deref(), Some(ids[3].as_str()));
+    // 从公共祖先切换则走最新的子分支
+    assert!(thread.set_head(&ids[1]).expect("set head"));
+    assert_eq!(thread.head_id.as_deref(), Some(reply.id.as_str()));
+    assert!(!thread.set_head(&edited.id).expect("set head"));
+    assert!(thread.set_head("missing").is_err());
+  }
+
+  #[test]
+  fn path_to_rejects_parent_cycles() {
+    let (mut thread, ids) = thread_with(&[("user", "q1"), ("assistant", "a1")]);
+    thread.messages[0].parent_id = Some(ids[1].clone());
+    let err = thread.path_to(None).expect_err("cycle");
+    assert!(err.contains("成环"));
+    assert!(thread.path_to(Some("missing")).is_err());
+    // latest_leaf 在环上也会停下
+    thread.latest_leaf(&ids[0]);
+  }
+
+  #[test]
+  fn fork_copies_only_the_root_to_tip_path() {
+    let (mut thread, ids) = thread_with(&[("user", "q1"), ("assistant", "a1"), ("user", "q2"), ("assistant", "a2")]);
+    thread.append(ChatMessage::text("user", "q2 b".to_string()), Some(ids[1].clone()), 10).expect("edit");
+
+    let fork = thread.fork(Some(&ids[2]), String::new(), 20).expect("fork");
+    assert_eq!(fork.forked_from.as_deref(), Some("t"));
+    assert_eq!(fork.title, "q1");
+    assert_eq!(fork.head_id.as_deref(), Some(ids[2].as_str()));
+    assert_eq!(contents(&fork.path_to(None).expect("path")), ["q1", "a1", "q2"]);
+    assert_eq!(fork.messages.len(), 3);
+    assert_eq!(fork.summary().branch_count, 1);
+
+    // 缺省从 head 分叉
+    let from_head = thread.fork(None, "copy".to_string(), 21).expect("fork");
+    assert_eq!(from_head.title, "copy");
+    assert_eq!(contents(&from_head.path_to(None).expect("path")), ["q1", "a1", "q2 b"]);
+  }
+
+  #[test]
+  fn saved_canvas_refs_protect_files_of_closed_projects() {
+    let referenced = format!("media-{}.mp4", "a".repeat(64));
+    let orphan = format!("media-{}.mp4", "b".repeat(64));
+    let canvas = serde_json::json!({ "nodes": [{ "data": { "localPath": format!("/cache/{MEDIA_CACHE_DIR}/{referenced}") } }] });
+    let mut names = std::collections::HashSet::new();
+    collect_cache_refs(&canvas, &mut names);
+    let refs: CanvasRefOwners = names.into_iter().map(|n| (n, vec![hash_key("closed-project")])).collect();
+
+    let mut state = MediaCacheState {
+      index: MediaCacheIndex::default(),
+      root: PathBuf::new(),
+      index_path: PathBuf::new(),
+      saved_at: 0,
+      unsaved: false,
+    };
+    for name in [&referenced, &orphan] {
+      let entry = MediaCacheEntry { kind: "media".to_string(), size: 10, ..Default::default() };
+      state.index.entries.insert(format!("{MEDIA_CACHE_DIR}/{name}"), entry);
+    }
+    let protected = state.protected_keys(&refs);
+    assert_eq!(protected.len(), 1);
+    assert!(protected.contains(&format!("{MEDIA_CACHE_DIR}/{referenced}")));
+    let report = state.evict_lru(0, &protected, None, true);
+    assert_eq!(report.evicted.len(), 1);
+    assert!(report.evicted[0].path.ends_with(&orphan));
+  }
+
+  #[test]
+  fn truncated_legacy_images_are_detected_by_format() {
+    let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+    png.extend_from_slice(&[0; 32]);
+    png.extend_from_slice(b"\0\0\0\0IEND\xAE\x42\x60\x82");
+    assert_eq!(image_bytes_complete(&png), Some(true));
+    assert_eq!(image_bytes_complete(&png[..png.len() - 10]), Some(false));
+
+    let jpeg = [0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x10, 0xFF, 0xD9, 0x00, 0x00];
+    assert_eq!(image_bytes_complete(&jpeg), Some(true));
+    assert_eq!(image_bytes_complete(&jpeg[..6]), Some(false));
+
+    let gif = b"GIF89a\x01\x00\x01\x00\x3B";
+    assert_eq!(image_bytes_complete(gif), Some(true));
+    assert_eq!(image_bytes_complete(&gif[..gif.len() - 1]), Some(false));
+
+    let mut webp = b"RIFF\x0C\x00\x00\x00WEBPVP8 ".to_vec();
+    assert_eq!(image_bytes_complete(&webp), Some(false));
+    webp.extend_from_slice(&[0; 4]);
+    assert_eq!(image_bytes_complete(&webp), Some(true));
+
+    assert_eq!(image_bytes_complete(b"<svg></svg>"), None);
+  }
+
+  #[test]
+  fn reconcile_drops_stale_temp_files_and_refreshes_size() {
+    let root = std::env::temp_dir().join(format!("nexus-cache-test-{}", std::process::id()));
+    let dir = root.join(MEDIA_CACHE_DIR);
+    std::fs::create_dir_all(&dir).expect("dir");
+    let name = format!("media-{}.mp4", "c".repeat(64));
+    write_bytes_atomic(&dir.join(&name), b"truncated").expect("write");
+    let stale = dir.join(format!("{name}.tmp.1-0"));
+    std::fs::write(&stale, b"partial").expect("tmp");
+    let old = std::time::SystemTime::now() - Duration::from_secs(2 * 24 * 3600);
+    std::fs::File::options().write(true).open(&stale).and_then(|f| f.set_modified(old)).expect("mtime");
+
+    let key = format!("{MEDIA_CACHE_DIR}/{name}");
+    let mut state = MediaCacheState {
+      index: MediaCacheIndex::default(),
+      root: root.clone(),
+      index_path: root.join(MEDIA_CACHE_INDEX_FILE),
+      saved_at: 0,
+      unsaved: false,
+    };
+    state.index.entries.insert(key.clone(), MediaCacheEntry { kind: "media".to_string(), size: 1024, ..Default::default() });
+    state.reconcile();
+    assert!(!stale.exists());
+    assert_eq!(state.index.entries.len(), 1);
+    assert_eq!(state.index.entries[&key].size, b"truncated".len() as u64);
+    let _ = std::fs::remove_dir_all(&root);
+  }
+
+  fn upstream_graph(texts: &[&str], url: &str) -> (Vec<GraphNode>, Vec<GraphEdge>) {
+    let edge = |source: &str| GraphEdge { source: source.to_string(), target: "cfg".to_string(), ..Default::default() };
+    let mut nodes = vec![test_node("focus", "text", serde_json::json!({ "content": "主提示词" })), test_node("cfg", "imageConfig", Value::Null)];
+    let mut edges = vec![GraphEdge { source: "focus".to_string(), target: "cfg".to_string(), ..Default::default() }];
+    for (i, text) in texts.iter().enumerate() {
+      nodes.push(test_node(&format!("t{i}"), "text", serde_json::json!({ "content": text })));
+      edges.push(edge(&format!("t{i}")));
+    }
+    if !url.is_empty() {
+      nodes.push(test_node("img", "image", serde_json::json!({ "url": url })));
+      edges.push(edge("img"));
+    }
+    (nodes, edges)
+  }
+
+  #[test]
+  fn upstream_text_stays_within_total_budget() {
+    let (nodes, edges) = upstream_graph(&[&"a".repeat(50), &"b".repeat(50)], "https://example.com/ref.png");
+    let options = UpstreamCollectOptions { max_total_chars: 60, ..Default::default() };
+    let out = graph_collect_upstream_inputs("focus".to_string(), nodes, edges, Some(options));
+    let used: usize = out.text.iter().map(|t| t.text.chars().count()).sum();
+    assert_eq!(used, 60);
+    assert!(out.text[1].truncated && out.text[1].text.ends_with('…'));
+    // 文本用完预算后图片整块丢弃
+    assert!(out.images.is_empty());
+    assert_eq!(out.dropped, vec!["img".to_string()]);
+    assert!(!out.truncated.contains(&"img".to_string()));
+  }
+
+  #[test]
+  fn upstream_data_url_image_is_dropped_once_budget_is_spent() {
+    let (nodes, edges) = upstream_graph(&[&"a".repeat(50)], "data:image/png;base64,AAAA");
+    let options = UpstreamCollectOptions { max_total_chars: 40, ..Default::default() };
+    let out = graph_collect_upstream_inputs("focus".to_string(), nodes, edges, Some(options));
+    assert!(out.images.is_empty());
+    assert_eq!(out.dropped, vec!["img".to_string()]);
+  }
+
+  #[test]
+  fn upstream_url_ellipsis_counts_against_total_budget() {
+    let url = format!("https://example.com/{}", "x".repeat(100));
+    let (nodes, edges) = upstream_graph(&[], &url);
+    let options = UpstreamCollectOptions { max_total_chars: 30, ..Default::default() };
+    let out = graph_collect_upstream_inputs("focus".to_string(), nodes, edges, Some(options));
+    assert_eq!(out.images[0].url.chars().count(), 30);
+    assert!(out.images[0].truncated);
+  }
+
+  fn video_config_graph(model: &str, reference_images: usize) -> (Vec<GraphNode>, Vec<GraphEdge>) {
+    let mut nodes = vec![
+      test_node("cfg", "videoConfig", serde_json::json!({ "model": model })),
+      test_node("prompt", "text", serde_json::json!({ "content": "镜头缓慢推进" })),
+    ];
+    let mut edges = vec![GraphEdge { source: "prompt".to_string(), target: "cfg".to_string(), ..Default::default() }];
+    for i in 0..reference_images {
+      nodes.push(test_node(&format!("img{i}"), "image", serde_json::json!({ "url": format!("https://example.com/{i}.png") })));
+      edges.push(GraphEdge {
+        source: format!("img{i}"),
+        target: "cfg".to_string(),
+        data: Some(serde_json::json!({ "imageRole": IMAGE_ROLE_REFERENCE })),
+      });
+    }
+    (nodes, edges)
+  }
+
+  #[test]
+  fn config_validation_requires_reference_image_for_grok_video() {
+    let (nodes, edges) = video_config_graph("grok-video-3", 0);
+    let out = graph_validate_config_inputs("cfg".to_string(), nodes, edges, None).expect("validation");
+    assert!(!out.ok);
+    assert!(out.errors.iter().any(|e| e.code == "reference_required"));
+
+    let (nodes, edges) = video_config_graph("grok-video-3-10s", 1);
+    assert!(graph_validate_config_inputs("cfg".to_string(), nodes, edges, None).expect("validation").ok);
+  }
+
+  #[test]
+  fn config_validation_rejects_too_many_reference_images() {
+    let (nodes, edges) = video_config_graph("grok-video-3", 4);
+    let out = graph_validate_config_inputs("cfg".to_string(), nodes, edges, None).expect("validation");
+    assert!(!out.ok);
+    let issue = out.errors.iter().find(|e| e.code == "too_many_images").expect("too_many_images");
+    assert_eq!(issue.node_ids.len(), 4);
+  }
+
+  fn test_plan(turns: usize, turn_chars: usize) -> ContextPlan {
+    let meter = ContextMeter::Chars;
+    let memory = ContextSection::new(
+      "memory",
+      "长期记忆：".to_string(),
+      SectionBody::Lines(vec!["- 喜欢赛博朋克风格".to_string(), "- 主角穿红衣".to_string()]),
+      200,
+      1.0,
+      &meter,
+    );
+    ContextPlan {
+      elision: "…".to_string(),
+      system: Some(ChatMessage::text("system", "你是画布助手".to_string())),
+      sections: memory.into_iter().collect(),
+      turns: (0..turns)
+        .map(|i| {
+          vec![
+            ChatMessage::text("user", format!("问题{i}{}", "问".repeat(turn_chars))),
+            ChatMessage::text("assistant", format!("回答{i}{}", "答".repeat(turn_chars))),
+          ]
+        })
+        .collect(),
+      user: ChatMessage::text("user", "现在怎么改？".to_string()),
+    }
+  }
+
+  #[test]
+  fn encoding_is_inferred_from_model_name() {
+    for model in ["gpt-4o", "gpt-4o-mini", "GPT-4o-2024-08-06", "gpt-5", "gpt-5-mini", "o3-mini"] {
+      assert_eq!(TokenCounter::for_model(model, "").name(), "o200k_base", "{model}");
+    }
+    for model in ["gpt-4", "gpt-3.5-turbo", "gemini-3-flash-preview", ""] {
+      assert_eq!(TokenCounter::for_model(model, "").name(), "cl100k_base", "{model}");
+    }
+    // 显式指定的编码优先于模型推断
+    assert_eq!(TokenCounter::for_model("gpt-5", "cl100k").name(), "cl100k_base");
+    assert_eq!(TokenCounter::for_model("gpt-4o", "heuristic").name(), "heuristic");
+  }
+
+  #[test]
+  fn heuristic_counts_one_token_per_cjk_char() {
+    let counter = TokenCounter::Heuristic;
+    assert_eq!(counter.count("主角穿红色风衣"), 7);
+    assert_eq!(counter.count("안녕하세요"), 5);
+    assert_eq!(counter.count("ひらがな"), 4);
+    // 其余字符约 4 个一 token，向上取整
+    assert_eq!(counter.count("abcd"), 1);
+    assert_eq!(counter.count("abcde"), 2);
+    assert_eq!(counter.count("红色 coat"), 2 + 2);
+    assert_eq!(counter.count(""), 0);
+  }
+
+  #[test]
+  fn token_fit_never_exceeds_max() {
+    let text = "Cyberpunk city at night, 霓虹灯下的雨夜街道，主角穿红色风衣 🌧️ walking past neon signs. ".repeat(8);
+    for counter in [TokenCounter::for_model("gpt-4o", ""), TokenCounter::for_model("gpt-4", ""), TokenCounter::Heuristic] {
+      let meter = ContextMeter::Tokens(counter);
+      let total = meter.len(&text);
+      for max in [0, 1, 2, 3, 5, 8, 13, 21, 34, 55, 89, total - 1, total, total + 10] {
+        for tail in [false, true] {
+          let fitted = meter.fit(&text, max, tail);
+          assert!(meter.len(&fitted) <= max, "{} max={max} tail={tail}", meter.unit());
+          if max >= total {
+            assert_eq!(fitted, text);
+          } else if tail {
+            assert!(text.ends_with(&fitted));
+          } else {
+            assert!(text.starts_with(&fitted));
+          }
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn fit_keeps_everything_under_budget() {
+    let meter = ContextMeter::Chars;
+    let plan = test_plan(2, 10);
+    let fit = plan.fit(10_000, &meter);
+    assert_eq!(fit.stage, "full");
+    assert_eq!(fit.messages.len(), 1 + 1 + 4 + 1);
+    assert_eq!(fit.messages.last().map(|m| m.content.as_str()), Some("现在怎么改？"));
+  }
+
+  #[test]
+  fn fit_drops_oldest_turns_before_sections() {
+    let meter = ContextMeter::Chars;
+    let plan = test_plan(6, 200);
+    let fit = plan.fit(900, &meter);
+    assert_eq!(fit.stage, "drop_turns");
+    assert!(meter.messages(&fit.messages) <= 900);
+    // 保留的是最近的对话
+    assert!(fit.messages.iter().any(|m| m.content.starts_with("问题5")));
+    assert!(!fit.messages.iter().any(|m| m.content.starts_with("问题0")));
+  }
+
+  #[test]
+  fn fit_falls_back_to_minimal_within_budget() {
+    let meter = ContextMeter::Chars;
+    let plan = test_plan(3, 50);
+    let fit = plan.fit(9, &meter);
+    assert_eq!(fit.stage, "minimal");
+    assert!(meter.messages(&fit.messages) <= 9);
+    assert_eq!(fit.messages.first().map(|m| m.role.as_str()), Some("system"));
+    // 用户原话只做头尾省略，不会被丢掉
+    let user = fit.messages.last().expect("user message");
+    assert_eq!(user.role, "user");
+    assert!(user.content.starts_with("现在") && user.content.ends_with("改？"));
+  }
 }
@@ -0,0 +1,79 @@
+/**
+ * MemoryPinMenu - 记忆置顶菜单
+ * 置顶的记忆每轮都会放入上下文（桌面端）；已置顶的排在前面
+ */
+import React, { useState } from 'react'
+import { listMemory, pinMemory, type MemoryItem } from '@/lib/memory'
+
+const MAX_MENU_ITEMS = 20
+
+const preview = (text: string) => {
+  const t = String(text || '').replace(/\s+/g, ' ')
+  return t.length > 32 ? `${t.slice(0, 32)}…` : t
+}
+
+export default function MemoryPinMenu({ projectId, onError }: { projectId?: string; onError?: (message: string) => void }) {
+  const [open, setOpen] = useState(false)
+  const [items, setItems] = useState<MemoryItem[] | null>(null)
+
+  const refresh = async () => {
+    const list = await listMemory(projectId).catch(() => null)
+    if (!Array.isArray(list)) {
+      setItems([])
+      return
+    }
+    const sorted = list
+      .filter((m) => m?.id && m.content)
+      .sort((a, b) => Number(b.pin != null) - Number(a.pin != null))
+      .slice(0, MAX_MENU_ITEMS)
+    setItems(sorted)
+  }
+
+  const toggleOpen = () => {
+    const next = !open
+    setOpen(next)
+    if (next) void refresh()
+  }
+
+  const toggle = async (item: MemoryItem) => {
+    const ok = await pinMemory(item.id, item.pin == null)
+    if (!ok) {
+      onError?.('记忆置顶失败')
+      return
+    }
+    await refresh()
+  }
+
+  return (
+    <div className="relative shrink-0">
+      <button
+        className="rounded-lg border border-[var(--border-color)] bg-[var(--bg-primary)] px-2 py-0.5 text-xs text-[var(--text-secondary)] transition-colors hover:text-[var(--text-primary)]"
+        title="置顶的记忆每轮都会放入上下文"
+        onClick={toggleOpen}
+      >
+        置顶记忆
+      </button>
+      {open ? (
+        <div className="absolute bottom-full right-0 z-20 mb-1 max-h-64 w-64 overflow-y-auto rounded-xl border border-[var(--border-color)] bg-[var(--bg-secondary)] p-1 shadow-xl">
+          {items === null ? (
+            <div className="px-2 py-1.5 text-xs text-[var(--text-secondary)]">加载中…</div>
+          ) : items.length === 0 ? (
+            <div className="px-2 py-1.5 text-xs text-[var(--text-secondary)]">（暂无记忆）</div>
+          ) : (
+            items.map((m) => (
+              <button
+                key={m.id}
+                className="block w-full truncate rounded-lg px-2 py-1.5 text-left text-xs text-[var(--text-primary)] hover:bg-[var(--bg-primary)]"
+                title={m.content}
+                onClick={() => void toggle(m)}
+              >
+                {m.pin != null ? '★ ' : ''}
+                {preview(m.content)}
+              </button>
+            ))
+          )}
+        </div>
+      ) : null}
+    </div>
+  )
+}
@@ -1,15 +1,47 @@
 import React, { useEffect, useMemo, useRef, useState, useCallback, memo } from 'react'
 import { Button } from '@/components/ui/button'
+import MemoryPinMenu from '@/components/MemoryPinMenu'
 import { useGraphStore } from '@/graph/store'
-import { buildCanvasContext, buildChatMessages, type ChatMessage } from '@/lib/contextEngine'
-import { loadMemoryState, saveMemoryState, searchMemory, type MemoryItem } from '@/lib/memory'
+import {
+  buildCanvasContext,
+  buildChatContext,
+  describeContextReport,
+  saveConversationSummary,
+  summarizeConversation,
+  type ChatMessage,
+  type ContextReport
+} from '@/lib/contextEngine'
+import {
+  listPinnedMemory,
+  loadMemoryState,
+  reinforceMemory,
+  rememberMemory,
+  searchMemory,
+  withPinnedMemory
+} from '@/lib/memory'
 import { twoStageStream, checkApiKey, classifyError } from '@/lib/nexusApi'
+import {
+  attachChatStream,
+  cancelChatStream,
+  isBackendChatAvailable,
+  loadPendingChatStream,
+  savePendingChatStream,
+  streamChatBackend
+} from '@/lib/chatStream'
+import {
+  appendThreadMessage,
+  ensureConversationThread,
+  loadThreadMessages,
+  pinThreadMessage,
+  resetConversationThread
+} from '@/lib/conversationThread'
 import { saveMedia } from '@/lib/mediaStorage'
 import { cn } from '@/lib/utils'
 import {
   buildPolishSystemPrompt,
   buildPolishUserText,
   collectUpstreamInputsForFocusAsync,
+  POLISH_UPSTREAM_OPTIONS,
   inferPolishModeFromGraph,
   inferPolishModeFromText,
   selectBestPromptTemplate
@@ -36,15 +68,19 @@ type UiMessage = {
   content: string
   createdAt: number
   streaming?: boolean
+  threadMessageId?: string
+  pin?: number // 置顶：每轮都放进上下文的置顶段
 }
 
 // 性能优化：memoized 消息组件
 const MessageItem = memo(function MessageItem({ 
   message, 
-  isLast 
+  isLast,
+  onTogglePin
 }: { 
   message: UiMessage
   isLast: boolean 
+  onTogglePin: (message: UiMessage) => void
 }) {
   // 阻止鼠标事件冒泡，防止 React Flow 或其他父组件干扰文本选择
   const handleMouseDown = (e: React.MouseEvent) => {
@@ -86,6 +122,17 @@ const MessageItem = memo(function MessageItem({
         }}
       >
         {message.content || (message.streaming ? '...' : '')}
+        {!message.streaming && message.content ? (
+          <div className="mt-1 flex justify-end">
+            <button
+              className="text-[11px] text-[var(--text-secondary)] opacity-70 transition-opacity hover:opacity-100"
+              title={message.pin != null ? '取消置顶（不再固定放入上下文）' : '置顶（每轮都放入上下文）'}
+              onClick={() => onTogglePin(message)}
+            >
+              {message.pin != null ? '已置顶' : '置顶'}
+            </button>
+          </div>
+        ) : null}
       </div>
     </div>
   )
@@ -95,6 +142,8 @@ const MessageItem = memo(function MessageItem({
     prevProps.message.id === nextProps.message.id &&
     prevProps.message.content === nextProps.message.content &&
     prevProps.message.streaming === nextProps.message.streaming &&
+    prevProps.message.pin === nextProps.message.pin &&
+    prevProps.message.threadMessageId === nextProps.message.threadMessageId &&
     prevProps.isLast === nextProps.isLast
   )
 })
@@ -125,8 +174,13 @@ const AUTO_EXECUTE_KEY = 'nexus-auto-execute-v1'
 const MEMORY_ENABLED_KEY = 'nexus-chat-memory-enabled'
 const WEB_SEARCH_KEY = 'nexus-web-search-enabled'
 const THINKING_KEY = 'nexus-thinking-enabled'
+// 画布摘要的字符预算，与拼装上下文时的 maxCanvasChars 对齐
+const CANVAS_CONTEXT_OPTIONS = { maxChars: 1200 }
 
 // 获取项目专属的聊天记录 key
+const getStreamResumeKey = (projectId: string) => `canvas:${projectId || 'default'}`
+// 每个项目的画布助手各自记住当前对话线程
+const getThreadKey = (projectId: string) => `canvas:${projectId || 'default'}`
 const getConversationKey = (projectId: string) => `${CONVERSATION_KEY_PREFIX}:${projectId || 'default'}`
 
 const loadConversation = (projectId: string) => {
@@ -205,6 +259,7 @@ export default function CanvasAssistantPanel({ onClose, onOpenSettings, variant
   const [busy, setBusy] = useState(false)
   const [error, setError] = useState<string | null>(null)
   const [mode, setMode] = useState<'chat' | 'polish'>('chat')
+  const [contextReport, setContextReport] = useState<ContextReport | null>(null)
 
   // Feature toggles
   const [autoExecute, setAutoExecute] = useState(() => loadBoolPref(AUTO_EXECUTE_KEY, false))
@@ -230,23 +285,63 @@ export default function CanvasAssistantPanel({ onClose, onOpenSettings, variant
   const fullTextRef = useRef('')
   const lastFlushTimeRef = useRef<number>(0)
   const prevProjectIdRef = useRef(projectId)
+  const resumeRequestRef = useRef<string | null>(null)
+
+  // 本地没有缓存的对话时，从后端线程恢复该项目的当前分支
+  const restoreFromThread = (pid: string) => {
+    void loadThreadMessages(getThreadKey(pid), pid).then((entries) => {
+      if (!entries || prevProjectIdRef.current !== pid) return
+      const restored = entries
+        .filter((e) => e.message.role === 'user' || e.message.role === 'assistant')
+        .map((e): UiMessage => ({
+          id: e.id,
+          role: e.message.role as UiRole,
+          content: e.message.content,
+          createdAt: e.createdAt,
+          threadMessageId: e.id,
+          pin: e.message.pin ?? undefined
+        }))
+      if (restored.length > 0) setMessages((prev) => (prev.length === 0 ? restored : prev))
+    })
+  }
+
+  useEffect(() => {
+    if (messages.length === 0) restoreFromThread(projectId)
+  }, [])
 
   // 当项目切换时，加载对应项目的聊天记录
   useEffect(() => {
     if (prevProjectIdRef.current !== projectId) {
       prevProjectIdRef.current = projectId
-      setMessages(loadConversation(projectId))
+      const loaded = loadConversation(projectId)
+      setMessages(loaded)
       setError(null)
       setAttachments([])
+      setContextReport(null)
+      if (loaded.length === 0) restoreFromThread(projectId)
     }
   }, [projectId])
 
-  const graphSnapshot = useGraphStore((s) => ({ nodes: s.nodes, edges: s.edges, selectedNodeId: s.selectedNodeId }))
+  const graphSnapshot = useGraphStore((s) => ({ nodes: s.nodes, edges: s.edges, selectedNodeId: s.selectedNodeId, selectedNodeIds: s.selectedNodeIds }))
   const addNode = useGraphStore((s) => s.addNode)
   const addEdge = useGraphStore((s) => s.addEdge)
   const withBatchUpdates = useGraphStore((s) => s.withBatchUpdates)
 
-  const canvasContext = useMemo(() => buildCanvasContext(graphSnapshot), [graphSnapshot])
+  const [canvasContext, setCanvasContext] = useState('')
+
+  // 空对话页的上下文预览（防抖）；真正发送时会按当时的画布重新构建
+  useEffect(() => {
+    let cancelled = false
+    const t = window.setTimeout(() => {
+      void buildCanvasContext({ ...graphSnapshot, options: CANVAS_CONTEXT_OPTIONS }).then((text) => {
+        if (!cancelled) setCanvasContext(text)
+      })
+    }, 200)
+    return () => {
+      cancelled = true
+      window.clearTimeout(t)
+    }
+  }, [graphSnapshot.nodes, graphSnapshot.edges, graphSnapshot.selectedNodeId, graphSnapshot.selectedNodeIds])
 
   // Workflow orchestrator
   const {
@@ -280,6 +375,56 @@ export default function CanvasAssistantPanel({ onClose, onOpenSettings, variant
     return () => window.clearTimeout(t)
   }, [projectId, messages])
 
+  // 页面刷新时后端的对话仍在继续：按记录的 requestId 重新挂上，把结果写回原来的助手消息。
+  // 刷新前未执行的工具调用不会补执行，只显示其中的文字部分
+  useEffect(() => {
+    const resumeKey = getStreamResumeKey(projectId)
+    const pending = loadPendingChatStream(resumeKey)
+    if (!pending) return
+    const target = messages[messages.length - 1]
+    if (!target || target.id !== pending.messageId || target.role !== 'assistant') {
+      savePendingChatStream(resumeKey, null)
+      return
+    }
+    resumeRequestRef.current = pending.requestId
+    assistantIdRef.current = pending.messageId
+    pendingTextRef.current = ''
+    fullTextRef.current = target.content
+    setBusy(true)
+    void attachChatStream(pending.requestId, {
+      onSnapshot: (content) => {
+        fullTextRef.current = ''
+        pendingTextRef.current = content
+        scheduleFlush()
+      },
+      onDelta: (delta) => {
+        pendingTextRef.current += delta
+        scheduleFlush()
+      }
+    })
+      .then((result) => {
+        if (rafUpdateRef.current) {
+          cancelAnimationFrame(rafUpdateRef.current)
+          clearTimeout(rafUpdateRef.current)
+        }
+        flushStreamingToState()
+        const content = (result ? String(result.message?.content || '') : '') || fullTextRef.current
+        const shown = parseToolCalls(content)?.message || content
+        finishAssistantMessage(pending.messageId, shown)
+        // 提问已记进线程时补记这次回复
+        if (messages[messages.length - 2]?.threadMessageId) void recordThreadReply(projectId, pending.messageId, shown)
+      })
+      .catch((e: any) => {
+        setError(classifyError(e).message)
+        finishAssistantMessage(pending.messageId, fullTextRef.current)
+      })
+      .finally(() => {
+        savePendingChatStream(resumeKey, null)
+        resumeRequestRef.current = null
+        setBusy(false)
+      })
+  }, [])
+
   useEffect(() => {
     if (initialScrollTop == null) return
     const el = listRef.current
@@ -346,11 +491,50 @@ export default function CanvasAssistantPanel({ onClose, onOpenSettings, variant
     }
   }
 
+  const finishAssistantMessage = (id: string, content: string) => {
+    setMessages((prev) => {
+      if (prev.length === 0) return prev
+      const last = prev[prev.length - 1]
+      if (last.id !== id) return prev
+      const next = prev.slice()
+      next[next.length - 1] = { ...last, content, streaming: false }
+      return next
+    })
+  }
+
+  const setThreadMessageId = (id: string, threadMessageId: string) => {
+    setMessages((prev) => prev.map((m) => (m.id === id ? { ...m, threadMessageId } : m)))
+  }
+
+  const recordThreadReply = async (pid: string, id: string, content: string) => {
+    const thread = await ensureConversationThread(getThreadKey(pid), pid)
+    const entry = await appendThreadMessage(thread, { role: 'assistant', content })
+    if (entry) setThreadMessageId(id, entry.id)
+  }
+
+  // 消息置顶：界面上标记，并写回对话线程（桌面端上下文按线程构建）。
+  // 经 ref 调用最新实现，MessageItem 拿到的回调保持不变，不破坏 memo
+  const toggleMessagePin = async (m: UiMessage) => {
+    const pinned = m.pin == null
+    if (m.threadMessageId) {
+      const thread = await ensureConversationThread(getThreadKey(projectId), projectId)
+      if (!(await pinThreadMessage(thread, m.threadMessageId, pinned))) {
+        setError('消息置顶失败')
+        return
+      }
+    }
+    setMessages((prev) => prev.map((x) => (x.id === m.id ? { ...x, pin: pinned ? 1 : undefined } : x)))
+  }
+  const togglePinRef = useRef(toggleMessagePin)
+  togglePinRef.current = toggleMessagePin
+  const onTogglePin = useCallback((m: UiMessage) => void togglePinRef.current(m), [])
+
   const stop = () => {
     if (controllerRef.current) {
       controllerRef.current.abort()
       controllerRef.current = null
     }
+    if (resumeRequestRef.current) void cancelChatStream(resumeRequestRef.current).catch(() => {})
     setBusy(false)
   }
 
@@ -359,7 +543,10 @@ export default function CanvasAssistantPanel({ onClose, onOpenSettings, variant
     setMessages([])
     setError(null)
     setAttachments([])
+    setContextReport(null)
     saveConversation(projectId, [])
+    saveConversationSummary(`canvas:${projectId || 'default'}`, null)
+    resetConversationThread(getThreadKey(projectId))
     saveScrollTop(0)
   }
 
@@ -375,16 +562,9 @@ export default function CanvasAssistantPanel({ onClose, onOpenSettings, variant
       /我更喜欢/.test(t)
     if (!should) return
 
-    const next: MemoryItem = {
-      id: makeId(),
-      content: t.replace(/^记住[:：]\s*/, ''),
-      importance: 0.6,
-      updatedAt: Date.now()
-    }
-    const mem = memoryRef.current
-    const merged = { ...mem, items: [next, ...(mem.items || [])].slice(0, 200) }
-    memoryRef.current = merged
-    saveMemoryState(merged)
+    void rememberMemory(memoryRef.current, t.replace(/^记住[:：]\s*/, ''), projectId).then((next) => {
+      memoryRef.current = next
+    })
   }
 
   const appendMessage = useCallback((role: UiRole, content: string) => {
@@ -657,7 +837,7 @@ export default function CanvasAssistantPanel({ onClose, onOpenSettings, variant
         console.log('[CanvasAssistant] executeWorkflow 完成')
       } else {
         // Chat mode
-        const conversation: ChatMessage[] = messages.map((m) => ({ role: m.role, content: m.content }))
+        const conversation: ChatMessage[] = messages.map((m) => ({ role: m.role, content: m.content, pin: m.pin ?? undefined }))
         const userMsg: UiMessage = { id: makeId(), role: 'user', content: text, createdAt: Date.now() }
         const assistantId = makeId()
         assistantIdRef.current = assistantId
@@ -672,7 +852,10 @@ export default function CanvasAssistantPanel({ onClose, onOpenSettings, variant
         maybeRemember(text)
 
         const mem = memoryRef.current
-        const hits = memoryEnabled ? await searchMemory(text, mem.items || [], 6, 0.12) : []
+        const canvasText = await buildCanvasContext({ ...graphSnapshot, options: CANVAS_CONTEXT_OPTIONS })
+        const hits = memoryEnabled ? await searchMemory(text, mem.items || [], 6, 0.12, projectId, conversation.map((m) => m.content)) : []
+        // 置顶记忆每轮都带上，由后端放入置顶段
+        const memoryItems = memoryEnabled ? withPinnedMemory(hits, await listPinnedMemory(projectId)) : []
         const isPolish = mode === 'polish'
         const inferredMode = inferPolishModeFromGraph(graphSnapshot.selectedNodeId || null, graphSnapshot.nodes, graphSnapshot.edges)
         const polishMode = isPolish ? inferredMode || inferPolishModeFromText(text) : null
@@ -681,7 +864,8 @@ export default function CanvasAssistantPanel({ onClose, onOpenSettings, variant
           ? await collectUpstreamInputsForFocusAsync({
               focusNodeId: graphSnapshot.selectedNodeId || null,
               nodes: graphSnapshot.nodes,
-              edges: graphSnapshot.edges
+              edges: graphSnapshot.edges,
+              options: POLISH_UPSTREAM_OPTIONS
             })
           : { text: [], images: [] }
 
@@ -689,7 +873,7 @@ export default function CanvasAssistantPanel({ onClose, onOpenSettings, variant
           ? await selectBestPromptTemplate({
               mode: polishMode || 'image',
               userText: text,
-              contextText: canvasContext
+              contextText: canvasText
             })
           : null
 
@@ -707,15 +891,23 @@ export default function CanvasAssistantPanel({ onClose, onOpenSettings, variant
             })
           : text
 
-        const finalMsgList = await buildChatMessages({
+        // 提问先记进线程，桌面端按线程分支（本次提问之前）取历史
+        const thread = await ensureConversationThread(getThreadKey(projectId), projectId)
+        const userEntry = await appendThreadMessage(thread, { role: 'user', content: text })
+        if (userEntry) setThreadMessageId(userMsg.id, userEntry.id)
+
+        const conversationSummary = await summarizeConversation(`canvas:${projectId || 'default'}`, conversation, { keepRecent: 16 })
+        const { messages: finalMsgList, report } = await buildChatContext({
           userText,
           systemPrompt,
           conversation,
-          memorySummary: mem.summary || '',
-          memoryItems: hits,
-          canvasContext,
-          config: { maxChars: 12000, maxHistory: 16, maxMemoryItems: 6, maxCanvasChars: 1200 }
+          memorySummary: [mem.summary, conversationSummary.summary].filter(Boolean).join('\n'),
+          memoryItems,
+          canvasContext: canvasText,
+          config: { maxChars: 12000, maxHistory: 16, maxMemoryItems: 6, maxCanvasChars: 1200 },
+          thread: thread && userEntry?.parentId ? { ...thread, tipId: userEntry.parentId } : null
         })
+        setContextReport(report)
 
         // 使用双阶段调用（如果启用了思考/联网）
         const inputMessages = finalMsgList.map((m: any) => ({
@@ -723,11 +915,27 @@ export default function CanvasAssistantPanel({ onClose, onOpenSettings, variant
           content: m.content
         }))
 
+        // 桌面端由后端代理对话（API Key 不进渲染进程）；回答阶段记录 requestId 以便刷新后恢复
+        const signal = controllerRef.current.signal
+        const resumeKey = getStreamResumeKey(projectId)
+        const backendStream = (await isBackendChatAvailable())
+          ? (model: string, msgs: ChatMessage[], stage: 'thinking' | 'answer') =>
+              streamChatBackend(msgs, {
+                model,
+                signal,
+                onStart:
+                  stage === 'answer'
+                    ? (requestId) => savePendingChatStream(resumeKey, { requestId, messageId: assistantId })
+                    : undefined
+              })
+          : undefined
+
         for await (const chunk of twoStageStream({
           input: inputMessages,
           useThinking: thinkingEnabled,
           useWebSearch: webSearchEnabled,
-          signal: controllerRef.current.signal
+          signal,
+          stream: backendStream
         })) {
           pendingTextRef.current += chunk
           scheduleFlush()
@@ -762,6 +970,9 @@ export default function CanvasAssistantPanel({ onClose, onOpenSettings, variant
           next[next.length - 1] = { ...last, content: fullTextRef.current, streaming: false }
           return next
         })
+        if (userEntry) void recordThreadReply(projectId, assistantId, fullTextRef.current)
+        // 回复成功后，按构建报告给实际放进上下文的记忆计一次使用
+        if (report) void reinforceMemory(report.memoryItems.filter((m) => m.included).map((m) => m.id))
       }
     } catch (e: any) {
       if (e?.name === 'AbortError') return
@@ -779,6 +990,7 @@ export default function CanvasAssistantPanel({ onClose, onOpenSettings, variant
         return prev
       })
     } finally {
+      savePendingChatStream(getStreamResumeKey(projectId), null)
       controllerRef.current = null
       setBusy(false)
     }
@@ -968,6 +1180,7 @@ export default function CanvasAssistantPanel({ onClose, onOpenSettings, variant
                 key={m.id} 
                 message={m} 
                 isLast={index === arr.length - 1}
+                onTogglePin={onTogglePin}
               />
             ))}
             <div ref={endRef} />
@@ -1069,10 +1282,22 @@ export default function CanvasAssistantPanel({ onClose, onOpenSettings, variant
               </Button>
             )}
           </div>
-          <div className="mt-2 text-xs text-[var(--text-secondary)]">
-            {autoExecute
-              ? '提示：自动模式会分析你的意图并创建工作流节点'
-              : '提示：用「记住：...」可写入长期记忆（本地）'}
+          <div className="mt-2 flex items-center justify-between gap-2 text-xs text-[var(--text-secondary)]">
+            <span>
+              {autoExecute
+                ? '提示：自动模式会分析你的意图并创建工作流节点'
+                : '提示：用「记住：...」可写入长期记忆（本地）'}
+            </span>
+            {!autoExecute ? (
+              <div className="flex shrink-0 items-center gap-2">
+                {contextReport ? (
+                  <span className="cursor-help" title={describeContextReport(contextReport).title}>
+                    {describeContextReport(contextReport).label}
+                  </span>
+                ) : null}
+                {memoryEnabled ? <MemoryPinMenu projectId={projectId} onError={setError} /> : null}
+              </div>
+            ) : null}
           </div>
         </div>
       </div>
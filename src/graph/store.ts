@@ -3,6 +3,7 @@ import type { EdgeType, GraphEdge, GraphNode, NodeType, Viewport } from '@/graph
 import { useProjectsStore } from '@/store/projects'
 import { getNodeSize } from '@/graph/nodeSizing'
 import { deleteMediaByNodeId, saveMedia } from '@/lib/mediaStorage'
+import { setMediaCacheProject } from '@/lib/workflow/cache'
 import {
   createSpatialIndex,
   indexNode,
@@ -434,6 +435,7 @@ export const useGraphStore = create<GraphState>((set, get) => ({
 
   hydrate: async (projectId) => {
     const id = String(projectId || '').trim() || 'default'
+    setMediaCacheProject(id) // 新下载的缓存文件登记到该项目
     const tauri = await tryTauriInvoke<PersistedCanvasV1 | null>('load_project_canvas', { projectId: id })
     const loaded = (tauri.ok ? (tauri.res as any) : null) || readLocal(id)
     const canvas = loaded && loaded.version === 1 ? loaded : null
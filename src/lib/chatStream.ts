@@ -0,0 +1,215 @@
+/**
+ * Backend chat stream | 桌面端由 Rust 后端代理的流式对话
+ * API Key 只保存在后端的服务商配置里，渲染进程按 id 引用；页面刷新后可按 requestId 重新挂上
+ */
+
+import { DEFAULT_API_BASE_URL } from '@/utils/constants'
+import { getTauri, tauriInvoke } from '@/lib/tauri'
+import type { ChatMessage } from '@/lib/contextEngine'
+
+export const DEFAULT_CHAT_PROVIDER_ID = 'default'
+
+const CHAT_STREAM_EVENT = 'nexus:chat-stream'
+const CHAT_STREAM_DONE_EVENT = 'nexus:chat-stream-done'
+const PENDING_STREAM_KEY_PREFIX = 'nexus-chat-pending-stream'
+
+export type ChatUsage = {
+  promptTokens: number
+  completionTokens: number
+  totalTokens: number
+}
+
+export type ChatCompletionResult = {
+  requestId: string
+  message: ChatMessage & { tool_calls?: any[] }
+  finishReason: string | null
+  usage: ChatUsage | null
+  cancelled: boolean
+}
+
+type ChatStreamDelta = { requestId: string; seq: number; delta: string }
+
+type ChatStreamDone = { requestId: string; result: ChatCompletionResult | null; error: string | null }
+
+type ChatStreamSnapshot = ChatStreamDone & { content: string; seq: number; finished: boolean }
+
+export type BackendStreamOptions = {
+  profileId?: string
+  model?: string
+  extra?: Record<string, unknown>
+  requestId?: string
+  signal?: AbortSignal
+  // 请求发出前回调 requestId，调用方据此记录待恢复的对话
+  onStart?: (requestId: string) => void
+}
+
+// 进行中的对话：刷新后按 messageId 找回对应的助手消息
+export type PendingChatStream = { requestId: string; messageId: string }
+
+export const isBackendChatAvailable = async () => (await getTauri()).isTauri
+
+export const makeChatRequestId = () => `chat-${Date.now()}-${Math.random().toString(36).slice(2, 8)}`
+
+const abortError = () => {
+  const err = new Error('Aborted')
+  err.name = 'AbortError'
+  return err
+}
+
+// 设置里的 Key 同步到后端默认服务商；清空 Key 时传空字符串
+export const syncChatProvider = (apiKey: string) => {
+  void tauriInvoke('chat_provider_save', {
+    profile: { id: DEFAULT_CHAT_PROVIDER_ID, name: 'Nexus', baseUrl: DEFAULT_API_BASE_URL, apiKey: apiKey || '' }
+  }).catch(() => {})
+}
+
+export const loadPendingChatStream = (key: string): PendingChatStream | null => {
+  try {
+    const raw = localStorage.getItem(`${PENDING_STREAM_KEY_PREFIX}:${key}`)
+    if (!raw) return null
+    const parsed = JSON.parse(raw)
+    if (!parsed?.requestId || !parsed?.messageId) return null
+    return { requestId: String(parsed.requestId), messageId: String(parsed.messageId) }
+  } catch {
+    return null
+  }
+}
+
+export const savePendingChatStream = (key: string, pending: PendingChatStream | null) => {
+  try {
+    if (pending) localStorage.setItem(`${PENDING_STREAM_KEY_PREFIX}:${key}`, JSON.stringify(pending))
+    else localStorage.removeItem(`${PENDING_STREAM_KEY_PREFIX}:${key}`)
+  } catch {
+    // ignore
+  }
+}
+
+// 后端流式对话：逐个产出文本增量；signal 中止时取消后端任务并抛出 AbortError
+export async function* streamChatBackend(messages: ChatMessage[], options: BackendStreamOptions = {}): AsyncGenerator<string> {
+  const { signal } = options
+  if (signal?.aborted) throw abortError()
+  const { invoke } = await import('@tauri-apps/api/core')
+  const { listen } = await import('@tauri-apps/api/event')
+  const requestId = options.requestId || makeChatRequestId()
+
+  const queue: string[] = []
+  let received = ''
+  let settled = false
+  let result: ChatCompletionResult | null = null
+  let failure: unknown = null
+  let wake: (() => void) | null = null
+  const notify = () => {
+    wake?.()
+    wake = null
+  }
+
+  const unlisten = await listen<ChatStreamDelta>(CHAT_STREAM_EVENT, (event) => {
+    const payload = event?.payload
+    if (payload?.requestId !== requestId || !payload.delta) return
+    queue.push(String(payload.delta))
+    notify()
+  })
+  const onAbort = () => {
+    void cancelChatStream(requestId).catch(() => {})
+  }
+  signal?.addEventListener('abort', onAbort)
+  options.onStart?.(requestId)
+
+  void invoke<ChatCompletionResult>('chat_stream', {
+    requestId,
+    messages,
+    profileId: options.profileId || DEFAULT_CHAT_PROVIDER_ID,
+    model: options.model,
+    extra: options.extra
+  })
+    .then(
+      (r) => {
+        result = r
+      },
+      (e) => {
+        failure = e
+      }
+    )
+    .finally(() => {
+      settled = true
+      notify()
+    })
+
+  try {
+    while (true) {
+      if (queue.length > 0) {
+        const delta = queue.shift() as string
+        received += delta
+        yield delta
+        continue
+      }
+      if (settled) break
+      await new Promise<void>((resolve) => {
+        wake = resolve
+      })
+    }
+  } finally {
+    unlisten()
+    signal?.removeEventListener('abort', onAbort)
+    // 调用方提前结束迭代时一并取消后端任务
+    if (!settled) void cancelChatStream(requestId).catch(() => {})
+  }
+
+  if (failure) throw failure instanceof Error ? failure : new Error(String(failure))
+  const final = result as ChatCompletionResult | null
+  if (final?.cancelled && signal?.aborted) throw abortError()
+  // 事件与命令返回值走不同通道，最后几段增量可能晚于结果到达：以结果为准补齐
+  const content = String(final?.message?.content || '')
+  if (content.length > received.length && content.startsWith(received)) yield content.slice(received.length)
+}
+
+// 页面刷新后重新挂上后端仍在进行（或刚结束）的对话：onSnapshot 收到已生成的全文，
+// 之后的增量按 seq 去重交给 onDelta；找不到该对话时返回 null
+export const attachChatStream = async (
+  requestId: string,
+  { onSnapshot, onDelta }: { onSnapshot?: (content: string) => void; onDelta?: (delta: string) => void } = {}
+): Promise<ChatCompletionResult | null> => {
+  if (!requestId || !(await isBackendChatAvailable())) return null
+  const { invoke } = await import('@tauri-apps/api/core')
+  const { listen } = await import('@tauri-apps/api/event')
+  let lastSeq: number | null = null
+  const buffered: ChatStreamDelta[] = []
+  const deliver = (payload: ChatStreamDelta) => {
+    if (lastSeq !== null && payload.seq <= lastSeq) return
+    lastSeq = payload.seq
+    onDelta?.(String(payload.delta))
+  }
+  let resolveDone: (done: ChatStreamDone) => void = () => {}
+  const done = new Promise<ChatStreamDone>((resolve) => {
+    resolveDone = resolve
+  })
+  const unlistenDelta = await listen<ChatStreamDelta>(CHAT_STREAM_EVENT, (event) => {
+    const payload = event?.payload
+    if (payload?.requestId !== requestId || !payload.delta) return
+    if (lastSeq === null) buffered.push(payload)
+    else deliver(payload)
+  })
+  const unlistenDone = await listen<ChatStreamDone>(CHAT_STREAM_DONE_EVENT, (event) => {
+    if (event?.payload?.requestId === requestId) resolveDone(event.payload)
+  })
+  try {
+    const snapshot = await invoke<ChatStreamSnapshot | null>('chat_stream_attach', { requestId })
+    if (!snapshot) return null
+    onSnapshot?.(String(snapshot.content || ''))
+    lastSeq = snapshot.seq || 0
+    buffered.forEach(deliver)
+    if (snapshot.finished) resolveDone(snapshot)
+    const outcome = await done
+    if (outcome.error) throw new Error(outcome.error)
+    return outcome.result
+  } finally {
+    unlistenDelta()
+    unlistenDone()
+  }
+}
+
+// 取消后端对话；streamChatBackend 会带着已收到的部分结束
+export const cancelChatStream = async (requestId: string) => {
+  if (!requestId) return
+  await tauriInvoke('chat_cancel', { requestId })
+}
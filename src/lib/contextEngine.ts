@@ -1,8 +1,9 @@
 import { tauriInvoke } from '@/lib/tauri'
 import type { MemoryItem } from '@/lib/memory'
 import type { GraphEdge, GraphNode } from '@/graph/types'
+import type { ThreadRef } from '@/lib/conversationThread'
 
-export type ChatMessage = { role: string; content: string }
+export type ChatMessage = { role: string; content: string; pin?: number | null }
 
 export type ContextConfig = {
   maxChars?: number
@@ -13,6 +14,58 @@ export type ContextConfig = {
   maxSummaryChars?: number
 }
 
+export type ContextSectionReport = {
+  key: string
+  included: boolean
+  size: number
+  originalSize: number
+  truncated: boolean
+  lines: number
+}
+
+export type ContextMemoryReport = { id: string; included: boolean; truncated: boolean; pinned: boolean }
+
+// Rust build_chat_context 的构建报告：预算用量、压缩阶段、各段与记忆条目是否放入
+export type ContextReport = {
+  unit: string
+  budget: number
+  used: number
+  stage: string
+  sections: ContextSectionReport[]
+  memoryItems: ContextMemoryReport[]
+  historyMessagesTotal: number
+  historyMessagesKept: number
+  turnsKept: number
+  turnsDropped: number
+  systemTruncated: boolean
+  userTruncated: boolean
+  images: { kept: number; dropped: number; inlined: number; estTokens: number; errors: string[] }
+}
+
+export type ChatContext = { messages: ChatMessage[]; report: ContextReport | null }
+
+const SECTION_LABELS: Record<string, string> = { pinned: '置顶', summary: '摘要', memory: '记忆', canvas: '画布' }
+
+// 输入框下方的一行概览 + 悬停时的明细
+export const describeContextReport = (report: ContextReport) => {
+  const unit = report.unit === 'chars' ? '字' : 'tokens'
+  const label = `上下文 ${report.used}/${report.budget} ${unit}`
+  const sections = report.sections
+    .map((s) => `${SECTION_LABELS[s.key] || s.key}${s.included ? (s.truncated ? '（截断）' : '') : '（未放入）'}`)
+    .join('、')
+  const memoryIncluded = report.memoryItems.filter((m) => m.included).length
+  const title = [
+    `压缩阶段：${report.stage}`,
+    `对话：保留 ${report.turnsKept} 轮，丢弃 ${report.turnsDropped} 轮`,
+    `记忆：放入 ${memoryIncluded}/${report.memoryItems.length} 条`,
+    sections ? `分段：${sections}` : '',
+    report.images.kept || report.images.dropped ? `图片：保留 ${report.images.kept}，丢弃 ${report.images.dropped}` : ''
+  ]
+    .filter(Boolean)
+    .join('\n')
+  return { label, title }
+}
+
 const normalizeText = (text: string) => String(text || '').replace(/\r\n/g, '\n').trim()
 
 const clamp = (n: number, a: number, b: number) => Math.max(a, Math.min(b, n))
@@ -34,7 +87,59 @@ const compactLines = (lines: string[], maxChars: number) => {
   return out.join('\n')
 }
 
-export const buildChatMessages = async (params: {
+// 滚动摘要：超出最近 keepRecent 条的旧对话由 Rust 抽取成摘要，游标随摘要一起存在本地
+export type ConversationSummaryState = { summary: string; summarizedUntil: number }
+
+const SUMMARY_KEY_PREFIX = 'nexus-conversation-summary-v1'
+
+export const loadConversationSummary = (key: string): ConversationSummaryState => {
+  try {
+    const raw = localStorage.getItem(`${SUMMARY_KEY_PREFIX}:${key}`)
+    const parsed = raw ? JSON.parse(raw) : null
+    if (parsed && typeof parsed.summary === 'string') {
+      return { summary: parsed.summary, summarizedUntil: Number(parsed.summarizedUntil) || 0 }
+    }
+  } catch {
+    // ignore
+  }
+  return { summary: '', summarizedUntil: 0 }
+}
+
+export const saveConversationSummary = (key: string, state: ConversationSummaryState | null) => {
+  try {
+    if (!state || (!state.summary && !state.summarizedUntil)) localStorage.removeItem(`${SUMMARY_KEY_PREFIX}:${key}`)
+    else localStorage.setItem(`${SUMMARY_KEY_PREFIX}:${key}`, JSON.stringify(state))
+  } catch {
+    // ignore
+  }
+}
+
+// 把滑出窗口的旧对话并入摘要并落盘；Web 端没有摘要器，原样返回上次的摘要
+export const summarizeConversation = async (
+  key: string,
+  conversation: ChatMessage[],
+  options: { keepRecent: number; maxSummaryChars?: number }
+): Promise<ConversationSummaryState> => {
+  const previous = loadConversationSummary(key)
+  try {
+    const res = await tauriInvoke<ConversationSummaryState>('summarize_conversation', {
+      conversation,
+      previousSummary: previous.summary || null,
+      maxSummaryChars: options.maxSummaryChars ?? 600,
+      keepRecent: options.keepRecent,
+      summarizedUntil: previous.summarizedUntil,
+      locale: 'zh'
+    })
+    if (!res) return previous
+    const next = { summary: res.summary || '', summarizedUntil: Number(res.summarizedUntil) || 0 }
+    saveConversationSummary(key, next)
+    return next
+  } catch {
+    return previous
+  }
+}
+
+type ChatContextParams = {
   userText: string
   systemPrompt: string
   conversation: ChatMessage[]
@@ -42,20 +147,29 @@ export const buildChatMessages = async (params: {
   memoryItems: MemoryItem[]
   canvasContext: string
   config?: ContextConfig
-}) => {
-  const userText = normalizeText(params.userText)
-  const systemPrompt = normalizeText(params.systemPrompt)
+  // 桌面端按线程分支取历史（给了就忽略 conversation）
+  thread?: ThreadRef | null
+}
 
-  const tauri = await tauriInvoke<ChatMessage[]>('build_chat_messages', {
-    userText,
-    systemPrompt,
+// 桌面端由 Rust 组装并返回报告；Web 端用下面的本地实现，没有报告
+export const buildChatContext = async (params: ChatContextParams): Promise<ChatContext> => {
+  const tauri = await tauriInvoke<ChatContext>('build_chat_context', {
+    userText: normalizeText(params.userText),
+    systemPrompt: normalizeText(params.systemPrompt),
     conversation: params.conversation || [],
     memorySummary: params.memorySummary || '',
     memoryItems: params.memoryItems || [],
     canvasContext: params.canvasContext || '',
-    config: params.config || null
+    config: params.config || null,
+    thread: params.thread || null
   })
-  if (tauri && Array.isArray(tauri) && tauri.length > 0) return tauri
+  if (tauri && Array.isArray(tauri.messages) && tauri.messages.length > 0) return tauri
+  return { messages: buildChatMessagesLocal(params), report: null }
+}
+
+const buildChatMessagesLocal = (params: ChatContextParams) => {
+  const userText = normalizeText(params.userText)
+  const systemPrompt = normalizeText(params.systemPrompt)
 
   const maxChars = clamp(Number(params.config?.maxChars || 12000), 2000, 50000)
   const maxHistory = clamp(Number(params.config?.maxHistory || 16), 4, 64)
@@ -127,11 +241,42 @@ const safeStringify = (v: unknown) => {
   }
 }
 
-export const buildCanvasContext = (params: {
+export type CanvasContextOptions = {
+  maxChars?: number
+  maxNodeChars?: number
+  neighborDepth?: number
+  maxFailures?: number
+  locale?: string
+}
+
+type CanvasContextParams = {
   nodes: GraphNode[]
   edges: GraphEdge[]
   selectedNodeId?: string | null
-}) => {
+  selectedNodeIds?: string[]
+  options?: CanvasContextOptions
+}
+
+// 画布上下文：桌面端由 Rust 按选中节点、邻居与失败记录做预算内摘要，Web 端回退到本地实现
+export const buildCanvasContext = async (params: CanvasContextParams) => {
+  const nodes = params.nodes || []
+  if (nodes.length === 0) return ''
+  const selectedNodeIds = [...new Set([...(params.selectedNodeId ? [params.selectedNodeId] : []), ...(params.selectedNodeIds || [])])]
+  try {
+    const rust = await tauriInvoke<{ text: string }>('graph_build_canvas_context', {
+      nodes,
+      edges: params.edges || [],
+      selectedNodeIds,
+      options: params.options || null
+    })
+    if (rust && typeof rust.text === 'string') return rust.text
+  } catch {
+    // 回退到本地实现
+  }
+  return buildCanvasContextLocal(params)
+}
+
+const buildCanvasContextLocal = (params: CanvasContextParams) => {
   const nodes = params.nodes || []
   const edges = params.edges || []
   const selectedNodeId = params.selectedNodeId || null
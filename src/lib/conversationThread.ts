@@ -0,0 +1,99 @@
+/**
+ * Conversation threads | 助手对话写入后端的对话线程
+ * 每个助手（按 key 区分）记住当前线程；桌面端上下文按线程分支取历史，Web 端无线程
+ */
+
+import { tauriInvoke } from '@/lib/tauri'
+import type { ChatMessage } from '@/lib/contextEngine'
+
+const THREAD_KEY_PREFIX = 'nexus-conversation-thread-v1'
+
+export type ThreadRef = { projectId: string; threadId: string; tipId?: string }
+
+export type ThreadMessage = {
+  id: string
+  parentId: string | null
+  message: ChatMessage & { pin?: number | null }
+  createdAt: number
+}
+
+type ConversationBranch = {
+  tipId: string | null
+  messages: ThreadMessage[]
+}
+
+const threadStorageKey = (key: string) => `${THREAD_KEY_PREFIX}:${key}`
+
+const loadThreadId = (key: string) => {
+  try {
+    return localStorage.getItem(threadStorageKey(key)) || ''
+  } catch {
+    return ''
+  }
+}
+
+// 清空对话即开始新线程，旧线程保留在后端
+export const resetConversationThread = (key: string) => {
+  try {
+    localStorage.removeItem(threadStorageKey(key))
+  } catch {
+    // ignore
+  }
+}
+
+// 取（必要时创建）该助手的当前线程；Web 端或创建失败时返回 null
+export const ensureConversationThread = async (key: string, projectId: string): Promise<ThreadRef | null> => {
+  const threadId = loadThreadId(key)
+  if (threadId) return { projectId, threadId }
+  try {
+    const created = await tauriInvoke<{ id: string }>('conversation_create', { projectId })
+    if (!created?.id) return null
+    localStorage.setItem(threadStorageKey(key), created.id)
+    return { projectId, threadId: created.id }
+  } catch {
+    return null
+  }
+}
+
+// 追加到线程当前分支末端；失败时返回 null，不影响对话本身
+export const appendThreadMessage = async (thread: ThreadRef | null, message: ChatMessage): Promise<ThreadMessage | null> => {
+  if (!thread) return null
+  try {
+    return await tauriInvoke<ThreadMessage>('conversation_append', {
+      projectId: thread.projectId,
+      threadId: thread.threadId,
+      message
+    })
+  } catch {
+    return null
+  }
+}
+
+// 置顶/取消置顶线程中的消息；构建上下文时置顶消息移入置顶段
+export const pinThreadMessage = async (thread: ThreadRef | null, messageId: string, pinned: boolean) => {
+  if (!thread) return false
+  try {
+    const entry = await tauriInvoke<ThreadMessage>('conversation_pin', {
+      projectId: thread.projectId,
+      threadId: thread.threadId,
+      messageId,
+      pinned
+    })
+    return !!entry
+  } catch {
+    return false
+  }
+}
+
+// 读取线程当前分支（根 → head）；线程已不存在时清掉记录并返回 null
+export const loadThreadMessages = async (key: string, projectId: string): Promise<ThreadMessage[] | null> => {
+  const threadId = loadThreadId(key)
+  if (!threadId) return null
+  try {
+    const branch = await tauriInvoke<ConversationBranch>('conversation_load', { projectId, threadId })
+    return branch?.messages || null
+  } catch {
+    resetConversationThread(key)
+    return null
+  }
+}
@@ -1,10 +1,12 @@
 import { tauriInvoke } from '@/lib/tauri'
+import { DEFAULT_API_BASE_URL } from '@/utils/constants'
 
 export type MemoryItem = {
   id: string
   content: string
   importance: number
   updatedAt: number
+  pin?: number | null // 置顶优先级；置顶条目每轮都放进上下文的置顶段
 }
 
 export type MemoryState = {
@@ -13,26 +15,66 @@ export type MemoryState = {
   items: MemoryItem[]
 }
 
+// OpenAI 兼容的 /embeddings 端点；Rust 端按端点 + 模型缓存记忆向量
+export type EmbeddingConfig = {
+  baseUrl: string
+  model: string
+  apiKey?: string | null
+  dimensions?: number | null
+}
+
 const STORAGE_KEY = 'nexus-memory-v1'
+const EMBEDDING_STORAGE_KEY = 'nexus-memory-embedding-v1'
+const DEFAULT_EMBEDDING_MODEL = 'text-embedding-3-small'
+// MMR 多样性：避免同一主题的记忆占满注入名额；对话上下文只取最近几轮
+const MEMORY_DIVERSITY = 0.3
+const MEMORY_CONTEXT_TURNS = 3
+// 旧版本地记忆只导入 Rust 一次，之后桌面端以 Rust 记忆库为准 | One-time legacy migration flag
+const MIGRATED_KEY = 'nexus-memory-migrated-v1'
+const MAX_LOCAL_ITEMS = 200
 
 const normalizeText = (text: string) => String(text || '').replace(/\r\n/g, '\n').trim()
 
+let migrating: Promise<void> | null = null
+
+const migrateLegacyOnce = (items: MemoryItem[]) => {
+  if (migrating || items.length === 0) return migrating
+  try {
+    if (localStorage.getItem(MIGRATED_KEY)) return null
+  } catch {
+    return null
+  }
+  migrating = tauriInvoke<number>('memory_migrate_legacy', { items, projectId: null })
+    .then((res) => {
+      if (res !== null) localStorage.setItem(MIGRATED_KEY, '1')
+    })
+    .catch(() => {})
+    .finally(() => {
+      migrating = null
+    })
+  return migrating
+}
+
 export const loadMemoryState = (): MemoryState => {
+  let state: MemoryState = { version: 1, summary: '', items: [] }
   try {
     const raw = localStorage.getItem(STORAGE_KEY)
-    if (!raw) return { version: 1, summary: '', items: [] }
-    const parsed = JSON.parse(raw)
-    if (!parsed || parsed.version !== 1) return { version: 1, summary: '', items: [] }
-    return {
-      version: 1,
-      summary: typeof parsed.summary === 'string' ? parsed.summary : '',
-      items: Array.isArray(parsed.items) ? parsed.items : []
+    const parsed = raw ? JSON.parse(raw) : null
+    if (parsed && parsed.version === 1) {
+      state = {
+        version: 1,
+        summary: typeof parsed.summary === 'string' ? parsed.summary : '',
+        items: Array.isArray(parsed.items) ? parsed.items : []
+      }
     }
   } catch {
-    return { version: 1, summary: '', items: [] }
+    // ignore
   }
+  void migrateLegacyOnce(state.items)
+  return state
 }
 
+// 只写本地（摘要 + Web 端条目）；桌面端条目增删改走下面的 Rust 命令
 export const saveMemoryState = (state: MemoryState) => {
   try {
     localStorage.setItem(STORAGE_KEY, JSON.stringify(state))
@@ -41,6 +83,113 @@ export const saveMemoryState = (state: MemoryState) => {
   }
 }
 
+export const listMemory = async (projectId?: string) => {
+  await migrating
+  return tauriInvoke<MemoryItem[]>('memory_list', { projectId: projectId || null, includeGlobal: true })
+}
+
+// 记住一条内容：桌面端写入 Rust（按项目作用域），Web 端回退到本地列表
+export const rememberMemory = async (state: MemoryState, content: string, projectId?: string): Promise<MemoryState> => {
+  const text = normalizeText(content)
+  if (!text) return state
+  await migrating
+  try {
+    const item = await tauriInvoke<MemoryItem>('memory_add', {
+      content: text,
+      importance: 0.6,
+      projectId: projectId || null,
+      source: 'chat',
+      dedupThreshold: 0.8
+    })
+    if (item) return state
+  } catch {
+    // 回退到本地
+  }
+  const id = globalThis.crypto?.randomUUID?.() || `mem_${Date.now()}_${Math.random().toString(16).slice(2)}`
+  const next: MemoryItem = { id, content: text, importance: 0.6, updatedAt: Date.now() }
+  const merged = { ...state, items: [next, ...(state.items || [])].slice(0, MAX_LOCAL_ITEMS) }
+  saveMemoryState(merged)
+  return merged
+}
+
+export const updateMemory = async (id: string, patch: { content?: string; importance?: number; tags?: string[] }) =>
+  tauriInvoke<MemoryItem>('memory_update', { id, ...patch })
+
+export const deleteMemory = async (id: string) => tauriInvoke<boolean>('memory_delete', { id })
+
+// 每轮只取置顶条目（含全局），与检索命中合并后一起交给上下文构建；Web 端没有置顶
+export const listPinnedMemory = async (projectId?: string): Promise<MemoryItem[]> => {
+  try {
+    const items = await tauriInvoke<MemoryItem[]>('memory_list', {
+      projectId: projectId || null,
+      includeGlobal: true,
+      pinnedOnly: true
+    })
+    return Array.isArray(items) ? items : []
+  } catch {
+    return []
+  }
+}
+
+export const pinMemory = async (id: string, pinned: boolean) => {
+  try {
+    return ((await tauriInvoke<number>('memory_pin', { ids: [id], pinned })) || 0) > 0
+  } catch {
+    return false
+  }
+}
+
+// 检索命中在前，未命中的置顶条目追加在后
+export const withPinnedMemory = (hits: MemoryItem[], pinned: MemoryItem[]) => {
+  const seen = new Set(hits.map((m) => m.id))
+  return hits.concat(pinned.filter((m) => m.pin != null && !seen.has(m.id)))
+}
+
+// 本轮真正放进上下文的记忆计一次使用：重要度回升、不再衰减
+export const reinforceMemory = async (ids: string[]) => {
+  const unique = Array.from(new Set(ids.filter(Boolean)))
+  if (unique.length === 0) return 0
+  try {
+    return (await tauriInvoke<number>('memory_reinforce', { ids: unique })) || 0
+  } catch {
+    return 0
+  }
+}
+
+let semanticDisabled = false
+
+// 未单独配置时用 Nexus 接口与当前 API Key；没有 Key 就不做语义检索
+export const loadEmbeddingConfig = (): EmbeddingConfig | null => {
+  try {
+    const raw = localStorage.getItem(EMBEDDING_STORAGE_KEY)
+    const parsed = raw ? JSON.parse(raw) : null
+    if (parsed && parsed.enabled === false) return null
+    if (parsed && typeof parsed.baseUrl === 'string' && parsed.baseUrl && typeof parsed.model === 'string' && parsed.model) {
+      return {
+        baseUrl: parsed.baseUrl,
+        model: parsed.model,
+        apiKey: typeof parsed.apiKey === 'string' ? parsed.apiKey : null,
+        dimensions: typeof parsed.dimensions === 'number' ? parsed.dimensions : null
+      }
+    }
+    const apiKey = localStorage.getItem('apiKey') || ''
+    if (!apiKey) return null
+    return { baseUrl: DEFAULT_API_BASE_URL, model: DEFAULT_EMBEDDING_MODEL, apiKey }
+  } catch {
+    return null
+  }
+}
+
+// 传 null 关闭语义检索
+export const saveEmbeddingConfig = (config: EmbeddingConfig | null) => {
+  try {
+    localStorage.setItem(EMBEDDING_STORAGE_KEY, JSON.stringify(config ? config : { enabled: false }))
+    semanticDisabled = false
+  } catch {
+    // ignore
+  }
+}
+
 const isCjk = (ch: string) => {
   if (!ch) return false
   const code = ch.charCodeAt(0)
@@ -88,16 +237,38 @@ const scoreMatch = (query: string, doc: string) => {
   return hit / denom
 }
 
-export const searchMemory = async (query: string, items: MemoryItem[], limit = 6, minScore = 0.12) => {
+export const searchMemory = async (
+  query: string,
+  items: MemoryItem[],
+  limit = 6,
+  minScore = 0.12,
+  projectId?: string,
+  context: string[] = []
+) => {
   const q = normalizeText(query)
   if (!q) return [] as MemoryItem[]
 
-  const tauri = await tauriInvoke<MemoryItem[]>('search_memory', {
+  await migrating
+  const args = {
     query: q,
-    items,
+    projectId: projectId || null,
     limit,
-    minScore
-  })
+    minScore,
+    weights: { diversity: MEMORY_DIVERSITY },
+    context: context.map(normalizeText).filter(Boolean).slice(-MEMORY_CONTEXT_TURNS)
+  }
+  const embedding = semanticDisabled ? null : loadEmbeddingConfig()
+  if (embedding) {
+    try {
+      const hits = await tauriInvoke<MemoryItem[]>('search_memory_semantic', { ...args, embedding })
+      if (hits && Array.isArray(hits)) return hits
+    } catch (err) {
+      // 端点不可用时本次会话改走纯词法检索，避免每轮都多一次失败请求
+      semanticDisabled = true
+      console.warn('[memory] semantic search unavailable, falling back to lexical:', err)
+    }
+  }
+  const tauri = await tauriInvoke<MemoryItem[]>('search_memory', args)
   if (tauri && Array.isArray(tauri)) return tauri
 
   const now = Date.now()
@@ -35,6 +35,8 @@ export interface TwoStageParams {
   useThinking: boolean
   useWebSearch: boolean
   signal?: AbortSignal
+  // 自定义流式通道（桌面端走后端代理）；不传则在渲染进程直接请求
+  stream?: (model: string, messages: ChatMessage[], stage: 'thinking' | 'answer') => AsyncIterable<string>
 }
 
 // ==================== 工具函数 ====================
@@ -428,7 +430,9 @@ export async function chatCompletions(data: {
 // ==================== 双阶段调用（思考模型 → 主模型）====================
 
 export async function* twoStageStream(params: TwoStageParams): AsyncGenerator<string> {
-  const { input, useThinking, useWebSearch, signal } = params
+  const { input, useThinking, useWebSearch, signal, stream } = params
+  const run = (stage: 'thinking' | 'answer', model: string, messages: ChatMessage[], maxRetries: number) =>
+    stream ? stream(model, messages, stage) : streamWithRetry('chat/completions', { model, messages }, { maxRetries, signal })
 
   // 检查 API Key
   const keyCheck = checkApiKey()
@@ -442,10 +446,7 @@ export async function* twoStageStream(params: TwoStageParams): AsyncGenerator<st
     // 之前丢弃 system messages 会导致“记忆/检索/上下文工程失效”
     const messagesWithContext: ChatMessage[] = Array.isArray(input) ? input : []
 
-    yield* streamWithRetry('chat/completions', {
-      model: MODELS.CHAT,
-      messages: messagesWithContext
-    }, { maxRetries: 2, signal })
+    yield* run('answer', MODELS.CHAT, messagesWithContext, 2)
     return
   }
 
@@ -472,13 +473,11 @@ ${useWebSearch ? '如果需要，可以搜索网络获取最新信息。' : ''}
       ...(Array.isArray(input) ? input : [])
     ]
 
-    for await (const chunk of streamWithRetry('chat/completions', {
-      model: MODELS.THINKING,
-      messages: thinkingMessages
-    }, { maxRetries: 1, signal })) {
+    for await (const chunk of run('thinking', MODELS.THINKING, thinkingMessages, 1)) {
       thinkingResult += chunk
     }
   } catch (error) {
+    if ((error as any)?.name === 'AbortError') throw error
     // 思考阶段失败，降级到直接主模型回答
     console.warn('[NexusAPI] 思考阶段失败，降级处理:', error)
     yield '（深度思考暂不可用，直接回答）\n\n'
@@ -488,10 +487,7 @@ ${useWebSearch ? '如果需要，可以搜索网络获取最新信息。' : ''}
       ...input.filter((m) => m.role !== 'system')
     ]
     
-    yield* streamWithRetry('chat/completions', {
-      model: MODELS.CHAT,
-      messages: messagesWithIdentity
-    }, { maxRetries: 2, signal })
+    yield* run('answer', MODELS.CHAT, messagesWithIdentity, 2)
     return
   }
 
@@ -519,10 +515,7 @@ ${thinkingResult}
     ...rest
   ]
 
-  yield* streamWithRetry('chat/completions', {
-    model: MODELS.CHAT,
-    messages: finalMessages
-  }, { maxRetries: 2, signal })
+  yield* run('answer', MODELS.CHAT, finalMessages, 2)
 }
 
 // ==================== AI 助手模型调用 ====================
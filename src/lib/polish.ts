@@ -222,17 +222,32 @@ export const collectUpstreamInputsForFocus = (params: { focusNodeId: string | nu
   return { text: textBlocks, images: imageBlocks }
 }
 
+// 润色时上游输入的预算与顺序：按连线优先级排列，总量封顶，避免多个长文本节点挤掉用户输入
+export const POLISH_UPSTREAM_OPTIONS = {
+  maxTextChars: 520,
+  maxUrlChars: 240,
+  maxTotalChars: 2400,
+  order: 'priority' as const
+}
+
 export const collectUpstreamInputsForFocusAsync = async (params: {
   focusNodeId: string | null
   nodes: GraphNode[]
   edges: GraphEdge[]
+  options?: {
+    maxTextChars?: number
+    maxUrlChars?: number
+    maxTotalChars?: number
+    order?: 'edge' | 'position' | 'priority'
+  }
 }) => {
   const { focusNodeId, nodes, edges } = params
   if (!focusNodeId) return { text: [] as any[], images: [] as any[] }
   const rust = await tauriInvoke<any>('graph_collect_upstream_inputs', {
     focusNodeId,
     nodes,
-    edges
+    edges,
+    options: params.options || null
   })
   if (rust && typeof rust === 'object') return rust
   return collectUpstreamInputsForFocus(params)
@@ -28,6 +28,42 @@ const pendingCache = new Map<string, Promise<CacheResult>>()
 // URL 到缓存 ID 的映射
 const urlToCacheId = new Map<string, string>()
 
+// 新下载的缓存文件登记到当前画布项目名下
+let cacheProjectId = ''
+
+export const setMediaCacheProject = (projectId?: string | null) => {
+  cacheProjectId = String(projectId || '').trim()
+}
+
+// 已打开的项目（画布、短剧工作台、剪辑页各自登记，按引用计数）：变化时把完整列表告知后端，
+// 这些项目下载但尚未保存进画布的文件不会被淘汰
+const openProjects = new Map<string, number>()
+
+const reportOpenProjects = () => {
+  void tauriInvoke('media_cache_set_open_projects', { projectIds: Array.from(openProjects.keys()) }).catch(() => {})
+}
+
+// 返回释放函数：页面离开或切换项目时调用
+export const openMediaCacheProject = (projectId?: string | null) => {
+  const id = String(projectId || '').trim()
+  if (!id) return () => {}
+  const count = openProjects.get(id) || 0
+  openProjects.set(id, count + 1)
+  if (count === 0) reportOpenProjects()
+  let released = false
+  return () => {
+    if (released) return
+    released = true
+    const left = (openProjects.get(id) || 1) - 1
+    if (left > 0) {
+      openProjects.set(id, left)
+      return
+    }
+    openProjects.delete(id)
+    reportOpenProjects()
+  }
+}
+
 const getApiKey = () => {
   try {
     return localStorage.getItem('apiKey') || ''
@@ -121,6 +157,7 @@ export const cacheMedia = async (
   options: {
     forceRefresh?: boolean
     metadata?: Record<string, unknown>
+    projectId?: string
   } = {}
 ): Promise<CacheResult> => {
   const u = String(url || '').trim()
@@ -141,7 +178,7 @@ export const cacheMedia = async (
     
     // Tauri 环境：使用 Rust 后端
     if (t.isTauri) {
-      return cacheTauri(u, category)
+      return cacheTauri(u, category, options.projectId ?? cacheProjectId)
     }
     
     // Web 环境：使用 IndexedDB
@@ -175,7 +212,7 @@ const absolutizeNexusApiUrl = (url: string) => {
   return u
 }
 
-const cacheTauri = async (url: string, category: CacheCategory): Promise<CacheResult> => {
+const cacheTauri = async (url: string, category: CacheCategory, projectId: string): Promise<CacheResult> => {
   // 如果是相对路径，转换为绝对 URL
   const absoluteUrl = absolutizeNexusApiUrl(url)
   if (absoluteUrl !== url) {
@@ -200,7 +237,8 @@ const cacheTauri = async (url: string, category: CacheCategory): Promise<CacheRe
     
     const path = await tauriInvoke<string>(command, { 
       url: absoluteUrl, 
-      authToken: token || null 
+      authToken: token || null,
+      projectId: projectId || null
     })
     
     if (!path) {
@@ -331,7 +369,7 @@ export const getCacheStats = async () => {
   return LocalCacheManager.getCacheStats()
 }
 
-export const resolveCachedImageUrl = async (url: string): Promise<{ displayUrl: string; localPath: string; error?: string }> => {
+export const resolveCachedImageUrl = async (url: string, projectId: string = cacheProjectId): Promise<{ displayUrl: string; localPath: string; error?: string }> => {
   const u = String(url || '').trim()
   if (!u) return { displayUrl: '', localPath: '' }
   if (u.startsWith('data:') || u.startsWith('blob:')) return { displayUrl: u, localPath: '' }
@@ -356,7 +394,7 @@ export const resolveCachedImageUrl = async (url: string): Promise<{ displayUrl:
     const token = getApiKey()
     try {
       console.log('[resolveCachedImageUrl] Tauri: 调用 cache_remote_image 命令')
-      const path = await tauriInvoke<string>('cache_remote_image', { url: absoluteUrl, authToken: token || null })
+      const path = await tauriInvoke<string>('cache_remote_image', { url: absoluteUrl, authToken: token || null, projectId: projectId || null })
       if (!path) {
         // 缓存失败时回退到原始 URL，而不是返回空（修复 Windows 图片不显示问题）
         console.warn('[resolveCachedImageUrl] Tauri: cache_remote_image 返回空，回退到原始 URL')
@@ -382,7 +420,7 @@ export const resolveCachedImageUrl = async (url: string): Promise<{ displayUrl:
   return { displayUrl: u, localPath: '' }
 }
 
-export const resolveCachedMediaUrl = async (url: string) => {
+export const resolveCachedMediaUrl = async (url: string, projectId: string = cacheProjectId) => {
   const u = String(url || '').trim()
   if (!u) return { displayUrl: '', localPath: '' }
   if (u.startsWith('data:') || u.startsWith('blob:')) return { displayUrl: u, localPath: '' }
@@ -401,7 +439,7 @@ export const resolveCachedMediaUrl = async (url: string) => {
     try {
       const token = getApiKey()
       console.log('[resolveCachedMediaUrl] Tauri: 调用 cache_remote_media 命令')
-      const path = await tauriInvoke<string>('cache_remote_media', { url: absoluteUrl, authToken: token || null })
+      const path = await tauriInvoke<string>('cache_remote_media', { url: absoluteUrl, authToken: token || null, projectId: projectId || null })
       if (!path) {
         // 缓存失败时回退到原始 URL（修复 Windows 媒体不显示问题）
         console.warn('[resolveCachedMediaUrl] Tauri: cache_remote_media 返回空，回退到原始 URL')
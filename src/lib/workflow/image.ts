@@ -3,6 +3,7 @@ import type { GraphNode } from '@/graph/types'
 import { DEFAULT_IMAGE_MODEL, IMAGE_MODELS, SEEDREAM_SIZE_OPTIONS, SEEDREAM_4K_SIZE_OPTIONS } from '@/config/models'
 import { getJson, postJson } from '@/lib/workflow/request'
 import { resolveCachedImageUrl } from '@/lib/workflow/cache'
+import { assertConfigInputs } from '@/lib/workflow/validate'
 import { saveMedia, isLargeData, isBase64Data } from '@/lib/mediaStorage'
 import { requestQueue, type QueueTask } from '@/lib/workflow/requestQueue'
 import { useAssetsStore } from '@/store/assets'
@@ -304,6 +305,7 @@ export const generateImageFromConfigNode = async (
   const modelCfg: any = (IMAGE_MODELS as any[]).find((m) => m.key === modelKey) || (IMAGE_MODELS as any[])[0]
   console.log('[generateImage] 模型配置:', { modelKey, fromOverrides: !!overrides?.model })
   if (!modelCfg) throw new Error('未找到模型配置')
+  await assertConfigInputs(configNodeId, String(modelCfg.key || modelKey))
 
   // 优先使用 overrides 参数
   const size = String(overrides?.size || d.size || modelCfg.defaultParams?.size || '')
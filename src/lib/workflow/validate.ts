@@ -0,0 +1,34 @@
+/**
+ * 配置节点输入校验
+ * 桌面端在发请求前由 Rust 按模型能力检查连线（提示词、参考图数量、首尾帧角色），Web 端跳过
+ */
+
+import { useGraphStore } from '@/graph/store'
+import { tauriInvoke } from '@/lib/tauri'
+
+type ConfigInputIssue = { code: string; message: string; nodeIds: string[] }
+
+type ConfigInputValidation = {
+  ok: boolean
+  errors: ConfigInputIssue[]
+  warnings: ConfigInputIssue[]
+}
+
+// 校验不通过时抛出包含全部错误的 Error；后端不可用或校验本身失败时交给各生成流程自己的检查
+export const assertConfigInputs = async (configNodeId: string, modelKey: string) => {
+  const { nodes, edges } = useGraphStore.getState()
+  let result: ConfigInputValidation | null = null
+  try {
+    result = await tauriInvoke<ConfigInputValidation>('graph_validate_config_inputs', {
+      configNodeId,
+      nodes,
+      edges,
+      modelKey: modelKey || null
+    })
+  } catch {
+    return
+  }
+  if (!result || result.ok) return
+  const messages = (result.errors || []).map((e) => e.message).filter(Boolean)
+  throw new Error(messages.join('；') || '输入校验未通过')
+}
@@ -4,6 +4,7 @@ import { DEFAULT_VIDEO_MODEL, VIDEO_MODELS } from '@/config/models'
 import * as modelsConfig from '@/config/models'
 import { getJson, postFormData, postJson } from '@/lib/workflow/request'
 import { resolveCachedMediaUrl } from '@/lib/workflow/cache'
+import { assertConfigInputs } from '@/lib/workflow/validate'
 import { getMedia, getMediaByNodeId, saveMedia, isLargeData, isBase64Data } from '@/lib/mediaStorage'
 import { requestQueue, type QueueTask } from '@/lib/workflow/requestQueue'
 import { useSettingsStore } from '@/store/settings'
@@ -1167,6 +1168,7 @@ export const generateVideoFromConfigNode = async (
     (VIDEO_MODELS as any[])[0]
   console.log('[generateVideo] 模型配置:', { modelKey, resolvedKey: String(modelCfg?.key || ''), modelCfg, fromOverrides: !!overrides?.model })
   if (!modelCfg) throw new Error('未找到模型配置')
+  await assertConfigInputs(configNodeId, String(modelCfg?.key || modelKey))
 
   // === 运行时输入能力校验（基于 models.js 的能力字段）===
   // 说明：部分模型不区分“首帧/参考图”语义（只接收 images[]），这里做兼容性折叠：
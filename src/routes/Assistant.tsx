@@ -2,14 +2,46 @@ import React, { useEffect, useMemo, useRef, useState } from 'react'
 import { Link } from 'react-router-dom'
 import { Button } from '@/components/ui/button'
 import SettingsDialog from '@/components/SettingsDialog'
+import MemoryPinMenu from '@/components/MemoryPinMenu'
 import { useGraphStore } from '@/graph/store'
-import { buildCanvasContext, buildChatMessages, type ChatMessage } from '@/lib/contextEngine'
-import { loadMemoryState, saveMemoryState, searchMemory, type MemoryItem } from '@/lib/memory'
+import {
+  buildCanvasContext,
+  buildChatContext,
+  describeContextReport,
+  saveConversationSummary,
+  summarizeConversation,
+  type ChatMessage,
+  type ContextReport
+} from '@/lib/contextEngine'
+import {
+  listPinnedMemory,
+  loadMemoryState,
+  reinforceMemory,
+  rememberMemory,
+  searchMemory,
+  withPinnedMemory
+} from '@/lib/memory'
 import { streamResponses } from '@/lib/nexusApi'
+import {
+  attachChatStream,
+  cancelChatStream,
+  isBackendChatAvailable,
+  loadPendingChatStream,
+  savePendingChatStream,
+  streamChatBackend
+} from '@/lib/chatStream'
+import {
+  appendThreadMessage,
+  ensureConversationThread,
+  loadThreadMessages,
+  pinThreadMessage,
+  resetConversationThread
+} from '@/lib/conversationThread'
 import {
   buildPolishSystemPrompt,
   buildPolishUserText,
   collectUpstreamInputsForFocusAsync,
+  POLISH_UPSTREAM_OPTIONS,
   inferPolishModeFromGraph,
   inferPolishModeFromText,
   selectBestPromptTemplate
@@ -23,12 +55,20 @@ type UiMessage = {
   content: string
   createdAt: number
   streaming?: boolean
+  threadMessageId?: string
+  pin?: number // 置顶：每轮都放进上下文的置顶段
 }
 
 const makeId = () => globalThis.crypto?.randomUUID?.() || `msg_${Date.now()}_${Math.random().toString(16).slice(2)}`
 
 const CONVERSATION_KEY = 'nexus-conversation-v1'
 const SCROLL_KEY = 'nexus-assistant-scrollTop-v1'
+const STREAM_RESUME_KEY = 'assistant'
+// 独立助手页的对话线程不属于任何项目
+const THREAD_KEY = 'assistant'
+const THREAD_PROJECT_ID = ''
+// 画布上下文与 maxCanvasChars 同一预算 | Canvas summary budget
+const CANVAS_CONTEXT_OPTIONS = { maxChars: 1200 }
 
 const loadConversation = () => {
   try {
@@ -76,6 +116,7 @@ export default function Assistant() {
   const [busy, setBusy] = useState(false)
   const [error, setError] = useState<string | null>(null)
   const [mode, setMode] = useState<'chat' | 'polish'>('chat')
+  const [contextReport, setContextReport] = useState<ContextReport | null>(null)
 
   const initialScrollTop = useMemo(() => loadScrollTop(), [])
   const memoryRef = useRef(loadMemoryState())
@@ -89,10 +130,26 @@ export default function Assistant() {
   const scrollTopRef = useRef<number>(0)
   const pendingTextRef = useRef('')
   const fullTextRef = useRef('')
+  const resumeRequestRef = useRef<string | null>(null)
 
-  const graphSnapshot = useGraphStore((s) => ({ nodes: s.nodes, edges: s.edges, selectedNodeId: s.selectedNodeId }))
+  const currentProjectId = useGraphStore((s) => s.projectId)
+  const graphSnapshot = useGraphStore((s) => ({ nodes: s.nodes, edges: s.edges, selectedNodeId: s.selectedNodeId, selectedNodeIds: s.selectedNodeIds }))
 
-  const canvasContext = useMemo(() => buildCanvasContext(graphSnapshot), [graphSnapshot])
+  const [canvasContext, setCanvasContext] = useState('')
+
+  // 画布上下文预览：画布变化后稍作延迟再让后端重建摘要；发送时另行按最新画布构建
+  useEffect(() => {
+    let cancelled = false
+    const t = window.setTimeout(() => {
+      void buildCanvasContext({ ...graphSnapshot, options: CANVAS_CONTEXT_OPTIONS }).then((text) => {
+        if (!cancelled) setCanvasContext(text)
+      })
+    }, 200)
+    return () => {
+      cancelled = true
+      window.clearTimeout(t)
+    }
+  }, [graphSnapshot.nodes, graphSnapshot.edges, graphSnapshot.selectedNodeId, graphSnapshot.selectedNodeIds])
 
   useEffect(() => {
     // Assistant 页面也需要读到当前画布上下文：默认加载当前 projectId（未进入画布时为 default）
@@ -105,6 +162,73 @@ export default function Assistant() {
     return () => window.clearTimeout(t)
   }, [messages])
 
+  // 本地没有缓存的对话时（如换了浏览器存储），从后端线程恢复当前分支
+  useEffect(() => {
+    if (messages.length > 0) return
+    let cancelled = false
+    void loadThreadMessages(THREAD_KEY, THREAD_PROJECT_ID).then((entries) => {
+      if (cancelled || !entries) return
+      const restored = entries
+        .filter((e) => e.message.role === 'user' || e.message.role === 'assistant')
+        .map((e): UiMessage => ({
+          id: e.id,
+          role: e.message.role as UiRole,
+          content: e.message.content,
+          createdAt: e.createdAt,
+          threadMessageId: e.id,
+          pin: e.message.pin ?? undefined
+        }))
+      if (restored.length > 0) setMessages((prev) => (prev.length === 0 ? restored : prev))
+    })
+    return () => {
+      cancelled = true
+    }
+  }, [])
+
+  // 页面刷新时后端的对话仍在继续：按记录的 requestId 重新挂上，把结果写回原来的助手消息
+  useEffect(() => {
+    const pending = loadPendingChatStream(STREAM_RESUME_KEY)
+    if (!pending) return
+    const target = messages[messages.length - 1]
+    if (!target || target.id !== pending.messageId || target.role !== 'assistant') {
+      savePendingChatStream(STREAM_RESUME_KEY, null)
+      return
+    }
+    resumeRequestRef.current = pending.requestId
+    assistantIdRef.current = pending.messageId
+    pendingTextRef.current = ''
+    fullTextRef.current = target.content
+    setBusy(true)
+    void attachChatStream(pending.requestId, {
+      onSnapshot: (content) => {
+        fullTextRef.current = ''
+        pendingTextRef.current = content
+        scheduleFlush()
+      },
+      onDelta: (delta) => {
+        pendingTextRef.current += delta
+        scheduleFlush()
+      }
+    })
+      .then((result) => {
+        if (rafUpdateRef.current) cancelAnimationFrame(rafUpdateRef.current)
+        flushStreamingToState()
+        const content = (result ? String(result.message?.content || '') : '') || fullTextRef.current
+        finishAssistantMessage(pending.messageId, content)
+        // 提问已记进线程时补记这次回复
+        if (messages[messages.length - 2]?.threadMessageId) void recordThreadReply(pending.messageId, content)
+      })
+      .catch((e: any) => {
+        setError(e?.message || '恢复对话失败')
+        finishAssistantMessage(pending.messageId, fullTextRef.current)
+      })
+      .finally(() => {
+        savePendingChatStream(STREAM_RESUME_KEY, null)
+        resumeRequestRef.current = null
+        setBusy(false)
+      })
+  }, [])
+
   useEffect(() => {
     if (initialScrollTop == null) return
     const el = listRef.current
@@ -160,11 +284,46 @@ export default function Assistant() {
     rafUpdateRef.current = requestAnimationFrame(flushStreamingToState)
   }
 
+  const finishAssistantMessage = (id: string, content: string) => {
+    setMessages((prev) => {
+      if (prev.length === 0) return prev
+      const last = prev[prev.length - 1]
+      if (last.id !== id) return prev
+      const next = prev.slice()
+      next[next.length - 1] = { ...last, content, streaming: false }
+      return next
+    })
+  }
+
+  const setThreadMessageId = (id: string, threadMessageId: string) => {
+    setMessages((prev) => prev.map((m) => (m.id === id ? { ...m, threadMessageId } : m)))
+  }
+
+  const recordThreadReply = async (id: string, content: string) => {
+    const thread = await ensureConversationThread(THREAD_KEY, THREAD_PROJECT_ID)
+    const entry = await appendThreadMessage(thread, { role: 'assistant', content })
+    if (entry) setThreadMessageId(id, entry.id)
+  }
+
+  // 消息置顶：界面上标记，并写回对话线程（桌面端上下文按线程构建）
+  const toggleMessagePin = async (m: UiMessage) => {
+    const pinned = m.pin == null
+    if (m.threadMessageId) {
+      const thread = await ensureConversationThread(THREAD_KEY, THREAD_PROJECT_ID)
+      if (!(await pinThreadMessage(thread, m.threadMessageId, pinned))) {
+        setError('消息置顶失败')
+        return
+      }
+    }
+    setMessages((prev) => prev.map((x) => (x.id === m.id ? { ...x, pin: pinned ? 1 : undefined } : x)))
+  }
+
   const stop = () => {
     if (controllerRef.current) {
       controllerRef.current.abort()
       controllerRef.current = null
     }
+    if (resumeRequestRef.current) void cancelChatStream(resumeRequestRef.current).catch(() => {})
     setBusy(false)
   }
 
@@ -172,7 +331,10 @@ export default function Assistant() {
     stop()
     setMessages([])
     setError(null)
+    setContextReport(null)
     saveConversation([])
+    saveConversationSummary('assistant', null)
+    resetConversationThread(THREAD_KEY)
     saveScrollTop(0)
   }
 
@@ -187,16 +349,9 @@ export default function Assistant() {
       /我更喜欢/.test(t)
     if (!should) return
 
-    const next: MemoryItem = {
-      id: makeId(),
-      content: t.replace(/^记住[:：]\s*/, ''),
-      importance: 0.6,
-      updatedAt: Date.now()
-    }
-    const mem = memoryRef.current
-    const merged = { ...mem, items: [next, ...(mem.items || [])].slice(0, 200) }
-    memoryRef.current = merged
-    saveMemoryState(merged)
+    void rememberMemory(memoryRef.current, t.replace(/^记住[:：]\s*/, ''), useGraphStore.getState().projectId).then((next) => {
+      memoryRef.current = next
+    })
   }
 
   const send = async () => {
@@ -211,7 +366,7 @@ export default function Assistant() {
     controllerRef.current?.abort()
     controllerRef.current = new AbortController()
 
-    const conversation: ChatMessage[] = messages.map((m) => ({ role: m.role, content: m.content }))
+    const conversation: ChatMessage[] = messages.map((m) => ({ role: m.role, content: m.content, pin: m.pin ?? undefined }))
     const userMsg: UiMessage = { id: makeId(), role: 'user', content: text, createdAt: Date.now() }
     const assistantId = makeId()
     assistantIdRef.current = assistantId
@@ -228,7 +383,11 @@ export default function Assistant() {
 
     try {
       const mem = memoryRef.current
-      const hits = await searchMemory(text, mem.items || [], 6, 0.12)
+      const canvasText = await buildCanvasContext({ ...graphSnapshot, options: CANVAS_CONTEXT_OPTIONS })
+      const projectId = useGraphStore.getState().projectId
+      const hits = await searchMemory(text, mem.items || [], 6, 0.12, projectId, conversation.map((m) => m.content))
+      // 置顶记忆每轮都带上，由后端放入置顶段
+      const memoryItems = withPinnedMemory(hits, await listPinnedMemory(projectId))
       const isPolish = mode === 'polish'
       const inferredMode = inferPolishModeFromGraph(graphSnapshot.selectedNodeId || null, graphSnapshot.nodes, graphSnapshot.edges)
       const polishMode = isPolish ? inferredMode || inferPolishModeFromText(text) : null
@@ -237,7 +396,8 @@ export default function Assistant() {
         ? await collectUpstreamInputsForFocusAsync({
             focusNodeId: graphSnapshot.selectedNodeId || null,
             nodes: graphSnapshot.nodes,
-            edges: graphSnapshot.edges
+            edges: graphSnapshot.edges,
+            options: POLISH_UPSTREAM_OPTIONS
           })
         : { text: [], images: [] }
 
@@ -245,7 +405,7 @@ export default function Assistant() {
         ? await selectBestPromptTemplate({
             mode: polishMode || 'image',
             userText: text,
-            contextText: canvasContext
+            contextText: canvasText
           })
         : null
 
@@ -262,17 +422,34 @@ export default function Assistant() {
           })
         : text
 
-      const finalMsgList = await buildChatMessages({
+      // 提问先记进线程，桌面端按线程分支（本次提问之前）取历史
+      const thread = await ensureConversationThread(THREAD_KEY, THREAD_PROJECT_ID)
+      const userEntry = await appendThreadMessage(thread, { role: 'user', content: text })
+      if (userEntry) setThreadMessageId(userMsg.id, userEntry.id)
+
+      const conversationSummary = await summarizeConversation('assistant', conversation, { keepRecent: 16 })
+      const { messages: finalMsgList, report } = await buildChatContext({
         userText,
         systemPrompt,
         conversation,
-        memorySummary: mem.summary || '',
-        memoryItems: hits,
-        canvasContext,
-        config: { maxChars: 12000, maxHistory: 16, maxMemoryItems: 6, maxCanvasChars: 1200 }
+        memorySummary: [mem.summary, conversationSummary.summary].filter(Boolean).join('\n'),
+        memoryItems,
+        canvasContext: canvasText,
+        config: { maxChars: 12000, maxHistory: 16, maxMemoryItems: 6, maxCanvasChars: 1200 },
+        thread: thread && userEntry?.parentId ? { ...thread, tipId: userEntry.parentId } : null
       })
-
-      for await (const chunk of streamResponses({ model: 'gpt-5-mini', input: finalMsgList }, controllerRef.current.signal)) {
+      setContextReport(report)
+
+      // 桌面端由后端代理对话（API Key 不进渲染进程），并记录 requestId 以便刷新后恢复
+      const signal = controllerRef.current.signal
+      const stream = (await isBackendChatAvailable())
+        ? streamChatBackend(finalMsgList, {
+            model: 'gpt-5-mini',
+            signal,
+            onStart: (requestId) => savePendingChatStream(STREAM_RESUME_KEY, { requestId, messageId: assistantId })
+          })
+        : streamResponses({ model: 'gpt-5-mini', input: finalMsgList }, signal)
+      for await (const chunk of stream) {
         pendingTextRef.current += chunk
         scheduleFlush()
       }
@@ -280,14 +457,10 @@ export default function Assistant() {
       if (rafUpdateRef.current) cancelAnimationFrame(rafUpdateRef.current)
       flushStreamingToState()
 
-      setMessages((prev) => {
-        if (prev.length === 0) return prev
-        const last = prev[prev.length - 1]
-        if (last.id !== assistantId) return prev
-        const next = prev.slice()
-        next[next.length - 1] = { ...last, content: fullTextRef.current, streaming: false }
-        return next
-      })
+      finishAssistantMessage(assistantId, fullTextRef.current)
+      if (userEntry) void recordThreadReply(assistantId, fullTextRef.current)
+      // 回复成功后，按构建报告给实际放进上下文的记忆计一次使用
+      if (report) void reinforceMemory(report.memoryItems.filter((m) => m.included).map((m) => m.id))
     } catch (e: any) {
       if (e?.name === 'AbortError') return
       setError(e?.message || '发送失败')
@@ -300,6 +473,7 @@ export default function Assistant() {
         return next
       })
     } finally {
+      savePendingChatStream(STREAM_RESUME_KEY, null)
       controllerRef.current = null
       setBusy(false)
     }
@@ -378,6 +552,17 @@ export default function Assistant() {
                     ].join(' ')}
                   >
                     {m.content || (m.streaming ? '...' : '')}
+                    {!m.streaming && m.content ? (
+                      <div className="mt-1 flex justify-end">
+                        <button
+                          className="text-[11px] text-[var(--text-secondary)] opacity-70 transition-opacity hover:opacity-100"
+                          title={m.pin != null ? '取消置顶（不再固定放入上下文）' : '置顶（每轮都放入上下文）'}
+                          onClick={() => void toggleMessagePin(m)}
+                        >
+                          {m.pin != null ? '已置顶' : '置顶'}
+                        </button>
+                      </div>
+                    ) : null}
                   </div>
                 </div>
               ))}
@@ -413,8 +598,16 @@ export default function Assistant() {
                 </Button>
               )}
             </div>
-            <div className="mt-2 text-xs text-[var(--text-secondary)]">
-              提示：用「记住：...」可以把偏好写入长期记忆（本地存储）。
+            <div className="mt-2 flex items-center justify-between gap-2 text-xs text-[var(--text-secondary)]">
+              <span>提示：用「记住：...」可以把偏好写入长期记忆（本地存储）。</span>
+              <div className="flex shrink-0 items-center gap-2">
+                {contextReport ? (
+                  <span className="cursor-help" title={describeContextReport(contextReport).title}>
+                    {describeContextReport(contextReport).label}
+                  </span>
+                ) : null}
+                <MemoryPinMenu projectId={currentProjectId} onError={setError} />
+              </div>
             </div>
           </div>
         </div>
@@ -37,6 +37,7 @@ import { ChevronDown, ChevronLeft, Download, History, Moon, Play, Settings, Sun,
 import { generateImageFromConfigNode } from '@/lib/workflow/image'
 import { generateVideoFromConfigNode } from '@/lib/workflow/video'
 import { saveCurrentAsTemplate } from '@/lib/workflowTemplates'
+import { openMediaCacheProject } from '@/lib/workflow/cache'
 
 // 功能开关
 // USE_REACT_FLOW: 使用 React Flow（推荐，完全对齐 Huobao 架构）
@@ -844,6 +845,9 @@ export default function Canvas() {
     }
   }, [])
 
+  // 画布打开期间登记为已打开项目（媒体缓存不淘汰其文件），离开或切换项目时释放
+  useEffect(() => openMediaCacheProject(String(id || '').trim() || 'default'), [id])
+
   // 同步画布素材到历史记录
   useEffect(() => {
     // 延迟执行，确保画布数据已加载
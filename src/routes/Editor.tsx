@@ -6,6 +6,7 @@ import { ArrowDown, ArrowUp, Download, FileDown, Film, Loader2, Pause, Play, Plu
 import { getMedia } from '@/lib/mediaStorage'
 import { downloadFile } from '@/lib/download'
 import { tauriInvoke } from '@/lib/tauri'
+import { openMediaCacheProject } from '@/lib/workflow/cache'
 import { loadShortDramaDraftV2 } from '@/lib/shortDrama/draftStorage'
 import { loadShortDramaEditorProjectV1, saveShortDramaEditorProjectV1 } from '@/lib/editor/editorStorage'
 import { useAssetsStore } from '@/store/assets'
@@ -57,6 +58,9 @@ export default function Editor() {
     setEditor(loadShortDramaEditorProjectV1(projectId))
   }, [projectId])
 
+  // 剪辑页打开期间登记为已打开项目，离开时释放
+  useEffect(() => openMediaCacheProject(projectId), [projectId])
+
   // 自动保存（debounced）
   useEffect(() => {
     const t = window.setTimeout(() => {
@@ -1,6 +1,7 @@
 import React, { useEffect } from 'react'
 import { useNavigate, useParams } from 'react-router-dom'
 import { useGraphStore } from '@/graph/store'
+import { openMediaCacheProject } from '@/lib/workflow/cache'
 import ShortDramaStudioShell from '@/components/shortDrama/ShortDramaStudioShell'
 
 export default function ShortDramaStudioPage() {
@@ -15,6 +16,9 @@ export default function ShortDramaStudioPage() {
     void useGraphStore.getState().setProjectId(projectId)
   }, [projectId])
 
+  // 工作台打开期间登记为已打开项目，离开时释放
+  useEffect(() => openMediaCacheProject(projectId), [projectId])
+
   return (
     <div className="h-full min-h-screen w-full bg-[var(--bg-primary)] text-[var(--text-primary)]">
       <ShortDramaStudioShell
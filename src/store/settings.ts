@@ -1,4 +1,5 @@
 import { create } from 'zustand'
+import { syncChatProvider } from '@/lib/chatStream'
 
 const API_KEY_STORAGE_KEY = 'apiKey'
 const API_KEYS_STORAGE_KEY = 'nexus-api-keys'
@@ -255,6 +256,7 @@ export const useSettingsStore = create<SettingsState>((set, get) => ({
     } catch {
       // ignore
     }
+    syncChatProvider(next)
     set({ apiKey: next })
   },
   clearApiKey: () => {
@@ -263,6 +265,7 @@ export const useSettingsStore = create<SettingsState>((set, get) => ({
     } catch {
       // ignore
     }
+    syncChatProvider('')
     set({ apiKey: '' })
   },
   toggleDark: () => {
@@ -528,3 +531,7 @@ export const useSettingsStore = create<SettingsState>((set, get) => ({
 
 // Initialize theme once on import | 初始化主题（只做一次）
 applyTheme(readDark())
+
+// 旧版本只存在 localStorage 的 Key：启动时同步一次到后端对话服务商
+const initialApiKey = readApiKey()
+if (initialApiKey) syncChatProvider(initialApiKey)
@@ -1,19 +1,27 @@
 /**
- * Assistant Memory Store | 助手记忆（本地持久化）
+ * Assistant Memory Store | 助手记忆（桌面端由 Rust 记忆库持有，Web 端本地持久化）
  * - 轻量“语义记忆”：用户偏好/背景/长期约束等
  * - 提供简单检索（无向量依赖），用于上下文拼装
  */
 
 import { ref } from 'vue'
+import { tauriInvoke } from '@/lib/tauri'
 
 const STORAGE_KEY = 'nexus-assistant-memory-v1'
+// 旧版本地记忆只导入 Rust 一次，之后 Rust 记忆库是唯一数据源 | One-time legacy migration flag
+const MIGRATED_KEY = 'nexus-assistant-memory-migrated-v1'
 const MAX_ITEMS = 200
 
 export const memorySummary = ref('')
+// 已并入摘要的对话条数（summarize_conversation 的游标），对话清空时归零
+export const memorySummaryUntil = ref(0)
 export const memoryItems = ref([])
 
 let loaded = false
 let saveTimer = null
+// 桌面端：条目由 Rust 持有，本地只存摘要；Web 端回退到 localStorage
+let rustOwned = false
+let projectId = ''
 
 const now = () => Date.now()
 
@@ -23,27 +31,62 @@ const safeParse = (raw) => {
   try { return JSON.parse(raw) } catch { return null }
 }
 
+const migrateLegacyOnce = async (items) => {
+  if (items.length === 0 || localStorage.getItem(MIGRATED_KEY)) return
+  try {
+    const res = await tauriInvoke('memory_migrate_legacy', { items, projectId: null })
+    if (res !== null) localStorage.setItem(MIGRATED_KEY, '1')
+  } catch {
+    // 下次加载再试
+  }
+}
+
+// 从 Rust 拉取当前项目（含全局）的记忆 | Reload items from the Rust store
+export const refreshMemoryItems = async () => {
+  try {
+    const res = await tauriInvoke('memory_list', { projectId, includeGlobal: true })
+    if (!Array.isArray(res)) return
+    rustOwned = true
+    memoryItems.value = res
+  } catch {
+    // 保留当前列表
+  }
+}
+
 export const loadMemory = () => {
   if (loaded) return
   loaded = true
+  let legacy = []
   try {
     const raw = localStorage.getItem(STORAGE_KEY)
     const parsed = raw ? safeParse(raw) : null
     if (parsed && typeof parsed === 'object') {
       if (typeof parsed.summary === 'string') memorySummary.value = parsed.summary
-      if (Array.isArray(parsed.items)) memoryItems.value = parsed.items.filter(Boolean)
+      if (Number.isInteger(parsed.summaryUntil)) memorySummaryUntil.value = parsed.summaryUntil
+      if (Array.isArray(parsed.items)) legacy = parsed.items.filter(Boolean)
     }
   } catch {
     // ignore
   }
+  memoryItems.value = legacy
+  migrateLegacyOnce(legacy).then(refreshMemoryItems)
+}
+
+// 切换项目时重新加载作用域内的记忆 | Scope memory to a project ('' = global only)
+export const setMemoryProject = (id) => {
+  loadMemory()
+  const next = id && id !== 'new' ? String(id) : ''
+  if (next === projectId) return
+  projectId = next
+  refreshMemoryItems()
 }
 
+export const getMemoryProject = () => projectId
+
 const persist = () => {
   try {
-    const payload = {
-      summary: memorySummary.value || '',
-      items: memoryItems.value || []
-    }
+    const payload = { summary: memorySummary.value || '', summaryUntil: memorySummaryUntil.value || 0 }
+    if (!rustOwned) payload.items = memoryItems.value || []
     localStorage.setItem(STORAGE_KEY, JSON.stringify(payload))
   } catch {
     // ignore
@@ -63,24 +106,48 @@ const makeId = () => {
   return uuid || `mem_${now()}_${Math.random().toString(16).slice(2)}`
 }
 
-export const setMemorySummary = (text) => {
+export const setMemorySummary = (text, until) => {
   loadMemory()
   memorySummary.value = normalizeText(text)
+  if (Number.isInteger(until)) memorySummaryUntil.value = until
   scheduleSave()
 }
 
-export const clearMemory = () => {
+// 对话清空后从头累积摘要游标（摘要本身保留）| Restart the summary cursor for a new conversation
+export const resetMemorySummaryCursor = () => {
+  loadMemory()
+  memorySummaryUntil.value = 0
+  scheduleSave()
+}
+
+export const clearMemory = async () => {
   loadMemory()
   memorySummary.value = ''
-  memoryItems.value = []
+  memorySummaryUntil.value = 0
   scheduleSave()
+  if (rustOwned) {
+    await tauriInvoke('memory_clear', { projectId }).catch(() => {})
+    await refreshMemoryItems()
+    return
+  }
+  memoryItems.value = []
 }
 
-export const addMemoryItem = (text, { importance = 0.6, tags = [], source = 'chat' } = {}) => {
+export const addMemoryItem = async (text, { importance = 0.6, tags = [], source = 'chat' } = {}) => {
   loadMemory()
   const content = normalizeText(text)
   if (!content) return null
 
+  if (rustOwned) {
+    try {
+      const item = await tauriInvoke('memory_add', { content, importance, projectId, tags, source, dedupThreshold: 0.8 })
+      await refreshMemoryItems()
+      return item?.id || null
+    } catch {
+      return null
+    }
+  }
+
   const existing = memoryItems.value.find(i => normalizeText(i?.content) === content)
   if (existing) {
     existing.updatedAt = now()
@@ -104,6 +171,68 @@ export const addMemoryItem = (text, { importance = 0.6, tags = [], source = 'cha
   return item.id
 }
 
+export const updateMemoryItem = async (id, { content, importance, tags } = {}) => {
+  loadMemory()
+  if (!id) return false
+  if (rustOwned) {
+    try {
+      await tauriInvoke('memory_update', { id, content, importance, tags })
+      await refreshMemoryItems()
+      return true
+    } catch {
+      return false
+    }
+  }
+  const item = memoryItems.value.find(i => i?.id === id)
+  if (!item) return false
+  if (content !== undefined) item.content = normalizeText(content)
+  if (importance !== undefined) item.importance = Math.max(0, Math.min(1, Number(importance) || 0))
+  if (Array.isArray(tags)) item.tags = tags.filter(Boolean)
+  item.updatedAt = now()
+  scheduleSave()
+  return true
+}
+
+// 置顶的记忆每轮都会放进上下文的置顶段 | Pinned memories go into every prompt
+export const setMemoryPinned = async (id, pinned) => {
+  loadMemory()
+  if (!id) return false
+  if (rustOwned) {
+    try {
+      const touched = await tauriInvoke('memory_pin', { ids: [id], pinned: !!pinned })
+      await refreshMemoryItems()
+      return touched > 0
+    } catch {
+      return false
+    }
+  }
+  const item = memoryItems.value.find(i => i?.id === id)
+  if (!item) return false
+  item.pin = pinned ? 1 : undefined
+  item.updatedAt = now()
+  memoryItems.value = memoryItems.value.slice()
+  scheduleSave()
+  return true
+}
+
+export const removeMemoryItem = async (id) => {
+  loadMemory()
+  if (!id) return false
+  if (rustOwned) {
+    try {
+      const removed = await tauriInvoke('memory_delete', { id })
+      await refreshMemoryItems()
+      return !!removed
+    } catch {
+      return false
+    }
+  }
+  const before = memoryItems.value.length
+  memoryItems.value = memoryItems.value.filter(i => i?.id !== id)
+  scheduleSave()
+  return memoryItems.value.length !== before
+}
+
 const isCjk = (ch) => /[\u4E00-\u9FFF]/.test(ch)
 
 const tokenize = (text) => {